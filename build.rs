@@ -0,0 +1,16 @@
+fn main() {
+    // The header of the C API is only written when it's built
+    #[cfg(feature = "capi")]
+    {
+        println!("cargo:rerun-if-changed=src/capi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        let dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", dir)).unwrap();
+        cbindgen::Builder::new()
+            .with_src(format!("{}/src/capi.rs", dir))
+            .with_config(config)
+            .generate()
+            .expect("Cannot generate the C header")
+            .write_to_file(format!("{}/include/pegasus_astro.h", dir));
+    }
+}
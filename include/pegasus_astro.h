@@ -0,0 +1,91 @@
+/* Generated by cbindgen from src/capi.rs with the capi feature, do not edit */
+
+#ifndef PEGASUS_ASTRO_H
+#define PEGASUS_ASTRO_H
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Outcome of a call, 0 on success
+typedef enum PegasusStatus {
+  PEGASUS_STATUS_OK = 0,
+  // A `NULL` pointer or a string that isn't UTF-8
+  PEGASUS_STATUS_INVALID_ARGUMENT = -1,
+  PEGASUS_STATUS_SERIAL = -2,
+  PEGASUS_STATUS_PARSE = -3,
+  PEGASUS_STATUS_PROTOCOL = -4,
+  PEGASUS_STATUS_VALIDATION = -5,
+  PEGASUS_STATUS_NOT_CONNECTED = -6,
+  PEGASUS_STATUS_UNSUPPORTED = -7,
+  PEGASUS_STATUS_PERMISSION_DENIED = -8,
+  PEGASUS_STATUS_READ_ONLY = -9,
+  PEGASUS_STATUS_BROKER = -10,
+  // The port is used by another program
+  PEGASUS_STATUS_DEVICE_BUSY = -11,
+} PegasusStatus;
+
+// A PPBA opened with [`pegasus_ppba_open`], opaque to C
+typedef struct PegasusPpba PegasusPpba;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Version of the library, static, not to be freed
+const char *pegasus_version(void);
+
+// Message of the last error of the calling thread, empty if none. Valid
+// until the next failing call of the thread, not to be freed.
+const char *pegasus_last_error(void);
+
+// Connect to the PPBA on `address` (a serial port, `tcp://host:port` or
+// `rfc2217://host:port`), to the first one plugged if `address` is `NULL`.
+// Returns `NULL` on failure.
+//
+// # Safety
+//
+// `address` is `NULL` or a valid NUL terminated string.
+struct PegasusPpba *pegasus_ppba_open(const char *address);
+
+// Disconnect from the PPBA and release it, `NULL` is ignored
+//
+// # Safety
+//
+// `ppba` is `NULL` or was returned by [`pegasus_ppba_open`] and not closed yet.
+void pegasus_ppba_close(struct PegasusPpba *ppba);
+
+// Read every property from the device, returns them as a JSON object, the
+// same as published by the MQTT driver on `devices/{id}`, or `NULL` on
+// failure. The string must be freed with [`pegasus_string_free`].
+//
+// # Safety
+//
+// `ppba` was returned by [`pegasus_ppba_open`] and not closed yet.
+char *pegasus_ppba_state_json(struct PegasusPpba *ppba);
+
+// Change a property, with the names and values of the MQTT driver, e.g.
+// `dew1_power_pct` and `60`
+//
+// # Safety
+//
+// `ppba` was returned by [`pegasus_ppba_open`] and not closed yet, `name`
+// and `value` are valid NUL terminated strings.
+enum PegasusStatus pegasus_ppba_set_property(struct PegasusPpba *ppba,
+                                             const char *name,
+                                             const char *value);
+
+// Release a string returned by the library, `NULL` is ignored
+//
+// # Safety
+//
+// `s` is `NULL` or was returned by the library and not freed yet.
+void pegasus_string_free(char *s);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* PEGASUS_ASTRO_H */
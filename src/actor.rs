@@ -0,0 +1,171 @@
+//! Tasks owning the devices, shared by the binaries so a device is never
+//! driven by two frontends at once.
+use crate::device::AstronomicalDevice;
+use crate::error::PegasusError;
+use log::debug;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, Notify};
+use uuid::Uuid;
+
+/// Requests queued for a device before senders have to wait
+const QUEUE_SIZE: usize = 32;
+
+/// Future borrowing the device for as long as it runs
+pub type DeviceFuture<'a, R> = Pin<Box<dyn Future<Output = R> + Send + 'a>>;
+
+type Job<D> = Box<dyn for<'a> FnOnce(&'a mut D) -> DeviceFuture<'a, ()> + Send>;
+
+/// Handle on a task owning a device, and so its serial port.
+///
+/// Polling, self tests and the updates of every frontend (MQTT, HTTP, TCP,
+/// INDI and Alpaca) send their work to the task and wait for the answer on a
+/// oneshot channel, requests are served one at a time in the order they were
+/// sent so nobody holds a lock across the serial I/O.
+/// Urgent requests, the changes asked by the users, skip the queue: they wait
+/// at most for the request being served, e.g. a single poll.
+/// The task and the device are dropped with the last handle.
+pub struct DeviceHandle<D> {
+    id: Uuid,
+    name: String,
+    address: String,
+    tx: mpsc::Sender<Job<D>>,
+    urgent_tx: mpsc::Sender<Job<D>>,
+    /// Aborts the request being served
+    reset: Arc<Notify>,
+}
+
+impl<D> Clone for DeviceHandle<D> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id,
+            name: self.name.clone(),
+            address: self.address.clone(),
+            tx: self.tx.clone(),
+            urgent_tx: self.urgent_tx.clone(),
+            reset: Arc::clone(&self.reset),
+        }
+    }
+}
+
+impl<D: AstronomicalDevice + Send + 'static> DeviceHandle<D> {
+    /// Move the device in its own task.
+    pub fn spawn(mut device: D) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Job<D>>(QUEUE_SIZE);
+        let (urgent_tx, mut urgent_rx) = mpsc::channel::<Job<D>>(QUEUE_SIZE);
+        let handle = Self {
+            id: device.get_id(),
+            name: device.get_name().clone(),
+            address: device.get_address().clone(),
+            tx,
+            urgent_tx,
+            reset: Arc::default(),
+        };
+
+        let reset = Arc::clone(&handle.reset);
+        tokio::spawn(async move {
+            loop {
+                // Both channels are closed together, with the last handle
+                let job = tokio::select! {
+                    biased;
+                    Some(job) = urgent_rx.recv() => job,
+                    Some(job) = rx.recv() => job,
+                    else => break,
+                };
+                // Dropping the job drops the I/O it was stuck on
+                let aborted = tokio::select! {
+                    _ = job(&mut device) => false,
+                    _ = reset.notified() => true,
+                };
+                if aborted {
+                    debug!(
+                        "Request to {} on {} aborted",
+                        device.get_name(),
+                        device.get_address()
+                    );
+                }
+            }
+            debug!(
+                "Releasing {} on {}",
+                device.get_name(),
+                device.get_address()
+            );
+        });
+        handle
+    }
+}
+
+impl<D: Send + 'static> DeviceHandle<D> {
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn address(&self) -> &String {
+        &self.address
+    }
+
+    /// Abort the request being served, e.g. a read stuck on a USB adapter that
+    /// stopped answering, its caller gets a [`PegasusError::Serial`] error.
+    /// Nothing happens if no request is being served.
+    pub fn reset(&self) {
+        self.reset.notify_waiters();
+    }
+
+    /// Run `f` on the device once the requests sent before are served and
+    /// return its result, fails with [`PegasusError::NotConnected`] only if
+    /// the device task is gone and with [`PegasusError::Serial`] if the
+    /// request was aborted by a [`reset`](Self::reset).
+    pub async fn call<R, F>(&self, f: F) -> Result<R, PegasusError>
+    where
+        R: Send + 'static,
+        F: for<'a> FnOnce(&'a mut D) -> DeviceFuture<'a, R> + Send + 'static,
+    {
+        self.send(&self.tx, f).await
+    }
+
+    /// Same as [`call`](Self::call) but served before the requests already
+    /// queued, for the changes asked by the users.
+    pub async fn call_urgent<R, F>(&self, f: F) -> Result<R, PegasusError>
+    where
+        R: Send + 'static,
+        F: for<'a> FnOnce(&'a mut D) -> DeviceFuture<'a, R> + Send + 'static,
+    {
+        self.send(&self.urgent_tx, f).await
+    }
+
+    async fn send<R, F>(&self, queue: &mpsc::Sender<Job<D>>, f: F) -> Result<R, PegasusError>
+    where
+        R: Send + 'static,
+        F: for<'a> FnOnce(&'a mut D) -> DeviceFuture<'a, R> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job<D> = Box::new(move |device| {
+            Box::pin(async move {
+                // The caller may have given up waiting, nothing to do then
+                let _ = tx.send(f(device).await);
+            })
+        });
+
+        let gone = || {
+            debug!("{} is not served anymore", self.name);
+            PegasusError::NotConnected
+        };
+        queue.send(job).await.map_err(|_| gone())?;
+        rx.await.map_err(|_| {
+            // The task is alive, it dropped the job
+            if !queue.is_closed() {
+                return PegasusError::Serial(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("The request to {} was aborted", self.name),
+                ));
+            }
+            gone()
+        })
+    }
+}
@@ -0,0 +1,218 @@
+use crate::error::PegasusError;
+use astrotools::properties::{Permission, Property};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
+
+/// State of charge under which a battery is low, in %
+pub const DEFAULT_LOW_SOC: f32 = 20.0;
+/// Currents below this are too small to estimate a runtime, in A
+const MIN_RUNTIME_AMPS: f32 = 0.05;
+
+/// Chemistry of a 12V battery, each with its own voltage curve
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Chemistry {
+    /// 4 LiFePO4 cells, the usual field battery
+    #[serde(rename = "lifepo4")]
+    LiFePo4,
+    /// Flooded, AGM or gel lead acid
+    LeadAcid,
+    /// 3 Li-ion cells (NMC)
+    LiIon,
+}
+
+impl Chemistry {
+    /// State of charge in % for decreasing voltages
+    fn curve(&self) -> &'static [(f32, f32)] {
+        match self {
+            Self::LiFePo4 => &[
+                (13.6, 100.0),
+                (13.4, 99.0),
+                (13.3, 90.0),
+                (13.2, 70.0),
+                (13.1, 40.0),
+                (13.0, 30.0),
+                (12.9, 20.0),
+                (12.8, 17.0),
+                (12.5, 14.0),
+                (12.0, 9.0),
+                (10.0, 0.0),
+            ],
+            Self::LeadAcid => &[
+                (12.7, 100.0),
+                (12.5, 90.0),
+                (12.42, 80.0),
+                (12.32, 70.0),
+                (12.2, 60.0),
+                (12.06, 50.0),
+                (11.9, 40.0),
+                (11.75, 30.0),
+                (11.58, 20.0),
+                (11.31, 10.0),
+                (10.5, 0.0),
+            ],
+            Self::LiIon => &[
+                (12.6, 100.0),
+                (12.3, 90.0),
+                (12.0, 75.0),
+                (11.7, 60.0),
+                (11.4, 40.0),
+                (11.1, 20.0),
+                (10.8, 10.0),
+                (9.0, 0.0),
+            ],
+        }
+    }
+}
+
+/// Battery powering a device, from the `[batteries]` tables of the config file
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BatteryConfig {
+    pub chemistry: Chemistry,
+    /// Nominal capacity in Ah
+    pub capacity_ah: f32,
+    /// `[volts, %]` pairs replacing the curve of the chemistry
+    #[serde(default)]
+    pub curve: Option<Vec<(f32, f32)>>,
+    /// State of charge in % under which the battery is low
+    #[serde(default = "default_low_soc")]
+    pub low_soc: f32,
+}
+
+fn default_low_soc() -> f32 {
+    DEFAULT_LOW_SOC
+}
+
+impl BatteryConfig {
+    pub fn validate(&self) -> Result<(), PegasusError> {
+        let invalid = |reason: String| Err(PegasusError::Validation(reason));
+        if self.capacity_ah.is_nan() || self.capacity_ah <= 0.0 {
+            return invalid(format!(
+                "Battery capacity must be positive: {}",
+                self.capacity_ah
+            ));
+        }
+        if !(0.0..=100.0).contains(&self.low_soc) {
+            return invalid(format!(
+                "Low battery threshold must be between 0 and 100 %: {}",
+                self.low_soc
+            ));
+        }
+        if let Some(curve) = &self.curve {
+            if curve.len() < 2 {
+                return invalid("Battery curve needs at least 2 points".to_string());
+            }
+            if curve.iter().any(|(_, pct)| !(0.0..=100.0).contains(pct)) {
+                return invalid("Battery curve percentages must be between 0 and 100".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    /// State of charge in % at `volts`, interpolated on the voltage curve
+    pub fn soc(&self, volts: f32) -> f32 {
+        let mut curve = match &self.curve {
+            Some(curve) => curve.clone(),
+            None => self.chemistry.curve().to_vec(),
+        };
+        curve.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let (first, last) = (curve[0], curve[curve.len() - 1]);
+        if volts >= first.0 {
+            return first.1;
+        }
+        if volts <= last.0 {
+            return last.1;
+        }
+        for pair in curve.windows(2) {
+            let ((high_v, high_pct), (low_v, low_pct)) = (pair[0], pair[1]);
+            if volts >= low_v {
+                return low_pct + (volts - low_v) / (high_v - low_v) * (high_pct - low_pct);
+            }
+        }
+        last.1
+    }
+}
+
+/// Estimated charge of the battery powering a device.
+///
+/// The state of charge is read on the voltage curve of the battery, so it's
+/// only accurate when the draw is low. Flattened in the serialized device as
+/// the read only `battery_soc` (%), `battery_remaining_ah`, `battery_runtime`
+/// (hours at the average current, `null` when nothing is drawn) and
+/// `battery_low` properties.
+#[derive(Clone, Debug)]
+pub struct BatteryModel {
+    config: BatteryConfig,
+    soc: Option<f32>,
+    runtime: Option<f32>,
+}
+
+impl BatteryModel {
+    pub fn new(config: BatteryConfig) -> Result<Self, PegasusError> {
+        config.validate()?;
+        Ok(Self {
+            config,
+            soc: None,
+            runtime: None,
+        })
+    }
+
+    pub fn config(&self) -> &BatteryConfig {
+        &self.config
+    }
+
+    /// Estimate the charge from the last readings
+    pub fn update(&mut self, volts: f32, average_amps: f32) {
+        let soc = self.config.soc(volts);
+        self.soc = Some(soc);
+        self.runtime = (average_amps >= MIN_RUNTIME_AMPS)
+            .then(|| self.config.capacity_ah * soc / 100.0 / average_amps);
+    }
+
+    /// State of charge in %, `None` before the first reading
+    pub fn soc(&self) -> Option<f32> {
+        self.soc
+    }
+
+    /// Ah left in the battery
+    pub fn remaining_ah(&self) -> Option<f32> {
+        self.soc.map(|soc| self.config.capacity_ah * soc / 100.0)
+    }
+
+    /// Hours left at the average current
+    pub fn runtime(&self) -> Option<f32> {
+        self.runtime
+    }
+
+    pub fn is_low(&self) -> bool {
+        self.soc.is_some_and(|soc| soc < self.config.low_soc)
+    }
+}
+
+impl Serialize for BatteryModel {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        let Some(soc) = self.soc else {
+            return map.end();
+        };
+        map.serialize_entry("battery_soc", &Property::new(soc, Permission::ReadOnly))?;
+        map.serialize_entry(
+            "battery_remaining_ah",
+            &Property::new(
+                self.remaining_ah().unwrap_or_default(),
+                Permission::ReadOnly,
+            ),
+        )?;
+        map.serialize_entry(
+            "battery_runtime",
+            &Property::new(self.runtime, Permission::ReadOnly),
+        )?;
+        map.serialize_entry(
+            "battery_low",
+            &Property::new(self.is_low(), Permission::ReadOnly),
+        )?;
+        map.end()
+    }
+}
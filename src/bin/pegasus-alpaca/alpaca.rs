@@ -0,0 +1,236 @@
+use pegasus_astro::error::PegasusError;
+use pegasus_astro::ppba::{AdjustableVoltage, DewChannel, PowerBoxSnapshot, Setting};
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Alpaca error numbers, see the ASCOM Alpaca API reference
+pub const NOT_IMPLEMENTED: i32 = 0x400;
+pub const INVALID_VALUE: i32 = 0x401;
+pub const NOT_CONNECTED: i32 = 0x407;
+pub const INVALID_OPERATION: i32 = 0x40B;
+
+/// Outcome of a device method that isn't a plain value
+#[derive(Debug)]
+pub enum MethodError {
+    /// Reported with HTTP 200 and the given Alpaca error number
+    Alpaca(i32, String),
+    /// Malformed request (missing or invalid parameter), reported with HTTP 400
+    BadRequest(String),
+}
+
+impl From<PegasusError> for MethodError {
+    /// Values the device refused are invalid, anything wrong with the link means it's gone
+    fn from(e: PegasusError) -> Self {
+        let number = match e {
+            PegasusError::Protocol(_) | PegasusError::Validation(_) => INVALID_VALUE,
+            PegasusError::Unsupported(_) => NOT_IMPLEMENTED,
+            PegasusError::ReadOnly(_) | PegasusError::PermissionDenied(_) => INVALID_OPERATION,
+            PegasusError::Serial(_)
+            | PegasusError::Parse(_)
+            | PegasusError::NotConnected
+            | PegasusError::DeviceBusy(_)
+            | PegasusError::Broker(_) => NOT_CONNECTED,
+        };
+        MethodError::Alpaca(number, e.to_string())
+    }
+}
+
+pub type MethodResult = Result<Option<Value>, MethodError>;
+
+pub fn not_implemented(method: &str) -> MethodError {
+    MethodError::Alpaca(NOT_IMPLEMENTED, format!("{} is not implemented", method))
+}
+
+/// Request parameters, Alpaca parameter names are case insensitive.
+#[derive(Debug, Default)]
+pub struct Params(HashMap<String, String>);
+
+impl Params {
+    pub fn new(raw: HashMap<String, String>) -> Self {
+        Self(
+            raw.into_iter()
+                .map(|(k, v)| (k.to_lowercase(), v))
+                .collect(),
+        )
+    }
+
+    pub fn client_transaction_id(&self) -> u32 {
+        self.0
+            .get("clienttransactionid")
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Parse the required parameter `name`, a missing or malformed one is a bad request
+    pub fn parse<T: std::str::FromStr>(&self, name: &str) -> Result<T, MethodError> {
+        self.0
+            .get(&name.to_lowercase())
+            .ok_or_else(|| MethodError::BadRequest(format!("Missing parameter {}", name)))?
+            .trim()
+            .parse()
+            .map_err(|_| MethodError::BadRequest(format!("Invalid value for {}", name)))
+    }
+
+    /// Alpaca booleans are sent as `True`/`False`
+    pub fn parse_bool(&self, name: &str) -> Result<bool, MethodError> {
+        match self.parse::<String>(name)?.to_lowercase().as_str() {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(MethodError::BadRequest(format!(
+                "Invalid value for {}",
+                name
+            ))),
+        }
+    }
+}
+
+/// Envelope of every Alpaca response
+#[derive(Debug, Serialize)]
+pub struct Response {
+    #[serde(rename = "Value", skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+    #[serde(rename = "ClientTransactionID")]
+    pub client_transaction_id: u32,
+    #[serde(rename = "ServerTransactionID")]
+    pub server_transaction_id: u32,
+    #[serde(rename = "ErrorNumber")]
+    pub error_number: i32,
+    #[serde(rename = "ErrorMessage")]
+    pub error_message: String,
+}
+
+/// A PPBA output or reading exposed as an Alpaca switch
+pub struct SwitchDef {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub min: f64,
+    pub max: f64,
+    pub step: f64,
+    /// Setting applied when writing the switch, None for read only switches
+    pub setting: Option<fn(f64) -> Result<Setting, String>>,
+    pub value: fn(&PowerBoxSnapshot) -> f64,
+}
+
+fn flag(on: bool) -> f64 {
+    if on {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+pub const SWITCHES: [SwitchDef; 11] = [
+    SwitchDef {
+        name: "Quad 12V output",
+        description: "The four 12V power outputs",
+        min: 0.0,
+        max: 1.0,
+        step: 1.0,
+        setting: Some(|v| Ok(Setting::Quadport(v > 0.0))),
+        value: |s| flag(s.quadport_status),
+    },
+    SwitchDef {
+        name: "Adjustable output",
+        description: "Adjustable voltage power output",
+        min: 0.0,
+        max: 1.0,
+        step: 1.0,
+        setting: Some(|v| Ok(Setting::AdjOutputStatus(v > 0.0))),
+        value: |s| flag(s.adj_output_status),
+    },
+    SwitchDef {
+        name: "Adjustable output voltage",
+        description: "Voltage of the adjustable output, one of 3, 5, 8, 9 or 12V",
+        min: 3.0,
+        max: 12.0,
+        step: 1.0,
+        setting: Some(|v| {
+            AdjustableVoltage::try_from(v as u8)
+                .map(Setting::AdjOutput)
+                .map_err(|e| e.to_string())
+        }),
+        value: |s| s.adj_output as f64,
+    },
+    SwitchDef {
+        name: "Dew heater A",
+        description: "PWM duty cycle of dew heater A",
+        min: 0.0,
+        max: 255.0,
+        step: 1.0,
+        setting: Some(|v| Ok(Setting::DewPower(DewChannel::A, v as u8))),
+        value: |s| s.dew1_power as f64,
+    },
+    SwitchDef {
+        name: "Dew heater B",
+        description: "PWM duty cycle of dew heater B",
+        min: 0.0,
+        max: 255.0,
+        step: 1.0,
+        setting: Some(|v| Ok(Setting::DewPower(DewChannel::B, v as u8))),
+        value: |s| s.dew2_power as f64,
+    },
+    SwitchDef {
+        name: "Auto dew",
+        description: "Let the device drive the dew heaters from the dew point",
+        min: 0.0,
+        max: 1.0,
+        step: 1.0,
+        setting: Some(|v| Ok(Setting::Autodew(v > 0.0))),
+        value: |s| flag(s.autodew),
+    },
+    SwitchDef {
+        name: "Input voltage",
+        description: "Input voltage (V)",
+        min: 0.0,
+        max: 20.0,
+        step: 0.01,
+        setting: None,
+        value: |s| s.input_voltage as f64,
+    },
+    SwitchDef {
+        name: "Total current",
+        description: "Total current drawn (A)",
+        min: 0.0,
+        max: 20.0,
+        step: 0.01,
+        setting: None,
+        value: |s| s.total_current as f64,
+    },
+    SwitchDef {
+        name: "12V outputs current",
+        description: "Current drawn by the 12V outputs (A)",
+        min: 0.0,
+        max: 20.0,
+        step: 0.01,
+        setting: None,
+        value: |s| s.current_12v_output as f64,
+    },
+    SwitchDef {
+        name: "Dew heater A current",
+        description: "Current drawn by dew heater A (A)",
+        min: 0.0,
+        max: 5.0,
+        step: 0.01,
+        setting: None,
+        value: |s| s.dew1_current as f64,
+    },
+    SwitchDef {
+        name: "Dew heater B current",
+        description: "Current drawn by dew heater B (A)",
+        min: 0.0,
+        max: 5.0,
+        step: 0.01,
+        setting: None,
+        value: |s| s.dew2_current as f64,
+    },
+];
+
+/// Switch selected by the `Id` parameter of a request
+pub fn switch(params: &Params) -> Result<&'static SwitchDef, MethodError> {
+    let id: i64 = params.parse("Id")?;
+    usize::try_from(id)
+        .ok()
+        .and_then(|id| SWITCHES.get(id))
+        .ok_or_else(|| MethodError::Alpaca(INVALID_VALUE, format!("Invalid switch id {}", id)))
+}
@@ -0,0 +1,530 @@
+use axum::extract::{Form, Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use clap::Parser;
+use env_logger::Env;
+use log::{debug, error, info, warn};
+use pegasus_astro::actor::DeviceHandle;
+use pegasus_astro::device::{AstronomicalDevice, PegasusDevice};
+use pegasus_astro::discovery::{self, DeviceFamily};
+use pegasus_astro::ppba::PegasusPowerBox;
+use pegasus_astro::transport::{self, SerialSettings};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::RwLock;
+
+mod alpaca;
+use alpaca::{MethodError, MethodResult, Params, INVALID_VALUE, NOT_CONNECTED, SWITCHES};
+
+/// Port the Alpaca discovery protocol listens on
+const DISCOVERY_PORT: u16 = 32227;
+const DISCOVERY_MESSAGE: &[u8] = b"alpacadiscovery1";
+
+#[derive(Debug, Parser)]
+#[command(version, about = "ASCOM Alpaca server for Pegasus Astro powerboxes", long_about = None)]
+struct Cli {
+    /// HTTP port Alpaca clients connect to
+    #[arg(long, env = "PEGASUS_ALPACA_PORT", default_value_t = 11111)]
+    port: u16,
+
+    /// Milliseconds between two readings of the devices
+    #[arg(long, env = "PEGASUS_ALPACA_POLL_MS", default_value_t = 1000)]
+    poll_ms: u64,
+
+    /// Don't answer Alpaca discovery requests
+    #[arg(long, env = "PEGASUS_ALPACA_NO_DISCOVERY")]
+    no_discovery: bool,
+
+    /// TOML file with the serial link of the devices in `[serial."{serial number or port}"]`
+    /// tables, e.g. the config file of the MQTT driver
+    #[arg(long, env = "PEGASUS_SERIAL_CONFIG")]
+    serial_config: Option<PathBuf>,
+}
+
+/// A PPBA and the time of its last successful reading
+struct Ppba {
+    device: DeviceHandle<PegasusPowerBox>,
+    last_update: RwLock<Option<Instant>>,
+}
+
+struct AppState {
+    devices: Vec<Ppba>,
+    transaction_id: AtomicU32,
+}
+
+type Shared = Arc<AppState>;
+
+/// Serial links of `--serial-config`, the defaults if not given
+fn serial_settings(path: Option<&std::path::Path>) -> SerialSettings {
+    match path.map(SerialSettings::from_file).transpose() {
+        Ok(settings) => settings.unwrap_or_default(),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    }
+}
+
+async fn discover(serial: &SerialSettings) -> Vec<Ppba> {
+    let mut devices = Vec::new();
+
+    let found = discovery::find(DeviceFamily::Ppba).unwrap_or_else(|e| {
+        error!("{}", e);
+        Vec::new()
+    });
+    for dev in found {
+        let mut device_name = String::from("PegausPowerBoxAdvanced");
+        debug!("name: {}", dev.port);
+        debug!("info: {:?}", dev);
+
+        if let Some(serial) = &dev.serial_number {
+            device_name = device_name + "-" + serial
+        }
+        let config = serial.resolve(
+            dev.serial_number.as_deref(),
+            &dev.port,
+            transport::DEFAULT_BAUD,
+        );
+        match PegasusPowerBox::try_with_config(&device_name, &dev.port, &config).await {
+            Ok(mut device) => {
+                if let Some(serial) = &dev.serial_number {
+                    device.set_serial_number(serial);
+                }
+                devices.push(Ppba {
+                    device: DeviceHandle::spawn(device),
+                    last_update: RwLock::new(None),
+                })
+            }
+            Err(e) => error!("Cannot start communication with {}: {}", &device_name, e),
+        }
+    }
+    devices
+}
+
+/// Periodically read the devices, reconnecting the unreachable ones.
+fn spawn_polling(state: Shared, every: Duration) {
+    tokio::spawn(async move {
+        loop {
+            for ppba in state.devices.iter() {
+                let polled = ppba
+                    .device
+                    .call(|d| {
+                        Box::pin(async move {
+                            if !d.is_connected() {
+                                if let Err(e) = d.reconnect().await {
+                                    debug!("{} still unreachable: {}", d.get_name(), e);
+                                    return false;
+                                }
+                            }
+                            d.fetch_props().await;
+                            d.is_connected()
+                        })
+                    })
+                    .await;
+                if polled.unwrap_or(false) {
+                    *ppba.last_update.write().await = Some(Instant::now());
+                }
+            }
+            tokio::time::sleep(every).await;
+        }
+    });
+}
+
+/// Answer the Alpaca discovery requests broadcast on the local network.
+async fn serve_discovery(alpaca_port: u16) {
+    let socket = match UdpSocket::bind(("0.0.0.0", DISCOVERY_PORT)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!(
+                "Cannot listen for Alpaca discovery on port {}: {}",
+                DISCOVERY_PORT, e
+            );
+            return;
+        }
+    };
+    let reply = json!({ "AlpacaPort": alpaca_port }).to_string();
+    let mut buf = [0u8; 64];
+
+    loop {
+        match socket.recv_from(&mut buf).await {
+            Ok((len, peer)) if buf[..len].starts_with(DISCOVERY_MESSAGE) => {
+                debug!("Alpaca discovery request from {}", peer);
+                if let Err(e) = socket.send_to(reply.as_bytes(), peer).await {
+                    warn!("Cannot answer discovery request from {}: {}", peer, e);
+                }
+            }
+            Ok(_) => (),
+            Err(e) => warn!("Cannot read discovery request: {}", e),
+        }
+    }
+}
+
+fn reply(state: &AppState, params: &Params, result: MethodResult) -> Response {
+    let (value, error_number, error_message) = match result {
+        Ok(value) => (value, 0, String::new()),
+        Err(MethodError::Alpaca(number, message)) => (None, number, message),
+        Err(MethodError::BadRequest(message)) => {
+            return (StatusCode::BAD_REQUEST, message).into_response()
+        }
+    };
+    Json(alpaca::Response {
+        value,
+        client_transaction_id: params.client_transaction_id(),
+        server_transaction_id: state.transaction_id.fetch_add(1, Ordering::Relaxed) + 1,
+        error_number,
+        error_message,
+    })
+    .into_response()
+}
+
+fn value(v: impl Into<Value>) -> MethodResult {
+    Ok(Some(v.into()))
+}
+
+async fn api_versions(
+    State(state): State<Shared>,
+    Query(raw): Query<HashMap<String, String>>,
+) -> Response {
+    let params = Params::new(raw);
+    reply(&state, &params, value(json!([1])))
+}
+
+async fn description(
+    State(state): State<Shared>,
+    Query(raw): Query<HashMap<String, String>>,
+) -> Response {
+    let params = Params::new(raw);
+    let description = json!({
+        "ServerName": "pegasus-alpaca",
+        "Manufacturer": "devDucks",
+        "ManufacturerVersion": env!("CARGO_PKG_VERSION"),
+        "Location": "",
+    });
+    reply(&state, &params, value(description))
+}
+
+async fn configured_devices(
+    State(state): State<Shared>,
+    Query(raw): Query<HashMap<String, String>>,
+) -> Response {
+    let params = Params::new(raw);
+    let mut devices = Vec::new();
+    for (number, ppba) in state.devices.iter().enumerate() {
+        let name = ppba.device.name();
+        for device_type in ["Switch", "ObservingConditions"] {
+            devices.push(json!({
+                "DeviceName": name,
+                "DeviceType": device_type,
+                "DeviceNumber": number,
+                "UniqueID": format!("{}-{}", name, device_type.to_lowercase()),
+            }));
+        }
+    }
+    reply(&state, &params, value(devices))
+}
+
+async fn device_get(
+    State(state): State<Shared>,
+    Path((device_type, number, method)): Path<(String, usize, String)>,
+    Query(raw): Query<HashMap<String, String>>,
+) -> Response {
+    let params = Params::new(raw);
+    let result = dispatch(&state, &device_type, number, &method, &params, false).await;
+    reply(&state, &params, result)
+}
+
+async fn device_put(
+    State(state): State<Shared>,
+    Path((device_type, number, method)): Path<(String, usize, String)>,
+    Form(raw): Form<HashMap<String, String>>,
+) -> Response {
+    let params = Params::new(raw);
+    let result = dispatch(&state, &device_type, number, &method, &params, true).await;
+    reply(&state, &params, result)
+}
+
+async fn dispatch(
+    state: &AppState,
+    device_type: &str,
+    number: usize,
+    method: &str,
+    params: &Params,
+    put: bool,
+) -> MethodResult {
+    let Some(ppba) = state.devices.get(number) else {
+        return Err(MethodError::BadRequest(format!(
+            "No {} device number {}",
+            device_type, number
+        )));
+    };
+    let interface_version = match device_type {
+        "switch" => 2,
+        "observingconditions" => 1,
+        _ => {
+            return Err(MethodError::BadRequest(format!(
+                "Unsupported device type {}",
+                device_type
+            )))
+        }
+    };
+
+    match (method, put) {
+        ("connected", false) => {
+            let connected = ppba
+                .device
+                .call(|d| Box::pin(async move { d.is_connected() }))
+                .await?;
+            return value(connected);
+        }
+        ("connected", true) => {
+            if params.parse_bool("Connected")? {
+                ppba.device
+                    .call_urgent(|d| {
+                        Box::pin(async move {
+                            if d.is_connected() {
+                                return Ok(());
+                            }
+                            d.reconnect().await
+                        })
+                    })
+                    .await??;
+            }
+            return Ok(None);
+        }
+        ("description", false) => return value("Pegasus Astro Pocket Powerbox Advance"),
+        ("driverinfo", false) => {
+            return value("pegasus-rs Alpaca driver for Pegasus Astro powerboxes")
+        }
+        ("driverversion", false) => return value(env!("CARGO_PKG_VERSION")),
+        ("interfaceversion", false) => return value(interface_version),
+        ("name", false) => return value(ppba.device.name().as_str()),
+        ("supportedactions", false) => return value(json!([])),
+        ("action" | "commandblind" | "commandbool" | "commandstring", true) => {
+            return Err(alpaca::not_implemented(method))
+        }
+        _ => (),
+    }
+
+    if device_type == "switch" {
+        switch(ppba, method, params, put).await
+    } else {
+        observing_conditions(ppba, method, params, put).await
+    }
+}
+
+async fn switch(ppba: &Ppba, method: &str, params: &Params, put: bool) -> MethodResult {
+    if method == "maxswitch" && !put {
+        return value(SWITCHES.len());
+    }
+    let def = alpaca::switch(params)?;
+
+    let new_value = match (method, put) {
+        ("canwrite", false) => return value(def.setting.is_some()),
+        ("getswitchname", false) => return value(def.name),
+        ("getswitchdescription", false) => return value(def.description),
+        ("minswitchvalue", false) => return value(def.min),
+        ("maxswitchvalue", false) => return value(def.max),
+        ("switchstep", false) => return value(def.step),
+        ("getswitch" | "getswitchvalue", false) => {
+            let (connected, snapshot) = ppba
+                .device
+                .call(|d| Box::pin(async move { (d.is_connected(), d.snapshot()) }))
+                .await?;
+            if !connected {
+                return Err(MethodError::Alpaca(
+                    NOT_CONNECTED,
+                    "Device not connected".into(),
+                ));
+            }
+            let current = (def.value)(&snapshot);
+            return if method == "getswitch" {
+                value(current > def.min)
+            } else {
+                value(current)
+            };
+        }
+        ("setswitch", true) => {
+            if params.parse_bool("State")? {
+                def.max
+            } else {
+                def.min
+            }
+        }
+        ("setswitchvalue", true) => params.parse::<f64>("Value")?,
+        ("setswitchname", true) => return Err(alpaca::not_implemented(method)),
+        _ => {
+            return Err(MethodError::BadRequest(format!(
+                "Unknown method {}",
+                method
+            )))
+        }
+    };
+
+    let Some(setting) = def.setting else {
+        return Err(alpaca::not_implemented(&format!("Writing {}", def.name)));
+    };
+    if !(def.min..=def.max).contains(&new_value) || new_value.fract() != 0.0 {
+        return Err(MethodError::Alpaca(
+            INVALID_VALUE,
+            format!("Invalid value {} for {}", new_value, def.name),
+        ));
+    }
+    ppba.device
+        .call_urgent(move |d| {
+            Box::pin(async move {
+                if !d.is_connected() {
+                    return Err(MethodError::Alpaca(
+                        NOT_CONNECTED,
+                        "Device not connected".into(),
+                    ));
+                }
+                let setting =
+                    setting(new_value).map_err(|e| MethodError::Alpaca(INVALID_VALUE, e))?;
+                d.apply(setting).await?;
+                Ok(None)
+            })
+        })
+        .await?
+}
+
+const SENSORS: [(&str, &str); 3] = [
+    (
+        "temperature",
+        "Ambient temperature (°C) from the PPBA probe",
+    ),
+    ("humidity", "Relative humidity (%) from the PPBA probe"),
+    ("dewpoint", "Dew point (°C) computed by the PPBA"),
+];
+
+async fn observing_conditions(
+    ppba: &Ppba,
+    method: &str,
+    params: &Params,
+    put: bool,
+) -> MethodResult {
+    match (method, put) {
+        ("averageperiod", false) => return value(0.0),
+        ("averageperiod", true) => {
+            let period: f64 = params.parse("AveragePeriod")?;
+            return if period == 0.0 {
+                Ok(None)
+            } else {
+                Err(MethodError::Alpaca(
+                    INVALID_VALUE,
+                    "Only instantaneous readings are supported".into(),
+                ))
+            };
+        }
+        ("refresh", true) => {
+            ppba.device
+                .call_urgent(|d| Box::pin(async move { d.fetch_props().await }))
+                .await?;
+            return Ok(None);
+        }
+        ("sensordescription", false) => {
+            let sensor = params.parse::<String>("SensorName")?.to_lowercase();
+            return match SENSORS.iter().find(|(name, _)| *name == sensor) {
+                Some((_, description)) => value(*description),
+                None => Err(alpaca::not_implemented(&sensor)),
+            };
+        }
+        ("timesincelastupdate", false) => {
+            let sensor = params.parse::<String>("SensorName")?.to_lowercase();
+            if !sensor.is_empty() && !SENSORS.iter().any(|(name, _)| *name == sensor) {
+                return Err(alpaca::not_implemented(&sensor));
+            }
+            return match *ppba.last_update.read().await {
+                Some(at) => value(at.elapsed().as_secs_f64()),
+                None => Err(MethodError::Alpaca(NOT_CONNECTED, "No reading yet".into())),
+            };
+        }
+        _ => (),
+    }
+
+    if put {
+        return Err(MethodError::BadRequest(format!(
+            "Unknown method {}",
+            method
+        )));
+    }
+    let (connected, snapshot) = ppba
+        .device
+        .call(|d| Box::pin(async move { (d.is_connected(), d.snapshot()) }))
+        .await?;
+    let reading = match method {
+        "temperature" => snapshot.temperature,
+        "humidity" => snapshot.humidity,
+        "dewpoint" => snapshot.dew_point,
+        "cloudcover" | "pressure" | "rainrate" | "skybrightness" | "skyquality"
+        | "skytemperature" | "starfwhm" | "winddirection" | "windgust" | "windspeed" => {
+            return Err(alpaca::not_implemented(method))
+        }
+        _ => {
+            return Err(MethodError::BadRequest(format!(
+                "Unknown method {}",
+                method
+            )))
+        }
+    };
+    if !connected {
+        return Err(MethodError::Alpaca(
+            NOT_CONNECTED,
+            "Device not connected".into(),
+        ));
+    }
+    value(reading as f64)
+}
+
+#[tokio::main]
+async fn main() {
+    let env = Env::default().filter_or("LS_LOG_LEVEL", "info");
+    env_logger::init_from_env(env);
+
+    let cli = Cli::parse();
+    let devices = discover(&serial_settings(cli.serial_config.as_deref())).await;
+
+    if devices.is_empty() {
+        warn!("No Pegasus device found on the system, exiting");
+        std::process::exit(0)
+    }
+
+    let state = Arc::new(AppState {
+        devices,
+        transaction_id: AtomicU32::new(0),
+    });
+    spawn_polling(Arc::clone(&state), Duration::from_millis(cli.poll_ms));
+    if !cli.no_discovery {
+        tokio::spawn(serve_discovery(cli.port));
+    }
+
+    let app = Router::new()
+        .route("/management/apiversions", get(api_versions))
+        .route("/management/v1/description", get(description))
+        .route("/management/v1/configureddevices", get(configured_devices))
+        .route(
+            "/api/v1/:device_type/:device_number/:method",
+            get(device_get).put(device_put),
+        )
+        .with_state(state);
+
+    let listener = match TcpListener::bind(("0.0.0.0", cli.port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Cannot listen on port {}: {}", cli.port, e);
+            std::process::exit(1)
+        }
+    };
+    info!("Alpaca server listening on port {}", cli.port);
+
+    if let Err(e) = axum::serve(listener, app).await {
+        error!("Alpaca server stopped: {}", e);
+        std::process::exit(1)
+    }
+}
@@ -0,0 +1,236 @@
+use clap::{Parser, Subcommand};
+use env_logger::Env;
+use log::debug;
+use pegasus_astro::device::AstronomicalDevice;
+use pegasus_astro::discovery::{self, DeviceFamily};
+use pegasus_astro::ppba::PegasusPowerBox;
+use pegasus_astro::trace::{self, Parsed};
+use pegasus_astro::transport::{self, SerialSettings};
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::Duration;
+
+mod report;
+use report::{Format, Printer, Report};
+
+/// The command was refused by the device or the device stopped answering
+const EXIT_FAILURE: i32 = 1;
+/// No PPBA matching `--device`, or more than one and `--device` is missing
+const EXIT_NO_DEVICE: i32 = 3;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Drive Pegasus Astro powerboxes from scripts", long_about = None)]
+struct Cli {
+    /// Serial port or USB serial number of the PPBA, can be omitted if only one is plugged
+    #[arg(short, long, global = true, env = "PEGASUS_DEVICE")]
+    device: Option<String>,
+
+    /// How `status` and `watch` print the readings
+    #[arg(short, long, global = true, value_enum, default_value_t)]
+    format: Format,
+
+    /// Same as `--format json`
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// TOML file with the serial link of the devices in `[serial."{serial number or port}"]`
+    /// tables, e.g. the config file of the MQTT driver
+    #[arg(long, global = true, env = "PEGASUS_SERIAL_CONFIG")]
+    serial_config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// List the Pegasus devices plugged, one `port<TAB>serial` per line
+    List,
+    /// Print the readings and settings of the PPBA
+    Status,
+    /// Change a setting, e.g. `set dew-a 60%` or `set quadport on`
+    ///
+    /// Settings are `dew-a` and `dew-b` (PWM 0-255 or a percentage), `quadport`,
+    /// `adj-output` and `autodew` (on/off) and `adj-voltage` (3, 5, 8, 9 or 12),
+    /// any other property of the MQTT driver is accepted as is.
+    Set { setting: String, value: String },
+    /// Reboot the PPBA
+    Reboot,
+    /// Print the readings of the PPBA until interrupted
+    Watch {
+        /// Milliseconds between two readings
+        #[arg(long, default_value_t = 1000)]
+        interval_ms: u64,
+    },
+    /// Feed the responses of a serial trace, written by `ppba --trace-serial`,
+    /// to the parser and print what was parsed
+    Replay {
+        file: PathBuf,
+        /// Only replay the traffic of this port
+        #[arg(long)]
+        address: Option<String>,
+    },
+}
+
+/// Property name and value of the driver matching a `set` command
+fn property(setting: &str, value: &str) -> (String, String) {
+    let switch = |v: &str| match v {
+        "on" => String::from("1"),
+        "off" => String::from("0"),
+        v => v.to_string(),
+    };
+
+    match setting {
+        "dew-a" | "dew-b" => {
+            let prop = if setting == "dew-a" {
+                "dew1_power"
+            } else {
+                "dew2_power"
+            };
+            match value.strip_suffix('%') {
+                Some(pct) => (format!("{}_pct", prop), pct.to_string()),
+                None => (prop.to_string(), value.to_string()),
+            }
+        }
+        "quadport" => ("quadport_status".to_string(), switch(value)),
+        "adj-output" => ("adj_output_status".to_string(), switch(value)),
+        "adj-voltage" => (
+            "adj_output".to_string(),
+            value.trim_end_matches(['V', 'v']).to_string(),
+        ),
+        other => (other.to_string(), switch(value)),
+    }
+}
+
+/// Connect to the PPBA matching `wanted`, the only one plugged if `None`
+async fn open(wanted: Option<&str>, serial: &SerialSettings) -> Result<PegasusPowerBox, String> {
+    let mut found: Vec<_> = discovery::find(DeviceFamily::Ppba)
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .filter(|dev| {
+            wanted.is_none_or(|w| dev.port == w || dev.serial_number.as_deref() == Some(w))
+        })
+        .collect();
+
+    let dev = match found.len() {
+        0 => return Err("No PPBA found".to_string()),
+        1 => found.remove(0),
+        _ => return Err("More than one PPBA found, pick one with --device".to_string()),
+    };
+    let mut device_name = String::from("PegausPowerBoxAdvanced");
+    debug!("name: {}", dev.port);
+    debug!("info: {:?}", dev);
+
+    if let Some(serial) = &dev.serial_number {
+        device_name = device_name + "-" + serial
+    }
+    let config = serial.resolve(
+        dev.serial_number.as_deref(),
+        &dev.port,
+        transport::DEFAULT_BAUD,
+    );
+    let mut device = PegasusPowerBox::try_with_config(&device_name, &dev.port, &config)
+        .await
+        .map_err(|e| e.to_string())?;
+    if let Some(serial) = &dev.serial_number {
+        device.set_serial_number(serial);
+    }
+    Ok(device)
+}
+
+/// Print every exchange of a trace with what its response was parsed to,
+/// fails if any response couldn't be parsed
+fn replay(file: &std::path::Path, address: Option<&str>) {
+    let entries = match trace::read(file) {
+        Ok(entries) => entries,
+        Err(e) => fail(EXIT_FAILURE, &e),
+    };
+    let mut failed = false;
+    for (exchange, parsed) in trace::replay(&entries) {
+        if address.is_some_and(|a| a != exchange.address) {
+            continue;
+        }
+        let response = exchange.response.as_deref().unwrap_or("<no response>");
+        let parsed = match parsed {
+            Ok(Parsed::Unparsed) => String::new(),
+            Ok(parsed) => format!("\t{:?}", parsed),
+            Err(e) => {
+                failed = true;
+                format!("\tPARSE ERROR: {}", e)
+            }
+        };
+        println!(
+            "{}\t{}\t{} -> {}{}",
+            exchange.timestamp, exchange.address, exchange.command, response, parsed
+        );
+    }
+    if failed {
+        exit(EXIT_FAILURE)
+    }
+}
+
+fn fail(code: i32, e: &(impl Display + ?Sized)) -> ! {
+    eprintln!("{}", e);
+    exit(code)
+}
+
+#[tokio::main]
+async fn main() {
+    // Quiet by default, the output is meant to be parsed
+    let env = Env::default().filter_or("LS_LOG_LEVEL", "warn");
+    env_logger::init_from_env(env);
+
+    let cli = Cli::parse();
+    let mut printer = Printer::new(if cli.json { Format::Json } else { cli.format });
+
+    if let Command::List = cli.command {
+        let found = match discovery::discover() {
+            Ok(found) => found,
+            Err(e) => fail(EXIT_FAILURE, &e),
+        };
+        for dev in found.into_iter().filter(|dev| dev.family.is_some()) {
+            println!("{}\t{}", dev.port, dev.serial_number.unwrap_or_default());
+        }
+        return;
+    }
+
+    if let Command::Replay { file, address } = &cli.command {
+        replay(file, address.as_deref());
+        return;
+    }
+
+    let serial = match cli.serial_config.as_deref().map(SerialSettings::from_file) {
+        Some(Ok(serial)) => serial,
+        Some(Err(e)) => fail(EXIT_FAILURE, &e),
+        None => SerialSettings::default(),
+    };
+    let mut device = match open(cli.device.as_deref(), &serial).await {
+        Ok(device) => device,
+        Err(e) => fail(EXIT_NO_DEVICE, &e),
+    };
+
+    match cli.command {
+        Command::List | Command::Replay { .. } => unreachable!(),
+        Command::Status => printer.print(&Report::of(&device.snapshot())),
+        Command::Set { setting, value } => {
+            let (prop_name, value) = property(&setting, &value);
+            if let Err(e) = device.update_property(&prop_name, &value).await {
+                fail(EXIT_FAILURE, &e);
+            }
+        }
+        Command::Reboot => {
+            if let Err(e) = device.reboot().await {
+                fail(EXIT_FAILURE, &e);
+            }
+        }
+        Command::Watch { interval_ms } => loop {
+            device.fetch_props().await;
+            if !device.is_connected() {
+                fail(EXIT_FAILURE, "The PPBA stopped answering");
+            }
+            printer.print(&Report::of(&device.snapshot()));
+            tokio::time::sleep(Duration::from_millis(interval_ms)).await;
+        },
+    }
+}
@@ -0,0 +1,123 @@
+use clap::ValueEnum;
+use pegasus_astro::ppba::PowerBoxSnapshot;
+use serde_json::{Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How readings are printed
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Format {
+    /// One aligned `property value` line per property
+    #[default]
+    Table,
+    /// One JSON object per reading and per line, for jq
+    Json,
+    /// A header and one line per reading, for spreadsheets
+    Csv,
+}
+
+/// A reading of a device with the time it was taken
+pub struct Report {
+    /// Milliseconds since the epoch
+    pub timestamp: u64,
+    pub id: String,
+    /// Every property with its typed value, sorted by name
+    pub properties: Map<String, Value>,
+}
+
+impl Report {
+    pub fn of(snapshot: &PowerBoxSnapshot) -> Self {
+        let mut properties = match serde_json::to_value(snapshot) {
+            Ok(Value::Object(props)) => props,
+            _ => Map::new(),
+        };
+        properties.remove("id");
+
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            id: snapshot.id.to_string(),
+            properties,
+        }
+    }
+}
+
+/// Print reports one after the other in the same format.
+pub struct Printer {
+    format: Format,
+    /// The CSV header goes out with the first report only
+    header_printed: bool,
+}
+
+impl Printer {
+    pub fn new(format: Format) -> Self {
+        Self {
+            format,
+            header_printed: false,
+        }
+    }
+
+    pub fn print(&mut self, report: &Report) {
+        match self.format {
+            Format::Table => print_table(report),
+            Format::Json => {
+                let mut obj = Map::new();
+                obj.insert("timestamp".to_string(), report.timestamp.into());
+                obj.insert("id".to_string(), report.id.clone().into());
+                obj.extend(report.properties.clone());
+                println!("{}", Value::Object(obj));
+            }
+            Format::Csv => {
+                if !self.header_printed {
+                    let names = report.properties.keys().map(|k| csv_field(k));
+                    let header: Vec<String> = ["timestamp".to_string(), "id".to_string()]
+                        .into_iter()
+                        .chain(names)
+                        .collect();
+                    println!("{}", header.join(","));
+                    self.header_printed = true;
+                }
+                let values = report.properties.values().map(|v| match v {
+                    Value::String(s) => csv_field(s),
+                    Value::Null => String::new(),
+                    v => csv_field(&v.to_string()),
+                });
+                let row: Vec<String> = [report.timestamp.to_string(), report.id.clone()]
+                    .into_iter()
+                    .chain(values)
+                    .collect();
+                println!("{}", row.join(","));
+            }
+        }
+    }
+}
+
+fn print_table(report: &Report) {
+    let width = report
+        .properties
+        .keys()
+        .map(|k| k.len())
+        .max()
+        .unwrap_or(0)
+        .max("timestamp".len());
+
+    println!("{:width$}  {}", "timestamp", report.timestamp);
+    println!("{:width$}  {}", "id", report.id);
+    for (name, value) in &report.properties {
+        match value {
+            Value::String(s) => println!("{:width$}  {}", name, s),
+            v => println!("{:width$}  {}", name, v),
+        }
+    }
+    println!();
+}
+
+/// Quote a CSV field if it contains a separator, a quote or a line break
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
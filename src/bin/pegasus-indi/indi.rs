@@ -0,0 +1,484 @@
+use pegasus_astro::ppba::PowerBoxSnapshot;
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use std::fmt::Write;
+use tokio::io::AsyncBufRead;
+
+/// State of a vector as shown by INDI clients
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum State {
+    Ok,
+    Alert,
+}
+
+impl State {
+    fn as_str(&self) -> &'static str {
+        match self {
+            State::Ok => "Ok",
+            State::Alert => "Alert",
+        }
+    }
+}
+
+struct SwitchElement {
+    name: &'static str,
+    label: &'static str,
+    on: bool,
+}
+
+struct NumberElement {
+    name: &'static str,
+    label: &'static str,
+    format: &'static str,
+    min: f64,
+    max: f64,
+    step: f64,
+    value: f64,
+}
+
+enum Elements {
+    /// Switches where exactly one element is On at any time
+    Switches(Vec<SwitchElement>),
+    Numbers {
+        writable: bool,
+        elements: Vec<NumberElement>,
+    },
+}
+
+/// An INDI property vector exposed for a PPBA
+pub struct Vector {
+    pub name: &'static str,
+    label: &'static str,
+    group: &'static str,
+    elements: Elements,
+}
+
+fn on_off(name: &'static str, label: &'static str, group: &'static str, on: bool) -> Vector {
+    Vector {
+        name,
+        label,
+        group,
+        elements: Elements::Switches(vec![
+            SwitchElement {
+                name: "ON",
+                label: "On",
+                on,
+            },
+            SwitchElement {
+                name: "OFF",
+                label: "Off",
+                on: !on,
+            },
+        ]),
+    }
+}
+
+fn reading(
+    name: &'static str,
+    label: &'static str,
+    format: &'static str,
+    value: f32,
+) -> NumberElement {
+    NumberElement {
+        name,
+        label,
+        format,
+        min: 0.0,
+        max: 0.0,
+        step: 0.0,
+        value: value as f64,
+    }
+}
+
+/// Every vector of a PPBA filled with the values of `s`
+pub fn vectors(s: &PowerBoxSnapshot) -> Vec<Vector> {
+    vec![
+        // The bridge keeps devices connected, clients still expect this vector
+        Vector {
+            name: "CONNECTION",
+            label: "Connection",
+            group: "Main Control",
+            elements: Elements::Switches(vec![
+                SwitchElement {
+                    name: "CONNECT",
+                    label: "Connect",
+                    on: true,
+                },
+                SwitchElement {
+                    name: "DISCONNECT",
+                    label: "Disconnect",
+                    on: false,
+                },
+            ]),
+        },
+        on_off("QUADPORT", "Quad 12V output", "Power", s.quadport_status),
+        on_off(
+            "ADJ_OUTPUT_SWITCH",
+            "Adjustable output",
+            "Power",
+            s.adj_output_status,
+        ),
+        Vector {
+            name: "ADJ_OUTPUT",
+            label: "Adjustable output",
+            group: "Power",
+            elements: Elements::Numbers {
+                writable: true,
+                elements: vec![NumberElement {
+                    name: "VOLTAGE",
+                    label: "Voltage (V)",
+                    format: "%.0f",
+                    min: 3.0,
+                    max: 12.0,
+                    step: 1.0,
+                    value: s.adj_output as f64,
+                }],
+            },
+        },
+        on_off("AUTODEW", "Auto dew", "Dew", s.autodew),
+        Vector {
+            name: "DEW_PWM",
+            label: "Dew heaters PWM",
+            group: "Dew",
+            elements: Elements::Numbers {
+                writable: true,
+                elements: vec![
+                    NumberElement {
+                        name: "DEW_A",
+                        label: "Dew A",
+                        format: "%.0f",
+                        min: 0.0,
+                        max: 255.0,
+                        step: 1.0,
+                        value: s.dew1_power as f64,
+                    },
+                    NumberElement {
+                        name: "DEW_B",
+                        label: "Dew B",
+                        format: "%.0f",
+                        min: 0.0,
+                        max: 255.0,
+                        step: 1.0,
+                        value: s.dew2_power as f64,
+                    },
+                ],
+            },
+        },
+        Vector {
+            name: "POWER_SENSORS",
+            label: "Power",
+            group: "Sensors",
+            elements: Elements::Numbers {
+                writable: false,
+                elements: vec![
+                    reading(
+                        "INPUT_VOLTAGE",
+                        "Input voltage (V)",
+                        "%.2f",
+                        s.input_voltage,
+                    ),
+                    reading(
+                        "TOTAL_CURRENT",
+                        "Total current (A)",
+                        "%.2f",
+                        s.total_current,
+                    ),
+                    reading(
+                        "OUTPUT_CURRENT",
+                        "12V outputs current (A)",
+                        "%.2f",
+                        s.current_12v_output,
+                    ),
+                    reading("AMP_HOURS", "Amp hours", "%.2f", s.amps_hours),
+                    reading("WATT_HOURS", "Watt hours", "%.2f", s.watt_hours),
+                ],
+            },
+        },
+        Vector {
+            name: "DEW_CURRENT",
+            label: "Dew heaters current",
+            group: "Sensors",
+            elements: Elements::Numbers {
+                writable: false,
+                elements: vec![
+                    reading("DEW_A", "Dew A (A)", "%.2f", s.dew1_current),
+                    reading("DEW_B", "Dew B (A)", "%.2f", s.dew2_current),
+                ],
+            },
+        },
+        Vector {
+            name: "ENVIRONMENT",
+            label: "Environment",
+            group: "Sensors",
+            elements: Elements::Numbers {
+                writable: false,
+                elements: vec![
+                    reading("TEMPERATURE", "Temperature (C)", "%.1f", s.temperature),
+                    reading("HUMIDITY", "Humidity (%)", "%.0f", s.humidity),
+                ],
+            },
+        },
+    ]
+}
+
+fn switch_value(on: bool) -> &'static str {
+    if on {
+        "On"
+    } else {
+        "Off"
+    }
+}
+
+impl Vector {
+    /// `def*Vector` message announcing this vector to clients
+    pub fn define(&self, device: &str) -> String {
+        let mut xml = String::new();
+        let device = escape(device);
+
+        match &self.elements {
+            Elements::Switches(elements) => {
+                let _ = writeln!(
+                    xml,
+                    r#"<defSwitchVector device="{}" name="{}" label="{}" group="{}" state="Idle" perm="rw" rule="OneOfMany" timeout="0">"#,
+                    device, self.name, self.label, self.group
+                );
+                for e in elements {
+                    let _ = writeln!(
+                        xml,
+                        r#"<defSwitch name="{}" label="{}">{}</defSwitch>"#,
+                        e.name,
+                        e.label,
+                        switch_value(e.on)
+                    );
+                }
+                xml.push_str("</defSwitchVector>\n");
+            }
+            Elements::Numbers { writable, elements } => {
+                let _ = writeln!(
+                    xml,
+                    r#"<defNumberVector device="{}" name="{}" label="{}" group="{}" state="Idle" perm="{}" timeout="0">"#,
+                    device,
+                    self.name,
+                    self.label,
+                    self.group,
+                    if *writable { "rw" } else { "ro" }
+                );
+                for e in elements {
+                    let _ = writeln!(
+                        xml,
+                        r#"<defNumber name="{}" label="{}" format="{}" min="{}" max="{}" step="{}">{}</defNumber>"#,
+                        e.name,
+                        escape(e.label),
+                        e.format,
+                        e.min,
+                        e.max,
+                        e.step,
+                        e.value
+                    );
+                }
+                xml.push_str("</defNumberVector>\n");
+            }
+        }
+        xml
+    }
+
+    /// `set*Vector` message with the current values of this vector
+    pub fn set(&self, device: &str, state: State, message: Option<&str>) -> String {
+        let mut xml = String::new();
+        let device = escape(device);
+        let message = message
+            .map(|m| format!(r#" message="{}""#, escape(m)))
+            .unwrap_or_default();
+
+        match &self.elements {
+            Elements::Switches(elements) => {
+                let _ = writeln!(
+                    xml,
+                    r#"<setSwitchVector device="{}" name="{}" state="{}"{}>"#,
+                    device,
+                    self.name,
+                    state.as_str(),
+                    message
+                );
+                for e in elements {
+                    let _ = writeln!(
+                        xml,
+                        r#"<oneSwitch name="{}">{}</oneSwitch>"#,
+                        e.name,
+                        switch_value(e.on)
+                    );
+                }
+                xml.push_str("</setSwitchVector>\n");
+            }
+            Elements::Numbers { elements, .. } => {
+                let _ = writeln!(
+                    xml,
+                    r#"<setNumberVector device="{}" name="{}" state="{}"{}>"#,
+                    device,
+                    self.name,
+                    state.as_str(),
+                    message
+                );
+                for e in elements {
+                    let _ = writeln!(
+                        xml,
+                        r#"<oneNumber name="{}">{}</oneNumber>"#,
+                        e.name, e.value
+                    );
+                }
+                xml.push_str("</setNumberVector>\n");
+            }
+        }
+        xml
+    }
+}
+
+/// Messages sent by INDI clients the bridge reacts to
+#[derive(Debug, PartialEq)]
+pub enum ClientCommand {
+    GetProperties {
+        device: Option<String>,
+    },
+    NewSwitch {
+        device: String,
+        name: String,
+        elements: Vec<(String, bool)>,
+    },
+    NewNumber {
+        device: String,
+        name: String,
+        elements: Vec<(String, f64)>,
+    },
+}
+
+impl ClientCommand {
+    /// Device and vector targeted by a `new*Vector` command
+    pub fn target(&self) -> Option<(&str, &str)> {
+        match self {
+            ClientCommand::GetProperties { .. } => None,
+            ClientCommand::NewSwitch { device, name, .. }
+            | ClientCommand::NewNumber { device, name, .. } => Some((device, name)),
+        }
+    }
+
+    /// Translate a `new*Vector` command into PPBA property updates
+    pub fn property_updates(&self) -> Result<Vec<(&'static str, String)>, String> {
+        match self {
+            ClientCommand::GetProperties { .. } => Ok(Vec::new()),
+            ClientCommand::NewSwitch { name, elements, .. } => {
+                let on = elements.iter().find(|(_, on)| *on).map(|(e, _)| e.as_str());
+                let prop_name = match name.as_str() {
+                    // Devices are always connected while the bridge runs
+                    "CONNECTION" => return Ok(Vec::new()),
+                    "QUADPORT" => "quadport_status",
+                    "ADJ_OUTPUT_SWITCH" => "adj_output_status",
+                    "AUTODEW" => "autodew",
+                    _ => return Err(format!("Unknown switch {}", name)),
+                };
+                match on {
+                    Some("ON") => Ok(vec![(prop_name, "1".to_string())]),
+                    Some("OFF") => Ok(vec![(prop_name, "0".to_string())]),
+                    _ => Err(format!("Invalid value for {}", name)),
+                }
+            }
+            ClientCommand::NewNumber { name, elements, .. } => elements
+                .iter()
+                .map(|(element, value)| {
+                    let prop_name = match (name.as_str(), element.as_str()) {
+                        ("DEW_PWM", "DEW_A") => "dew1_power",
+                        ("DEW_PWM", "DEW_B") => "dew2_power",
+                        ("ADJ_OUTPUT", "VOLTAGE") => "adj_output",
+                        _ => return Err(format!("{}.{} cannot be updated", name, element)),
+                    };
+                    Ok((prop_name, format!("{}", value.round() as i64)))
+                })
+                .collect(),
+        }
+    }
+}
+
+fn attr(e: &BytesStart, name: &str) -> Option<String> {
+    e.try_get_attribute(name)
+        .ok()
+        .flatten()
+        .and_then(|a| a.unescape_value().ok())
+        .map(|v| v.into_owned())
+}
+
+/// Read the next command sent by a client, `None` once the client disconnected.
+///
+/// Messages the bridge doesn't handle (texts, BLOBs, ...) are skipped.
+pub async fn read_command<R>(reader: &mut Reader<R>) -> Result<Option<ClientCommand>, String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut buf = Vec::new();
+    let mut command: Option<ClientCommand> = None;
+    let mut element: Option<String> = None;
+
+    loop {
+        buf.clear();
+        match reader
+            .read_event_into_async(&mut buf)
+            .await
+            .map_err(|e| e.to_string())?
+        {
+            Event::Eof => return Ok(None),
+            Event::Empty(e) if e.local_name().as_ref() == b"getProperties" => {
+                return Ok(Some(ClientCommand::GetProperties {
+                    device: attr(&e, "device"),
+                }))
+            }
+            Event::Start(e) => match e.local_name().as_ref() {
+                b"getProperties" => {
+                    command = Some(ClientCommand::GetProperties {
+                        device: attr(&e, "device"),
+                    })
+                }
+                b"newSwitchVector" => {
+                    command = Some(ClientCommand::NewSwitch {
+                        device: attr(&e, "device").unwrap_or_default(),
+                        name: attr(&e, "name").unwrap_or_default(),
+                        elements: Vec::new(),
+                    })
+                }
+                b"newNumberVector" => {
+                    command = Some(ClientCommand::NewNumber {
+                        device: attr(&e, "device").unwrap_or_default(),
+                        name: attr(&e, "name").unwrap_or_default(),
+                        elements: Vec::new(),
+                    })
+                }
+                b"oneSwitch" | b"oneNumber" => element = attr(&e, "name"),
+                _ => (),
+            },
+            Event::Text(t) => {
+                let (Some(name), Ok(text)) = (&element, t.unescape()) else {
+                    continue;
+                };
+                match &mut command {
+                    Some(ClientCommand::NewSwitch { elements, .. }) => {
+                        elements.push((name.clone(), text.trim() == "On"))
+                    }
+                    Some(ClientCommand::NewNumber { elements, .. }) => {
+                        if let Ok(value) = text.trim().parse() {
+                            elements.push((name.clone(), value))
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            Event::End(e) => match e.local_name().as_ref() {
+                b"oneSwitch" | b"oneNumber" => element = None,
+                b"getProperties" | b"newSwitchVector" | b"newNumberVector" => {
+                    if let Some(command) = command.take() {
+                        return Ok(Some(command));
+                    }
+                }
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+}
@@ -0,0 +1,300 @@
+use clap::Parser;
+use env_logger::Env;
+use log::{debug, error, info, warn};
+use pegasus_astro::actor::DeviceHandle;
+use pegasus_astro::device::AstronomicalDevice;
+use pegasus_astro::discovery::{self, DeviceFamily};
+use pegasus_astro::error::PegasusError;
+use pegasus_astro::ppba::PegasusPowerBox;
+use pegasus_astro::transport::{self, SerialSettings};
+use quick_xml::Reader;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc};
+
+mod indi;
+use indi::{ClientCommand, State};
+
+type Ppba = DeviceHandle<PegasusPowerBox>;
+
+#[derive(Debug, Parser)]
+#[command(version, about = "INDI server for Pegasus Astro powerboxes", long_about = None)]
+struct Cli {
+    /// TCP port INDI clients connect to
+    #[arg(long, env = "PEGASUS_INDI_PORT", default_value_t = 7624)]
+    port: u16,
+
+    /// Milliseconds between two readings of the devices
+    #[arg(long, env = "PEGASUS_INDI_POLL_MS", default_value_t = 1000)]
+    poll_ms: u64,
+
+    /// TOML file with the serial link of the devices in `[serial."{serial number or port}"]`
+    /// tables, e.g. the config file of the MQTT driver
+    #[arg(long, env = "PEGASUS_SERIAL_CONFIG")]
+    serial_config: Option<PathBuf>,
+}
+
+/// Serial links of `--serial-config`, the defaults if not given
+fn serial_settings(path: Option<&std::path::Path>) -> SerialSettings {
+    match path.map(SerialSettings::from_file).transpose() {
+        Ok(settings) => settings.unwrap_or_default(),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    }
+}
+
+async fn discover(serial: &SerialSettings) -> Vec<Ppba> {
+    let mut devices = Vec::new();
+
+    let found = discovery::find(DeviceFamily::Ppba).unwrap_or_else(|e| {
+        error!("{}", e);
+        Vec::new()
+    });
+    for dev in found {
+        let mut device_name = String::from("PegausPowerBoxAdvanced");
+        debug!("name: {}", dev.port);
+        debug!("info: {:?}", dev);
+
+        if let Some(serial) = &dev.serial_number {
+            device_name = device_name + "-" + serial
+        }
+        let config = serial.resolve(
+            dev.serial_number.as_deref(),
+            &dev.port,
+            transport::DEFAULT_BAUD,
+        );
+        match PegasusPowerBox::try_with_config(&device_name, &dev.port, &config).await {
+            Ok(mut device) => {
+                if let Some(serial) = &dev.serial_number {
+                    device.set_serial_number(serial);
+                }
+                devices.push(DeviceHandle::spawn(device))
+            }
+            Err(e) => error!("Cannot start communication with {}: {}", &device_name, e),
+        }
+    }
+    devices
+}
+
+/// Periodically read the device and send the new values to every client.
+fn spawn_polling(device: Ppba, updates: broadcast::Sender<String>, every: Duration) {
+    tokio::spawn(async move {
+        loop {
+            let xml = device
+                .call(|d| {
+                    Box::pin(async move {
+                        if !d.is_connected() {
+                            if let Err(e) = d.reconnect().await {
+                                debug!("{} still unreachable: {}", d.get_name(), e);
+                            }
+                        }
+                        d.fetch_props().await;
+
+                        let state = if d.is_connected() {
+                            State::Ok
+                        } else {
+                            State::Alert
+                        };
+                        indi::vectors(&d.snapshot())
+                            .iter()
+                            .filter(|v| v.name != "CONNECTION")
+                            .map(|v| v.set(d.get_name(), state, None))
+                            .collect::<String>()
+                    })
+                })
+                .await;
+            match xml {
+                // Sending fails only when no client is connected
+                Ok(xml) => {
+                    let _ = updates.send(xml);
+                }
+                Err(e) => debug!("Cannot poll {}: {}", device.name(), e),
+            }
+            tokio::time::sleep(every).await;
+        }
+    });
+}
+
+fn find_device<'a>(devices: &'a [Ppba], name: &str) -> Option<&'a Ppba> {
+    devices.iter().find(|d| d.name() == name)
+}
+
+/// Apply a command received from a client, replies are sent through `replies`.
+async fn handle_command(
+    command: ClientCommand,
+    devices: &[Ppba],
+    replies: &mpsc::UnboundedSender<String>,
+) {
+    if let ClientCommand::GetProperties { device } = &command {
+        for d in devices {
+            if device.as_ref().is_some_and(|name| name != d.name()) {
+                continue;
+            }
+            let snapshot = match d.call(|d| Box::pin(async move { d.snapshot() })).await {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    warn!("Cannot read {}: {}", d.name(), e);
+                    continue;
+                }
+            };
+            for vector in indi::vectors(&snapshot) {
+                let _ = replies.send(vector.define(d.name()));
+            }
+        }
+        return;
+    }
+
+    let Some((device_name, vector_name)) = command.target() else {
+        return;
+    };
+    let Some(device) = find_device(devices, device_name) else {
+        debug!("Ignoring command for unknown device {}", device_name);
+        return;
+    };
+
+    let updates = command.property_updates();
+    let applied = device
+        .call_urgent(|d| {
+            Box::pin(async move {
+                let result = match updates {
+                    Ok(updates) => {
+                        let mut result = Ok(());
+                        for (prop_name, value) in updates {
+                            result = d.update_property(prop_name, &value).await;
+                            if result.is_err() {
+                                break;
+                            }
+                        }
+                        result
+                    }
+                    Err(e) => Err(PegasusError::Validation(e)),
+                };
+                (result, d.snapshot())
+            })
+        })
+        .await;
+    let (result, snapshot) = match applied {
+        Ok(applied) => applied,
+        Err(e) => {
+            warn!("Cannot apply {} on {}: {}", vector_name, device.name(), e);
+            return;
+        }
+    };
+
+    let (state, message) = match &result {
+        Ok(_) => (State::Ok, None),
+        Err(e) => {
+            warn!("Cannot apply {} on {}: {}", vector_name, device.name(), e);
+            (State::Alert, Some(e.to_string()))
+        }
+    };
+    if let Some(vector) = indi::vectors(&snapshot)
+        .iter()
+        .find(|v| v.name == vector_name)
+    {
+        let _ = replies.send(vector.set(device.name(), state, message.as_deref()));
+    }
+}
+
+async fn serve_client(
+    stream: TcpStream,
+    devices: Arc<Vec<Ppba>>,
+    updates: broadcast::Sender<String>,
+) {
+    let peer = stream
+        .peer_addr()
+        .map(|a| a.to_string())
+        .unwrap_or_default();
+    info!("INDI client connected from {}", peer);
+
+    let (read_half, mut write_half) = stream.into_split();
+    let (replies, mut replies_rx) = mpsc::unbounded_channel::<String>();
+    let mut updates_rx = updates.subscribe();
+
+    let writer = tokio::spawn(async move {
+        loop {
+            let xml = tokio::select! {
+                Some(xml) = replies_rx.recv() => xml,
+                update = updates_rx.recv() => match update {
+                    Ok(xml) => xml,
+                    Err(RecvError::Lagged(n)) => {
+                        warn!("Slow INDI client, {} updates skipped", n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                else => break,
+            };
+            if write_half.write_all(xml.as_bytes()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut reader = Reader::from_reader(BufReader::new(read_half));
+    reader.config_mut().trim_text(true);
+
+    loop {
+        match indi::read_command(&mut reader).await {
+            Ok(Some(command)) => {
+                debug!("INDI command from {}: {:?}", peer, command);
+                handle_command(command, &devices, &replies).await;
+            }
+            Ok(None) => break,
+            Err(e) => {
+                warn!("Invalid INDI message from {}: {}", peer, e);
+                break;
+            }
+        }
+    }
+
+    writer.abort();
+    info!("INDI client {} disconnected", peer);
+}
+
+#[tokio::main]
+async fn main() {
+    let env = Env::default().filter_or("LS_LOG_LEVEL", "info");
+    env_logger::init_from_env(env);
+
+    let cli = Cli::parse();
+    let devices = Arc::new(discover(&serial_settings(cli.serial_config.as_deref())).await);
+
+    if devices.is_empty() {
+        warn!("No Pegasus device found on the system, exiting");
+        std::process::exit(0)
+    }
+
+    let (updates, _) = broadcast::channel(64);
+    for d in devices.iter() {
+        spawn_polling(
+            d.clone(),
+            updates.clone(),
+            Duration::from_millis(cli.poll_ms),
+        );
+    }
+
+    let listener = match TcpListener::bind(("0.0.0.0", cli.port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Cannot listen on port {}: {}", cli.port, e);
+            std::process::exit(1)
+        }
+    };
+    info!("INDI server listening on port {}", cli.port);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(serve_client(stream, Arc::clone(&devices), updates.clone()));
+            }
+            Err(e) => error!("Cannot accept INDI client: {}", e),
+        }
+    }
+}
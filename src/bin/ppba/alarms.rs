@@ -0,0 +1,339 @@
+use pegasus_astro::limits::{CurrentTrip, LimitAction, Output};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Property update done when an alarm is raised, same as an MQTT update payload
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlarmAction {
+    pub prop_name: String,
+    pub value: String,
+}
+
+/// Raise an alarm when a reading goes `below` or `above` a threshold, e.g.
+/// `input_voltage` below 11.5 V to protect a battery.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Alarm {
+    /// Name used in the events, `{property}_low` or `{property}_high` by default
+    pub name: Option<String>,
+    pub property: String,
+    pub below: Option<f64>,
+    pub above: Option<f64>,
+    /// How far back past the threshold the reading must go to clear the alarm
+    #[serde(default)]
+    pub hysteresis: f64,
+    /// Consecutive readings past the threshold before the alarm is raised
+    #[serde(default = "default_samples")]
+    pub samples: u32,
+    pub action: Option<AlarmAction>,
+}
+
+fn default_samples() -> u32 {
+    1
+}
+
+impl Alarm {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.below.is_some() == self.above.is_some() {
+            return Err(format!(
+                "Alarm on {} needs exactly one of below or above",
+                self.property
+            ));
+        }
+        if self.hysteresis < 0.0 {
+            return Err(format!(
+                "Alarm on {} has a negative hysteresis: {}",
+                self.property, self.hysteresis
+            ));
+        }
+        if self.samples == 0 {
+            return Err(format!(
+                "Alarm on {} needs at least 1 sample",
+                self.property
+            ));
+        }
+        Ok(())
+    }
+
+    /// `battery_low`, raised when the estimated charge of a battery goes under
+    /// `low_soc` % and cleared once back 5 % over it
+    pub fn battery_low(low_soc: f32) -> Self {
+        Self {
+            name: Some("battery_low".to_string()),
+            property: "battery_soc".to_string(),
+            below: Some(f64::from(low_soc)),
+            above: None,
+            hysteresis: 5.0,
+            samples: 3,
+            action: None,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None if self.below.is_some() => format!("{}_low", self.property),
+            None => format!("{}_high", self.property),
+        }
+    }
+
+    fn threshold(&self) -> f64 {
+        self.below.or(self.above).unwrap_or_default()
+    }
+
+    fn is_past(&self, value: f64) -> bool {
+        match (self.below, self.above) {
+            (Some(below), _) => value < below,
+            (_, Some(above)) => value > above,
+            _ => false,
+        }
+    }
+
+    fn is_back(&self, value: f64) -> bool {
+        match (self.below, self.above) {
+            (Some(below), _) => value >= below + self.hysteresis,
+            (_, Some(above)) => value <= above - self.hysteresis,
+            _ => true,
+        }
+    }
+}
+
+/// Published on `devices/{id}/alarms` when an alarm is raised or cleared
+#[derive(Clone, Debug, Serialize)]
+pub struct AlarmEvent {
+    pub alarm: String,
+    /// `raised` or `cleared`
+    pub event: &'static str,
+    pub property: String,
+    pub value: f64,
+    pub threshold: f64,
+    /// The update done because of the alarm, only when raised
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<AlarmAction>,
+}
+
+impl AlarmEvent {
+    /// `{output}_over_current`, raised when an output was cut or reduced for
+    /// drawing over its current limit
+    pub fn current_trip(trip: &CurrentTrip) -> Self {
+        let (output, property, power) = match trip.output {
+            Output::Quadport => ("quadport", "current_12v_output", "quadport_status"),
+            Output::Dew1 => ("dew1", "dew1_current", "dew1_power"),
+            Output::Dew2 => ("dew2", "dew2_current", "dew2_power"),
+        };
+        // A reduction that couldn't be sent has no PWM
+        let value = match (trip.action, trip.pwm) {
+            (LimitAction::Cut, _) => Some("0".to_string()),
+            (LimitAction::Reduce, pwm) => pwm.map(|pwm| pwm.to_string()),
+        };
+        Self {
+            alarm: format!("{}_over_current", output),
+            event: "raised",
+            property: property.to_string(),
+            value: f64::from(trip.current),
+            threshold: f64::from(trip.limit),
+            action: value.map(|value| AlarmAction {
+                prop_name: power.to_string(),
+                value,
+            }),
+        }
+    }
+}
+
+/// State of the alarms of a single device
+pub struct AlarmMonitor {
+    alarms: Vec<Alarm>,
+    /// Consecutive readings past the threshold for every alarm
+    over: Vec<u32>,
+    raised: Vec<bool>,
+}
+
+impl AlarmMonitor {
+    pub fn new(alarms: &[Alarm]) -> Self {
+        Self {
+            alarms: alarms.to_vec(),
+            over: vec![0; alarms.len()],
+            raised: vec![false; alarms.len()],
+        }
+    }
+
+    /// Check the readings of a serialized device state, returns the alarms
+    /// raised or cleared by it. Alarms on properties the device doesn't have
+    /// are ignored.
+    pub fn check(&mut self, state: &Value) -> Vec<AlarmEvent> {
+        let mut events = Vec::new();
+        for (i, alarm) in self.alarms.iter().enumerate() {
+            let Some(value) = state
+                .get(&alarm.property)
+                .and_then(|p| p.get("value"))
+                .and_then(Value::as_f64)
+            else {
+                continue;
+            };
+
+            let event = if self.raised[i] {
+                if !alarm.is_back(value) {
+                    continue;
+                }
+                self.raised[i] = false;
+                self.over[i] = 0;
+                "cleared"
+            } else {
+                if !alarm.is_past(value) {
+                    self.over[i] = 0;
+                    continue;
+                }
+                self.over[i] += 1;
+                if self.over[i] < alarm.samples {
+                    continue;
+                }
+                self.raised[i] = true;
+                "raised"
+            };
+
+            events.push(AlarmEvent {
+                alarm: alarm.name(),
+                event,
+                property: alarm.property.clone(),
+                value,
+                threshold: alarm.threshold(),
+                action: if event == "raised" {
+                    alarm.action.clone()
+                } else {
+                    None
+                },
+            });
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn low_voltage(samples: u32) -> Alarm {
+        Alarm {
+            name: None,
+            property: "input_voltage".to_string(),
+            below: Some(11.5),
+            above: None,
+            hysteresis: 0.5,
+            samples,
+            action: Some(AlarmAction {
+                prop_name: "quadport_status".to_string(),
+                value: "0".to_string(),
+            }),
+        }
+    }
+
+    fn state(voltage: f64) -> Value {
+        json!({"input_voltage": {"value": voltage}})
+    }
+
+    /// Events of every reading, in order
+    fn events(monitor: &mut AlarmMonitor, readings: &[f64]) -> Vec<&'static str> {
+        readings
+            .iter()
+            .flat_map(|voltage| monitor.check(&state(*voltage)))
+            .map(|event| event.event)
+            .collect()
+    }
+
+    #[test]
+    fn raised_past_the_threshold_with_the_action() {
+        let mut monitor = AlarmMonitor::new(&[low_voltage(1)]);
+        assert!(monitor.check(&state(11.5)).is_empty());
+        let events = monitor.check(&state(11.4));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].alarm, "input_voltage_low");
+        assert_eq!(events[0].event, "raised");
+        assert_eq!(events[0].value, 11.4);
+        assert_eq!(events[0].threshold, 11.5);
+        assert_eq!(
+            events[0].action.as_ref().unwrap().prop_name,
+            "quadport_status"
+        );
+        // Raised once
+        assert!(monitor.check(&state(11.0)).is_empty());
+    }
+
+    #[test]
+    fn cleared_past_the_hysteresis_only() {
+        let mut monitor = AlarmMonitor::new(&[low_voltage(1)]);
+        assert_eq!(events(&mut monitor, &[11.0, 11.5, 11.9]), ["raised"]);
+        let cleared = monitor.check(&state(12.0));
+        assert_eq!(cleared.len(), 1);
+        assert_eq!(cleared[0].event, "cleared");
+        assert!(cleared[0].action.is_none());
+        assert_eq!(events(&mut monitor, &[11.4]), ["raised"]);
+    }
+
+    #[test]
+    fn raised_after_consecutive_samples() {
+        let mut monitor = AlarmMonitor::new(&[low_voltage(3)]);
+        // A reading back within the threshold starts the count again
+        assert!(events(&mut monitor, &[11.0, 11.0, 12.0, 11.0, 11.0]).is_empty());
+        assert_eq!(events(&mut monitor, &[11.0]), ["raised"]);
+        // Cleared at once
+        assert_eq!(events(&mut monitor, &[12.0]), ["cleared"]);
+        assert!(events(&mut monitor, &[11.0, 11.0]).is_empty());
+        assert_eq!(events(&mut monitor, &[11.0]), ["raised"]);
+    }
+
+    #[test]
+    fn high_alarms_clear_below_the_hysteresis() {
+        let alarm = Alarm {
+            name: Some("too_hot".to_string()),
+            property: "temperature".to_string(),
+            below: None,
+            above: Some(30.0),
+            hysteresis: 2.0,
+            samples: 1,
+            action: None,
+        };
+        let mut monitor = AlarmMonitor::new(&[alarm]);
+        let check = |monitor: &mut AlarmMonitor, value: f64| {
+            monitor
+                .check(&json!({"temperature": {"value": value}}))
+                .into_iter()
+                .map(|event| (event.alarm, event.event))
+                .collect::<Vec<_>>()
+        };
+        assert!(check(&mut monitor, 30.0).is_empty());
+        assert_eq!(
+            check(&mut monitor, 31.0),
+            [("too_hot".to_string(), "raised")]
+        );
+        assert!(check(&mut monitor, 28.5).is_empty());
+        assert_eq!(
+            check(&mut monitor, 28.0),
+            [("too_hot".to_string(), "cleared")]
+        );
+    }
+
+    #[test]
+    fn missing_properties_are_ignored() {
+        let mut monitor = AlarmMonitor::new(&[low_voltage(1)]);
+        assert!(monitor
+            .check(&json!({"temperature": {"value": 0.0}}))
+            .is_empty());
+        assert!(monitor
+            .check(&json!({"input_voltage": {"value": null}}))
+            .is_empty());
+    }
+
+    #[test]
+    fn invalid_alarms_are_refused() {
+        assert!(low_voltage(1).validate().is_ok());
+        assert!(low_voltage(0).validate().is_err());
+        let mut both = low_voltage(1);
+        both.above = Some(14.0);
+        assert!(both.validate().is_err());
+        let mut negative = low_voltage(1);
+        negative.hysteresis = -1.0;
+        assert!(negative.validate().is_err());
+    }
+}
@@ -0,0 +1,74 @@
+use log::{debug, error, warn};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Names given by the users to their devices, keyed by device id.
+///
+/// The `[aliases]` table of the config file gives the defaults, the aliases
+/// set over MQTT override them and are saved to a JSON file.
+pub struct Aliases {
+    path: PathBuf,
+    configured: HashMap<Uuid, String>,
+    saved: HashMap<Uuid, String>,
+}
+
+/// `~/.pegasus_ppba_aliases.json`, next to the settings
+pub fn default_path() -> PathBuf {
+    crate::settings::default_path().with_file_name(".pegasus_ppba_aliases.json")
+}
+
+impl Aliases {
+    pub fn load(path: PathBuf, configured: HashMap<Uuid, String>) -> Self {
+        let saved = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring corrupted aliases {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self {
+            path,
+            configured,
+            saved,
+        }
+    }
+
+    /// Replace the aliases of the config file, e.g. once reloaded
+    pub fn set_configured(&mut self, configured: HashMap<Uuid, String>) {
+        self.configured = configured;
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<&str> {
+        self.saved
+            .get(id)
+            .or_else(|| self.configured.get(id))
+            .map(String::as_str)
+    }
+
+    /// Name a device, an empty alias goes back to the one of the config file.
+    pub fn set(&mut self, id: Uuid, alias: &str) {
+        let alias = alias.trim();
+        if alias.is_empty() {
+            self.saved.remove(&id);
+        } else {
+            self.saved.insert(id, alias.to_owned());
+        }
+        debug!("Saving aliases to {}", self.path.display());
+
+        let result = serde_json::to_string_pretty(&self.saved)
+            .map_err(|e| e.to_string())
+            .and_then(|content| std::fs::write(&self.path, content).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Cannot write aliases to {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// `alias (id)`, or the id alone, to name a device in the logs
+    pub fn label(&self, id: &Uuid) -> String {
+        match self.get(id) {
+            Some(alias) => format!("{} ({})", alias, id),
+            None => id.to_string(),
+        }
+    }
+}
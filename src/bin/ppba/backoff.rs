@@ -0,0 +1,40 @@
+use serde::Serialize;
+use std::time::Duration;
+
+const MIN_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Connection state of a device, published on `devices/{id}/status`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionStatus {
+    Connected,
+    Disconnected,
+    /// Polling stopped making progress, the device is being reset
+    Stalled,
+}
+
+/// Delay between reconnection attempts, doubled after every failure up to one minute.
+#[derive(Debug)]
+pub struct Backoff {
+    next: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self { next: MIN_DELAY }
+    }
+}
+
+impl Backoff {
+    /// Delay to wait before the next attempt
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = self.next;
+        self.next = (self.next * 2).min(MAX_DELAY);
+        delay
+    }
+
+    pub fn reset(&mut self) {
+        self.next = MIN_DELAY;
+    }
+}
@@ -0,0 +1,278 @@
+use crate::schema;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Default number of publications kept while the broker is unreachable, at the
+/// default polling rate this is a bit more than one hour of data per device.
+pub const DEFAULT_CAPACITY: usize = 10_000;
+/// Default age after which buffered messages are pruned even if never replayed.
+pub const DEFAULT_RETENTION_DAYS: u64 = 14;
+
+/// A state publication that couldn't reach the broker.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BufferedMessage {
+    pub topic: String,
+    /// Milliseconds since UNIX epoch at the moment the state was fetched
+    pub timestamp: u64,
+    pub state: serde_json::Value,
+}
+
+impl BufferedMessage {
+    /// Payload to publish when replaying this message, it carries the original
+    /// timestamp so consumers can put the sample back where it belongs.
+    pub fn replay_payload(&self) -> String {
+        schema::payload(&serde_json::json!({"timestamp": self.timestamp, "state": self.state}))
+    }
+}
+
+/// Bounded store-and-forward buffer backed by a JSON lines file.
+///
+/// Every message is appended to the file as soon as it is pushed so the
+/// buffered history survives a driver restart too, when the buffer is full the
+/// oldest messages are dropped first. Messages older than the retention period
+/// are pruned so a driver that never reaches a broker doesn't keep stale data forever.
+pub struct OfflineBuffer {
+    path: PathBuf,
+    capacity: usize,
+    retention: Duration,
+    messages: VecDeque<BufferedMessage>,
+    /// Lines still present in the file that were already dropped from memory
+    stale_lines: usize,
+}
+
+/// `~/.pegasus_ppba_buffer_{client id}.jsonl`, next to the settings so it
+/// survives a reboot. Two drivers connect with different client ids, each
+/// gets its own file.
+pub fn default_path(client_id: &str) -> PathBuf {
+    let client_id: String = client_id
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect();
+    crate::settings::default_path()
+        .with_file_name(format!(".pegasus_ppba_buffer_{}.jsonl", client_id))
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl OfflineBuffer {
+    pub fn new(path: PathBuf, capacity: usize, retention: Duration) -> Self {
+        let mut messages = VecDeque::new();
+
+        if let Ok(file) = File::open(&path) {
+            for line in BufReader::new(file).lines().map_while(Result::ok) {
+                match serde_json::from_str::<BufferedMessage>(&line) {
+                    Ok(msg) => messages.push_back(msg),
+                    Err(e) => warn!("Skipping corrupted buffered message: {}", e),
+                }
+            }
+        }
+
+        let mut buffer = Self {
+            path,
+            capacity,
+            retention,
+            messages,
+            stale_lines: 0,
+        };
+
+        if buffer.messages.len() > buffer.capacity {
+            let excess = buffer.messages.len() - buffer.capacity;
+            buffer.messages.drain(..excess);
+            buffer.compact();
+        }
+        buffer.prune();
+
+        if !buffer.messages.is_empty() {
+            debug!(
+                "Loaded {} buffered messages from {}",
+                buffer.messages.len(),
+                buffer.path.display()
+            );
+        }
+        buffer
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Buffer a state, nothing is kept with a capacity of 0.
+    pub fn push(&mut self, topic: String, state: serde_json::Value) {
+        if self.capacity == 0 {
+            return;
+        }
+        let msg = BufferedMessage {
+            topic,
+            timestamp: now_millis(),
+            state,
+        };
+
+        if let Err(e) = self.append(&msg) {
+            error!("Cannot write buffered message to disk: {}", e);
+        }
+        self.messages.push_back(msg);
+
+        if self.messages.len() > self.capacity {
+            self.messages.pop_front();
+            self.stale_lines += 1;
+
+            // Rewriting the file on every push would be wasteful, wait until
+            // enough dropped messages piled up before compacting it.
+            if self.stale_lines >= self.capacity {
+                self.compact();
+            }
+        }
+    }
+
+    /// Drop the messages older than the retention period, returns how many
+    /// messages were removed.
+    pub fn prune(&mut self) -> usize {
+        let oldest_allowed = now_millis().saturating_sub(self.retention.as_millis() as u64);
+        let before = self.messages.len();
+
+        // Messages are pushed in chronological order, the old ones are at the front
+        while self
+            .messages
+            .front()
+            .is_some_and(|msg| msg.timestamp < oldest_allowed)
+        {
+            self.messages.pop_front();
+        }
+
+        let pruned = before - self.messages.len();
+        if pruned > 0 {
+            debug!("Pruned {} buffered messages past retention", pruned);
+            self.compact();
+        }
+        pruned
+    }
+
+    /// Take all the buffered messages in the order they were pushed and clear
+    /// the backing file.
+    pub fn drain(&mut self) -> Vec<BufferedMessage> {
+        let messages: Vec<BufferedMessage> = self.messages.drain(..).collect();
+        self.compact();
+        messages
+    }
+
+    fn append(&self, msg: &BufferedMessage) -> std::io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(msg)?)
+    }
+
+    /// Rewrite the file with the messages kept. They're written to another
+    /// file renamed over it, a crash in between leaves the previous one.
+    fn compact(&mut self) {
+        let tmp = self.path.with_extension("jsonl.tmp");
+        let result = File::create(&tmp)
+            .and_then(|file| {
+                let mut file = BufWriter::new(file);
+                for msg in &self.messages {
+                    writeln!(file, "{}", serde_json::to_string(msg)?)?;
+                }
+                file.flush()?;
+                file.get_ref().sync_all()
+            })
+            .and_then(|()| std::fs::rename(&tmp, &self.path));
+
+        match result {
+            Ok(_) => self.stale_lines = 0,
+            Err(e) => error!("Cannot compact {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("ppba_test_buffer_{}.jsonl", uuid::Uuid::new_v4()))
+    }
+
+    #[test]
+    fn default_path_is_per_client() {
+        let path = default_path("observatory/1_ppba");
+        assert_eq!(
+            path.file_name().unwrap(),
+            ".pegasus_ppba_buffer_observatory_1_ppba.jsonl"
+        );
+        assert_ne!(path, default_path("other_ppba"));
+    }
+
+    fn reload(path: &std::path::Path) -> Vec<String> {
+        OfflineBuffer::new(path.to_path_buf(), 10, Duration::from_secs(3600))
+            .drain()
+            .into_iter()
+            .map(|msg| msg.topic)
+            .collect()
+    }
+
+    #[test]
+    fn prune_drops_the_messages_past_retention() {
+        let path = temp_path();
+        let mut buffer = OfflineBuffer::new(path.clone(), 10, Duration::from_secs(3600));
+        for i in 0..4 {
+            buffer.push(format!("devices/{}", i), serde_json::json!(i));
+        }
+        let two_hours_ago = now_millis() - 2 * 3600 * 1000;
+        buffer.messages[0].timestamp = two_hours_ago;
+        buffer.messages[1].timestamp = two_hours_ago;
+
+        assert_eq!(buffer.prune(), 2);
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.prune(), 0);
+        // The file was rewritten without them
+        assert_eq!(reload(&path), ["devices/2", "devices/3"]);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn nothing_is_buffered_without_capacity() {
+        let path = temp_path();
+        let mut buffer = OfflineBuffer::new(path.clone(), 0, Duration::from_secs(3600));
+        for i in 0..3 {
+            buffer.push(format!("devices/{}", i), serde_json::json!(i));
+        }
+        assert!(buffer.is_empty());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn compaction_replaces_the_file() {
+        let path = temp_path();
+        let mut buffer = OfflineBuffer::new(path.clone(), 2, Duration::from_secs(3600));
+        for i in 0..5 {
+            buffer.push(format!("devices/{}", i), serde_json::json!(i));
+        }
+        // The file is rewritten once as many messages were dropped as kept
+        let topics: Vec<String> = OfflineBuffer::new(path.clone(), 2, Duration::from_secs(3600))
+            .drain()
+            .into_iter()
+            .map(|msg| msg.topic)
+            .collect();
+        assert_eq!(topics, ["devices/3", "devices/4"]);
+        assert!(!path.with_extension("jsonl.tmp").exists());
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,62 @@
+use log::{debug, error, warn};
+use pegasus_astro::calibration::SensorCalibration;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Offsets of the temperature and humidity sensors, keyed by device id.
+///
+/// Given to the devices when they are connected. The `[calibration]` tables
+/// of the config file give the defaults, the offsets set at runtime with the
+/// `temperature_offset` and `humidity_offset` properties override them and
+/// are saved to a JSON file.
+#[derive(Default)]
+pub struct Calibrations {
+    path: PathBuf,
+    configured: HashMap<Uuid, SensorCalibration>,
+    saved: HashMap<Uuid, SensorCalibration>,
+}
+
+/// `~/.pegasus_ppba_calibration.json`, next to the settings
+pub fn default_path() -> PathBuf {
+    crate::settings::default_path().with_file_name(".pegasus_ppba_calibration.json")
+}
+
+impl Calibrations {
+    pub fn load(path: PathBuf, configured: HashMap<Uuid, SensorCalibration>) -> Self {
+        let saved = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring corrupted calibration {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self {
+            path,
+            configured,
+            saved,
+        }
+    }
+
+    /// Offsets of a device, none if it was never calibrated
+    pub fn get(&self, id: &Uuid) -> SensorCalibration {
+        self.saved
+            .get(id)
+            .or_else(|| self.configured.get(id))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Keep the offsets a device took
+    pub fn set(&mut self, id: Uuid, calibration: SensorCalibration) {
+        self.saved.insert(id, calibration);
+        debug!("Saving calibration to {}", self.path.display());
+
+        let result = serde_json::to_string_pretty(&self.saved)
+            .map_err(|e| e.to_string())
+            .and_then(|content| std::fs::write(&self.path, content).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Cannot write calibration to {}: {}", self.path.display(), e);
+        }
+    }
+}
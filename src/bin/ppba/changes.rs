@@ -0,0 +1,51 @@
+use serde_json::{Map, Value};
+
+/// Remembers the last published state of a device to publish only what changed.
+///
+/// Properties are the top level fields of the serialized device, a property
+/// is reported when its serialized value differs from the previous one.
+#[derive(Debug, Default)]
+pub struct ChangeTracker {
+    last: Map<String, Value>,
+}
+
+impl ChangeTracker {
+    /// Properties of `state` that changed since the previous call, all of
+    /// them the first time.
+    pub fn changes(&mut self, state: &Value) -> Vec<(String, Value)> {
+        let current = match state.as_object() {
+            Some(current) => current,
+            None => return Vec::new(),
+        };
+
+        let changed: Vec<(String, Value)> = current
+            .iter()
+            .filter(|(name, value)| self.last.get(*name) != Some(*value))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+
+        self.last = current.clone();
+        changed
+    }
+
+    /// Whether a state was seen already, [`changes`](Self::changes) then
+    /// only returns what changed
+    pub fn has_baseline(&self) -> bool {
+        !self.last.is_empty()
+    }
+}
+
+/// Settings of a serialized device state, the properties clients can write
+pub fn settings(state: &Value) -> Value {
+    let settings = state
+        .as_object()
+        .map(|props| {
+            props
+                .iter()
+                .filter(|(_, prop)| prop.get("permission") == Some(&Value::from("ReadWrite")))
+                .filter_map(|(name, prop)| Some((name.clone(), prop.get("value")?.clone())))
+                .collect()
+        })
+        .unwrap_or_default();
+    Value::Object(settings)
+}
@@ -0,0 +1,784 @@
+use crate::alarms::Alarm;
+use crate::groups::PollingGroup;
+use crate::history;
+use crate::i18n::Language;
+use crate::labels::Labels;
+use crate::logging::{self, LogConfig, LogFormat};
+use crate::rules::DewRule;
+use crate::schedule::ScheduledAction;
+use crate::steps;
+use crate::throttle;
+use crate::watchdog;
+use crate::weather::WeatherConfig;
+use clap::{Parser, ValueEnum};
+use log::{debug, LevelFilter};
+use pegasus_astro::battery::BatteryConfig;
+use pegasus_astro::calibration::SensorCalibration;
+use pegasus_astro::dewpoint::{Magnus, MagnusPreset};
+use pegasus_astro::ppba::Profile;
+use pegasus_astro::smoothing::Filter;
+use pegasus_astro::sun::Site;
+use pegasus_astro::transport::{RetryOverrides, RetryPolicies, RetryPolicy, SerialSettings};
+use rumqttc::{TlsConfiguration, Transport};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use uuid::Uuid;
+
+const DEFAULT_HOST: &str = "127.0.0.1";
+const DEFAULT_WS_PATH: &str = "/mqtt";
+const DEFAULT_CLIENT_ID_PREFIX: &str = "pegasus";
+const DEFAULT_KEEP_ALIVE_SECS: u64 = 5;
+
+/// Command line of the driver, every option can be given as an env var too.
+///
+/// Options not given on the command line nor in the environment are taken
+/// from the TOML file passed with `--config`, if any.
+#[derive(Debug, Parser)]
+#[command(version, about = "MQTT driver for Pegasus Astro powerboxes", long_about = None)]
+pub struct Cli {
+    /// TOML configuration file
+    #[arg(long, env = "PPBA_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Host of the MQTT broker
+    #[arg(long, env = "PPBA_MQTT_HOST")]
+    pub mqtt_host: Option<String>,
+
+    /// Port of the MQTT broker, the default depends on the transport
+    #[arg(long, env = "PPBA_MQTT_PORT")]
+    pub mqtt_port: Option<u16>,
+
+    /// How to reach the MQTT broker
+    #[arg(long, env = "PPBA_MQTT_TRANSPORT", value_enum)]
+    pub mqtt_transport: Option<MqttTransport>,
+
+    /// PEM file with the CA certificates trusted for `mqtts` and `wss`,
+    /// the system ones are used if not set
+    #[arg(long, env = "PPBA_MQTT_CA_CERT")]
+    pub mqtt_ca_cert: Option<PathBuf>,
+
+    /// PEM file with the client certificate, for brokers requiring mutual TLS
+    #[arg(long, env = "PPBA_MQTT_CLIENT_CERT")]
+    pub mqtt_client_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the client certificate
+    #[arg(long, env = "PPBA_MQTT_CLIENT_KEY")]
+    pub mqtt_client_key: Option<PathBuf>,
+
+    /// Path of the MQTT endpoint for `ws` and `wss`
+    #[arg(long, env = "PPBA_MQTT_WS_PATH")]
+    pub mqtt_ws_path: Option<String>,
+
+    #[arg(long, env = "PPBA_MQTT_USERNAME")]
+    pub mqtt_username: Option<String>,
+
+    #[arg(long, env = "PPBA_MQTT_PASSWORD", hide_env_values = true)]
+    pub mqtt_password: Option<String>,
+
+    /// Prefix of the MQTT client id, the driver connects as `{prefix}_ppba`
+    #[arg(long, env = "PPBA_MQTT_CLIENT_ID_PREFIX")]
+    pub mqtt_client_id_prefix: Option<String>,
+
+    /// MQTT keep alive interval in seconds
+    #[arg(long, env = "PPBA_MQTT_KEEP_ALIVE")]
+    pub mqtt_keep_alive: Option<u64>,
+
+    /// Seconds between two scans for plugged and unplugged devices, 0 disables hot-plug
+    #[arg(long, env = "PPBA_RESCAN_INTERVAL", default_value_t = 5)]
+    pub rescan_interval: u64,
+
+    /// Simulated PPBAs to drive along with the real devices, with drifting
+    /// readings, to build clients on a machine without hardware
+    #[arg(long, env = "PPBA_SIMULATE", default_value_t = 0)]
+    pub simulate: u32,
+
+    /// Seconds between two publications of the full state of a device
+    #[arg(long, env = "PPBA_SNAPSHOT_INTERVAL", default_value_t = 30)]
+    pub snapshot_interval: u64,
+
+    /// Publish the full state of the devices without the retain flag, the
+    /// state retained by a previous run is cleared
+    #[arg(long, env = "PPBA_NO_RETAIN")]
+    pub no_retain: bool,
+
+    /// Publish the topics of every device under the namespace of its family,
+    /// e.g. `upb/{id}` instead of `devices/{id}`
+    #[arg(long, env = "PPBA_FAMILY_TOPICS")]
+    pub family_topics: bool,
+
+    /// Seconds a device may go without a poll, past its polling interval,
+    /// before it is reset and reported as stalled, 0 disables the watchdog
+    #[arg(long, env = "PPBA_STALL_TIMEOUT", default_value_t = watchdog::DEFAULT_STALL_TIMEOUT)]
+    pub stall_timeout: u64,
+
+    /// Sudden change of a current, in A, published as a `current_step` event, 0 disables them
+    #[arg(long, env = "PPBA_CURRENT_STEP", default_value_t = steps::DEFAULT_STEP_AMPS)]
+    pub current_step: f64,
+
+    /// Language of the display names and units of the properties on `devices/{id}/schema`
+    #[arg(long, env = "PPBA_LANGUAGE", value_enum, default_value_t = Language::En)]
+    pub language: Language,
+
+    /// Seconds between two heartbeats on `drivers/pegasus_ppba/heartbeat`, 0 disables them
+    #[arg(long, env = "PPBA_HEARTBEAT_INTERVAL", default_value_t = 10)]
+    pub heartbeat_interval: u64,
+
+    /// Updates a device accepts per second on MQTT, the ones over the limit are dropped, 0 disables the limit
+    #[arg(long, env = "PPBA_UPDATE_RATE", default_value_t = throttle::DEFAULT_RATE)]
+    pub update_rate: f64,
+
+    /// Updates a device accepts at once on MQTT before being limited to `--update-rate`
+    #[arg(long, env = "PPBA_UPDATE_BURST", default_value_t = throttle::DEFAULT_BURST)]
+    pub update_burst: u32,
+
+    /// Milliseconds to wait for the next value of a dew heater before sending it, 0 sends every value
+    #[arg(long, env = "PPBA_DEW_DEBOUNCE_MS", default_value_t = throttle::DEFAULT_DEBOUNCE_MS)]
+    pub dew_debounce_ms: u64,
+
+    /// Samples of every device kept in memory for `devices/{id}/history/get`
+    #[arg(long, env = "PPBA_HISTORY_CAPACITY", default_value_t = history::DEFAULT_CAPACITY)]
+    pub history_capacity: usize,
+
+    /// SQLite database where every sample and setting change is logged
+    #[cfg(feature = "sqlite")]
+    #[arg(long, env = "PPBA_JOURNAL")]
+    pub journal: Option<PathBuf>,
+
+    /// Days of samples and events kept in the journal
+    #[cfg(feature = "sqlite")]
+    #[arg(
+        long,
+        env = "PPBA_JOURNAL_RETENTION_DAYS",
+        default_value_t = crate::journal::DEFAULT_RETENTION_DAYS
+    )]
+    pub journal_retention_days: u64,
+
+    /// Format of the log records
+    #[arg(long, env = "PPBA_LOG_FORMAT", value_enum)]
+    pub log_format: Option<LogFormat>,
+
+    /// File to log to instead of stderr, rotated every night
+    #[arg(long, env = "PPBA_LOG_FILE")]
+    pub log_file: Option<PathBuf>,
+
+    /// Days of rotated log files kept, 0 keeps them all
+    #[arg(long, env = "PPBA_LOG_RETENTION_DAYS")]
+    pub log_retention_days: Option<u32>,
+
+    /// File where the last known settings of every PPBA are saved
+    #[arg(long, env = "PPBA_SETTINGS_FILE")]
+    pub settings_file: Option<PathBuf>,
+
+    /// File where the aliases set over MQTT are saved
+    #[arg(long, env = "PPBA_ALIASES_FILE")]
+    pub aliases_file: Option<PathBuf>,
+
+    /// File where the output labels set at runtime are saved
+    #[arg(long, env = "PPBA_LABELS_FILE")]
+    pub labels_file: Option<PathBuf>,
+
+    /// File where the sensor offsets set at runtime are saved
+    #[arg(long, env = "PPBA_CALIBRATION_FILE")]
+    pub calibration_file: Option<PathBuf>,
+
+    /// File where the profiles saved over MQTT are written
+    #[arg(long, env = "PPBA_PROFILES_FILE")]
+    pub profiles_file: Option<PathBuf>,
+
+    /// File where the ids of the devices found are kept across restarts
+    #[arg(long, env = "PPBA_REGISTRY_FILE")]
+    pub registry_file: Option<PathBuf>,
+
+    /// File where the device states are buffered while the broker is
+    /// unreachable, `~/.pegasus_ppba_buffer_{client id}.jsonl` by default
+    #[arg(long, env = "PPBA_BUFFER_FILE")]
+    pub buffer_file: Option<PathBuf>,
+
+    /// Append every byte written to and read from the devices to this file,
+    /// to replay it with `pegasus-cli replay`
+    #[arg(long, env = "PPBA_TRACE_SERIAL")]
+    pub trace_serial: Option<PathBuf>,
+
+    /// Profile applied to every PPBA when the driver stops, e.g. one with the
+    /// dew heaters off
+    #[arg(long, env = "PPBA_SHUTDOWN_PROFILE")]
+    pub shutdown_profile: Option<String>,
+
+    /// Drive a PPBA on the given port, e.g. one without a USB serial number,
+    /// as `PORT` or `PORT@BAUD`, can be repeated. Without a baud rate the one
+    /// of its `[serial]` table is used, 9600 by default
+    #[arg(long, env = "PPBA_ADD_DEVICES", value_delimiter = ',')]
+    pub add_device: Vec<String>,
+
+    /// Address the line delimited JSON TCP server listens on, e.g. `0.0.0.0:9624`, disabled if not set
+    #[arg(long, env = "PPBA_TCP_ADDR")]
+    pub tcp_addr: Option<std::net::SocketAddr>,
+
+    /// Address the HTTP API listens on, e.g. `0.0.0.0:8080`, disabled if not set
+    #[cfg(feature = "http-api")]
+    #[arg(long, env = "PPBA_HTTP_ADDR")]
+    pub http_addr: Option<std::net::SocketAddr>,
+
+    /// Token the HTTP clients must send as `Authorization: Bearer <token>`,
+    /// anyone reaching the address is trusted if not set
+    #[cfg(feature = "http-api")]
+    #[arg(long, env = "PPBA_HTTP_TOKEN", hide_env_values = true)]
+    pub http_token: Option<String>,
+
+    /// PEM certificate chain to serve the HTTP API over TLS, needs `--http-tls-key`
+    #[cfg(feature = "http-api")]
+    #[arg(long, env = "PPBA_HTTP_TLS_CERT")]
+    pub http_tls_cert: Option<PathBuf>,
+
+    /// PEM private key of `--http-tls-cert`
+    #[cfg(feature = "http-api")]
+    #[arg(long, env = "PPBA_HTTP_TLS_KEY")]
+    pub http_tls_key: Option<PathBuf>,
+
+    /// Validate and log the setting changes of every device without sending
+    /// them, e.g. to test an automation pipeline against live hardware
+    #[arg(long, env = "PPBA_READ_ONLY")]
+    pub read_only: bool,
+
+    /// Same as `--read-only` for a single device, by USB serial number or
+    /// port, can be repeated
+    #[arg(long, env = "PPBA_READ_ONLY_DEVICES", value_delimiter = ',')]
+    pub read_only_device: Vec<String>,
+
+    /// Reapply the saved settings to every PPBA found, e.g. after a power cycle
+    #[arg(long, env = "PPBA_RESTORE_SETTINGS")]
+    pub restore_settings: bool,
+
+    /// Install the driver as a service started at boot with the other
+    /// arguments given, a systemd unit on Linux, and exit
+    #[arg(long)]
+    pub install_service: bool,
+
+    /// Run under the Windows service manager, set by `--install-service`
+    #[cfg(windows)]
+    #[arg(long, hide = true)]
+    pub windows_service: bool,
+
+    /// Advertise the driver on the LAN over mDNS as a `_pegasus._tcp` service
+    #[arg(long, env = "PPBA_MDNS")]
+    pub mdns: bool,
+
+    /// Instance name of the mDNS advertisement, `ppba on <hostname>` by default
+    #[arg(long, env = "PPBA_MDNS_NAME", requires = "mdns")]
+    pub mdns_name: Option<String>,
+
+    /// Run the self test on every connected PPBA, print the reports and exit
+    #[arg(long)]
+    pub self_test: bool,
+
+    /// Diagnose every connected PPBA (serial latency, firmware, sensor ranges
+    /// and dew heaters PWM read back), print the reports and exit
+    #[arg(long, conflicts_with = "self_test")]
+    pub diagnose: bool,
+}
+
+/// Content of the TOML configuration file
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    #[serde(default)]
+    mqtt: MqttFileConfig,
+    #[cfg(feature = "http-api")]
+    #[serde(default)]
+    http: HttpFileConfig,
+    #[serde(default)]
+    logging: LoggingFileConfig,
+    #[serde(default)]
+    dew_rules: Vec<DewRule>,
+    #[serde(default)]
+    alarms: Vec<Alarm>,
+    #[serde(default)]
+    schedule: Vec<ScheduledAction>,
+    #[serde(default)]
+    smoothing: BTreeMap<String, Filter>,
+    #[serde(default)]
+    aliases: HashMap<Uuid, String>,
+    #[serde(default)]
+    labels: HashMap<Uuid, Labels>,
+    #[serde(default)]
+    calibration: HashMap<Uuid, SensorCalibration>,
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
+    #[serde(default)]
+    serial: SerialSettings,
+    #[serde(default)]
+    retry: HashMap<String, RetryOverrides>,
+    #[serde(default)]
+    batteries: HashMap<String, BatteryConfig>,
+    #[serde(default)]
+    weather: Option<WeatherConfig>,
+    #[serde(default)]
+    site: Option<Site>,
+    #[serde(default)]
+    dew_point: DewPointConfig,
+    #[serde(default)]
+    polling_groups: BTreeMap<String, PollingGroup>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MqttFileConfig {
+    host: Option<String>,
+    port: Option<u16>,
+    username: Option<String>,
+    password: Option<String>,
+    client_id_prefix: Option<String>,
+    keep_alive: Option<u64>,
+    transport: Option<MqttTransport>,
+    ca_cert: Option<PathBuf>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+    ws_path: Option<String>,
+}
+
+/// Levels are `off`, `error`, `warn`, `info`, `debug` or `trace`
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LoggingFileConfig {
+    level: Option<String>,
+    #[serde(default)]
+    modules: BTreeMap<String, String>,
+    format: Option<LogFormat>,
+    file: Option<PathBuf>,
+    retention_days: Option<u32>,
+}
+
+/// Formula of the computed dew point, a preset or the Magnus coefficients
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DewPointConfig {
+    formula: Option<MagnusPreset>,
+    b: Option<f32>,
+    c: Option<f32>,
+}
+
+#[cfg(feature = "http-api")]
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HttpFileConfig {
+    addr: Option<std::net::SocketAddr>,
+    token: Option<String>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+}
+
+/// Transport used to talk to the MQTT broker
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MqttTransport {
+    /// Plain TCP
+    #[default]
+    Mqtt,
+    /// TCP over TLS
+    Mqtts,
+    /// WebSocket, needs the `websocket` feature
+    Ws,
+    /// WebSocket over TLS, needs the `websocket` feature
+    Wss,
+}
+
+impl MqttTransport {
+    fn default_port(self) -> u16 {
+        match self {
+            Self::Mqtt => 1883,
+            Self::Mqtts => 8883,
+            Self::Ws => 80,
+            Self::Wss => 443,
+        }
+    }
+}
+
+/// Certificates of a TLS connection, read from the PEM files of the configuration
+#[derive(Debug, Default)]
+pub struct TlsFiles {
+    /// Trusted CA certificates, the system ones when `None`
+    pub ca_cert: Option<PathBuf>,
+    /// Client certificate and private key
+    pub client_auth: Option<(PathBuf, PathBuf)>,
+}
+
+/// Everything needed to serve the HTTP API
+#[cfg(feature = "http-api")]
+#[derive(Debug)]
+pub struct HttpConfig {
+    pub addr: std::net::SocketAddr,
+    /// Bearer token required on every request
+    pub token: Option<String>,
+    /// PEM certificate chain and private key, plain HTTP when `None`
+    pub tls: Option<(PathBuf, PathBuf)>,
+}
+
+/// Everything needed to connect to the MQTT broker
+#[derive(Debug)]
+pub struct MqttConfig {
+    pub host: String,
+    pub port: u16,
+    /// Username and password, only sent if both are set
+    pub credentials: Option<(String, String)>,
+    pub client_id_prefix: String,
+    pub keep_alive: Duration,
+    pub transport: MqttTransport,
+    pub tls: TlsFiles,
+    pub ws_path: String,
+}
+
+impl MqttConfig {
+    pub fn client_id(&self) -> String {
+        format!("{}_ppba", self.client_id_prefix)
+    }
+
+    /// Address given to the MQTT client, WebSocket transports want a full URL.
+    pub fn broker_addr(&self) -> String {
+        match self.transport {
+            MqttTransport::Mqtt | MqttTransport::Mqtts => self.host.clone(),
+            MqttTransport::Ws | MqttTransport::Wss => format!(
+                "{}://{}:{}{}",
+                if self.transport == MqttTransport::Ws {
+                    "ws"
+                } else {
+                    "wss"
+                },
+                self.host,
+                self.port,
+                self.ws_path
+            ),
+        }
+    }
+
+    /// Build the transport of the MQTT client, reading the certificate files.
+    pub fn rumqttc_transport(&self) -> Result<Transport, String> {
+        match self.transport {
+            MqttTransport::Mqtt => Ok(Transport::tcp()),
+            MqttTransport::Mqtts => Ok(Transport::tls_with_config(self.tls_configuration()?)),
+            #[cfg(feature = "websocket")]
+            MqttTransport::Ws => Ok(Transport::ws()),
+            #[cfg(feature = "websocket")]
+            MqttTransport::Wss => Ok(Transport::wss_with_config(self.tls_configuration()?)),
+            #[cfg(not(feature = "websocket"))]
+            MqttTransport::Ws | MqttTransport::Wss => {
+                Err("WebSocket transports need the driver built with the websocket feature".into())
+            }
+        }
+    }
+
+    fn tls_configuration(&self) -> Result<TlsConfiguration, String> {
+        let read = |path: &Path| {
+            std::fs::read(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))
+        };
+        let client_auth = match &self.tls.client_auth {
+            Some((cert, key)) => Some((read(cert)?, read(key)?)),
+            None => None,
+        };
+
+        match &self.tls.ca_cert {
+            Some(ca) => Ok(TlsConfiguration::Simple {
+                ca: read(ca)?,
+                alpn: None,
+                client_auth,
+            }),
+            None if client_auth.is_some() => {
+                Err("A CA certificate is required with a client certificate".to_string())
+            }
+            // Trust the certificates of the system
+            None => Ok(TlsConfiguration::default()),
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<FileConfig, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    toml::from_str(&content).map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+}
+
+impl Cli {
+    fn file_config(&self) -> Result<FileConfig, String> {
+        match &self.config {
+            Some(path) => {
+                debug!("Loading configuration from {}", path.display());
+                read_file(path)
+            }
+            None => Ok(FileConfig::default()),
+        }
+    }
+
+    /// Content of the config file as is, empty without one, to tell which
+    /// tables changed when it's reloaded
+    pub fn raw_config(&self) -> Result<toml::Table, String> {
+        let Some(path) = &self.config else {
+            return Ok(toml::Table::new());
+        };
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+        content
+            .parse()
+            .map_err(|e| format!("Invalid config {}: {}", path.display(), e))
+    }
+
+    /// Where and how to serve the HTTP API, `None` if it's disabled. Merged
+    /// like [`Self::mqtt_config`].
+    #[cfg(feature = "http-api")]
+    pub fn http_config(&self) -> Result<Option<HttpConfig>, String> {
+        let http = self.file_config()?.http;
+
+        let Some(addr) = self.http_addr.or(http.addr) else {
+            return Ok(None);
+        };
+        let token = self.http_token.clone().or(http.token);
+        if token
+            .as_deref()
+            .is_some_and(|token| token.trim().is_empty())
+        {
+            return Err("The HTTP API token cannot be empty".to_string());
+        }
+        let cert = self.http_tls_cert.clone().or(http.tls_cert);
+        let key = self.http_tls_key.clone().or(http.tls_key);
+        let tls = match (cert, key) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => return Err("HTTP TLS certificate and key must be set together".to_string()),
+        };
+        Ok(Some(HttpConfig { addr, token, tls }))
+    }
+
+    /// Where and how to log, merged like [`Self::mqtt_config`].
+    pub fn logging(&self) -> Result<LogConfig, String> {
+        let logging = self.file_config()?.logging;
+
+        let parse = |level: &str| {
+            level
+                .parse::<LevelFilter>()
+                .map_err(|_| format!("Invalid log level {}", level))
+        };
+        let level = match &logging.level {
+            Some(level) => parse(level)?,
+            None => LevelFilter::Info,
+        };
+        let modules = logging
+            .modules
+            .iter()
+            .map(|(module, level)| Ok((module.clone(), parse(level)?)))
+            .collect::<Result<_, String>>()?;
+        Ok(LogConfig {
+            level,
+            modules,
+            format: self.log_format.or(logging.format).unwrap_or_default(),
+            file: self.log_file.clone().or(logging.file),
+            retention_days: self
+                .log_retention_days
+                .or(logging.retention_days)
+                .unwrap_or(logging::DEFAULT_RETENTION_DAYS),
+        })
+    }
+
+    /// Merge command line, environment and config file, in this order of precedence.
+    pub fn mqtt_config(&self) -> Result<MqttConfig, String> {
+        let mqtt = self.file_config()?.mqtt;
+
+        let username = self.mqtt_username.clone().or(mqtt.username);
+        let password = self.mqtt_password.clone().or(mqtt.password);
+        let credentials = match (username, password) {
+            (Some(username), Some(password)) => Some((username, password)),
+            (None, None) => None,
+            _ => return Err("MQTT username and password must be set together".to_string()),
+        };
+
+        let client_cert = self.mqtt_client_cert.clone().or(mqtt.client_cert);
+        let client_key = self.mqtt_client_key.clone().or(mqtt.client_key);
+        let client_auth = match (client_cert, client_key) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => return Err("MQTT client certificate and key must be set together".to_string()),
+        };
+        let transport = self.mqtt_transport.or(mqtt.transport).unwrap_or_default();
+
+        Ok(MqttConfig {
+            host: self
+                .mqtt_host
+                .clone()
+                .or(mqtt.host)
+                .unwrap_or_else(|| DEFAULT_HOST.to_string()),
+            port: self
+                .mqtt_port
+                .or(mqtt.port)
+                .unwrap_or_else(|| transport.default_port()),
+            credentials,
+            client_id_prefix: self
+                .mqtt_client_id_prefix
+                .clone()
+                .or(mqtt.client_id_prefix)
+                .unwrap_or_else(|| DEFAULT_CLIENT_ID_PREFIX.to_string()),
+            keep_alive: Duration::from_secs(
+                self.mqtt_keep_alive
+                    .or(mqtt.keep_alive)
+                    .unwrap_or(DEFAULT_KEEP_ALIVE_SECS),
+            ),
+            transport,
+            tls: TlsFiles {
+                ca_cert: self.mqtt_ca_cert.clone().or(mqtt.ca_cert),
+                client_auth,
+            },
+            ws_path: self
+                .mqtt_ws_path
+                .clone()
+                .or(mqtt.ws_path)
+                .unwrap_or_else(|| DEFAULT_WS_PATH.to_string()),
+        })
+    }
+
+    /// Dew point rules of the `[[dew_rules]]` tables of the config file.
+    pub fn dew_rules(&self) -> Result<Vec<DewRule>, String> {
+        let rules = self.file_config()?.dew_rules;
+        for rule in &rules {
+            rule.validate()?;
+        }
+        Ok(rules)
+    }
+
+    /// External sensor of the `[weather]` table of the config file, if any.
+    pub fn weather(&self) -> Result<Option<WeatherConfig>, String> {
+        let weather = self.file_config()?.weather;
+        if let Some(weather) = &weather {
+            weather.validate()?;
+        }
+        Ok(weather)
+    }
+
+    /// Polling groups of the `[polling_groups.{name}]` tables of the config file.
+    pub fn polling_groups(&self) -> Result<BTreeMap<String, PollingGroup>, String> {
+        let groups = self.file_config()?.polling_groups;
+        for (name, group) in &groups {
+            group
+                .validate()
+                .map_err(|e| format!("Invalid polling group {}: {}", name, e))?;
+        }
+        Ok(groups)
+    }
+
+    /// Alarms of the `[[alarms]]` tables of the config file.
+    pub fn alarms(&self) -> Result<Vec<Alarm>, String> {
+        let alarms = self.file_config()?.alarms;
+        for alarm in &alarms {
+            alarm.validate()?;
+        }
+        Ok(alarms)
+    }
+
+    /// Scheduled actions of the `[[schedule]]` tables of the config file.
+    pub fn schedule(&self) -> Result<Vec<ScheduledAction>, String> {
+        let config = self.file_config()?;
+        for action in &config.schedule {
+            action.validate(config.site.as_ref())?;
+        }
+        Ok(config.schedule)
+    }
+
+    /// Location of the observatory of the `[site]` table of the config file, if any.
+    pub fn site(&self) -> Result<Option<Site>, String> {
+        let site = self.file_config()?.site;
+        if let Some(site) = &site {
+            site.validate()?;
+        }
+        Ok(site)
+    }
+
+    /// Filter of every reading of the `[smoothing]` table of the config file.
+    pub fn smoothing(&self) -> Result<BTreeMap<String, Filter>, String> {
+        let smoothing = self.file_config()?.smoothing;
+        for (reading, filter) in &smoothing {
+            filter
+                .validate()
+                .map_err(|e| format!("Invalid smoothing of {}: {}", reading, e))?;
+        }
+        Ok(smoothing)
+    }
+
+    /// Alias of every device id of the `[aliases]` table of the config file.
+    pub fn aliases(&self) -> Result<HashMap<Uuid, String>, String> {
+        Ok(self.file_config()?.aliases)
+    }
+
+    /// Labels of the outputs of every device id of the `[labels]` tables of the config file.
+    pub fn labels(&self) -> Result<HashMap<Uuid, Labels>, String> {
+        Ok(self.file_config()?.labels)
+    }
+
+    /// Sensor offsets of every device id of the `[calibration]` tables of the config file.
+    pub fn calibration(&self) -> Result<HashMap<Uuid, SensorCalibration>, String> {
+        let calibration = self.file_config()?.calibration;
+        for (id, offsets) in &calibration {
+            offsets
+                .validate()
+                .map_err(|e| format!("Calibration of {}: {}", id, e))?;
+        }
+        Ok(calibration)
+    }
+
+    /// Coefficients of the dew point computed by every device, from the
+    /// `[dew_point]` table of the config file.
+    pub fn dew_point_formula(&self) -> Result<Magnus, String> {
+        let config = self.file_config()?.dew_point;
+        let formula = match (config.formula, config.b, config.c) {
+            (None, None, None) => Magnus::default(),
+            (Some(preset), None, None) => preset.coefficients(),
+            (None, Some(b), Some(c)) => Magnus { b, c },
+            _ => return Err("The dew point needs either a formula or both b and c".to_string()),
+        };
+        formula.validate().map_err(|e| e.to_string())?;
+        Ok(formula)
+    }
+
+    /// PPBA profiles of the `[profiles.{name}]` tables of the config file.
+    pub fn profiles(&self) -> Result<BTreeMap<String, Profile>, String> {
+        Ok(self.file_config()?.profiles)
+    }
+
+    /// Serial links of the `[serial]` tables of the config file.
+    pub fn serial_settings(&self) -> Result<SerialSettings, String> {
+        let serial = self.file_config()?.serial;
+        serial.validate().map_err(|e| e.to_string())?;
+        Ok(serial)
+    }
+
+    /// Retry policy of `PPBA_MAX_RETRIES`, `PPBA_RETRY_DELAY_MS` and
+    /// `PPBA_RETRY_BACKOFF`, with the `[retry."{command}"]` tables of the
+    /// config file for single commands.
+    pub fn retry_policies(&self) -> Result<RetryPolicies, String> {
+        let overrides = self.file_config()?.retry;
+        RetryPolicies::with_overrides(RetryPolicy::from_env(), &overrides)
+            .map_err(|e| e.to_string())
+    }
+
+    /// Batteries of the `[batteries]` tables of the config file, keyed by USB
+    /// serial number or port.
+    pub fn batteries(&self) -> Result<HashMap<String, BatteryConfig>, String> {
+        let batteries = self.file_config()?.batteries;
+        for (device, battery) in &batteries {
+            battery
+                .validate()
+                .map_err(|e| format!("Invalid battery of {}: {}", device, e))?;
+        }
+        Ok(batteries)
+    }
+
+    /// Ports and baud rates, if given, of the devices given with `--add-device`.
+    pub fn manual_devices(&self) -> Result<Vec<(String, Option<u32>)>, String> {
+        self.add_device
+            .iter()
+            .map(|device| match device.rsplit_once('@') {
+                Some((port, baud)) => baud
+                    .parse()
+                    .map(|baud| (port.to_string(), Some(baud)))
+                    .map_err(|_| format!("Invalid baud rate of {}", device)),
+                None => Ok((device.clone(), None)),
+            })
+            .collect()
+    }
+}
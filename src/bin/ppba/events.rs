@@ -0,0 +1,77 @@
+//! Structured events of the devices, published on `devices/{id}/events` for
+//! automations to react on instead of scraping the logs.
+use crate::buffer::now_millis;
+use crate::schema;
+use crate::topics;
+use log::error;
+use rumqttc::{AsyncClient, QoS};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::sync::atomic::{AtomicU64, Ordering};
+use uuid::Uuid;
+
+/// Sequence number of the last event, shared by every device
+static SEQ: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// Polling started or the device came back, with its `name` and `address`
+    Connected,
+    Disconnected,
+    /// A setting changed, `name` and `value`
+    PropertyChanged,
+    /// Same fields as on `devices/{id}/alarms`
+    AlarmRaised,
+    AlarmCleared,
+    /// An update failed, same fields as on `devices/{id}/update/error`
+    CommandFailed,
+    /// A dew rule changed a heater
+    DewRule,
+    /// A current changed suddenly, same fields as [`CurrentStep`](crate::steps::CurrentStep)
+    CurrentStep,
+}
+
+/// Published on `devices/{id}/events`, the fields of the event are next to
+/// the common ones
+#[derive(Debug, Serialize)]
+pub struct Event {
+    /// Grows by one with every event of every device, starts from 1 with the
+    /// driver so clients can tell when they missed some
+    pub seq: u64,
+    /// Milliseconds since epoch
+    pub timestamp: u64,
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    #[serde(flatten)]
+    pub data: Map<String, Value>,
+}
+
+impl Event {
+    pub fn new(kind: EventKind, data: impl Serialize) -> Self {
+        let data = match serde_json::to_value(data) {
+            Ok(Value::Object(data)) => data,
+            _ => Map::new(),
+        };
+        Self {
+            seq: SEQ.fetch_add(1, Ordering::Relaxed) + 1,
+            timestamp: now_millis(),
+            kind,
+            data,
+        }
+    }
+}
+
+/// Queue the event without waiting for room in the client queue, it's dropped
+/// if there is none, e.g. while the broker is unreachable
+pub fn publish(client: &AsyncClient, id: Uuid, kind: EventKind, data: impl Serialize) {
+    let event = Event::new(kind, data);
+    if let Err(e) = client.try_publish(
+        format!("{}/events", topics::device(&id)),
+        QoS::AtLeastOnce,
+        false,
+        schema::payload(&event),
+    ) {
+        error!("Cannot publish the {:?} event of {}: {}", kind, id, e);
+    }
+}
@@ -0,0 +1,105 @@
+use pegasus_astro::device;
+use pegasus_astro::error::PegasusError;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+/// Devices polled at the same interval, e.g. `fast` for the main rig and
+/// `slow` for the rest, from the `[polling_groups]` tables of the config file
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PollingGroup {
+    /// Milliseconds between two polls
+    pub interval_ms: u64,
+    /// USB serial numbers or ports of the devices of the group
+    #[serde(default)]
+    pub devices: Vec<String>,
+}
+
+impl PollingGroup {
+    pub fn validate(&self) -> Result<(), String> {
+        device::check_polling_interval(self.interval_ms)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// Group of every device, the one of the config file unless another one was
+/// assigned at runtime with the `polling_group` property
+#[derive(Default)]
+pub struct PollingGroups {
+    groups: BTreeMap<String, PollingGroup>,
+    assigned: HashMap<Uuid, String>,
+}
+
+impl PollingGroups {
+    pub fn new(groups: BTreeMap<String, PollingGroup>) -> Self {
+        Self {
+            groups,
+            assigned: HashMap::new(),
+        }
+    }
+
+    /// Replace the groups of the config file, returns the devices whose
+    /// group has a new interval. Devices whose group is gone are taken out of
+    /// it and keep their interval.
+    pub fn set_configured(&mut self, groups: BTreeMap<String, PollingGroup>) -> Vec<(Uuid, u64)> {
+        self.assigned.retain(|_, name| groups.contains_key(name));
+        let changed = self
+            .assigned
+            .iter()
+            .filter_map(|(id, name)| {
+                let interval = groups.get(name)?.interval_ms;
+                let previous = self.groups.get(name).map(|group| group.interval_ms);
+                (previous != Some(interval)).then_some((*id, interval))
+            })
+            .collect();
+        self.groups = groups;
+        changed
+    }
+
+    /// Put a device just connected in the group listing its serial number or
+    /// its port, unless it was given one already
+    pub fn attach(&mut self, id: Uuid, serial: Option<&str>, address: &str) {
+        if self.assigned.contains_key(&id) {
+            return;
+        }
+        let listed = |key: &str| {
+            self.groups
+                .iter()
+                .find(|(_, group)| group.devices.iter().any(|d| d == key))
+                .map(|(name, _)| name.clone())
+        };
+        if let Some(name) = serial.and_then(listed).or_else(|| listed(address)) {
+            self.assigned.insert(id, name);
+        }
+    }
+
+    pub fn group_of(&self, id: &Uuid) -> Option<&str> {
+        self.assigned.get(id).map(String::as_str)
+    }
+
+    /// Polling interval of the group of a device, if it has one
+    pub fn interval_of(&self, id: &Uuid) -> Option<u64> {
+        self.groups
+            .get(self.group_of(id)?)
+            .map(|group| group.interval_ms)
+    }
+
+    /// Move a device to another group, returns the interval of the group. An
+    /// empty name takes it out of its group, its interval is then left as is.
+    pub fn assign(&mut self, id: Uuid, name: &str) -> Result<Option<u64>, PegasusError> {
+        let name = name.trim();
+        if name.is_empty() {
+            self.assigned.remove(&id);
+            return Ok(None);
+        }
+        let group = self
+            .groups
+            .get(name)
+            .ok_or_else(|| PegasusError::Validation(format!("Unknown polling group {}", name)))?;
+        let interval = group.interval_ms;
+        self.assigned.insert(id, name.to_owned());
+        Ok(Some(interval))
+    }
+}
@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{HashMap, VecDeque};
+use uuid::Uuid;
+
+/// Default number of samples kept per device, one hour at the default polling rate.
+pub const DEFAULT_CAPACITY: usize = 7200;
+
+/// Readings recorded in the history, when the device has them
+pub const RECORDED: [&str; 9] = [
+    "input_voltage",
+    "current",
+    "total_current",
+    "current_12v_output",
+    "dew1_current",
+    "dew2_current",
+    "temperature",
+    "humidity",
+    "dew_point",
+];
+
+/// Readings of a device at a given time
+#[derive(Clone, Debug, Serialize)]
+pub struct Sample {
+    /// Milliseconds since UNIX epoch at the moment the readings were fetched
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub readings: Map<String, Value>,
+}
+
+impl Sample {
+    /// Pick the recorded readings out of the serialized state of a device.
+    pub fn from_state(timestamp: u64, state: &Value) -> Self {
+        let readings = RECORDED
+            .iter()
+            .filter_map(|name| {
+                let value = state.get(name)?.get("value")?;
+                Some((name.to_string(), value.clone()))
+            })
+            .collect();
+        Self {
+            timestamp,
+            readings,
+        }
+    }
+}
+
+/// Payload expected on `devices/{id}/history/get`, every field is optional
+#[derive(Debug, Default, Deserialize)]
+pub struct HistoryRequest {
+    /// Only samples taken from this time on, milliseconds since UNIX epoch
+    pub since: Option<u64>,
+    /// At most this many samples, the most recent ones
+    pub limit: Option<usize>,
+    /// Echoed back in the response to match it with the request
+    pub request_id: Option<Value>,
+}
+
+/// In memory time series of the readings of every device.
+///
+/// Each device keeps its last `capacity` samples, the oldest are dropped
+/// first. Samples of unplugged devices are kept, ids are stable so they are
+/// found again once the device is back.
+pub struct History {
+    capacity: usize,
+    samples: HashMap<Uuid, VecDeque<Sample>>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            samples: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, id: Uuid, sample: Sample) {
+        if self.capacity == 0 {
+            return;
+        }
+        let samples = self.samples.entry(id).or_default();
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    /// Samples of the device matching the request, oldest first.
+    pub fn query(&self, id: &Uuid, request: &HistoryRequest) -> Vec<Sample> {
+        let Some(samples) = self.samples.get(id) else {
+            return Vec::new();
+        };
+        let matching: Vec<&Sample> = samples
+            .iter()
+            .filter(|s| request.since.is_none_or(|since| s.timestamp >= since))
+            .collect();
+        let skip = request
+            .limit
+            .map_or(0, |limit| matching.len().saturating_sub(limit));
+
+        matching.into_iter().skip(skip).cloned().collect()
+    }
+}
@@ -0,0 +1,363 @@
+//! HTTP/JSON API next to the MQTT topics, for scripting environments that
+//! cannot easily speak MQTT (curl, Node-RED, Python, ...).
+use crate::aliases::Aliases;
+use crate::config::HttpConfig;
+use crate::snapshots::Snapshots;
+use crate::{setting_value, spawn_any_update, DeviceInfo, PegasusDriver, UpdatePropertyRequest};
+use axum::extract::{Path, Request, State};
+use axum::http::{header, HeaderValue, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, put};
+use axum::{Json, Router};
+use futures_util::stream::{self, Stream};
+use hyper_util::rt::TokioIo;
+use hyper_util::service::TowerToHyperService;
+use log::{debug, error, info, warn};
+use pegasus_astro::ramp::DewRamp;
+use rumqttc::AsyncClient;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io::BufReader;
+use std::path::Path as FilePath;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, RwLock};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+use uuid::Uuid;
+
+/// Changes buffered for every event stream before the slowest ones start lagging
+const CHANGES_CAPACITY: usize = 256;
+
+/// A property of a device that changed, sent on `/devices/{id}/events` and `/events`
+#[derive(Clone, Debug, Serialize)]
+struct PropertyChange {
+    #[serde(skip)]
+    id: Uuid,
+    name: String,
+    /// The property as published on MQTT, `{"value": ..., "permission": ...}`
+    value: Value,
+}
+
+/// A change sent on `/events`, with the device it belongs to
+#[derive(Serialize)]
+struct DeviceChange<'a> {
+    id: Uuid,
+    #[serde(flatten)]
+    change: &'a PropertyChange,
+}
+
+/// Last state of every device, fed by the pollers
+pub struct LiveStates {
+    states: Mutex<HashMap<Uuid, Value>>,
+    changes: broadcast::Sender<PropertyChange>,
+}
+
+impl Default for LiveStates {
+    fn default() -> Self {
+        Self {
+            states: Mutex::default(),
+            changes: broadcast::channel(CHANGES_CAPACITY).0,
+        }
+    }
+}
+
+impl LiveStates {
+    /// Keep the state of a poll, the properties that changed go to the event streams
+    pub fn update(&self, id: Uuid, state: &Value) {
+        let mut states = self.states.lock().unwrap();
+        let previous = states.get(&id).and_then(Value::as_object);
+        for (name, value) in state.as_object().into_iter().flatten() {
+            if previous.and_then(|p| p.get(name)) != Some(value) {
+                // Nobody listening is fine
+                let _ = self.changes.send(PropertyChange {
+                    id,
+                    name: name.clone(),
+                    value: value.clone(),
+                });
+            }
+        }
+        states.insert(id, state.clone());
+    }
+
+    /// Forget an unplugged device
+    pub fn remove(&self, id: &Uuid) {
+        self.states.lock().unwrap().remove(id);
+    }
+}
+
+#[derive(Clone)]
+pub struct ApiState {
+    pub driver: Arc<RwLock<PegasusDriver>>,
+    /// Answers the reads without waiting for the devices
+    pub snapshots: Arc<Snapshots>,
+    pub live: Arc<LiveStates>,
+    pub aliases: Arc<Mutex<Aliases>>,
+    pub ramp: DewRamp,
+    /// Failed updates are published like the ones received on MQTT
+    pub client: AsyncClient,
+}
+
+/// Body of `PUT /devices/{id}/properties/{name}`
+#[derive(Debug, Deserialize)]
+struct PropertyValue {
+    /// As accepted on `devices/{id}/update`, numbers and booleans are taken as is
+    value: Value,
+    #[serde(default)]
+    immediate: bool,
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    (status, Json(json!({ "error": message }))).into_response()
+}
+
+fn no_device(id: Uuid) -> Response {
+    error(StatusCode::NOT_FOUND, format!("No device {}", id))
+}
+
+async fn list_devices(State(state): State<ApiState>) -> Json<Vec<DeviceInfo>> {
+    let mut infos = state.snapshots.devices().to_vec();
+    let aliases = state.aliases.lock().unwrap();
+    for info in infos.iter_mut() {
+        info.alias = aliases.get(&info.id).map(str::to_owned);
+    }
+    Json(infos)
+}
+
+async fn get_device(State(state): State<ApiState>, Path(id): Path<Uuid>) -> Response {
+    if !state.snapshots.ids().contains(&id) {
+        return no_device(id);
+    }
+    match state.snapshots.state(&id) {
+        Some(snapshot) => Json(&snapshot.state).into_response(),
+        None => error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("{} was not read yet", id),
+        ),
+    }
+}
+
+/// Applied like the updates received on MQTT, the new value shows up on the
+/// next poll, so the request is only accepted.
+async fn put_property(
+    State(state): State<ApiState>,
+    Path((id, name)): Path<(Uuid, String)>,
+    Json(body): Json<PropertyValue>,
+) -> Response {
+    let value = setting_value(body.value);
+
+    let driver = state.driver.read().await;
+    if name == "alias" {
+        if !driver.ids().contains(&id) {
+            return no_device(id);
+        }
+        info!("{} renamed to {:?}", id, value);
+        state.aliases.lock().unwrap().set(id, &value);
+        return StatusCode::ACCEPTED.into_response();
+    }
+
+    let request = UpdatePropertyRequest {
+        prop_name: name,
+        value,
+        immediate: body.immediate,
+    };
+    if spawn_any_update(&driver, &id, request, state.ramp, &state.client) {
+        StatusCode::ACCEPTED.into_response()
+    } else {
+        no_device(id)
+    }
+}
+
+/// Server-sent events, one `property` event per property that changed
+async fn device_events(State(state): State<ApiState>, Path(id): Path<Uuid>) -> Response {
+    if !state.snapshots.ids().contains(&id) {
+        return no_device(id);
+    }
+    let changes = state.live.changes.subscribe();
+    Sse::new(property_events(changes, Some(id)))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Server-sent `property` events of every device, with the id of the device,
+/// so a client follows them all on a single connection instead of polling
+async fn all_events(State(state): State<ApiState>) -> Response {
+    let changes = state.live.changes.subscribe();
+    Sse::new(property_events(changes, None))
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+/// Changes of the device `id`, or of every device with their id
+fn property_events(
+    changes: broadcast::Receiver<PropertyChange>,
+    id: Option<Uuid>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    stream::unfold(changes, move |mut changes| async move {
+        loop {
+            match changes.recv().await {
+                Ok(change) if id.is_none_or(|id| change.id == id) => {
+                    let event = Event::default().event("property");
+                    let event = match id {
+                        Some(_) => event.json_data(&change),
+                        None => event.json_data(DeviceChange {
+                            id: change.id,
+                            change: &change,
+                        }),
+                    };
+                    return Some((Ok(event.unwrap_or_default()), changes));
+                }
+                // The client missed some changes, the next ones are still worth sending
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    })
+}
+
+/// Compare in a time that doesn't depend on how much of the token is right
+fn same_token(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Reject the requests without `Authorization: Bearer <token>`
+async fn require_token(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match given {
+        Some(given) if same_token(given.trim().as_bytes(), token.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => {
+            let mut response = error(
+                StatusCode::UNAUTHORIZED,
+                "Missing or wrong token".to_string(),
+            );
+            response
+                .headers_mut()
+                .insert(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+            response
+        }
+    }
+}
+
+pub fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/devices", get(list_devices))
+        .route("/events", get(all_events))
+        .route("/devices/:id", get(get_device))
+        .route("/devices/:id/properties/:name", put(put_property))
+        .route("/devices/:id/events", get(device_events))
+        .with_state(state)
+}
+
+fn tls_config(cert: &FilePath, key: &FilePath) -> Result<ServerConfig, String> {
+    let open = |path: &FilePath| {
+        std::fs::File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| format!("Cannot read {}: {}", path.display(), e))
+    };
+    let certs = rustls_pemfile::certs(&mut open(cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Invalid certificate {}: {}", cert.display(), e))?;
+    let key = rustls_pemfile::private_key(&mut open(key)?)
+        .map_err(|e| format!("Invalid private key {}: {}", key.display(), e))?
+        .ok_or_else(|| format!("No private key in {}", key.display()))?;
+    ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("Invalid TLS certificate or key: {}", e))
+}
+
+/// Serve every connection accepted over TLS, the failed handshakes are only
+/// logged so a bad client doesn't stop the API
+async fn serve_tls(listener: TcpListener, acceptor: TlsAcceptor, app: Router) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                warn!("Cannot accept HTTP connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let service = TowerToHyperService::new(app.clone());
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+            let connection = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service);
+            if let Err(e) = connection.await {
+                debug!("HTTP connection with {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+pub async fn serve(config: HttpConfig, state: ApiState) {
+    let addr = config.addr;
+    let mut app = router(state);
+    match config.token {
+        Some(token) => {
+            app = app.layer(middleware::from_fn_with_state(
+                Arc::<str>::from(token),
+                require_token,
+            ))
+        }
+        None if !addr.ip().is_loopback() => {
+            warn!(
+                "HTTP API on {} has no token, anyone reaching it can drive the devices",
+                addr
+            )
+        }
+        None => {}
+    }
+    let acceptor = match &config.tls {
+        Some((cert, key)) => match tls_config(cert, key) {
+            Ok(tls) => Some(TlsAcceptor::from(Arc::new(tls))),
+            Err(e) => {
+                error!("Cannot serve the HTTP API over TLS: {}", e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Cannot listen for HTTP requests on {}: {}", addr, e);
+            return;
+        }
+    };
+    match acceptor {
+        Some(acceptor) => {
+            info!("HTTP API listening on {} over TLS", addr);
+            serve_tls(listener, acceptor, app).await
+        }
+        None => {
+            info!("HTTP API listening on {}", addr);
+            if let Err(e) = axum::serve(listener, app).await {
+                error!("HTTP API stopped: {}", e);
+            }
+        }
+    }
+}
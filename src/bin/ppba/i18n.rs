@@ -0,0 +1,546 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+
+/// Language of the display names and units in `devices/{id}/schema`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    #[default]
+    En,
+    It,
+    De,
+    Fr,
+}
+
+impl Language {
+    /// ISO 639-1 code
+    pub fn code(self) -> &'static str {
+        match self {
+            Self::En => "en",
+            Self::It => "it",
+            Self::De => "de",
+            Self::Fr => "fr",
+        }
+    }
+
+    fn pick(self, texts: &[&'static str; 4]) -> &'static str {
+        texts[self as usize]
+    }
+}
+
+/// Display name of the properties in English, Italian, German and French.
+/// `dew1` and `dew2` name the outputs of the energy counters.
+const LABELS: &[(&str, [&str; 4])] = &[
+    ("name", ["Name", "Nome", "Name", "Nom"]),
+    ("address", ["Port", "Porta", "Anschluss", "Port"]),
+    (
+        "baud",
+        ["Baud rate", "Velocità (baud)", "Baudrate", "Débit (bauds)"],
+    ),
+    (
+        "serial_number",
+        [
+            "Serial number",
+            "Numero di serie",
+            "Seriennummer",
+            "Numéro de série",
+        ],
+    ),
+    (
+        "fw_version",
+        [
+            "Firmware version",
+            "Versione firmware",
+            "Firmware-Version",
+            "Version du firmware",
+        ],
+    ),
+    (
+        "capabilities",
+        [
+            "Capabilities",
+            "Funzionalità",
+            "Funktionen",
+            "Fonctionnalités",
+        ],
+    ),
+    ("reboot", ["Reboot", "Riavvio", "Neustart", "Redémarrage"]),
+    (
+        "read_only",
+        [
+            "Read only",
+            "Sola lettura",
+            "Schreibgeschützt",
+            "Lecture seule",
+        ],
+    ),
+    (
+        "input_voltage",
+        [
+            "Input voltage",
+            "Tensione di ingresso",
+            "Eingangsspannung",
+            "Tension d'entrée",
+        ],
+    ),
+    ("current", ["Current", "Corrente", "Strom", "Courant"]),
+    (
+        "total_current",
+        [
+            "Total current",
+            "Corrente totale",
+            "Gesamtstrom",
+            "Courant total",
+        ],
+    ),
+    (
+        "current_12v_output",
+        [
+            "12V outputs current",
+            "Corrente uscite 12V",
+            "Strom der 12-V-Ausgänge",
+            "Courant des sorties 12 V",
+        ],
+    ),
+    (
+        "average_amps",
+        [
+            "Average current",
+            "Corrente media",
+            "Durchschnittsstrom",
+            "Courant moyen",
+        ],
+    ),
+    (
+        "amps_hours",
+        [
+            "Charge drawn",
+            "Carica consumata",
+            "Entnommene Ladung",
+            "Charge consommée",
+        ],
+    ),
+    (
+        "watt_hours",
+        [
+            "Energy drawn",
+            "Energia consumata",
+            "Verbrauchte Energie",
+            "Énergie consommée",
+        ],
+    ),
+    ("power", ["Power", "Potenza", "Leistung", "Puissance"]),
+    (
+        "average_power",
+        [
+            "Average power",
+            "Potenza media",
+            "Durchschnittsleistung",
+            "Puissance moyenne",
+        ],
+    ),
+    (
+        "energy_daily",
+        [
+            "Daily energy",
+            "Energia giornaliera",
+            "Tagesenergie",
+            "Énergie quotidienne",
+        ],
+    ),
+    (
+        "pwr_warn",
+        [
+            "Power warning",
+            "Avviso alimentazione",
+            "Stromwarnung",
+            "Alerte d'alimentation",
+        ],
+    ),
+    (
+        "tripped_outputs",
+        [
+            "Tripped outputs",
+            "Uscite scattate",
+            "Ausgelöste Ausgänge",
+            "Sorties disjonctées",
+        ],
+    ),
+    (
+        "uptime",
+        [
+            "Uptime",
+            "Tempo di attività",
+            "Betriebszeit",
+            "Temps de fonctionnement",
+        ],
+    ),
+    (
+        "uptime_seconds",
+        [
+            "Uptime",
+            "Tempo di attività",
+            "Betriebszeit",
+            "Temps de fonctionnement",
+        ],
+    ),
+    (
+        "uptime_human",
+        [
+            "Uptime",
+            "Tempo di attività",
+            "Betriebszeit",
+            "Temps de fonctionnement",
+        ],
+    ),
+    (
+        "temperature",
+        ["Temperature", "Temperatura", "Temperatur", "Température"],
+    ),
+    (
+        "humidity",
+        ["Humidity", "Umidità", "Luftfeuchtigkeit", "Humidité"],
+    ),
+    (
+        "dew_point",
+        [
+            "Dew point",
+            "Punto di rugiada",
+            "Taupunkt",
+            "Point de rosée",
+        ],
+    ),
+    (
+        "dew_point_computed",
+        [
+            "Computed dew point",
+            "Punto di rugiada calcolato",
+            "Berechneter Taupunkt",
+            "Point de rosée calculé",
+        ],
+    ),
+    (
+        "dew_margin",
+        [
+            "Dew margin",
+            "Margine dal punto di rugiada",
+            "Abstand zum Taupunkt",
+            "Marge au point de rosée",
+        ],
+    ),
+    (
+        "temperature_offset",
+        [
+            "Temperature offset",
+            "Correzione temperatura",
+            "Temperaturkorrektur",
+            "Correction de température",
+        ],
+    ),
+    (
+        "humidity_offset",
+        [
+            "Humidity offset",
+            "Correzione umidità",
+            "Feuchtekorrektur",
+            "Correction d'humidité",
+        ],
+    ),
+    (
+        "quadport_status",
+        [
+            "Quad 12V output",
+            "Uscita 12V quadrupla",
+            "12-V-Vierfachausgang",
+            "Sortie 12 V quadruple",
+        ],
+    ),
+    (
+        "adj_output_status",
+        [
+            "Adjustable output",
+            "Uscita regolabile",
+            "Einstellbarer Ausgang",
+            "Sortie réglable",
+        ],
+    ),
+    (
+        "adj_output",
+        [
+            "Adjustable output voltage",
+            "Tensione uscita regolabile",
+            "Spannung des einstellbaren Ausgangs",
+            "Tension de la sortie réglable",
+        ],
+    ),
+    (
+        "power_status_on_boot",
+        [
+            "Outputs on at power up",
+            "Uscite accese all'avvio",
+            "Beim Einschalten aktive Ausgänge",
+            "Sorties allumées au démarrage",
+        ],
+    ),
+    (
+        "dew1",
+        [
+            "Dew heater A",
+            "Fascia anticondensa A",
+            "Taukappenheizung A",
+            "Chauffage anti-rosée A",
+        ],
+    ),
+    (
+        "dew2",
+        [
+            "Dew heater B",
+            "Fascia anticondensa B",
+            "Taukappenheizung B",
+            "Chauffage anti-rosée B",
+        ],
+    ),
+    (
+        "dew1_power",
+        [
+            "Dew heater A power",
+            "Potenza fascia anticondensa A",
+            "Leistung Taukappenheizung A",
+            "Puissance chauffage anti-rosée A",
+        ],
+    ),
+    (
+        "dew2_power",
+        [
+            "Dew heater B power",
+            "Potenza fascia anticondensa B",
+            "Leistung Taukappenheizung B",
+            "Puissance chauffage anti-rosée B",
+        ],
+    ),
+    (
+        "dew_power",
+        [
+            "Dew heater power",
+            "Potenza fascia anticondensa",
+            "Leistung Taukappenheizung",
+            "Puissance chauffage anti-rosée",
+        ],
+    ),
+    (
+        "dew1_current",
+        [
+            "Dew heater A current",
+            "Corrente fascia anticondensa A",
+            "Strom Taukappenheizung A",
+            "Courant chauffage anti-rosée A",
+        ],
+    ),
+    (
+        "dew2_current",
+        [
+            "Dew heater B current",
+            "Corrente fascia anticondensa B",
+            "Strom Taukappenheizung B",
+            "Courant chauffage anti-rosée B",
+        ],
+    ),
+    (
+        "autodew",
+        [
+            "Auto dew",
+            "Anticondensa automatica",
+            "Automatische Taukappenheizung",
+            "Anti-rosée automatique",
+        ],
+    ),
+    (
+        "dew_ramp_ms",
+        [
+            "Dew heater ramp",
+            "Rampa fasce anticondensa",
+            "Rampe der Taukappenheizung",
+            "Rampe du chauffage anti-rosée",
+        ],
+    ),
+    (
+        "polling_interval",
+        [
+            "Polling interval",
+            "Intervallo di lettura",
+            "Abfrageintervall",
+            "Intervalle d'interrogation",
+        ],
+    ),
+    (
+        "battery_soc",
+        [
+            "Battery charge",
+            "Carica batteria",
+            "Akkuladung",
+            "Charge de la batterie",
+        ],
+    ),
+    (
+        "battery_remaining_ah",
+        [
+            "Battery remaining capacity",
+            "Capacità residua batteria",
+            "Restkapazität des Akkus",
+            "Capacité restante de la batterie",
+        ],
+    ),
+    (
+        "battery_runtime",
+        [
+            "Battery runtime",
+            "Autonomia batteria",
+            "Akkulaufzeit",
+            "Autonomie de la batterie",
+        ],
+    ),
+    (
+        "battery_low",
+        [
+            "Battery low",
+            "Batteria scarica",
+            "Akku schwach",
+            "Batterie faible",
+        ],
+    ),
+    (
+        "position",
+        ["Position", "Posizione", "Position", "Position"],
+    ),
+    (
+        "moving",
+        ["Moving", "In movimento", "In Bewegung", "En mouvement"],
+    ),
+    (
+        "backlash",
+        [
+            "Backlash",
+            "Gioco meccanico",
+            "Umkehrspiel",
+            "Jeu mécanique",
+        ],
+    ),
+    (
+        "reverse",
+        ["Reversed", "Direzione invertita", "Umgekehrt", "Inversé"],
+    ),
+];
+
+/// Display names of the properties derived from another one, `{}` being the
+/// display name of the other one
+const SUFFIXES: &[(&str, [&str; 4])] = &[
+    ("_pct", ["{} (%)", "{} (%)", "{} (%)", "{} (%)"]),
+    (
+        "_smoothed",
+        [
+            "{} (smoothed)",
+            "{} (filtrata)",
+            "{} (geglättet)",
+            "{} (lissage)",
+        ],
+    ),
+    (
+        "_energy_today",
+        [
+            "{}: energy today",
+            "{}: energia di oggi",
+            "{}: Energie heute",
+            "{} : énergie du jour",
+        ],
+    ),
+    (
+        "_energy",
+        ["{}: energy", "{}: energia", "{}: Energie", "{} : énergie"],
+    ),
+];
+
+/// Names of the units
+const UNITS: &[(&str, [&str; 4])] = &[
+    ("V", ["volts", "volt", "Volt", "volts"]),
+    ("A", ["amperes", "ampere", "Ampere", "ampères"]),
+    ("W", ["watts", "watt", "Watt", "watts"]),
+    ("Wh", ["watt-hours", "wattora", "Wattstunden", "wattheures"]),
+    (
+        "Ah",
+        [
+            "ampere-hours",
+            "amperora",
+            "Amperestunden",
+            "ampères-heures",
+        ],
+    ),
+    (
+        "°C",
+        [
+            "degrees Celsius",
+            "gradi Celsius",
+            "Grad Celsius",
+            "degrés Celsius",
+        ],
+    ),
+    ("%", ["percent", "percento", "Prozent", "pour cent"]),
+    (
+        "ms",
+        [
+            "milliseconds",
+            "millisecondi",
+            "Millisekunden",
+            "millisecondes",
+        ],
+    ),
+    ("s", ["seconds", "secondi", "Sekunden", "secondes"]),
+    ("h", ["hours", "ore", "Stunden", "heures"]),
+    (
+        "pwm",
+        [
+            "PWM duty cycle (0-255)",
+            "duty cycle PWM (0-255)",
+            "PWM-Tastgrad (0-255)",
+            "rapport cyclique PWM (0-255)",
+        ],
+    ),
+];
+
+fn lookup(
+    table: &[(&str, [&'static str; 4])],
+    key: &str,
+    language: Language,
+) -> Option<&'static str> {
+    table
+        .iter()
+        .find(|(name, _)| *name == key)
+        .map(|(_, texts)| language.pick(texts))
+}
+
+/// Display name of a property. The ones not translated, e.g. the outputs of
+/// a UPB, get their name with spaces.
+pub fn label(name: &str, language: Language) -> String {
+    if let Some(label) = lookup(LABELS, name, language) {
+        return label.to_owned();
+    }
+    for (suffix, patterns) in SUFFIXES {
+        let Some(base) = name.strip_suffix(suffix) else {
+            continue;
+        };
+        // The energy counters are named after the outputs
+        let base_label = lookup(LABELS, &format!("{}_status", base), language)
+            .map(str::to_owned)
+            .unwrap_or_else(|| label(base, language));
+        return language.pick(patterns).replace("{}", &base_label);
+    }
+    let mut label = name.replace('_', " ");
+    if let Some(first) = label.get_mut(..1) {
+        first.make_ascii_uppercase();
+    }
+    label
+}
+
+/// Name of a unit, `None` for an unknown one
+pub fn unit_label(unit: &str, language: Language) -> Option<&'static str> {
+    lookup(UNITS, unit, language)
+}
@@ -0,0 +1,117 @@
+use crate::buffer::now_millis;
+use crate::history::{Sample, RECORDED};
+use log::debug;
+use rusqlite::{params, params_from_iter, Connection};
+use serde_json::Value;
+use std::path::Path;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Default age after which samples and events are deleted from the journal.
+pub const DEFAULT_RETENTION_DAYS: u64 = 30;
+
+/// SQLite log of the readings and setting changes of every device.
+///
+/// `samples` has one row per poll with a column per recorded reading,
+/// `events` one row per setting or connection change, so power drops or dew
+/// events can be investigated after the fact with plain SQL.
+pub struct Journal {
+    conn: Connection,
+    retention: Duration,
+    insert_sample: String,
+}
+
+impl Journal {
+    pub fn open(path: &Path, retention: Duration) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        let columns: Vec<String> = RECORDED.iter().map(|r| format!("{} REAL", r)).collect();
+        conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS samples (
+                device TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                {}
+            );
+            CREATE INDEX IF NOT EXISTS samples_device_time ON samples (device, timestamp);
+            CREATE TABLE IF NOT EXISTS events (
+                device TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                property TEXT NOT NULL,
+                value TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS events_device_time ON events (device, timestamp);",
+            columns.join(",\n")
+        ))?;
+
+        let placeholders: Vec<String> = (1..=RECORDED.len() + 2)
+            .map(|i| format!("?{}", i))
+            .collect();
+        let insert_sample = format!(
+            "INSERT INTO samples (device, timestamp, {}) VALUES ({})",
+            RECORDED.join(", "),
+            placeholders.join(", ")
+        );
+
+        Ok(Self {
+            conn,
+            retention,
+            insert_sample,
+        })
+    }
+
+    /// Log a poll, readings the device doesn't have are left NULL.
+    pub fn record_sample(&self, device: &Uuid, sample: &Sample) -> rusqlite::Result<()> {
+        let mut values: Vec<Box<dyn rusqlite::ToSql>> = vec![
+            Box::new(device.to_string()),
+            Box::new(sample.timestamp as i64),
+        ];
+        values.extend(RECORDED.iter().map(|name| {
+            Box::new(sample.readings.get(*name).and_then(Value::as_f64)) as Box<dyn rusqlite::ToSql>
+        }));
+
+        self.conn
+            .prepare_cached(&self.insert_sample)?
+            .execute(params_from_iter(values))?;
+        Ok(())
+    }
+
+    /// Log the new values of properties that changed, in a single transaction.
+    pub fn record_events(
+        &mut self,
+        device: &Uuid,
+        changes: &[(String, Value)],
+    ) -> rusqlite::Result<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let timestamp = now_millis() as i64;
+        let tx = self.conn.transaction()?;
+        {
+            let mut insert = tx.prepare_cached(
+                "INSERT INTO events (device, timestamp, property, value) VALUES (?1, ?2, ?3, ?4)",
+            )?;
+            for (property, value) in changes {
+                let value = match value {
+                    Value::String(s) => s.clone(),
+                    v => v.to_string(),
+                };
+                insert.execute(params![device.to_string(), timestamp, property, value])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// Delete what is older than the retention period, returns how many rows were removed.
+    pub fn prune(&self) -> rusqlite::Result<usize> {
+        let oldest_allowed = now_millis().saturating_sub(self.retention.as_millis() as u64) as i64;
+        let pruned = self
+            .conn
+            .execute("DELETE FROM samples WHERE timestamp < ?1", [oldest_allowed])?
+            + self
+                .conn
+                .execute("DELETE FROM events WHERE timestamp < ?1", [oldest_allowed])?;
+        if pruned > 0 {
+            debug!("Pruned {} journal rows past retention", pruned);
+        }
+        Ok(pruned)
+    }
+}
@@ -0,0 +1,81 @@
+use log::{debug, error, warn};
+use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Labels of the outputs of a device, keyed by output, e.g. `quadport`
+pub type Labels = BTreeMap<String, String>;
+
+/// What the users plugged on the outputs of their devices, e.g. `camera` on
+/// the quadport or `dew strap 2x` on `dew1`, keyed by device id.
+///
+/// Published as the `{output}_label` properties of the devices. The
+/// `[labels]` tables of the config file give the defaults, the labels set at
+/// runtime override them and are saved to a JSON file.
+#[derive(Default)]
+pub struct OutputLabels {
+    path: PathBuf,
+    configured: HashMap<Uuid, Labels>,
+    saved: HashMap<Uuid, Labels>,
+}
+
+/// `~/.pegasus_ppba_labels.json`, next to the settings
+pub fn default_path() -> PathBuf {
+    crate::settings::default_path().with_file_name(".pegasus_ppba_labels.json")
+}
+
+impl OutputLabels {
+    pub fn load(path: PathBuf, configured: HashMap<Uuid, Labels>) -> Self {
+        let saved = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring corrupted labels {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self {
+            path,
+            configured,
+            saved,
+        }
+    }
+
+    /// Replace the labels of the config file, e.g. once reloaded
+    pub fn set_configured(&mut self, configured: HashMap<Uuid, Labels>) {
+        self.configured = configured;
+    }
+
+    pub fn get(&self, id: &Uuid, output: &str) -> Option<&str> {
+        self.saved
+            .get(id)
+            .and_then(|labels| labels.get(output))
+            .or_else(|| {
+                self.configured
+                    .get(id)
+                    .and_then(|labels| labels.get(output))
+            })
+            .map(String::as_str)
+    }
+
+    /// Label an output, an empty label goes back to the one of the config file.
+    pub fn set(&mut self, id: Uuid, output: &str, label: &str) {
+        let label = label.trim();
+        let labels = self.saved.entry(id).or_default();
+        if label.is_empty() {
+            labels.remove(output);
+        } else {
+            labels.insert(output.to_owned(), label.to_owned());
+        }
+        if labels.is_empty() {
+            self.saved.remove(&id);
+        }
+        debug!("Saving labels to {}", self.path.display());
+
+        let result = serde_json::to_string_pretty(&self.saved)
+            .map_err(|e| e.to_string())
+            .and_then(|content| std::fs::write(&self.path, content).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Cannot write labels to {}: {}", self.path.display(), e);
+        }
+    }
+}
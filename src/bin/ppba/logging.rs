@@ -0,0 +1,286 @@
+use chrono::{DateTime, Local, NaiveDate};
+use clap::ValueEnum;
+use env_logger::fmt::WriteStyle;
+use env_logger::{Builder, Logger, Target};
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Days of rotated log files kept by default
+pub const DEFAULT_RETENTION_DAYS: u32 = 14;
+
+/// Level set on `drivers/pegasus_ppba/loglevel`, 0 when the configured
+/// ones apply
+static OVERRIDE: AtomicUsize = AtomicUsize::new(0);
+/// Most verbose configured level, restored by [`set_level`]
+static CONFIGURED: AtomicUsize = AtomicUsize::new(LevelFilter::Info as usize);
+
+/// How every record is written
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// One human readable line per record
+    #[default]
+    Text,
+    /// One JSON object per line, for log shippers
+    Json,
+}
+
+/// Where and how to log, merged from the command line and the `[logging]`
+/// table of the config file
+#[derive(Debug)]
+pub struct LogConfig {
+    /// Level of every module not in `modules`
+    pub level: LevelFilter,
+    /// Levels of single modules, e.g. `pegasus_astro::transport`
+    pub modules: Vec<(String, LevelFilter)>,
+    pub format: LogFormat,
+    /// Rotated every night, stderr when `None`
+    pub file: Option<PathBuf>,
+    /// Days of rotated files kept, 0 keeps them all
+    pub retention_days: u32,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::Info,
+            modules: Vec::new(),
+            format: LogFormat::default(),
+            file: None,
+            retention_days: DEFAULT_RETENTION_DAYS,
+        }
+    }
+}
+
+impl LogConfig {
+    /// `env_logger` filters, `LS_LOG_LEVEL` wins over the configuration
+    fn filters(&self) -> String {
+        if let Ok(filters) = std::env::var("LS_LOG_LEVEL") {
+            return filters;
+        }
+        std::iter::once(self.level.to_string())
+            .chain(
+                self.modules
+                    .iter()
+                    .map(|(module, level)| format!("{}={}", module, level)),
+            )
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Log file renamed to `{name}.{date}` once the day is over, the files older
+/// than the retention are deleted at the same time.
+struct RollingFile {
+    path: PathBuf,
+    file: File,
+    /// Day of the records in `file`
+    date: NaiveDate,
+    retention_days: u32,
+}
+
+impl RollingFile {
+    fn open(path: &Path, retention_days: u32) -> io::Result<Self> {
+        let today = Local::now().date_naive();
+        // Left over by a previous run, rotated if it's from another day
+        let date = std::fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .map(|modified| DateTime::<Local>::from(modified).date_naive())
+            .unwrap_or(today);
+        let mut rolling = Self {
+            path: path.to_path_buf(),
+            file: Self::append(path)?,
+            date,
+            retention_days,
+        };
+        if date != today {
+            rolling.rotate(today)?;
+        }
+        Ok(rolling)
+    }
+
+    fn append(path: &Path) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn rotated_path(&self, date: NaiveDate) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}", date.format("%Y-%m-%d")));
+        self.path.with_file_name(name)
+    }
+
+    fn rotate(&mut self, today: NaiveDate) -> io::Result<()> {
+        self.file.flush()?;
+        std::fs::rename(&self.path, self.rotated_path(self.date))?;
+        self.file = Self::append(&self.path)?;
+        self.date = today;
+        self.prune(today);
+        Ok(())
+    }
+
+    /// Delete the rotated files past the retention, errors are ignored as
+    /// there is nowhere to log them
+    fn prune(&self, today: NaiveDate) {
+        if self.retention_days == 0 {
+            return;
+        }
+        let Some(name) = self.path.file_name().and_then(|name| name.to_str()) else {
+            return;
+        };
+        let prefix = format!("{}.", name);
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let file_name = entry.file_name();
+            let Some(date) = file_name
+                .to_str()
+                .and_then(|file_name| file_name.strip_prefix(&prefix))
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok())
+            else {
+                continue;
+            };
+            if (today - date).num_days() > i64::from(self.retention_days) {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+}
+
+/// Handle on the log file given to both loggers of [`DriverLogger`]
+#[derive(Clone)]
+struct SharedFile(Arc<Mutex<RollingFile>>);
+
+impl Write for SharedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rolling = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let today = Local::now().date_naive();
+        if today != rolling.date {
+            rolling.rotate(today)?;
+        }
+        rolling.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .file
+            .flush()
+    }
+}
+
+fn builder(config: &LogConfig, file: Option<&SharedFile>) -> Builder {
+    let mut builder = Builder::new();
+    if let Some(file) = file {
+        builder
+            .target(Target::Pipe(Box::new(file.clone())))
+            .write_style(WriteStyle::Never);
+    }
+    if config.format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": Local::now().to_rfc3339(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder
+}
+
+/// Logger whose level can be changed at runtime, e.g. to debug a device
+/// without restarting the driver.
+struct DriverLogger {
+    /// Filtered by the configured levels
+    configured: Logger,
+    /// Takes every record, used once the level is overridden
+    everything: Logger,
+}
+
+impl DriverLogger {
+    fn level(&self) -> Option<LevelFilter> {
+        match OVERRIDE.load(Ordering::Relaxed) {
+            0 => None,
+            level => LevelFilter::iter().nth(level - 1),
+        }
+    }
+}
+
+impl Log for DriverLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match self.level() {
+            Some(level) => metadata.level() <= level,
+            None => self.configured.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        match self.level() {
+            Some(level) if record.level() <= level => self.everything.log(record),
+            Some(_) => {}
+            None => self.configured.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.configured.flush();
+    }
+}
+
+/// Log with the configured levels, or the ones of `LS_LOG_LEVEL` if it's set
+pub fn init(config: &LogConfig) -> Result<(), String> {
+    let file = match &config.file {
+        Some(path) => Some(SharedFile(Arc::new(Mutex::new(
+            RollingFile::open(path, config.retention_days)
+                .map_err(|e| format!("Cannot log to {}: {}", path.display(), e))?,
+        )))),
+        None => None,
+    };
+    let configured = builder(config, file.as_ref())
+        .parse_filters(&config.filters())
+        .build();
+    let everything = builder(config, file.as_ref())
+        .filter_level(LevelFilter::Trace)
+        .build();
+    let max_level = configured.filter();
+    let logger = DriverLogger {
+        configured,
+        everything,
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(max_level);
+    }
+    CONFIGURED.store(max_level as usize, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Log every module at `level`, `None` goes back to the configured levels
+pub fn set_level(level: Option<LevelFilter>) {
+    match level {
+        Some(level) => {
+            OVERRIDE.store(level as usize + 1, Ordering::Relaxed);
+            log::set_max_level(level);
+        }
+        None => {
+            OVERRIDE.store(0, Ordering::Relaxed);
+            let configured = CONFIGURED.load(Ordering::Relaxed);
+            log::set_max_level(
+                LevelFilter::iter()
+                    .nth(configured)
+                    .unwrap_or(LevelFilter::Info),
+            );
+        }
+    }
+}
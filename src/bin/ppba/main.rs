@@ -1,161 +1,3487 @@
 use log::{debug, error, info, warn};
 
-pub mod ppba;
-use env_logger::Env;
-use pegasus_astro::utils::look_for_devices;
-use ppba::PegasusPowerBox;
-use std::sync::{Arc, RwLock};
+mod alarms;
+mod aliases;
+mod backoff;
+mod buffer;
+mod calibration;
+mod changes;
+mod config;
+mod events;
+mod groups;
+mod history;
+#[cfg(feature = "http-api")]
+mod http;
+mod i18n;
+#[cfg(feature = "sqlite")]
+mod journal;
+mod labels;
+mod logging;
+mod mdns;
+mod profiles;
+mod registry;
+mod reload;
+mod rules;
+mod schedule;
+mod schema;
+mod selftest;
+mod service;
+mod settings;
+mod snapshots;
+mod steps;
+mod tcp;
+mod throttle;
+mod topics;
+mod watchdog;
+mod weather;
+use alarms::{Alarm, AlarmEvent, AlarmMonitor};
+use aliases::Aliases;
+use astrotools::properties::{Permission, Property};
+use backoff::{Backoff, ConnectionStatus};
+use buffer::OfflineBuffer;
+use calibration::Calibrations;
+use changes::ChangeTracker;
+use clap::Parser;
+use config::{Cli, MqttConfig};
+use events::EventKind;
+use groups::PollingGroups;
+use history::{History, HistoryRequest, Sample};
+use i18n::Language;
+#[cfg(feature = "sqlite")]
+use journal::Journal;
+use labels::OutputLabels;
+use logging::LogConfig;
+use pegasus_astro::actor::DeviceHandle;
+use pegasus_astro::battery::BatteryConfig;
+use pegasus_astro::calibration::SensorCalibration;
+use pegasus_astro::device::{AstronomicalDevice, PegasusDevice};
+use pegasus_astro::dewpoint::Magnus;
+use pegasus_astro::discovery::{self, DeviceFamily, DiscoveredDevice};
+use pegasus_astro::error::PegasusError;
+use pegasus_astro::focuscube::FocusCube;
+use pegasus_astro::limits::CurrentLimits;
+use pegasus_astro::ppba::{DewChannel, PegasusPowerBox, Profile, SavedState, Setting};
+use pegasus_astro::ppbm::PocketPowerBoxMicro;
+use pegasus_astro::ramp::DewRamp;
+use pegasus_astro::sim::FakePpbaPort;
+use pegasus_astro::smoothing::Filter;
+use pegasus_astro::trace;
+use pegasus_astro::transport::{self, RetryPolicies, RetryPolicy, SerialConfig, SerialSettings};
+use pegasus_astro::upbv2::UltimatePowerBoxV2;
+use pegasus_astro::utils;
+use profiles::{ProfileRequest, ProfileStore};
+use registry::{Registry, RegistryEntry};
+use reload::ReloadReport;
+use rules::DewRule;
+use schedule::{Minutes, Schedule, ScheduledAction};
+use serde::{Deserialize, Serialize};
+use settings::SettingsStore;
+use snapshots::{Snapshot, Snapshots};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use steps::StepDetector;
+use throttle::Throttle;
+use weather::Weather;
+
+use chrono::{Local, TimeZone, Timelike};
 
 use rumqttc::Event::{Incoming, Outgoing};
-use rumqttc::Packet::Publish;
-use rumqttc::{AsyncClient, MqttOptions, QoS};
+use rumqttc::Packet::{ConnAck, Publish};
+use rumqttc::{AsyncClient, EventLoop, LastWill, MqttOptions, QoS};
+
+use tokio::sync::{mpsc, watch, Notify, RwLock};
+use tokio::task::JoinHandle;
+use tokio::{signal, task};
+use uuid::Uuid;
+use watchdog::{Recovery, Watchdog};
+
+use rumqttc::ClientError;
+
+/// Retained driver status, `offline` is set by the broker if the driver dies
+const DRIVER_STATUS_TOPIC: &str = "drivers/pegasus_ppba/status";
+const DRIVER_HEARTBEAT_TOPIC: &str = "drivers/pegasus_ppba/heartbeat";
+/// Retained profiles of the PPBAs, keyed by name
+const PROFILES_TOPIC: &str = "drivers/pegasus_ppba/profiles";
+/// How long the shutdown waits for a device or for the broker
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+/// Requests to drive a PPBA on a given port
+const ADD_DEVICE_TOPIC: &str = "devices/ppba/add";
+/// Requests to scan for plugged and unplugged devices right away
+const RESCAN_TOPIC: &str = "drivers/pegasus_ppba/rescan";
+/// Requests to reload the config file, like a SIGHUP
+const RELOAD_TOPIC: &str = "drivers/pegasus_ppba/reload";
+/// What the last reload applied, or why it failed
+const RELOAD_RESULT_TOPIC: &str = "drivers/pegasus_ppba/reload/result";
+/// Requests to change the log level, `{"level": "debug"}`
+const LOG_LEVEL_TOPIC: &str = "drivers/pegasus_ppba/loglevel";
+
+type Ppba = DeviceHandle<PegasusPowerBox>;
+type Upb = DeviceHandle<UltimatePowerBoxV2>;
+type Ppbm = DeviceHandle<PocketPowerBoxMicro>;
+type Focuser = DeviceHandle<FocusCube>;
+
+#[derive(Default)]
+struct PegasusDriver {
+    devices: Vec<Ppba>,
+    upb_devices: Vec<Upb>,
+    ppbm_devices: Vec<Ppbm>,
+    focusers: Vec<Focuser>,
+    /// Addresses that failed to connect, they are retried only once replugged
+    failed: HashSet<String>,
+    /// Addresses of the devices added by hand, they are kept even if not found by the scans
+    manual: HashSet<String>,
+    /// Settings to reapply to the PPBAs found, if restoring is enabled
+    restore_from: Option<Arc<Mutex<SettingsStore>>>,
+    /// Retry policy given to every device found
+    retry: RetryPolicies,
+    /// Readings smoothed on every device having them
+    smoothing: BTreeMap<String, Filter>,
+    /// Serial links configured per device
+    serial: SerialSettings,
+    read_only: ReadOnly,
+    /// Ids of the devices found, kept across restarts
+    registry: Option<Registry>,
+    /// Batteries powering the PPBAs, keyed by USB serial number or port
+    batteries: HashMap<String, BatteryConfig>,
+    /// Low battery alarm of every PPBA with a battery
+    battery_alarms: HashMap<Uuid, Alarm>,
+    /// Polling group of every device
+    polling_groups: Arc<Mutex<PollingGroups>>,
+    /// What is plugged on the outputs of every device
+    labels: Arc<Mutex<OutputLabels>>,
+    /// Offsets of the sensor of every device
+    calibrations: Arc<Mutex<Calibrations>>,
+    /// Coefficients of the dew point computed by every device
+    dew_formula: Magnus,
+    /// Held during a whole [`rescan`], two scans at once would open the same ports
+    scanning: Arc<tokio::sync::Mutex<()>>,
+}
+
+/// Devices whose settings are validated and logged but never sent
+#[derive(Debug, Default)]
+struct ReadOnly {
+    /// Every device, `--read-only`
+    all: bool,
+    /// USB serial numbers and ports of `--read-only-device`
+    devices: HashSet<String>,
+}
+
+impl ReadOnly {
+    fn applies(&self, serial: Option<&str>, address: &str) -> bool {
+        self.all
+            || serial.is_some_and(|serial| self.devices.contains(serial))
+            || self.devices.contains(address)
+    }
+}
+
+/// What a scan found plugged
+struct Scan {
+    /// Every port plugged and the ones of the devices added by hand
+    plugged: HashSet<String>,
+    /// Devices on ports neither driven nor failed before
+    found: Vec<DiscoveredDevice>,
+}
+
+/// Remove the devices of the families matching from `devices` and return them
+fn take_family(
+    devices: &mut Vec<DiscoveredDevice>,
+    matches: impl Fn(DeviceFamily) -> bool,
+) -> Vec<DiscoveredDevice> {
+    let (taken, rest) = std::mem::take(devices)
+        .into_iter()
+        .partition(|dev| dev.family.is_some_and(&matches));
+    *devices = rest;
+    taken
+}
+
+/// Kind of the devices driven, also the namespace of their topics with
+/// `--family-topics`
+trait Family {
+    /// `ppba`, `upb`, `ppbm` or `focuser`
+    const FAMILY: &'static str;
+    /// Outputs the users can label, see [`OutputLabels`]
+    const OUTPUTS: &'static [&'static str];
+}
+
+impl Family for PegasusPowerBox {
+    const FAMILY: &'static str = "ppba";
+    const OUTPUTS: &'static [&'static str] = &["quadport", "adj_output", "dew1", "dew2"];
+}
+
+impl Family for UltimatePowerBoxV2 {
+    const FAMILY: &'static str = "upb";
+    const OUTPUTS: &'static [&'static str] = &[
+        "power_port_1",
+        "power_port_2",
+        "power_port_3",
+        "power_port_4",
+        "usb_port_1",
+        "usb_port_2",
+        "usb_port_3",
+        "usb_port_4",
+        "usb_port_5",
+        "usb_port_6",
+        "dew_1",
+        "dew_2",
+        "dew_3",
+        "adj_output",
+    ];
+}
+
+impl Family for PocketPowerBoxMicro {
+    const FAMILY: &'static str = "ppbm";
+    const OUTPUTS: &'static [&'static str] = &["dew"];
+}
+
+impl Family for FocusCube {
+    const FAMILY: &'static str = "focuser";
+    const OUTPUTS: &'static [&'static str] = &[];
+}
+
+/// Families found by the scans and driven the same way, see
+/// [`PegasusDriver::connect_family`]. The PPBAs have their own path, they
+/// can be added by hand and get their settings restored.
+trait Connect: Family + AstronomicalDevice + Sized + Send + 'static {
+    /// Name of the devices, followed by their USB serial number
+    const NAME: &'static str;
+    const BAUD: u32;
+
+    fn open(
+        name: &str,
+        address: &str,
+        config: &SerialConfig,
+    ) -> impl Future<Output = Result<Self, PegasusError>> + Send;
+    /// Devices of the family driven by `driver`
+    fn handles(driver: &mut PegasusDriver) -> &mut Vec<DeviceHandle<Self>>;
+    fn set_serial_number(&mut self, serial_number: &str);
+    fn set_id(&mut self, id: Uuid);
+    fn set_retry_policies(&mut self, retry: RetryPolicies);
+    fn set_read_only(&mut self, read_only: bool);
+
+    /// The families without sensors ignore the sensor settings
+    fn set_calibration(&mut self, _calibration: SensorCalibration) -> Result<(), PegasusError> {
+        Ok(())
+    }
+
+    fn set_dew_point_formula(&mut self, _formula: Magnus) -> Result<(), PegasusError> {
+        Ok(())
+    }
+
+    fn set_smoothing(&mut self, _reading: &str, _filter: Filter) -> Result<(), PegasusError> {
+        Ok(())
+    }
+}
+
+impl Connect for UltimatePowerBoxV2 {
+    const NAME: &'static str = "PegasusUltimatePowerBoxV2";
+    const BAUD: u32 = transport::DEFAULT_BAUD;
+
+    fn open(
+        name: &str,
+        address: &str,
+        config: &SerialConfig,
+    ) -> impl Future<Output = Result<Self, PegasusError>> + Send {
+        Self::with_config(name, address, config)
+    }
+
+    fn handles(driver: &mut PegasusDriver) -> &mut Vec<DeviceHandle<Self>> {
+        &mut driver.upb_devices
+    }
+
+    fn set_serial_number(&mut self, serial_number: &str) {
+        Self::set_serial_number(self, serial_number)
+    }
+
+    fn set_id(&mut self, id: Uuid) {
+        Self::set_id(self, id)
+    }
+
+    fn set_retry_policies(&mut self, retry: RetryPolicies) {
+        Self::set_retry_policies(self, retry)
+    }
+
+    fn set_read_only(&mut self, read_only: bool) {
+        Self::set_read_only(self, read_only)
+    }
+
+    fn set_calibration(&mut self, calibration: SensorCalibration) -> Result<(), PegasusError> {
+        Self::set_calibration(self, calibration)
+    }
+
+    fn set_dew_point_formula(&mut self, formula: Magnus) -> Result<(), PegasusError> {
+        Self::set_dew_point_formula(self, formula)
+    }
+
+    fn set_smoothing(&mut self, reading: &str, filter: Filter) -> Result<(), PegasusError> {
+        Self::set_smoothing(self, reading, filter)
+    }
+}
+
+impl Connect for PocketPowerBoxMicro {
+    const NAME: &'static str = "PegasusPocketPowerBoxMicro";
+    const BAUD: u32 = transport::DEFAULT_BAUD;
+
+    fn open(
+        name: &str,
+        address: &str,
+        config: &SerialConfig,
+    ) -> impl Future<Output = Result<Self, PegasusError>> + Send {
+        Self::try_with_config(name, address, config)
+    }
+
+    fn handles(driver: &mut PegasusDriver) -> &mut Vec<DeviceHandle<Self>> {
+        &mut driver.ppbm_devices
+    }
+
+    fn set_serial_number(&mut self, serial_number: &str) {
+        Self::set_serial_number(self, serial_number)
+    }
+
+    fn set_id(&mut self, id: Uuid) {
+        Self::set_id(self, id)
+    }
+
+    fn set_retry_policies(&mut self, retry: RetryPolicies) {
+        Self::set_retry_policies(self, retry)
+    }
+
+    fn set_read_only(&mut self, read_only: bool) {
+        Self::set_read_only(self, read_only)
+    }
+
+    fn set_calibration(&mut self, calibration: SensorCalibration) -> Result<(), PegasusError> {
+        Self::set_calibration(self, calibration)
+    }
+
+    fn set_dew_point_formula(&mut self, formula: Magnus) -> Result<(), PegasusError> {
+        Self::set_dew_point_formula(self, formula)
+    }
+
+    fn set_smoothing(&mut self, reading: &str, filter: Filter) -> Result<(), PegasusError> {
+        Self::set_smoothing(self, reading, filter)
+    }
+}
+
+impl Connect for FocusCube {
+    const NAME: &'static str = "PegasusFocusCube";
+    const BAUD: u32 = transport::FOCUSER_BAUD;
+
+    fn open(
+        name: &str,
+        address: &str,
+        config: &SerialConfig,
+    ) -> impl Future<Output = Result<Self, PegasusError>> + Send {
+        Self::try_with_config(name, address, config)
+    }
+
+    fn handles(driver: &mut PegasusDriver) -> &mut Vec<DeviceHandle<Self>> {
+        &mut driver.focusers
+    }
+
+    fn set_serial_number(&mut self, serial_number: &str) {
+        Self::set_serial_number(self, serial_number)
+    }
+
+    fn set_id(&mut self, id: Uuid) {
+        Self::set_id(self, id)
+    }
+
+    fn set_retry_policies(&mut self, retry: RetryPolicies) {
+        Self::set_retry_policies(self, retry)
+    }
+
+    fn set_read_only(&mut self, read_only: bool) {
+        Self::set_read_only(self, read_only)
+    }
+}
+
+/// Identity of a device, published when it is plugged or unplugged
+#[derive(Clone, Debug, Serialize)]
+struct DeviceInfo {
+    id: Uuid,
+    name: String,
+    address: String,
+    family: &'static str,
+    /// Name given by the user, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alias: Option<String>,
+}
+
+impl DeviceInfo {
+    fn of<D: AstronomicalDevice + Family>(device: &D) -> Self {
+        Self {
+            id: device.get_id(),
+            name: device.get_name().clone(),
+            address: device.get_address().clone(),
+            family: D::FAMILY,
+            alias: None,
+        }
+    }
+
+    fn of_handle<D: Family + Send + 'static>(handle: &DeviceHandle<D>) -> Self {
+        Self {
+            id: handle.id(),
+            name: handle.name().clone(),
+            address: handle.address().clone(),
+            family: D::FAMILY,
+            alias: None,
+        }
+    }
+}
+
+/// Drop from `devices` the ones whose address is not plugged anymore
+fn drop_unplugged<D: Family + Send + 'static>(
+    devices: &mut Vec<DeviceHandle<D>>,
+    plugged: &HashSet<String>,
+) -> Vec<DeviceInfo> {
+    let mut kept = Vec::new();
+    let mut removed = Vec::new();
+
+    for d in devices.drain(..) {
+        let info = DeviceInfo::of_handle(&d);
+        if plugged.contains(&info.address) {
+            kept.push(d);
+        } else {
+            warn!("{} unplugged from {}", info.name, info.address);
+            removed.push(info);
+        }
+    }
+    *devices = kept;
+    removed
+}
+
+impl PegasusDriver {
+    /// A driver without devices, see [`rescan`]
+    fn new(
+        restore_from: Option<Arc<Mutex<SettingsStore>>>,
+        smoothing: BTreeMap<String, Filter>,
+        serial: SerialSettings,
+        read_only: ReadOnly,
+        registry: Registry,
+        batteries: HashMap<String, BatteryConfig>,
+        polling_groups: Arc<Mutex<PollingGroups>>,
+    ) -> Self {
+        Self {
+            restore_from,
+            retry: RetryPolicies::new(RetryPolicy::from_env()),
+            smoothing,
+            serial,
+            read_only,
+            registry: Some(registry),
+            batteries,
+            polling_groups,
+            ..Default::default()
+        }
+    }
+
+    /// List what is plugged, `None` if it cannot be known
+    fn scan(&self) -> Option<Scan> {
+        let discovered = match discovery::discover() {
+            Ok(discovered) => discovered,
+            Err(e) => {
+                // Not knowing what is plugged must not drop every device
+                error!("{}, skipping the scan", e);
+                return None;
+            }
+        };
+        let plugged = discovered
+            .iter()
+            .map(|dev| dev.port.clone())
+            .chain(self.manual.iter().cloned())
+            .collect();
+        let found = discovered
+            .into_iter()
+            .filter(|dev| !self.failed.contains(&dev.port) && !self.find_by_address(&dev.port))
+            .collect();
+        Some(Scan { plugged, found })
+    }
+
+    /// Stop driving the devices that are not `plugged` anymore, returns them
+    fn drop_unplugged(&mut self, plugged: &HashSet<String>) -> Vec<DeviceInfo> {
+        let mut removed = drop_unplugged(&mut self.devices, plugged);
+        removed.extend(drop_unplugged(&mut self.upb_devices, plugged));
+        removed.extend(drop_unplugged(&mut self.ppbm_devices, plugged));
+        removed.extend(drop_unplugged(&mut self.focusers, plugged));
+        self.failed.retain(|address| plugged.contains(address));
+        removed
+    }
+
+    /// Configure a device of the family `D` just connected and start driving
+    /// it, `None` if its port got driven meanwhile
+    fn drive_family<D: Connect>(
+        &mut self,
+        mut device: D,
+        serial: Option<&str>,
+    ) -> Option<DeviceInfo> {
+        let address = device.get_address().clone();
+        if self.find_by_address(&address) {
+            warn!("{} is already driven", address);
+            return None;
+        }
+        if let Some(serial) = serial {
+            device.set_serial_number(serial);
+        } else if let Some(id) = self.known_id(D::FAMILY, &address) {
+            device.set_id(id);
+        }
+        device.set_retry_policies(self.retry.clone());
+        let calibration = self.calibrations.lock().unwrap().get(&device.get_id());
+        if let Err(e) = device.set_dew_point_formula(self.dew_formula) {
+            warn!(
+                "Ignoring the dew point formula for {}: {}",
+                device.get_name(),
+                e
+            );
+        }
+        if let Err(e) = device.set_calibration(calibration) {
+            warn!("Ignoring the calibration of {}: {}", device.get_name(), e);
+        }
+        device.set_read_only(self.read_only.applies(serial, &address));
+        for (reading, filter) in &self.smoothing {
+            // Not every device has every reading
+            if let Err(e) = device.set_smoothing(reading, *filter) {
+                debug!("{}", e);
+            }
+        }
+        let info = DeviceInfo::of(&device);
+        self.register(&info, serial);
+        D::handles(self).push(DeviceHandle::spawn(device));
+        Some(info)
+    }
+
+    /// Connect a PPBA and drive it, its settings are restored if enabled
+    async fn connect_ppba(
+        &mut self,
+        device_name: &str,
+        address: &str,
+        config: &SerialConfig,
+        serial: Option<&str>,
+        limits: &CurrentLimits,
+    ) -> Result<DeviceInfo, PegasusError> {
+        let device = PegasusPowerBox::try_with_config(device_name, address, config).await?;
+        self.drive_ppba(device, address, serial, limits).await
+    }
+
+    /// Configure a PPBA just connected and start driving it
+    async fn drive_ppba(
+        &mut self,
+        mut device: PegasusPowerBox,
+        address: &str,
+        serial: Option<&str>,
+        limits: &CurrentLimits,
+    ) -> Result<DeviceInfo, PegasusError> {
+        let saved = self.prepare_ppba(&mut device, address, serial, limits)?;
+        restore_settings(&mut device, saved).await;
+        let info = DeviceInfo::of(&device);
+        self.register(&info, serial);
+        self.devices.push(DeviceHandle::spawn(device));
+        Ok(info)
+    }
+
+    /// Configure a PPBA just connected, returns its settings to restore
+    fn prepare_ppba(
+        &mut self,
+        device: &mut PegasusPowerBox,
+        address: &str,
+        serial: Option<&str>,
+        limits: &CurrentLimits,
+    ) -> Result<Option<SavedState>, PegasusError> {
+        let device_name = device.get_name().clone();
+        if let Some(serial) = serial {
+            device.set_serial_number(serial);
+        } else if let Some(id) = self.known_id(PegasusPowerBox::FAMILY, address) {
+            device.set_id(id);
+        }
+        device.set_current_limits(limits.clone());
+        device.set_retry_policies(self.retry.clone());
+        let calibration = self.calibrations.lock().unwrap().get(&device.get_id());
+        if let Err(e) = device.set_dew_point_formula(self.dew_formula) {
+            warn!("Ignoring the dew point formula for {}: {}", device_name, e);
+        }
+        if let Err(e) = device.set_calibration(calibration) {
+            warn!("Ignoring the calibration of {}: {}", device_name, e);
+        }
+        device.set_read_only(self.read_only.applies(serial, address));
+        let battery = serial
+            .and_then(|serial| self.batteries.get(serial))
+            .or_else(|| self.batteries.get(address));
+        if let Some(battery) = battery {
+            device.set_battery(battery.clone())?;
+            self.battery_alarms
+                .insert(device.get_id(), Alarm::battery_low(battery.low_soc));
+        }
+        for (reading, filter) in &self.smoothing {
+            // Not every device has every reading
+            if let Err(e) = device.set_smoothing(reading, *filter) {
+                debug!("{}", e);
+            }
+        }
+        Ok(self
+            .restore_from
+            .as_ref()
+            .and_then(|store| store.lock().unwrap().get(&device_name).cloned()))
+    }
+
+    /// Start driving a PPBA prepared by [`prepare_ppba`](Self::prepare_ppba),
+    /// `None` if its port got driven meanwhile
+    fn drive_prepared_ppba(
+        &mut self,
+        device: PegasusPowerBox,
+        serial: Option<&str>,
+    ) -> Option<DeviceInfo> {
+        if self.find_by_address(device.get_address()) {
+            warn!("{} is already driven", device.get_address());
+            return None;
+        }
+        let info = DeviceInfo::of(&device);
+        self.register(&info, serial);
+        self.devices.push(DeviceHandle::spawn(device));
+        Some(info)
+    }
+
+    /// Id of the device without serial number found on `address` in a previous run
+    fn known_id(&self, family: &str, address: &str) -> Option<Uuid> {
+        self.registry.as_ref()?.id_of(family, address)
+    }
+
+    fn register(&mut self, info: &DeviceInfo, serial: Option<&str>) {
+        topics::register(info.id, info.family);
+        self.polling_groups
+            .lock()
+            .unwrap()
+            .attach(info.id, serial, &info.address);
+        if let Some(registry) = &mut self.registry {
+            registry.record(
+                info.id,
+                RegistryEntry {
+                    family: info.family.to_owned(),
+                    serial_number: serial.map(str::to_owned),
+                    address: info.address.clone(),
+                },
+            );
+        }
+    }
+
+    /// Drive a PPBA on a port given by the user, e.g. one without a USB
+    /// serial number. It's never dropped by the rescans. `baud` overrides the
+    /// one configured for the port.
+    async fn add_ppba(
+        &mut self,
+        address: &str,
+        baud: Option<u32>,
+        limits: &CurrentLimits,
+    ) -> Result<DeviceInfo, PegasusError> {
+        if self.manual.contains(address) || self.find_by_address(address) {
+            return Err(PegasusError::Validation(format!(
+                "{} is already driven",
+                address
+            )));
+        }
+        let device_name = format!("PegausPowerBoxAdvanced-{}", address);
+        let mut config = self.serial.resolve(None, address, transport::DEFAULT_BAUD);
+        if let Some(baud) = baud {
+            config.baud = baud;
+        }
+        let info = self
+            .connect_ppba(&device_name, address, &config, None, limits)
+            .await?;
+        self.manual.insert(address.to_owned());
+        info!("{} connected on {}", info.name, info.address);
+        Ok(info)
+    }
+
+    /// Drive `count` simulated PPBAs on `sim://0`, `sim://1`... Their serial
+    /// numbers, and so their ids, are the same at every start and they're
+    /// never dropped by the rescans.
+    async fn add_simulated(&mut self, count: u32, limits: &CurrentLimits) {
+        for index in 0..count {
+            let address = format!("sim://{}", index);
+            let serial = format!("SIM{:04}", index);
+            let device_name = format!("PegausPowerBoxAdvanced-{}", serial);
+            let port = Box::new(FakePpbaPort::simulated(index));
+            let res = match PegasusPowerBox::new_with_port(
+                &device_name,
+                &address,
+                transport::DEFAULT_BAUD,
+                port,
+            )
+            .await
+            {
+                Ok(device) => {
+                    self.drive_ppba(device, &address, Some(&serial), limits)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            match res {
+                Ok(info) => {
+                    self.manual.insert(address);
+                    warn!("{} simulated on {}", info.name, info.address);
+                }
+                Err(e) => error!("Cannot simulate {}: {}", device_name, e),
+            }
+        }
+    }
+
+    fn find_by_address(&self, address: &str) -> bool {
+        self.devices.iter().any(|d| d.address() == address)
+            || self.upb_devices.iter().any(|d| d.address() == address)
+            || self.ppbm_devices.iter().any(|d| d.address() == address)
+            || self.focusers.iter().any(|d| d.address() == address)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+            && self.upb_devices.is_empty()
+            && self.ppbm_devices.is_empty()
+            && self.focusers.is_empty()
+    }
+
+    fn ids(&self) -> Vec<Uuid> {
+        self.devices
+            .iter()
+            .map(|d| d.id())
+            .chain(self.upb_devices.iter().map(|d| d.id()))
+            .chain(self.ppbm_devices.iter().map(|d| d.id()))
+            .chain(self.focusers.iter().map(|d| d.id()))
+            .collect()
+    }
+
+    fn infos(&self) -> Vec<DeviceInfo> {
+        self.devices
+            .iter()
+            .map(DeviceInfo::of_handle)
+            .chain(self.upb_devices.iter().map(DeviceInfo::of_handle))
+            .chain(self.ppbm_devices.iter().map(DeviceInfo::of_handle))
+            .chain(self.focusers.iter().map(DeviceInfo::of_handle))
+            .collect()
+    }
+
+    /// The device with the given id, whatever its kind
+    fn find_any(&self, id: &Uuid) -> Option<AnyDevice> {
+        self.find_device(id)
+            .map(AnyDevice::Ppba)
+            .or_else(|| self.find_upb(id).map(AnyDevice::Upb))
+            .or_else(|| self.find_ppbm(id).map(AnyDevice::Ppbm))
+            .or_else(|| self.find_focuser(id).map(AnyDevice::Focuser))
+    }
+
+    fn find_device(&self, id: &Uuid) -> Option<Ppba> {
+        self.devices.iter().find(|d| d.id() == *id).cloned()
+    }
+
+    fn find_upb(&self, id: &Uuid) -> Option<Upb> {
+        self.upb_devices.iter().find(|d| d.id() == *id).cloned()
+    }
+
+    fn find_ppbm(&self, id: &Uuid) -> Option<Ppbm> {
+        self.ppbm_devices.iter().find(|d| d.id() == *id).cloned()
+    }
+
+    fn find_focuser(&self, id: &Uuid) -> Option<Focuser> {
+        self.focusers.iter().find(|d| d.id() == *id).cloned()
+    }
+}
+
+/// A device driven by the driver, whatever its kind
+#[derive(Clone)]
+enum AnyDevice {
+    Ppba(Ppba),
+    Upb(Upb),
+    Ppbm(Ppbm),
+    Focuser(Focuser),
+}
+
+impl AnyDevice {
+    /// Apply an update request, once the device answered
+    async fn update(
+        self,
+        request: &UpdatePropertyRequest,
+        ramp: DewRamp,
+    ) -> Result<(), PegasusError> {
+        match self {
+            Self::Ppba(d) => update_property(d, request, ramp).await,
+            Self::Upb(d) => update_other_device(d, request).await,
+            Self::Ppbm(d) => update_other_device(d, request).await,
+            Self::Focuser(d) => update_other_device(d, request).await,
+        }
+    }
+
+    /// Outputs the users can label
+    fn outputs(&self) -> &'static [&'static str] {
+        match self {
+            Self::Ppba(_) => PegasusPowerBox::OUTPUTS,
+            Self::Upb(_) => UltimatePowerBoxV2::OUTPUTS,
+            Self::Ppbm(_) => PocketPowerBoxMicro::OUTPUTS,
+            Self::Focuser(_) => FocusCube::OUTPUTS,
+        }
+    }
+
+    /// Abort the request the device is serving, see [`DeviceHandle::reset`]
+    fn reset(&self) {
+        match self {
+            Self::Ppba(d) => d.reset(),
+            Self::Upb(d) => d.reset(),
+            Self::Ppbm(d) => d.reset(),
+            Self::Focuser(d) => d.reset(),
+        }
+    }
+}
+
+/// Start polling the device with the given id, whatever its kind
+fn start_polling(
+    driver: &PegasusDriver,
+    id: &Uuid,
+    publisher: Publisher,
+) -> Option<JoinHandle<()>> {
+    let mut publisher = publisher;
+    publisher.battery_alarm = driver.battery_alarms.get(id).cloned();
+    if let Some(d) = driver.find_device(id) {
+        let rules = rules::run(
+            d.clone(),
+            publisher.dew_rules.clone(),
+            publisher.client.clone(),
+            publisher.weather.clone(),
+        );
+        // A single task, so both stop when the device is unplugged
+        return Some(task::spawn(async move {
+            tokio::join!(poll_device(d, publisher), rules);
+        }));
+    }
+    if let Some(d) = driver.find_upb(id) {
+        return Some(spawn_polling(d, publisher));
+    }
+    if let Some(d) = driver.find_ppbm(id) {
+        return Some(spawn_polling(d, publisher));
+    }
+    driver.find_focuser(id).map(|d| spawn_polling(d, publisher))
+}
+
+/// Payload expected on `devices/ppba/add`
+#[derive(Debug, Deserialize)]
+struct AddDeviceRequest {
+    /// Serial port of the device
+    port: String,
+    /// The one configured for the port if not given
+    baud: Option<u32>,
+}
+
+/// Payload expected on `drivers/pegasus_ppba/loglevel`
+#[derive(Debug, Deserialize)]
+struct LogLevelRequest {
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`, `LS_LOG_LEVEL` applies again if not given
+    level: Option<String>,
+}
+
+/// Payload expected on `devices/{id}/update`
+#[derive(Debug, Deserialize)]
+struct UpdatePropertyRequest {
+    prop_name: String,
+    value: String,
+    /// Apply a dew heater change at once, skipping the ramp
+    #[serde(default)]
+    immediate: bool,
+}
+
+/// Value of an update given as JSON, strings are taken as is and booleans as 0/1
+fn setting_value(value: serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(value) => value,
+        serde_json::Value::Bool(value) => u8::from(value).to_string(),
+        value => value.to_string(),
+    }
+}
+
+/// Error of an update, published on `devices/{id}/update/error`
+#[derive(Debug, Serialize)]
+struct UpdateError {
+    prop_name: String,
+    value: String,
+    /// What went wrong, see [`UpdateError::kind`]
+    kind: &'static str,
+    error: String,
+}
+
+impl UpdateError {
+    fn new(request: &UpdatePropertyRequest, e: &PegasusError) -> Self {
+        Self {
+            prop_name: request.prop_name.clone(),
+            value: request.value.clone(),
+            kind: Self::kind(e),
+            error: e.to_string(),
+        }
+    }
+
+    /// Stable name of the error for clients to match on
+    fn kind(e: &PegasusError) -> &'static str {
+        match e {
+            PegasusError::PermissionDenied(_) => "permission_denied",
+            PegasusError::Validation(_) => "invalid_value",
+            PegasusError::Unsupported(_) => "unsupported",
+            PegasusError::ReadOnly(_) => "read_only",
+            PegasusError::Protocol(_) => "refused",
+            PegasusError::Serial(_)
+            | PegasusError::Parse(_)
+            | PegasusError::NotConnected
+            | PegasusError::DeviceBusy(_)
+            | PegasusError::Broker(_) => "not_connected",
+        }
+    }
+}
+
+/// Refuse the update of a property the clients can only read
+async fn check_writable<D>(device: &DeviceHandle<D>, prop_name: &str) -> Result<(), PegasusError>
+where
+    D: PegasusDevice + Serialize + Send + 'static,
+{
+    let prop_name = prop_name.to_owned();
+    device
+        .call_urgent(move |d| Box::pin(async move { d.check_writable(&prop_name) }))
+        .await
+        .and_then(|res| res)
+}
+
+async fn update_property(
+    device: Ppba,
+    request: &UpdatePropertyRequest,
+    ramp: DewRamp,
+) -> Result<(), PegasusError> {
+    check_writable(&device, &request.prop_name).await?;
+    let (channel, target) =
+        match PegasusPowerBox::parse_setting(&request.prop_name, &request.value)? {
+            Setting::DewPower(channel, target) => (channel, target),
+            setting => {
+                return device
+                    .call_urgent(move |d| d.apply(setting))
+                    .await
+                    .and_then(|res| res)
+            }
+        };
+
+    if request.immediate {
+        return device
+            .call_urgent(move |d| {
+                Box::pin(async move { d.set_dew_power_at_once(channel, target).await })
+            })
+            .await
+            .and_then(|res| res);
+    }
+    if !ramp_dew_power(&device, channel, target, ramp).await? {
+        warn!(
+            "Ramp of {} interrupted by another change",
+            request.prop_name
+        );
+    }
+    Ok(())
+}
+
+/// Bring a dew heater to `target` through the steps of `ramp`, spread over
+/// the `dew_ramp_ms` of the device when it's set. The device is released
+/// between two steps, returns false if someone else changed the heater in
+/// the meantime: the newest request wins.
+async fn ramp_dew_power(
+    device: &Ppba,
+    channel: DewChannel,
+    target: u8,
+    ramp: DewRamp,
+) -> Result<bool, PegasusError> {
+    let (mut expected, duration) = device
+        .call_urgent(move |d| Box::pin(async move { (d.dew_power(channel), d.dew_ramp()) }))
+        .await?;
+    let ramp = ramp.over(duration, expected, target);
+    let steps = ramp.steps(expected, target);
+    if steps.len() > 1 {
+        debug!("Ramping dew heater {:?} through {:?}", channel, steps);
+    }
+
+    for (i, pwm) in steps.into_iter().enumerate() {
+        if i > 0 {
+            tokio::time::sleep(ramp.interval).await;
+        }
+
+        let res = device
+            .call_urgent(move |d| {
+                Box::pin(async move {
+                    if d.dew_power(channel) != expected {
+                        return Ok(false);
+                    }
+                    d.set_dew_power_at_once(channel, pwm).await.map(|_| true)
+                })
+            })
+            .await
+            .and_then(|res| res);
+        if !res? {
+            return Ok(false);
+        }
+        expected = pwm;
+    }
+    Ok(true)
+}
+
+/// Apply an update request on a device without dew ramp nor self test support
+async fn update_other_device<D>(
+    device: DeviceHandle<D>,
+    request: &UpdatePropertyRequest,
+) -> Result<(), PegasusError>
+where
+    D: PegasusDevice + Serialize + Send + 'static,
+{
+    check_writable(&device, &request.prop_name).await?;
+    let (prop_name, value) = (request.prop_name.clone(), request.value.clone());
+    device
+        .call_urgent(move |d| Box::pin(async move { d.update_property(&prop_name, &value).await }))
+        .await
+        .and_then(|res| res)
+}
+
+/// Payload expected on `devices/{id}/update/batch`
+#[derive(Debug, Deserialize)]
+struct BatchUpdateRequest {
+    /// Applied at once, dew heater changes are never ramped
+    updates: Vec<UpdatePropertyRequest>,
+    /// Echoed back in the response to match it with the request
+    request_id: Option<serde_json::Value>,
+}
+
+/// Result of an update of a batch, published on `devices/{id}/update/results`
+#[derive(Debug, Serialize)]
+struct UpdateResult {
+    prop_name: String,
+    ok: bool,
+    /// Valid but not sent, the device is read-only
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    dry_run: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+/// Apply a batch of updates in a single request to the device, so no poll
+/// nor other update runs in between, and publish the result of every update.
+async fn update_batch<D>(device: DeviceHandle<D>, request: BatchUpdateRequest, client: AsyncClient)
+where
+    D: PegasusDevice + Serialize + Send + 'static,
+{
+    let updates: Vec<(String, String)> = request
+        .updates
+        .into_iter()
+        .map(|u| (u.prop_name, u.value))
+        .collect();
+    let names: Vec<String> = updates.iter().map(|(name, _)| name.clone()).collect();
+    let results = device
+        .call_urgent(move |d| Box::pin(async move { d.update_properties(&updates).await }))
+        .await;
+
+    let results: Vec<UpdateResult> = match results {
+        Ok(results) => names
+            .into_iter()
+            .zip(results)
+            .map(|(prop_name, res)| match res {
+                Ok(()) => UpdateResult {
+                    prop_name,
+                    ok: true,
+                    dry_run: false,
+                    error: None,
+                },
+                // Valid, only the device didn't get it
+                Err(e) if e.is_read_only() => UpdateResult {
+                    prop_name,
+                    ok: true,
+                    dry_run: true,
+                    error: None,
+                },
+                Err(e) => {
+                    error!("Cannot update {}: {}", prop_name, e);
+                    UpdateResult {
+                        prop_name,
+                        ok: false,
+                        dry_run: false,
+                        error: Some(e.to_string()),
+                    }
+                }
+            })
+            .collect(),
+        // The device is gone, nothing was applied
+        Err(e) => names
+            .into_iter()
+            .map(|prop_name| UpdateResult {
+                prop_name,
+                ok: false,
+                dry_run: false,
+                error: Some(e.to_string()),
+            })
+            .collect(),
+    };
+    let response = serde_json::json!({
+        "request_id": request.request_id,
+        "results": results,
+    });
+    if let Err(e) = client
+        .publish(
+            format!("{}/update/results", topics::device(&device.id())),
+            QoS::AtLeastOnce,
+            false,
+            schema::payload(&response),
+        )
+        .await
+    {
+        error!("Cannot publish the update results: {}", e);
+    }
+}
+
+/// Spawn a batch of updates of a device whatever its kind
+fn spawn_batch<D>(device: DeviceHandle<D>, payload: &[u8], client: &AsyncClient)
+where
+    D: PegasusDevice + Serialize + Send + 'static,
+{
+    match serde_json::from_slice::<BatchUpdateRequest>(payload) {
+        Ok(request) => {
+            tokio::spawn(update_batch(device, request, client.clone()));
+        }
+        Err(e) => error!("Invalid batch update request: {}", e),
+    }
+}
+
+/// Spawn the update of a device whatever its kind, returns false if there is no such device.
+///
+/// Every update goes through here, whatever it comes from: writes to read-only
+/// properties are refused and every failure is published on `devices/{id}/update/error`.
+fn spawn_any_update(
+    driver: &PegasusDriver,
+    id: &Uuid,
+    request: UpdatePropertyRequest,
+    ramp: DewRamp,
+    client: &AsyncClient,
+) -> bool {
+    let Some(device) = driver.find_any(id) else {
+        return false;
+    };
+    let (id, client) = (*id, client.clone());
+    // Labels are kept by the driver, whatever the kind of device
+    if let Some(output) = request.prop_name.strip_suffix("_label") {
+        if device.outputs().contains(&output) {
+            info!("{} of {} labelled {:?}", output, id, request.value);
+            driver
+                .labels
+                .lock()
+                .unwrap()
+                .set(id, output, &request.value);
+        } else {
+            let e = PegasusError::Unsupported(format!("No output {} to label", output));
+            tokio::spawn(async move {
+                publish_update_error(&client, id, UpdateError::new(&request, &e)).await;
+            });
+        }
+        return true;
+    }
+    // Groups are kept by the driver, the device only gets the interval
+    let mut request = request;
+    if request.prop_name == "polling_group" {
+        let assigned = driver
+            .polling_groups
+            .lock()
+            .unwrap()
+            .assign(id, &request.value);
+        match assigned {
+            Ok(Some(ms)) => {
+                info!("{} moved to polling group {}", id, request.value);
+                request = UpdatePropertyRequest {
+                    prop_name: "polling_interval".to_string(),
+                    value: ms.to_string(),
+                    immediate: false,
+                };
+            }
+            Ok(None) => {
+                info!("{} taken out of its polling group", id);
+                return true;
+            }
+            Err(e) => {
+                tokio::spawn(async move {
+                    publish_update_error(&client, id, UpdateError::new(&request, &e)).await;
+                });
+                return true;
+            }
+        }
+    }
+    let calibrations = Arc::clone(&driver.calibrations);
+    tokio::spawn(async move {
+        match device.update(&request, ramp).await {
+            // Sensor offsets are saved once the device took them
+            Ok(()) => {
+                let mut calibrations = calibrations.lock().unwrap();
+                let calibration = request
+                    .value
+                    .parse()
+                    .ok()
+                    .and_then(|offset| calibrations.get(&id).with(&request.prop_name, offset));
+                if let Some(calibration) = calibration {
+                    calibrations.set(id, calibration);
+                }
+            }
+            Err(e) => {
+                error!("Cannot update {}: {}", request.prop_name, e);
+                publish_update_error(&client, id, UpdateError::new(&request, &e)).await;
+            }
+        }
+    });
+    true
+}
+
+/// Spawn an update received on MQTT unless its device is over the rate limit
+fn spawn_limited_update(
+    driver: &PegasusDriver,
+    id: Uuid,
+    request: UpdatePropertyRequest,
+    ramp: DewRamp,
+    client: &AsyncClient,
+    throttle: &Throttle,
+) {
+    if !throttle.allow(id) {
+        warn!("Too many updates for {}, {} dropped", id, request.prop_name);
+    } else if !spawn_any_update(driver, &id, request, ramp, client) {
+        error!("No device found for {}", id);
+    }
+}
+
+async fn publish_update_error(client: &AsyncClient, id: Uuid, error: UpdateError) {
+    events::publish(client, id, EventKind::CommandFailed, &error);
+    if let Err(e) = client
+        .publish(
+            format!("{}/update/error", topics::device(&id)),
+            QoS::AtLeastOnce,
+            false,
+            schema::payload(&error),
+        )
+        .await
+    {
+        error!("Cannot publish the update error: {}", e);
+    }
+}
+
+/// Run the scheduled actions at the start of every minute, local time, each
+/// minute once, see [`Minutes`].
+async fn run_schedule(
+    driver: Arc<RwLock<PegasusDriver>>,
+    schedule: Arc<Mutex<Schedule>>,
+    ramp: DewRamp,
+    client: AsyncClient,
+) {
+    let mut minutes = Minutes::starting_at(Local::now().timestamp().div_euclid(60));
+    loop {
+        let now = Local::now();
+        let into_minute = Duration::new(now.second() as u64, now.nanosecond());
+        tokio::time::sleep(Duration::from_secs(60).saturating_sub(into_minute)).await;
+
+        let now = Local::now();
+        let driver = driver.read().await;
+        let ids = driver.ids();
+        let mut due: Vec<(Uuid, ScheduledAction)> = Vec::new();
+        {
+            let schedule = schedule.lock().unwrap();
+            for minute in minutes.advance(now.timestamp().div_euclid(60)) {
+                let Some(time) = Local.timestamp_opt(minute * 60, 0).single() else {
+                    continue;
+                };
+                for (id, action) in schedule.due(&time, &ids) {
+                    // Minutes run late only set the last value of a property
+                    due.retain(|(i, a)| *i != id || a.prop_name != action.prop_name);
+                    due.push((id, action));
+                }
+            }
+        }
+        for (id, action) in due {
+            info!(
+                "Scheduled action {}: setting {} to {} on {}",
+                action.name(),
+                action.prop_name,
+                action.value,
+                id
+            );
+            let request = UpdatePropertyRequest {
+                prop_name: action.prop_name,
+                value: action.value,
+                immediate: false,
+            };
+            spawn_any_update(&driver, &id, request, ramp, &client);
+        }
+    }
+}
+
+/// Topics of the driver itself, not of a device
+async fn subscribe_driver(
+    client: &AsyncClient,
+    weather: Option<&Weather>,
+) -> Result<(), ClientError> {
+    for topic in [
+        ADD_DEVICE_TOPIC,
+        RESCAN_TOPIC,
+        RELOAD_TOPIC,
+        LOG_LEVEL_TOPIC,
+    ] {
+        client.subscribe(topic, QoS::ExactlyOnce).await?;
+    }
+    if let Some(weather) = weather {
+        client.subscribe(weather.topic(), QoS::AtMostOnce).await?;
+    }
+    Ok(())
+}
+
+async fn subscribe(client: AsyncClient, ids: &Vec<Uuid>) -> Result<(), ClientError> {
+    for id in ids {
+        client
+            .subscribe(format!("{}/update", topics::device(id)), QoS::ExactlyOnce)
+            .await?;
+        client
+            .subscribe(
+                format!("{}/update/batch", topics::device(id)),
+                QoS::ExactlyOnce,
+            )
+            .await?;
+        client
+            .subscribe(
+                format!("{}/self_test", topics::device(id)),
+                QoS::ExactlyOnce,
+            )
+            .await?;
+        client
+            .subscribe(format!("{}/diagnose", topics::device(id)), QoS::ExactlyOnce)
+            .await?;
+        client
+            .subscribe(
+                format!("{}/history/get", topics::device(id)),
+                QoS::AtLeastOnce,
+            )
+            .await?;
+        client
+            .subscribe(format!("{}/schedule", topics::device(id)), QoS::AtLeastOnce)
+            .await?;
+        client
+            .subscribe(format!("{}/profile", topics::device(id)), QoS::ExactlyOnce)
+            .await?;
+        client
+            .subscribe(
+                format!("{}/profile/save", topics::device(id)),
+                QoS::ExactlyOnce,
+            )
+            .await?
+    }
+
+    Ok(())
+}
+
+async fn unsubscribe(client: AsyncClient, id: &Uuid) -> Result<(), ClientError> {
+    client
+        .unsubscribe(format!("{}/update", topics::device(id)))
+        .await?;
+    client
+        .unsubscribe(format!("{}/update/batch", topics::device(id)))
+        .await?;
+    client
+        .unsubscribe(format!("{}/self_test", topics::device(id)))
+        .await?;
+    client
+        .unsubscribe(format!("{}/diagnose", topics::device(id)))
+        .await?;
+    client
+        .unsubscribe(format!("{}/history/get", topics::device(id)))
+        .await?;
+    client
+        .unsubscribe(format!("{}/schedule", topics::device(id)))
+        .await?;
+    client
+        .unsubscribe(format!("{}/profile", topics::device(id)))
+        .await?;
+    client
+        .unsubscribe(format!("{}/profile/save", topics::device(id)))
+        .await
+}
+
+/// Periodically look for plugged and unplugged devices.
+///
+/// New devices are polled and subscribed as the ones found at startup and
+/// announced on `devices/{id}/new`, unplugged ones stop being polled and are
+/// announced on `devices/{id}/delete`.
+async fn watch_devices(
+    driver: Arc<RwLock<PegasusDriver>>,
+    pollers: Arc<Mutex<HashMap<Uuid, JoinHandle<()>>>>,
+    limits: CurrentLimits,
+    every: Duration,
+    publisher: Publisher,
+) {
+    let mut interval = tokio::time::interval(every);
+    // The first tick completes immediately, devices were just scanned at startup
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+        rescan_devices(&driver, &pollers, &limits, &publisher).await;
+    }
+}
+
+/// Drive the devices plugged since the last scan, forget the unplugged ones
+async fn rescan_devices(
+    driver: &RwLock<PegasusDriver>,
+    pollers: &Mutex<HashMap<Uuid, JoinHandle<()>>>,
+    limits: &CurrentLimits,
+    publisher: &Publisher,
+) {
+    let c = &publisher.client;
+    let (added, mut removed) = rescan(driver, limits).await;
+    {
+        let aliases = publisher.aliases.lock().unwrap();
+        for info in removed.iter_mut() {
+            info.alias = aliases.get(&info.id).map(str::to_owned);
+        }
+    }
+
+    for info in removed {
+        if let Some(poller) = pollers.lock().unwrap().remove(&info.id) {
+            poller.abort();
+        }
+        publisher.watchdog.forget(&info.id);
+        #[cfg(feature = "http-api")]
+        publisher.live.remove(&info.id);
+        publisher.snapshots.remove(&info.id);
+        if let Err(e) = unsubscribe(c.clone(), &info.id).await {
+            error!("Cannot unsubscribe from {} topics: {}", info.name, e);
+        }
+        if let Err(e) = c
+            .publish(
+                format!("{}/delete", topics::device(&info.id)),
+                QoS::AtLeastOnce,
+                false,
+                schema::payload(&info),
+            )
+            .await
+        {
+            error!("Cannot announce removal of {}: {}", info.name, e);
+        }
+    }
+
+    start_devices(driver, pollers, publisher, added).await;
+}
+
+/// Connect the devices plugged since the last scan and drop the ones that
+/// were unplugged, returns the added and the removed devices.
+///
+/// The driver is locked only to read the settings and to add or remove the
+/// devices, never while a port is probed or opened: the devices already
+/// driven go on being polled and updated meanwhile.
+async fn rescan(
+    driver: &RwLock<PegasusDriver>,
+    limits: &CurrentLimits,
+) -> (Vec<DeviceInfo>, Vec<DeviceInfo>) {
+    let scanning = Arc::clone(&driver.read().await.scanning);
+    let _scanning = scanning.lock().await;
+
+    let Some(Scan { plugged, mut found }) = driver.read().await.scan() else {
+        return (Vec::new(), Vec::new());
+    };
+    let removed = driver.write().await.drop_unplugged(&plugged);
+
+    // Ports without a known serial number are probed once per plug
+    for dev in found.iter_mut().filter(|dev| dev.family.is_none()) {
+        dev.family = utils::probe(&dev.port)
+            .await
+            .and_then(|model| model.family());
+        if dev.family.is_none() {
+            debug!("No Pegasus device answered on {}", dev.port);
+            driver.write().await.failed.insert(dev.port.clone());
+        }
+    }
+    let ppba_found = take_family(&mut found, |f| f == DeviceFamily::Ppba);
+    let upb_found = take_family(&mut found, |f| f == DeviceFamily::Upb);
+    let ppbm_found = take_family(&mut found, |f| f == DeviceFamily::Ppbm);
+    let focuser_found = take_family(&mut found, DeviceFamily::is_focuser);
+
+    let mut added = Vec::new();
+    for dev in ppba_found {
+        debug!("info: {:?}", dev);
+        let device_name = match &dev.serial_number {
+            Some(serial) => format!("PegausPowerBoxAdvanced-{}", serial),
+            None => String::from("PegausPowerBoxAdvanced"),
+        };
+        match connect_found_ppba(driver, &device_name, &dev, limits).await {
+            Ok(info) => added.extend(info),
+            Err(e) => {
+                error!("Cannot start communication with {}: {}", &device_name, e);
+                driver.write().await.failed.insert(dev.port);
+            }
+        }
+    }
+    connect_found::<UltimatePowerBoxV2>(driver, upb_found, &mut added).await;
+    connect_found::<PocketPowerBoxMicro>(driver, ppbm_found, &mut added).await;
+    connect_found::<FocusCube>(driver, focuser_found, &mut added).await;
+
+    for info in &added {
+        info!("{} connected on {}", info.name, info.address);
+    }
+    (added, removed)
+}
+
+/// Connect a PPBA found by a scan and drive it, `None` if its port got
+/// driven meanwhile
+async fn connect_found_ppba(
+    driver: &RwLock<PegasusDriver>,
+    device_name: &str,
+    dev: &DiscoveredDevice,
+    limits: &CurrentLimits,
+) -> Result<Option<DeviceInfo>, PegasusError> {
+    let serial = dev.serial_number.as_deref();
+    let config = driver
+        .read()
+        .await
+        .serial
+        .resolve(serial, &dev.port, transport::DEFAULT_BAUD);
+    let mut device = PegasusPowerBox::try_with_config(device_name, &dev.port, &config).await?;
+    let saved = driver
+        .write()
+        .await
+        .prepare_ppba(&mut device, &dev.port, serial, limits)?;
+    restore_settings(&mut device, saved).await;
+    Ok(driver.write().await.drive_prepared_ppba(device, serial))
+}
+
+/// Connect the devices of the family `D` found by a scan and add them to `added`
+async fn connect_found<D: Connect>(
+    driver: &RwLock<PegasusDriver>,
+    found: Vec<DiscoveredDevice>,
+    added: &mut Vec<DeviceInfo>,
+) {
+    for dev in found {
+        debug!("info: {:?}", dev);
+        match connect_family::<D>(driver, &dev).await {
+            Ok(info) => added.extend(info),
+            Err(e) => {
+                error!(
+                    "Cannot start communication with {} on {}: {}",
+                    D::NAME,
+                    dev.port,
+                    e
+                );
+                driver.write().await.failed.insert(dev.port);
+            }
+        }
+    }
+}
+
+/// Connect a device of the family `D` found by a scan and drive it, `None`
+/// if its port got driven meanwhile
+async fn connect_family<D: Connect>(
+    driver: &RwLock<PegasusDriver>,
+    dev: &DiscoveredDevice,
+) -> Result<Option<DeviceInfo>, PegasusError> {
+    let serial = dev.serial_number.as_deref();
+    let device_name = match serial {
+        Some(serial) => format!("{}-{}", D::NAME, serial),
+        None => D::NAME.to_owned(),
+    };
+    let config = driver
+        .read()
+        .await
+        .serial
+        .resolve(serial, &dev.port, D::BAUD);
+    let device = D::open(&device_name, &dev.port, &config).await?;
+    Ok(driver.write().await.drive_family(device, serial))
+}
+
+/// Apply the settings saved in a previous run to a PPBA just connected
+async fn restore_settings(device: &mut PegasusPowerBox, saved: Option<SavedState>) {
+    if let Some(state) = saved {
+        if let Err(e) = device.restore_settings(&state).await {
+            error!("Cannot restore settings of {}: {}", device.get_name(), e);
+        }
+    }
+}
+
+/// Poll the devices just connected, subscribe to their topics and announce
+/// them on `devices/{id}/new`.
+async fn start_devices(
+    driver: &RwLock<PegasusDriver>,
+    pollers: &Mutex<HashMap<Uuid, JoinHandle<()>>>,
+    publisher: &Publisher,
+    added: Vec<DeviceInfo>,
+) {
+    let c = &publisher.client;
+    for mut info in added {
+        info.alias = publisher
+            .aliases
+            .lock()
+            .unwrap()
+            .get(&info.id)
+            .map(str::to_owned);
+        let poller = start_polling(&*driver.read().await, &info.id, publisher.clone());
+        if let Some(poller) = poller {
+            pollers.lock().unwrap().insert(info.id, poller);
+        }
+        if let Err(e) = subscribe(c.clone(), &vec![info.id]).await {
+            error!("Cannot subscribe to {} topics: {}", info.name, e);
+        }
+        if let Err(e) = c
+            .publish(
+                format!("{}/new", topics::device(&info.id)),
+                QoS::AtLeastOnce,
+                false,
+                schema::payload(&info),
+            )
+            .await
+        {
+            error!("Cannot announce {}: {}", info.name, e);
+        }
+    }
+    publisher.snapshots.set_devices(driver.read().await.infos());
+}
+
+/// Reset the devices whose polling task stopped making progress and publish
+/// their `stalled` status, restart the task if the reset didn't help.
+async fn watch_polling(
+    driver: Arc<RwLock<PegasusDriver>>,
+    pollers: Arc<Mutex<HashMap<Uuid, JoinHandle<()>>>>,
+    publisher: Publisher,
+) {
+    let watchdog = Arc::clone(&publisher.watchdog);
+    let mut interval = tokio::time::interval(watchdog.period());
+    loop {
+        interval.tick().await;
+        for (id, recovery) in watchdog.overdue() {
+            let driver = driver.read().await;
+            let Some(device) = driver.find_any(&id) else {
+                watchdog.forget(&id);
+                continue;
+            };
+            let topic = topics::device(&id);
+            publisher.publish_status(&topic, ConnectionStatus::Stalled);
+            device.reset();
+            if recovery == Recovery::Restart {
+                warn!(
+                    "Polling of {} still stalled, restarting it",
+                    publisher.label(&id)
+                );
+                let poller = start_polling(&driver, &id, publisher.clone());
+                let mut pollers = pollers.lock().unwrap();
+                if let Some(old) = pollers.remove(&id) {
+                    old.abort();
+                }
+                if let Some(poller) = poller {
+                    pollers.insert(id, poller);
+                }
+            } else {
+                warn!("Polling of {} stalled, resetting it", publisher.label(&id));
+            }
+        }
+    }
+}
+
+/// Publish in order everything that was buffered while the broker was unreachable.
+///
+/// Replayed states go to `devices/{id}/replay` together with their original
+/// timestamp, so that live consumers of `devices/{id}` never mistake an old
+/// sample for the current state of the device.
+async fn replay_buffered(client: AsyncClient, buffer: Arc<Mutex<OfflineBuffer>>) {
+    let messages = buffer.lock().unwrap().drain();
+
+    if messages.is_empty() {
+        return;
+    }
+
+    info!("Replaying {} buffered state messages", messages.len());
+
+    for msg in messages {
+        if let Err(e) = client
+            .publish(
+                format!("{}/replay", msg.topic),
+                QoS::AtLeastOnce,
+                false,
+                msg.replay_payload(),
+            )
+            .await
+        {
+            error!("Cannot replay buffered message: {}", e);
+        }
+    }
+}
+
+/// Publish the last state of every device on `devices/{id}` right after
+/// connecting, so dashboards don't wait for the next poll
+async fn publish_birth(c: &AsyncClient, publisher: &Publisher) {
+    for (id, snapshot) in publisher.snapshots.states().iter() {
+        if let Err(e) = c
+            .publish(
+                topics::device(id),
+                QoS::AtLeastOnce,
+                publisher.retain,
+                snapshot.payload.clone(),
+            )
+            .await
+        {
+            error!("Cannot publish the state of {}: {}", id, e);
+        }
+    }
+}
+
+/// Publish a connection state change on `devices/{id}/status`, the message is
+/// retained so clients connecting later know if the device is reachable.
+async fn publish_status(c: &AsyncClient, topic: &str, status: ConnectionStatus) {
+    if let Err(e) = c
+        .publish(
+            format!("{}/status", topic),
+            QoS::AtLeastOnce,
+            true,
+            schema::payload(&serde_json::json!({ "status": status })),
+        )
+        .await
+    {
+        error!("Cannot publish connection status: {}", e);
+    }
+}
+
+/// Save the settings of every connected PPBA
+async fn save_settings(driver: &RwLock<PegasusDriver>, settings: &Mutex<SettingsStore>) {
+    let devices = driver.read().await.devices.clone();
+    for d in devices {
+        let saved = d
+            .call(|d| Box::pin(async move { d.is_connected().then(|| d.saved_state()) }))
+            .await;
+        if let Ok(Some(state)) = saved {
+            settings.lock().unwrap().save(d.name(), state);
+        }
+    }
+}
+
+/// Put the PPBAs in their safe state, if any, announce every device on
+/// `devices/{id}/offline` and disconnect from the broker once everything is
+/// queued, so the messages are sent before the driver exits.
+async fn shutdown(c: &AsyncClient, driver: &RwLock<PegasusDriver>, safe_state: Option<Profile>) {
+    let driver = driver.read().await;
+    if let Some(profile) = safe_state {
+        for d in &driver.devices {
+            let profile = profile.clone();
+            let res = tokio::time::timeout(
+                SHUTDOWN_GRACE,
+                d.call_urgent(move |d| Box::pin(async move { d.apply_profile(&profile).await })),
+            )
+            .await;
+            match res {
+                Ok(Ok(Ok(()))) => info!("{} left in its safe state", d.name()),
+                Ok(Ok(Err(e))) | Ok(Err(e)) => {
+                    error!("Cannot leave {} in its safe state: {}", d.name(), e)
+                }
+                Err(_) => error!("{} took too long to reach its safe state", d.name()),
+            }
+        }
+    }
+
+    // Queuing waits for the broker, which may be gone
+    let announced = tokio::time::timeout(SHUTDOWN_GRACE, announce_offline(c, driver.infos()));
+    if announced.await.is_err() {
+        warn!("The MQTT broker is unreachable, the devices going offline aren't announced");
+    }
+}
+
+/// Announce every device on `devices/{id}/offline` and the driver going
+/// offline, then disconnect from the broker
+async fn announce_offline(c: &AsyncClient, infos: Vec<DeviceInfo>) {
+    for info in infos {
+        let topic = topics::device(&info.id);
+        publish_status(c, &topic, ConnectionStatus::Disconnected).await;
+        if let Err(e) = c
+            .publish(
+                format!("{}/offline", topic),
+                QoS::AtLeastOnce,
+                false,
+                schema::payload(&info),
+            )
+            .await
+        {
+            error!("Cannot announce {} going offline: {}", info.name, e);
+        }
+    }
+    // The last will is not sent on a clean disconnection
+    if let Err(e) = c
+        .publish(
+            DRIVER_STATUS_TOPIC,
+            QoS::AtLeastOnce,
+            true,
+            driver_status("offline"),
+        )
+        .await
+    {
+        error!("Cannot publish driver status: {}", e);
+    }
+    if let Err(e) = c.disconnect().await {
+        error!("Cannot disconnect from the MQTT broker: {}", e);
+    }
+}
+
+/// Everything the config file changes without a restart
+struct Reloader {
+    /// Content of the config file last applied
+    file: toml::Table,
+    schedule: Arc<Mutex<Schedule>>,
+    aliases: Arc<Mutex<Aliases>>,
+    labels: Arc<Mutex<OutputLabels>>,
+    profiles: Arc<Mutex<ProfileStore>>,
+    polling_groups: Arc<Mutex<PollingGroups>>,
+    alarms: watch::Sender<Arc<[Alarm]>>,
+    dew_rules: watch::Sender<Arc<[DewRule]>>,
+}
+
+/// What a reload changed besides the state shared with the tasks
+struct Reloaded {
+    report: ReloadReport,
+    /// Options to reconnect with if the `[mqtt]` table changed
+    mqtt: Option<MqttOptions>,
+    /// Devices whose polling group has a new interval
+    intervals: Vec<(Uuid, u64)>,
+}
+
+impl Reloader {
+    /// Read the config file again and apply the tables that changed, nothing
+    /// changes if any table is invalid.
+    fn reload(&mut self, cli: &Cli) -> Result<Reloaded, String> {
+        let file = cli.raw_config()?;
+        let (actions, site, aliases, labels, profiles) = (
+            cli.schedule()?,
+            cli.site()?,
+            cli.aliases()?,
+            cli.labels()?,
+            cli.profiles()?,
+        );
+        let (alarms, dew_rules, groups) = (cli.alarms()?, cli.dew_rules()?, cli.polling_groups()?);
+        let mqtt = mqtt_options(&cli.mqtt_config()?)?;
+        // The tables only read at start are checked too, so the next start doesn't fail
+        cli.logging()?;
+        cli.smoothing()?;
+        cli.serial_settings()?;
+        cli.batteries()?;
+        cli.calibration()?;
+        cli.dew_point_formula()?;
+        cli.weather()?;
+        #[cfg(feature = "http-api")]
+        cli.http_config()?;
+
+        let report = ReloadReport::new(&self.file, &file);
+        self.file = file;
+        self.schedule.lock().unwrap().set_configured(actions, site);
+        self.aliases.lock().unwrap().set_configured(aliases);
+        self.labels.lock().unwrap().set_configured(labels);
+        self.profiles.lock().unwrap().set_configured(profiles);
+        if report.changed("alarms") {
+            self.alarms.send_replace(alarms.into());
+        }
+        if report.changed("dew_rules") {
+            self.dew_rules.send_replace(dew_rules.into());
+        }
+        let intervals = if report.changed("polling_groups") {
+            self.polling_groups.lock().unwrap().set_configured(groups)
+        } else {
+            Vec::new()
+        };
+        Ok(Reloaded {
+            mqtt: report.changed("mqtt").then_some(mqtt),
+            intervals,
+            report,
+        })
+    }
+}
+
+/// Reload the config file on SIGHUP or on `drivers/pegasus_ppba/reload`.
+///
+/// The devices stay connected: new alarms and dew rules are picked up by the
+/// polling tasks, new polling intervals are sent to the devices and the
+/// broker connection is reopened with the new MQTT settings. What was applied,
+/// or why nothing was, is published on `drivers/pegasus_ppba/reload/result`.
+async fn reload_config(
+    cli: &Cli,
+    reloader: &mut Reloader,
+    eventloop: &mut EventLoop,
+    driver: &RwLock<PegasusDriver>,
+    ramp: DewRamp,
+    client: &AsyncClient,
+) {
+    let report = match reloader.reload(cli) {
+        Ok(reloaded) => {
+            if let Some(options) = reloaded.mqtt {
+                info!("Reconnecting to the MQTT broker with the new settings");
+                eventloop.mqtt_options = options;
+                eventloop.clean();
+            }
+            let driver = driver.read().await;
+            for (id, ms) in reloaded.intervals {
+                let request = UpdatePropertyRequest {
+                    prop_name: "polling_interval".to_string(),
+                    value: ms.to_string(),
+                    immediate: false,
+                };
+                spawn_any_update(&driver, &id, request, ramp, client);
+            }
+            info!(
+                "Configuration reloaded, applied {:?}, restart required for {:?}",
+                reloaded.report.applied, reloaded.report.restart_required
+            );
+            let c = client.clone();
+            let profiles = Arc::clone(&reloader.profiles);
+            tokio::spawn(async move { publish_profiles(&c, &profiles).await });
+            reloaded.report
+        }
+        Err(e) => {
+            error!("Cannot reload the configuration: {}", e);
+            ReloadReport::failed(e)
+        }
+    };
+    // Published from another task, the event loop isn't polled until we return
+    let c = client.clone();
+    tokio::spawn(async move {
+        if let Err(e) = c
+            .publish(
+                RELOAD_RESULT_TOPIC,
+                QoS::AtLeastOnce,
+                false,
+                schema::payload(&report),
+            )
+            .await
+        {
+            error!("Cannot publish the reload result: {}", e);
+        }
+    });
+}
 
-use tokio::{signal, task};
-use uuid::Uuid;
+/// Options of the connection to the broker, the client id stays the same
+/// across restarts
+fn mqtt_options(config: &MqttConfig) -> Result<MqttOptions, String> {
+    let mut options = MqttOptions::new(config.client_id(), config.broker_addr(), config.port);
+    options.set_transport(config.rumqttc_transport()?);
+    options.set_keep_alive(config.keep_alive);
+    options.set_clean_session(false);
+    if let Some((username, password)) = &config.credentials {
+        options.set_credentials(username, password);
+    }
+    options.set_last_will(LastWill::new(
+        DRIVER_STATUS_TOPIC,
+        driver_status("offline"),
+        QoS::AtLeastOnce,
+        true,
+    ));
+    Ok(options)
+}
 
-use rumqttc::ClientError;
+/// Publish every profile on `drivers/pegasus_ppba/profiles`, retained
+async fn publish_profiles(c: &AsyncClient, profiles: &Mutex<ProfileStore>) {
+    let payload = match serde_json::to_string(&profiles.lock().unwrap().all()) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("Cannot serialize the profiles: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = c
+        .publish(PROFILES_TOPIC, QoS::AtLeastOnce, true, payload)
+        .await
+    {
+        error!("Cannot publish the profiles: {}", e);
+    }
+}
+
+fn driver_status(status: &str) -> String {
+    schema::payload(&serde_json::json!({ "status": status }))
+}
 
-type PPBA = Arc<RwLock<PegasusPowerBox>>;
+/// Periodically tell supervisors the driver is alive, with its uptime in
+/// seconds, the number of devices it drives, its version, the responses
+/// of the devices discarded for answering another command and the updates
+/// dropped or coalesced by the [`Throttle`].
+async fn heartbeat(
+    client: AsyncClient,
+    driver: Arc<RwLock<PegasusDriver>>,
+    online: Arc<AtomicBool>,
+    throttle: Arc<Throttle>,
+    every: Duration,
+) {
+    let started = Instant::now();
+    let mut interval = tokio::time::interval(every);
+
+    loop {
+        interval.tick().await;
+        if !online.load(Ordering::Relaxed) {
+            continue;
+        }
+        let payload = serde_json::json!({
+            "uptime": started.elapsed().as_secs(),
+            "devices": driver.read().await.ids().len(),
+            "version": env!("CARGO_PKG_VERSION"),
+            "stale_responses": transport::stale_responses(),
+            "dropped_updates": throttle.dropped(),
+            "coalesced_updates": throttle.coalesced(),
+        });
+        if let Err(e) = client
+            .publish(
+                DRIVER_HEARTBEAT_TOPIC,
+                QoS::AtMostOnce,
+                false,
+                schema::payload(&payload),
+            )
+            .await
+        {
+            error!("Cannot publish heartbeat: {}", e);
+        }
+    }
+}
 
-#[derive(Default, Clone)]
-struct PPBADriver {
-    devices: Vec<PPBA>,
+/// Everything a polling task needs to publish the state of its device
+#[derive(Clone)]
+struct Publisher {
+    client: AsyncClient,
+    online: Arc<AtomicBool>,
+    buffer: Arc<Mutex<OfflineBuffer>>,
+    /// How often the full state is published for clients that just connected
+    snapshot_every: Duration,
+    /// Whether the full state is retained
+    retain: bool,
+    /// Polling group of every device, shared with the driver
+    polling_groups: Arc<Mutex<PollingGroups>>,
+    /// Labels of the outputs of every device, shared with the driver
+    labels: Arc<Mutex<OutputLabels>>,
+    /// Last full state of every device, published again as soon as the
+    /// broker connection is (re)established and served by the TCP and HTTP APIs
+    snapshots: Arc<Snapshots>,
+    history: Arc<Mutex<History>>,
+    /// Dew point rules applied to every PPBA, replaced by a reload
+    dew_rules: watch::Receiver<Arc<[DewRule]>>,
+    /// Ambient conditions preferred by the dew rules, if a sensor is configured
+    weather: Option<Weather>,
+    /// Thresholds checked at every poll of every device, replaced by a reload
+    alarms: watch::Receiver<Arc<[Alarm]>>,
+    /// Low state of charge alarm of the battery of the device, if configured
+    battery_alarm: Option<Alarm>,
+    /// Change of a current reported as a step, in A, 0 if they aren't looked for
+    current_step: f64,
+    /// Language of the display names on `devices/{id}/schema`
+    language: Language,
+    aliases: Arc<Mutex<Aliases>>,
+    /// Fed by every polling task at every poll
+    watchdog: Arc<Watchdog>,
+    /// SQLite log of the samples and setting changes, if enabled
+    #[cfg(feature = "sqlite")]
+    journal: Option<Arc<Mutex<Journal>>>,
+    /// States served by the HTTP API
+    #[cfg(feature = "http-api")]
+    live: Arc<http::LiveStates>,
 }
 
-impl PPBADriver {
-    fn new() -> Self {
-        let found = look_for_devices("PPBA");
-        let mut devices: Vec<PPBA> = Vec::new();
+impl Publisher {
+    /// How a device is named in the logs
+    fn label(&self, id: &Uuid) -> String {
+        self.aliases.lock().unwrap().label(id)
+    }
+
+    /// Alarms checked on the device, marked as seen
+    fn device_alarms(&mut self) -> Vec<Alarm> {
+        self.alarms
+            .borrow_and_update()
+            .iter()
+            .chain(&self.battery_alarm)
+            .cloned()
+            .collect()
+    }
 
-        for dev in found {
-            let mut device_name = String::from("PegausPowerBoxAdvanced");
-            debug!("name: {}", dev.0);
-            debug!("info: {:?}", dev.1);
+    fn is_online(&self) -> bool {
+        self.online.load(Ordering::Relaxed)
+    }
 
-            if let Some(serial) = dev.1.serial_number {
-                device_name = device_name + "-" + &serial
+    /// Queue a message without waiting, so the polling never blocks on the
+    /// broker: nothing is queued while it's unreachable and the message is
+    /// dropped if the client queue is full. Returns whether it was queued.
+    fn publish(&self, topic: String, retain: bool, payload: impl Into<Vec<u8>>) -> bool {
+        if !self.is_online() {
+            return false;
+        }
+        match self
+            .client
+            .try_publish(&topic, QoS::AtLeastOnce, retain, payload)
+        {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("Cannot publish on {}: {}", topic, e);
+                false
             }
-            let device = Arc::new(RwLock::new(PegasusPowerBox::new(
-                &device_name,
-                &dev.0,
-                9600,
-                500,
-            )));
-            devices.push(device);
         }
-        Self { devices }
+    }
+
+    /// Same as [`publish_status`] without waiting, see [`publish`](Self::publish)
+    fn publish_status(&self, topic: &str, status: ConnectionStatus) -> bool {
+        let payload = schema::payload(&serde_json::json!({ "status": status }));
+        self.publish(format!("{}/status", topic), true, payload)
+    }
+
+    /// Publish an event of the device, dropped while the broker is unreachable
+    fn event(&self, id: Uuid, kind: EventKind, data: impl Serialize) {
+        if self.is_online() {
+            events::publish(&self.client, id, kind, data);
+        }
     }
 }
 
-async fn subscribe(client: AsyncClient, ids: &Vec<Uuid>) -> Result<(), ClientError> {
-    for id in ids {
-        client
-            .subscribe(
-                format!("{}", format_args!("devices/{}/update", &id)),
-                QoS::ExactlyOnce,
-            )
-            .await?
+#[cfg(feature = "sqlite")]
+impl Publisher {
+    /// Log a poll in the journal with the settings that changed since the previous one
+    fn journal_poll(&self, id: &Uuid, sample: &Sample, changes: &[(String, serde_json::Value)]) {
+        if let Some(journal) = &self.journal {
+            let mut journal = journal.lock().unwrap();
+            let res = journal
+                .record_sample(id, sample)
+                .and_then(|_| journal.record_events(id, changes));
+            if let Err(e) = res {
+                error!("Cannot write to the journal: {}", e);
+            }
+        }
     }
 
-    Ok(())
+    fn journal_status(&self, id: &Uuid, status: ConnectionStatus) {
+        if let Some(journal) = &self.journal {
+            let change = ("status".to_string(), serde_json::json!(status));
+            if let Err(e) = journal.lock().unwrap().record_events(id, &[change]) {
+                error!("Cannot write to the journal: {}", e);
+            }
+        }
+    }
 }
 
-#[tokio::main]
-async fn main() {
-    //    console_subscriber::init();
-    let env = Env::default().filter_or("LS_LOG_LEVEL", "info");
-    env_logger::init_from_env(env);
+/// Periodically fetch the properties of a device and publish its state.
+///
+/// Only the properties that changed since the last poll are published, each
+/// on `devices/{id}/properties/{name}`, the full state is published on
+/// `devices/{id}` every `snapshot_every` and retained for late joiners.
+///
+/// When the device stops answering it is marked as disconnected and its port
+/// is reopened with an exponential backoff until it answers again, the same
+/// happens when a poll is aborted by the [`Watchdog`].
+///
+/// Nothing here waits for the broker: while it's unreachable the states are
+/// buffered, the events and alarms are dropped and the status of the device
+/// is published once it's back.
+async fn poll_device<D>(device: DeviceHandle<D>, publisher: Publisher)
+where
+    D: AstronomicalDevice + Family + Serialize + Send + 'static,
+{
+    let mut publisher = publisher;
+    let d_id = device.id();
+    info!(
+        "Start polling {} {} on {}",
+        device.name(),
+        publisher.label(&d_id),
+        device.address()
+    );
+    let topic = topics::device(&d_id);
+    publisher.watchdog.feed(d_id, Duration::ZERO);
+    let mut backoff = Backoff::default();
+    let mut status = ConnectionStatus::Connected;
+    // Whether the last change of `status` reached the broker
+    let mut status_sent = true;
+    let mut tracker = ChangeTracker::default();
+    let mut settings_tracker = ChangeTracker::default();
+    let mut alarms = AlarmMonitor::new(&publisher.device_alarms());
+    let mut steps = StepDetector::new(publisher.current_step);
+    let mut last_snapshot: Option<Instant> = None;
+    // Properties last described on `devices/{id}/schema`
+    let mut described: Vec<String> = Vec::new();
+    if !publisher.retain {
+        // An empty retained message deletes the one left by a previous run
+        publisher.publish(topic.clone(), true, "");
+    }
+    let group_interval = publisher.polling_groups.lock().unwrap().interval_of(&d_id);
+    if let Some(ms) = group_interval {
+        let res = device
+            .call_urgent(move |d| {
+                Box::pin(
+                    async move { d.update_property("polling_interval", &ms.to_string()).await },
+                )
+            })
+            .await
+            .and_then(|res| res);
+        if let Err(e) = res {
+            error!("Cannot apply the polling group of {}: {}", d_id, e);
+        }
+    }
+    let connected = serde_json::json!({ "name": device.name(), "address": device.address() });
+    publisher.event(d_id, EventKind::Connected, &connected);
+    loop {
+        let now = Instant::now();
+        if !status_sent {
+            status_sent = publisher.publish_status(&topic, status);
+        }
+
+        if status != ConnectionStatus::Connected {
+            let res = device.call(|d| d.reconnect()).await.and_then(|res| res);
+            if let Err(e) = res {
+                let delay = backoff.next_delay();
+                warn!("Reconnection failed: {}, retrying in {:?}", e, delay);
+                publisher.watchdog.feed(d_id, delay);
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+            backoff.reset();
+            status = ConnectionStatus::Connected;
+            status_sent = publisher.publish_status(&topic, status);
+            publisher.event(d_id, EventKind::Connected, &connected);
+            #[cfg(feature = "sqlite")]
+            publisher.journal_status(&d_id, status);
+        }
+
+        // Everything is read in a single request so the state is consistent
+        let poll = device
+            .call(|d| {
+                Box::pin(async move {
+                    d.fetch_props().await;
+                    if !d.is_connected() {
+                        return None;
+                    }
+                    let trips = d.enforce_current_limits().await;
+                    let state = match serde_json::to_value(&*d) {
+                        Ok(state) => state,
+                        Err(e) => {
+                            error!("Cannot serialize the state of {}: {}", d.get_name(), e);
+                            return None;
+                        }
+                    };
+                    Some((trips, state, d.polling_interval(), d.read_times().cloned()))
+                })
+            })
+            .await;
+        let poll = match poll {
+            Ok(poll) => poll,
+            Err(PegasusError::NotConnected) => {
+                error!("Stop polling: {}", PegasusError::NotConnected);
+                publisher.watchdog.forget(&d_id);
+                if status == ConnectionStatus::Connected {
+                    publisher.event(d_id, EventKind::Disconnected, ());
+                }
+                return;
+            }
+            Err(e) => {
+                // Aborted by the watchdog, which published the stalled status
+                warn!("Polling of {} stalled: {}", publisher.label(&d_id), e);
+                status = ConnectionStatus::Stalled;
+                publisher.event(d_id, EventKind::Disconnected, ());
+                #[cfg(feature = "sqlite")]
+                publisher.journal_status(&d_id, status);
+                continue;
+            }
+        };
+
+        // Keep the entry around while the device is unreachable, it's
+        // polled again as soon as the port can be reopened
+        let Some((trips, mut state, interval, read_times)) = poll else {
+            warn!("Lost connection with device {}", publisher.label(&d_id));
+            publisher.watchdog.feed(d_id, Duration::ZERO);
+            status = ConnectionStatus::Disconnected;
+            status_sent = publisher.publish_status(&topic, status);
+            publisher.event(d_id, EventKind::Disconnected, ());
+            #[cfg(feature = "sqlite")]
+            publisher.journal_status(&d_id, status);
+            continue;
+        };
+
+        // The alias is kept by the driver, published as a property of the device
+        let alias = publisher
+            .aliases
+            .lock()
+            .unwrap()
+            .get(&d_id)
+            .unwrap_or_default()
+            .to_owned();
+        state["alias"] = driver_property(alias);
+        let group = publisher
+            .polling_groups
+            .lock()
+            .unwrap()
+            .group_of(&d_id)
+            .unwrap_or_default()
+            .to_owned();
+        state["polling_group"] = driver_property(group);
+        {
+            let labels = publisher.labels.lock().unwrap();
+            for output in D::OUTPUTS {
+                let label = labels.get(&d_id, output).unwrap_or_default().to_owned();
+                state[format!("{}_label", output)] = driver_property(label);
+            }
+        }
+
+        let polled_at = buffer::now_millis();
+        let sample = Sample::from_state(polled_at, &state);
+        // Every setting is new on the first poll, those aren't changes
+        let has_baseline = settings_tracker.has_baseline();
+        let setting_changes = settings_tracker.changes(&changes::settings(&state));
+        #[cfg(feature = "sqlite")]
+        publisher.journal_poll(&d_id, &sample, &setting_changes);
+        if has_baseline {
+            for (name, value) in &setting_changes {
+                let change = serde_json::json!({ "name": name, "value": value });
+                publisher.event(d_id, EventKind::PropertyChanged, &change);
+            }
+        }
+        publisher.history.lock().unwrap().record(d_id, sample);
+        #[cfg(feature = "http-api")]
+        publisher.live.update(d_id, &state);
+
+        for trip in trips {
+            publisher.publish(format!("{}/alerts", &topic), false, schema::payload(&trip));
+            let event = AlarmEvent::current_trip(&trip);
+            publisher.publish(format!("{}/alarms", &topic), false, schema::payload(&event));
+            publisher.event(d_id, EventKind::AlarmRaised, &event);
+        }
+
+        // Alarms of a reloaded config file start over
+        if publisher.alarms.has_changed().unwrap_or(false) {
+            alarms = AlarmMonitor::new(&publisher.device_alarms());
+        }
+        for mut event in alarms.check(&state) {
+            warn!(
+                "Alarm {} {} on {}: {} = {}",
+                event.alarm,
+                event.event,
+                publisher.label(&d_id),
+                event.property,
+                event.value
+            );
+            if let Some(action) = event.action.clone() {
+                let res = device
+                    .call(move |d| {
+                        Box::pin(async move {
+                            d.update_property(&action.prop_name, &action.value).await
+                        })
+                    })
+                    .await
+                    .and_then(|res| res);
+                if let Err(e) = res {
+                    error!("Cannot apply the action of alarm {}: {}", event.alarm, e);
+                    event.action = None;
+                }
+            }
+            publisher.publish(format!("{}/alarms", &topic), false, schema::payload(&event));
+            let kind = match event.event {
+                "raised" => EventKind::AlarmRaised,
+                _ => EventKind::AlarmCleared,
+            };
+            publisher.event(d_id, kind, &event);
+        }
 
-    let driver = PPBADriver::new();
+        if publisher.current_step > 0.0 {
+            for step in steps.check(&state) {
+                info!(
+                    "{} of {}: {:?} from {:.2}A to {:.2}A",
+                    step.property,
+                    publisher.label(&d_id),
+                    step.kind,
+                    step.before,
+                    step.after
+                );
+                publisher.event(d_id, EventKind::CurrentStep, &step);
+            }
+        }
 
-    if driver.devices.is_empty() {
-        warn!("No PPBA found on the system, exiting");
-        std::process::exit(0)
+        // Only in the full state, the properties are published when they change
+        let full_state =
+            schema::with_read_times(state.clone(), read_times.as_ref(), interval, polled_at);
+        let payload = schema::payload(&full_state);
+        publisher.snapshots.update(
+            d_id,
+            Snapshot {
+                state: full_state.clone(),
+                payload: payload.clone(),
+            },
+        );
+        // Still buffered if the broker went away before we noticed
+        let mut sent = publisher.is_online();
+        if sent {
+            // Described again only when properties come and go
+            let names: Vec<&String> = state
+                .as_object()
+                .into_iter()
+                .flat_map(|o| o.keys())
+                .collect();
+            if names != described.iter().collect::<Vec<_>>() {
+                let schema = schema::describe(&state, publisher.language).to_string();
+                sent = publisher.publish(format!("{}/schema", &topic), true, schema);
+                described = names.into_iter().cloned().collect();
+            }
+            for (name, value) in tracker.changes(&state) {
+                let property_topic = format!("{}/properties/{}", &topic, name);
+                sent = sent && publisher.publish(property_topic, false, value.to_string());
+            }
+            if sent && last_snapshot.is_none_or(|t| t.elapsed() >= publisher.snapshot_every) {
+                sent = publisher.publish(topic.clone(), publisher.retain, payload);
+                last_snapshot = Some(now);
+            }
+        }
+        if !sent {
+            let mut buffer = publisher.buffer.lock().unwrap();
+            buffer.push(topic.clone(), schema::with_envelope(full_state));
+            debug!("Broker unreachable, {} messages buffered", buffer.len());
+            // Everything is published again once the broker is back
+            tracker = ChangeTracker::default();
+            last_snapshot = None;
+            described.clear();
+        }
+        let elapsed = now.elapsed();
+        info!("Refreshed and publishing state took: {:.2?}", elapsed);
+        publisher.watchdog.feed(d_id, interval);
+        // Read at every cycle, so interval changes apply right away
+        tokio::time::sleep(interval).await;
     }
+}
 
-    let mut mqttoptions = MqttOptions::new("pegasus_ppba", "127.0.0.1", 1883);
-    mqttoptions.set_keep_alive(Duration::from_secs(5));
-    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+/// A property kept by the driver, serialized like the ones of the devices
+fn driver_property(value: String) -> serde_json::Value {
+    // Never fails for a string
+    serde_json::to_value(Property::new(value, Permission::ReadWrite)).unwrap_or_default()
+}
 
-    let mut devices_id = Vec::with_capacity(driver.devices.len());
+fn spawn_polling<D>(device: DeviceHandle<D>, publisher: Publisher) -> JoinHandle<()>
+where
+    D: AstronomicalDevice + Family + Serialize + Send + 'static,
+{
+    task::spawn(poll_device(device, publisher))
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if cli.install_service {
+        match service::install() {
+            Ok(message) => println!("{}", message),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            }
+        }
+        return;
+    }
+    #[cfg(windows)]
+    if cli.windows_service {
+        if let Err(e) = service::run_service(|| run(Cli::parse())) {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        }
+        return;
+    }
+    run(cli)
+}
 
-    for d in &driver.devices {
-        devices_id.push(d.read().unwrap().id)
+/// Drive the devices until asked to stop
+#[tokio::main]
+async fn run(cli: Cli) {
+    if let Err(e) = cli.logging().and_then(|config| logging::init(&config)) {
+        let _ = logging::init(&LogConfig::default());
+        error!("{}", e);
+        std::process::exit(1)
+    }
+    if let Some(path) = &cli.trace_serial {
+        if let Err(e) = trace::start(path) {
+            error!(
+                "Cannot trace the serial traffic to {}: {}",
+                path.display(),
+                e
+            );
+            std::process::exit(1)
+        }
+        warn!("Tracing the serial traffic to {}", path.display());
     }
+    topics::use_family_namespaces(cli.family_topics);
+    let mqtt_config = match cli.mqtt_config() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
 
-    subscribe(client.clone(), &devices_id).await.unwrap();
+    #[cfg(feature = "http-api")]
+    let http_config = match cli.http_config() {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
 
-    match eventloop.poll().await {
-        Err(rumqttc::ConnectionError::ConnectionRefused(_))
-        | Err(rumqttc::ConnectionError::Io(_)) => {
-            error!("The MQTT broker is not avialble, aborting");
-            std::process::exit(0)
+    let dew_formula = match cli.dew_point_formula() {
+        Ok(formula) => formula,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
+    let weather = match cli.weather() {
+        Ok(weather) => weather.map(|config| Weather::new(config, dew_formula)),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
+    let dew_rules = match cli.dew_rules() {
+        Ok(rules) => rules,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
+
+    let alarms = match cli.alarms() {
+        Ok(alarms) => alarms,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
+
+    let schedule = match cli
+        .schedule()
+        .and_then(|actions| Ok((actions, cli.site()?)))
+    {
+        Ok((actions, site)) => Arc::new(Mutex::new(Schedule::new(actions, site))),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
+
+    let limits = CurrentLimits::from_env();
+    let settings = Arc::new(Mutex::new(SettingsStore::load(
+        cli.settings_file
+            .clone()
+            .unwrap_or_else(settings::default_path),
+    )));
+    let restore_from = cli.restore_settings.then(|| Arc::clone(&settings));
+    let smoothing = match cli.smoothing() {
+        Ok(smoothing) => smoothing,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
+    let aliases = match cli.aliases() {
+        Ok(configured) => Arc::new(Mutex::new(Aliases::load(
+            cli.aliases_file
+                .clone()
+                .unwrap_or_else(aliases::default_path),
+            configured,
+        ))),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
+    let labels = match cli.labels() {
+        Ok(configured) => Arc::new(Mutex::new(OutputLabels::load(
+            cli.labels_file.clone().unwrap_or_else(labels::default_path),
+            configured,
+        ))),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
+    let calibrations = match cli.calibration() {
+        Ok(configured) => Arc::new(Mutex::new(Calibrations::load(
+            cli.calibration_file
+                .clone()
+                .unwrap_or_else(calibration::default_path),
+            configured,
+        ))),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
         }
+    };
+    let profiles = match cli.profiles() {
+        Ok(configured) => Arc::new(Mutex::new(ProfileStore::load(
+            cli.profiles_file
+                .clone()
+                .unwrap_or_else(profiles::default_path),
+            configured,
+        ))),
         Err(e) => {
-            error!("An error occured: {} - aborting", e);
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
+    if let Some(name) = &cli.shutdown_profile {
+        if profiles.lock().unwrap().get(name).is_none() {
+            error!("No profile named {} for the shutdown", name);
+            std::process::exit(1)
+        }
+    }
+    let manual_devices = match cli.manual_devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
+    let serial = match cli.serial_settings() {
+        Ok(serial) => serial,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
+    let retry = match cli.retry_policies() {
+        Ok(retry) => retry,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
+    let read_only = ReadOnly {
+        all: cli.read_only,
+        devices: cli.read_only_device.iter().cloned().collect(),
+    };
+    if read_only.all {
+        warn!("Read-only mode, no setting will be sent to the devices");
+    }
+    let batteries = match cli.batteries() {
+        Ok(batteries) => batteries,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
+    let registry = Registry::load(
+        cli.registry_file
+            .clone()
+            .unwrap_or_else(registry::default_path),
+    );
+    let polling_groups = match cli.polling_groups() {
+        Ok(groups) => Arc::new(Mutex::new(PollingGroups::new(groups))),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
+    let mut driver = PegasusDriver::new(
+        restore_from,
+        smoothing,
+        serial,
+        read_only,
+        registry,
+        batteries,
+        Arc::clone(&polling_groups),
+    );
+    driver.retry = retry;
+    driver.labels = Arc::clone(&labels);
+    driver.calibrations = calibrations;
+    driver.dew_formula = dew_formula;
+    let driver = RwLock::new(driver);
+    rescan(&driver, &limits).await;
+    let mut driver = driver.into_inner();
+    for (port, baud) in manual_devices {
+        if let Err(e) = driver.add_ppba(&port, baud, &limits).await {
+            error!(
+                "Cannot start communication with the PPBA on {}: {}",
+                port, e
+            );
+        }
+    }
+
+    driver.add_simulated(cli.simulate, &limits).await;
+
+    if driver.is_empty() {
+        if cli.self_test || cli.diagnose || cli.rescan_interval == 0 {
+            warn!("No Pegasus device found on the system, exiting");
             std::process::exit(0)
         }
-        _ => (),
+        info!("No Pegasus device found on the system, waiting for one to be plugged");
+    }
+
+    // Run the self test on every device, print the reports and exit
+    if cli.self_test {
+        let mut passed = true;
+        for d in &driver.devices {
+            let Ok(report) = d.call(|d| Box::pin(selftest::self_test(d))).await else {
+                continue;
+            };
+            passed &= report.passed;
+            match serde_json::to_string_pretty(&report) {
+                Ok(report) => println!("{}", report),
+                Err(e) => error!("Cannot print the report of {}: {}", d.name(), e),
+            }
+        }
+        std::process::exit(if passed { 0 } else { 1 })
+    }
+
+    // Same with the diagnosis
+    if cli.diagnose {
+        let mut passed = true;
+        for d in &driver.devices {
+            let Ok(report) = d.call(|d| Box::pin(selftest::diagnose(d))).await else {
+                continue;
+            };
+            passed &= report.report.passed;
+            match serde_json::to_string_pretty(&report) {
+                Ok(report) => println!("{}", report),
+                Err(e) => error!("Cannot print the report of {}: {}", d.name(), e),
+            }
+        }
+        std::process::exit(if passed { 0 } else { 1 })
     }
 
+    // A stable client id together with a persistent session lets the broker keep
+    // our subscriptions and queue the update requests sent while we reconnect.
+    let mqttoptions = match mqtt_options(&mqtt_config) {
+        Ok(options) => options,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1)
+        }
+    };
+    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+
+    // Sent while the event loop runs, there are more requests than the client queues
+    let c = client.clone();
+    let ids = driver.ids();
+    let c_weather = weather.clone();
+    tokio::spawn(async move {
+        let res = match subscribe(c.clone(), &ids).await {
+            Ok(()) => subscribe_driver(&c, c_weather.as_ref()).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            error!("Cannot subscribe to the topics of the driver: {}", e);
+        }
+    });
+
+    // Connected before the polling starts, if the broker is unreachable the
+    // states are buffered until it's back like during any other outage
+    let (online, mut first_event) = match eventloop.poll().await {
+        Ok(event) => (true, Some(event)),
+        Err(e) => {
+            error!(
+                "The MQTT broker at {}:{} is not available: {}",
+                mqtt_config.host, mqtt_config.port, e
+            );
+            warn!("Device states will be buffered until the broker is back");
+            service::notify_status("MQTT broker unreachable, buffering the device states");
+            (false, None)
+        }
+    };
+
+    let ramp = DewRamp::from_env();
+    let online = Arc::new(AtomicBool::new(online));
+    let retention_days = std::env::var("PPBA_BUFFER_RETENTION_DAYS")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(buffer::DEFAULT_RETENTION_DAYS);
+    let buffer = Arc::new(Mutex::new(OfflineBuffer::new(
+        cli.buffer_file
+            .clone()
+            .unwrap_or_else(|| buffer::default_path(&mqtt_config.client_id())),
+        buffer::DEFAULT_CAPACITY,
+        Duration::from_secs(retention_days * 24 * 3600),
+    )));
+
+    #[cfg(feature = "sqlite")]
+    let journal = cli.journal.as_ref().map(|path| {
+        let retention = Duration::from_secs(cli.journal_retention_days * 24 * 3600);
+        match Journal::open(path, retention) {
+            Ok(journal) => {
+                info!("Logging telemetry to {}", path.display());
+                Arc::new(Mutex::new(journal))
+            }
+            Err(e) => {
+                error!("Cannot open the journal {}: {}", path.display(), e);
+                std::process::exit(1)
+            }
+        }
+    });
+
+    let c_buffer = Arc::clone(&buffer);
+    #[cfg(feature = "sqlite")]
+    let c_journal = journal.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(3600));
+        loop {
+            interval.tick().await;
+            c_buffer.lock().unwrap().prune();
+            #[cfg(feature = "sqlite")]
+            if let Some(journal) = &c_journal {
+                if let Err(e) = journal.lock().unwrap().prune() {
+                    error!("Cannot prune the journal: {}", e);
+                }
+            }
+        }
+    });
+
     eventloop.network_options.set_connection_timeout(5);
 
-    let c_client = client.clone();
+    let history = Arc::new(Mutex::new(History::new(cli.history_capacity)));
+    let (alarms_tx, alarms_rx) = watch::channel(Arc::from(alarms));
+    let (dew_rules_tx, dew_rules_rx) = watch::channel(Arc::from(dew_rules));
+    let publisher = Publisher {
+        client: client.clone(),
+        online: Arc::clone(&online),
+        buffer: Arc::clone(&buffer),
+        snapshot_every: Duration::from_secs(cli.snapshot_interval),
+        retain: !cli.no_retain,
+        polling_groups: Arc::clone(&polling_groups),
+        labels: Arc::clone(&labels),
+        snapshots: Arc::default(),
+        history: Arc::clone(&history),
+        dew_rules: dew_rules_rx,
+        weather: weather.clone(),
+        alarms: alarms_rx,
+        battery_alarm: None,
+        current_step: cli.current_step,
+        language: cli.language,
+        aliases: Arc::clone(&aliases),
+        watchdog: Arc::new(Watchdog::new(Duration::from_secs(cli.stall_timeout))),
+        #[cfg(feature = "sqlite")]
+        journal,
+        #[cfg(feature = "http-api")]
+        live: Arc::default(),
+    };
+    publisher.snapshots.set_devices(driver.infos());
+    let mut pollers = HashMap::new();
+    for id in driver.ids() {
+        let poller = start_polling(&driver, &id, publisher.clone());
+        if let Some(poller) = poller {
+            pollers.insert(id, poller);
+        }
+    }
+    let pollers = Arc::new(Mutex::new(pollers));
+
+    let driver = Arc::new(RwLock::new(driver));
+
+    let throttle = Arc::new(Throttle::new(
+        cli.update_rate,
+        cli.update_burst,
+        Duration::from_millis(cli.dew_debounce_ms),
+    ));
+    if cli.heartbeat_interval > 0 {
+        tokio::spawn(heartbeat(
+            client.clone(),
+            Arc::clone(&driver),
+            Arc::clone(&online),
+            Arc::clone(&throttle),
+            Duration::from_secs(cli.heartbeat_interval),
+        ));
+    }
+
+    // Everything changing the devices, stopped before shutting down
+    let mut tasks = vec![tokio::spawn(run_schedule(
+        Arc::clone(&driver),
+        Arc::clone(&schedule),
+        ramp,
+        client.clone(),
+    ))];
+
+    let c_driver = Arc::clone(&driver);
+    let c_settings = Arc::clone(&settings);
+    tasks.push(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(10));
+        loop {
+            interval.tick().await;
+            save_settings(&c_driver, &c_settings).await;
+        }
+    }));
+
+    if cli.stall_timeout > 0 {
+        tasks.push(tokio::spawn(watch_polling(
+            Arc::clone(&driver),
+            Arc::clone(&pollers),
+            publisher.clone(),
+        )));
+    }
+
+    if cli.rescan_interval > 0 {
+        tasks.push(tokio::spawn(watch_devices(
+            Arc::clone(&driver),
+            Arc::clone(&pollers),
+            limits.clone(),
+            Duration::from_secs(cli.rescan_interval),
+            publisher.clone(),
+        )));
+    }
+
+    // Not in `tasks`, it's withdrawn once the devices are stopped
+    let mdns_stop = Arc::new(Notify::new());
+    if cli.mdns {
+        let endpoints = mdns::Endpoints {
+            #[cfg(feature = "http-api")]
+            http: http_config
+                .as_ref()
+                .map(|config| (config.addr.port(), config.tls.is_some())),
+            #[cfg(not(feature = "http-api"))]
+            http: None,
+            tcp: cli.tcp_addr.map(|addr| addr.port()),
+            mqtt: format!("{}:{}", mqtt_config.host, mqtt_config.port),
+        };
+        tokio::spawn(mdns::advertise(
+            Arc::clone(&driver),
+            cli.mdns_name.clone(),
+            endpoints,
+            Arc::clone(&mdns_stop),
+        ));
+    }
 
+    if let Some(addr) = cli.tcp_addr {
+        tasks.push(tokio::spawn(tcp::serve(
+            addr,
+            tcp::TcpState {
+                driver: Arc::clone(&driver),
+                snapshots: Arc::clone(&publisher.snapshots),
+                aliases: Arc::clone(&aliases),
+                ramp,
+            },
+        )));
+    }
+
+    #[cfg(feature = "http-api")]
+    if let Some(config) = http_config {
+        tasks.push(tokio::spawn(http::serve(
+            config,
+            http::ApiState {
+                driver: Arc::clone(&driver),
+                snapshots: Arc::clone(&publisher.snapshots),
+                live: Arc::clone(&publisher.live),
+                aliases: Arc::clone(&aliases),
+                ramp,
+                client: client.clone(),
+            },
+        )));
+    }
+
+    let c_client = client.clone();
+    let c_driver = Arc::clone(&driver);
+    let c_pollers = Arc::clone(&pollers);
+    let c_profiles = Arc::clone(&profiles);
+    let shutdown_profile = cli.shutdown_profile.clone();
     tokio::spawn(async move {
-        signal::ctrl_c().await.unwrap();
-        debug!("ctrl-c received!");
-        c_client.disconnect().await.unwrap();
+        service::stop_requested().await;
+        service::notify_stopping();
+        mdns_stop.notify_one();
+        for task in tasks {
+            task.abort();
+        }
+        for (_, poller) in c_pollers.lock().unwrap().drain() {
+            poller.abort();
+        }
+        // Saved before the safe state, it's the one to restore at the next start
+        save_settings(&c_driver, &settings).await;
+        let safe_state =
+            shutdown_profile.and_then(|name| c_profiles.lock().unwrap().get(&name).cloned());
+        shutdown(&c_client, &c_driver, safe_state).await;
+        // The main loop returns once the disconnection is sent, unless the broker is unreachable
+        tokio::time::sleep(SHUTDOWN_GRACE).await;
+        warn!("Pending MQTT messages dropped, the broker is unreachable");
+        service::notify_stopped();
         std::process::exit(0);
     });
 
-    for d in &driver.devices {
-        let device = Arc::clone(d);
-        let c = client.clone();
-        task::spawn(async move {
-            let d_id = device.read().unwrap().id;
-            loop {
-                let now = Instant::now();
-                device.write().unwrap().fetch_props();
-                let serialized = serde_json::to_string(&*device.read().unwrap()).unwrap();
-                c.publish(
-                    format!("{}", format_args!("devices/{}", &d_id)),
-                    QoS::AtLeastOnce,
-                    false,
-                    serialized,
-                )
-                .await
-                .unwrap();
-                let elapsed = now.elapsed();
-                info!("Refreshed and publishing state took: {:.2?}", elapsed);
-                tokio::time::sleep(Duration::from_millis(500)).await;
+    let mut reloader = Reloader {
+        file: cli.raw_config().unwrap_or_default(),
+        schedule: Arc::clone(&schedule),
+        aliases: Arc::clone(&aliases),
+        labels: Arc::clone(&labels),
+        profiles: Arc::clone(&profiles),
+        polling_groups: Arc::clone(&polling_groups),
+        alarms: alarms_tx,
+        dew_rules: dew_rules_tx,
+    };
+    let (reload_tx, mut reloads) = mpsc::unbounded_channel();
+    #[cfg(unix)]
+    {
+        let reload_tx = reload_tx.clone();
+        tokio::spawn(async move {
+            let mut hangups = match signal::unix::signal(signal::unix::SignalKind::hangup()) {
+                Ok(hangups) => hangups,
+                Err(e) => {
+                    error!("Cannot listen to SIGHUP: {}", e);
+                    return;
+                }
+            };
+            while hangups.recv().await.is_some() {
+                info!("SIGHUP received");
+                let _ = reload_tx.send(());
             }
         });
     }
 
-    while let Ok(event) = eventloop.poll().await {
+    service::notify_ready(driver.read().await.ids().len());
+    tokio::spawn(service::watchdog());
+
+    loop {
+        let polled = match first_event.take() {
+            // The connection acknowledged at startup, handled as every other
+            Some(event) => Ok(event),
+            None => tokio::select! {
+                polled = eventloop.poll() => polled,
+                Some(()) = reloads.recv() => {
+                    reload_config(&cli, &mut reloader, &mut eventloop, &driver, ramp, &client).await;
+                    continue;
+                }
+            },
+        };
+        let event = match polled {
+            Ok(event) => event,
+            Err(e) => {
+                if online.swap(false, Ordering::Relaxed) {
+                    warn!("Lost connection to the MQTT broker: {}", e);
+                    warn!("Device states will be buffered until the broker is back");
+                    service::notify_status("MQTT broker unreachable, buffering the device states");
+                }
+                // Polling again makes rumqttc try to reconnect, don't hammer the broker
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
         debug!("Received = {:?}", event);
         match event {
             Incoming(inc) => match inc {
+                ConnAck(ack) => {
+                    info!("Connection to the MQTT broker established");
+                    online.store(true, Ordering::Relaxed);
+                    service::notify_status("Connected to the MQTT broker");
+                    // Overwrite the last will the broker may have published
+                    let c = client.clone();
+                    let c_profiles = Arc::clone(&profiles);
+                    let c_publisher = publisher.clone();
+                    tokio::spawn(async move {
+                        publish_profiles(&c, &c_profiles).await;
+                        if let Err(e) = c
+                            .publish(
+                                DRIVER_STATUS_TOPIC,
+                                QoS::AtLeastOnce,
+                                true,
+                                driver_status("online"),
+                            )
+                            .await
+                        {
+                            error!("Cannot publish driver status: {}", e);
+                        }
+                        publish_birth(&c, &c_publisher).await;
+                    });
+                    // The broker may have lost our session (e.g. it was restarted
+                    // without persistence), in that case subscriptions are gone too.
+                    if !ack.session_present {
+                        let c = client.clone();
+                        let ids = driver.read().await.ids();
+                        let weather = publisher.weather.clone();
+                        tokio::spawn(async move {
+                            let res = match subscribe(c.clone(), &ids).await {
+                                Ok(()) => subscribe_driver(&c, weather.as_ref()).await,
+                                Err(e) => Err(e),
+                            };
+                            if let Err(e) = res {
+                                error!("Cannot resubscribe to device topics: {}", e);
+                            }
+                        });
+                    }
+                    // Including the states buffered during a previous run
+                    if !buffer.lock().unwrap().is_empty() {
+                        tokio::spawn(replay_buffered(client.clone(), Arc::clone(&buffer)));
+                    }
+                }
                 Publish(data) => {
-                    // All topics are in the form of devices/{UUID}/{action} so let's
-                    // take advantage of this fact and avoid a string split
-                    match &data.topic[45..data.topic.len()] {
-                        "update" => {
-                            info!(
-                                "received message from topic: {}\nmessage: {:?}",
-                                &data.topic, &data.payload
-                            );
+                    if let Some(weather) = publisher
+                        .weather
+                        .as_ref()
+                        .filter(|w| w.topic() == data.topic)
+                    {
+                        match weather.update(&data.payload) {
+                            Ok(conditions) => debug!("Weather update: {:?}", conditions),
+                            Err(e) => warn!("Invalid weather update: {}", e),
+                        }
+                        continue;
+                    }
+                    if data.topic == RESCAN_TOPIC {
+                        info!("Rescan requested");
+                        let driver = Arc::clone(&driver);
+                        let pollers = Arc::clone(&pollers);
+                        let publisher = publisher.clone();
+                        let limits = limits.clone();
+                        tokio::spawn(async move {
+                            rescan_devices(&driver, &pollers, &limits, &publisher).await
+                        });
+                        continue;
+                    }
+                    if data.topic == RELOAD_TOPIC {
+                        info!("Reload requested");
+                        let _ = reload_tx.send(());
+                        continue;
+                    }
+                    if data.topic == LOG_LEVEL_TOPIC {
+                        let level = serde_json::from_slice::<LogLevelRequest>(&data.payload)
+                            .map_err(|e| e.to_string())
+                            .and_then(|request| {
+                                request
+                                    .level
+                                    .map(|level| level.parse().map_err(|_| level))
+                                    .transpose()
+                            });
+                        match level {
+                            Ok(level) => {
+                                logging::set_level(level);
+                                info!("Log level set to {}", log::max_level());
+                            }
+                            Err(e) => error!("Invalid log level request: {}", e),
+                        }
+                        continue;
+                    }
+                    if data.topic == ADD_DEVICE_TOPIC {
+                        match serde_json::from_slice::<AddDeviceRequest>(&data.payload) {
+                            Ok(request) => {
+                                let driver = Arc::clone(&driver);
+                                let pollers = Arc::clone(&pollers);
+                                let publisher = publisher.clone();
+                                let limits = limits.clone();
+                                tokio::spawn(async move {
+                                    let res = driver
+                                        .write()
+                                        .await
+                                        .add_ppba(&request.port, request.baud, &limits)
+                                        .await;
+                                    match res {
+                                        Ok(info) => {
+                                            start_devices(&driver, &pollers, &publisher, vec![info])
+                                                .await
+                                        }
+                                        Err(e) => {
+                                            error!("Cannot add the PPBA on {}: {}", request.port, e)
+                                        }
+                                    }
+                                });
+                            }
+                            Err(e) => error!("Invalid add request: {}", e),
+                        }
+                        continue;
+                    }
+
+                    let Some((id, action)) = topics::parse(&data.topic) else {
+                        warn!("Ignoring a message on {}", data.topic);
+                        continue;
+                    };
+
+                    if action == "history/get" {
+                        let request = if data.payload.is_empty() {
+                            Ok(HistoryRequest::default())
+                        } else {
+                            serde_json::from_slice::<HistoryRequest>(&data.payload)
+                        };
+                        match request {
+                            Ok(request) => {
+                                let samples = history.lock().unwrap().query(&id, &request);
+                                let c = client.clone();
+                                tokio::spawn(async move {
+                                    let response = serde_json::json!({
+                                        "request_id": request.request_id,
+                                        "samples": samples,
+                                    });
+                                    if let Err(e) = c
+                                        .publish(
+                                            format!("{}/history", topics::device(&id)),
+                                            QoS::AtLeastOnce,
+                                            false,
+                                            schema::payload(&response),
+                                        )
+                                        .await
+                                    {
+                                        error!("Cannot publish history: {}", e);
+                                    }
+                                });
+                            }
+                            Err(e) => error!("Invalid history request: {}", e),
+                        }
+                        continue;
+                    }
+
+                    if action == "schedule" {
+                        match serde_json::from_slice::<Vec<ScheduledAction>>(&data.payload) {
+                            Ok(actions) => {
+                                let count = actions.len();
+                                match schedule.lock().unwrap().set(id, actions) {
+                                    Ok(()) => {
+                                        info!("{} scheduled actions received for {}", count, id)
+                                    }
+                                    Err(e) => error!("Invalid schedule: {}", e),
+                                }
+                            }
+                            Err(e) => error!("Invalid schedule: {}", e),
+                        }
+                        continue;
+                    }
+
+                    if action == "update" {
+                        info!(
+                            "received message from topic: {}\nmessage: {:?}",
+                            &data.topic, &data.payload
+                        );
+                        let request =
+                            match serde_json::from_slice::<UpdatePropertyRequest>(&data.payload) {
+                                Ok(request) => request,
+                                Err(e) => {
+                                    error!("Invalid update request: {}", e);
+                                    continue;
+                                }
+                            };
+                        if !driver.read().await.ids().contains(&id) {
+                            error!("No device found for topic {}", &data.topic);
+                        } else if request.prop_name == "alias" {
+                            // Aliases are kept by the driver, whatever the kind of device
+                            info!("{} renamed to {:?}", id, request.value);
+                            aliases.lock().unwrap().set(id, &request.value);
+                        } else if throttle.debounces(&request.prop_name) {
+                            // Only the last value of a slider drag is sent
+                            let prop_name = request.prop_name.clone();
+                            if throttle.coalesce(id, request) {
+                                let driver = Arc::clone(&driver);
+                                let throttle = Arc::clone(&throttle);
+                                let client = client.clone();
+                                tokio::spawn(async move {
+                                    tokio::time::sleep(throttle.debounce()).await;
+                                    if let Some(request) = throttle.take(id, &prop_name) {
+                                        let driver = driver.read().await;
+                                        spawn_limited_update(
+                                            &driver, id, request, ramp, &client, &throttle,
+                                        );
+                                    }
+                                });
+                            }
+                        } else {
+                            let driver = driver.read().await;
+                            spawn_limited_update(&driver, id, request, ramp, &client, &throttle);
+                        }
+                        continue;
+                    }
+                    if action == "update/batch" && !throttle.allow(id) {
+                        warn!("Too many updates for {}, batch dropped", id);
+                        continue;
+                    }
+
+                    let upb = driver.read().await.find_upb(&id);
+                    if let Some(upb) = upb {
+                        match action {
+                            "update/batch" => spawn_batch(upb, &data.payload, &client),
+                            _ => warn!("{} is not supported by UPBv2 devices", action),
+                        }
+                        continue;
+                    }
+                    let ppbm = driver.read().await.find_ppbm(&id);
+                    if let Some(ppbm) = ppbm {
+                        match action {
+                            "update/batch" => spawn_batch(ppbm, &data.payload, &client),
+                            _ => warn!("{} is not supported by PPBM devices", action),
+                        }
+                        continue;
+                    }
+                    let focuser = driver.read().await.find_focuser(&id);
+                    if let Some(focuser) = focuser {
+                        match action {
+                            "update/batch" => spawn_batch(focuser, &data.payload, &client),
+                            _ => warn!("{} is not supported by focusers", action),
+                        }
+                        continue;
+                    }
+
+                    let device = driver.read().await.find_device(&id);
+                    let device = match device {
+                        Some(device) => device,
+                        None => {
+                            error!("No device found for topic {}", &data.topic);
+                            continue;
+                        }
+                    };
+
+                    match action {
+                        "update/batch" => spawn_batch(device, &data.payload, &client),
+                        "profile" => {
+                            let request = serde_json::from_slice::<ProfileRequest>(&data.payload);
+                            let profile = match request {
+                                Ok(request) => profiles
+                                    .lock()
+                                    .unwrap()
+                                    .get(&request.name)
+                                    .cloned()
+                                    .ok_or(format!("No profile named {}", request.name)),
+                                Err(e) => Err(format!("Invalid profile request: {}", e)),
+                            };
+                            match profile {
+                                Ok(profile) => {
+                                    tokio::spawn(async move {
+                                        let res = device
+                                            .call_urgent(move |d| {
+                                                Box::pin(
+                                                    async move { d.apply_profile(&profile).await },
+                                                )
+                                            })
+                                            .await
+                                            .and_then(|res| res);
+                                        if let Err(e) = res {
+                                            error!("Cannot apply the profile: {}", e);
+                                        }
+                                    });
+                                }
+                                Err(e) => error!("{}", e),
+                            }
+                        }
+                        "profile/save" => {
+                            match serde_json::from_slice::<ProfileRequest>(&data.payload) {
+                                Ok(request) => {
+                                    let c = client.clone();
+                                    let profiles = Arc::clone(&profiles);
+                                    tokio::spawn(async move {
+                                        let Ok(profile) = device
+                                            .call(|d| Box::pin(async move { d.capture_profile() }))
+                                            .await
+                                        else {
+                                            return;
+                                        };
+                                        info!("Saving profile {}: {:?}", request.name, profile);
+                                        profiles.lock().unwrap().save(&request.name, profile);
+                                        publish_profiles(&c, &profiles).await;
+                                    });
+                                }
+                                Err(e) => error!("Invalid profile request: {}", e),
+                            }
+                        }
+                        "self_test" => {
+                            let c = client.clone();
+                            let topic = format!("{}/report", &data.topic);
+                            tokio::spawn(async move {
+                                let Ok(report) =
+                                    device.call(|d| Box::pin(selftest::self_test(d))).await
+                                else {
+                                    return;
+                                };
+                                let payload = schema::payload(&report);
+                                if let Err(e) =
+                                    c.publish(topic, QoS::AtLeastOnce, false, payload).await
+                                {
+                                    error!("Cannot publish the report: {}", e);
+                                }
+                            });
+                        }
+                        "diagnose" => {
+                            let c = client.clone();
+                            let topic = format!("{}/report", &data.topic);
+                            tokio::spawn(async move {
+                                let Ok(report) =
+                                    device.call(|d| Box::pin(selftest::diagnose(d))).await
+                                else {
+                                    return;
+                                };
+                                let payload = schema::payload(&report);
+                                if let Err(e) =
+                                    c.publish(topic, QoS::AtLeastOnce, false, payload).await
+                                {
+                                    error!("Cannot publish the report: {}", e);
+                                }
+                            });
                         }
                         _ => (),
                     }
                 }
                 _ => debug!("Incoming event: {:?}", inc),
             },
+            Outgoing(rumqttc::Outgoing::Disconnect) => {
+                info!("Disconnected from the MQTT broker, exiting");
+                return;
+            }
             Outgoing(out) => {
                 debug!("Outgoing MQTT event: {:?}", out);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Publisher of a driver whose broker just went away: the event loop is
+    /// never polled so nothing takes the messages out of the client queue
+    fn unreachable_broker(alarms: Vec<Alarm>, buffer: PathBuf) -> (Publisher, EventLoop) {
+        let options = MqttOptions::new("ppba_test", "localhost", 1883);
+        let (client, eventloop) = AsyncClient::new(options, 10);
+        let dew_rules: Arc<[DewRule]> = Arc::from(Vec::new());
+        let publisher = Publisher {
+            client,
+            online: Arc::new(AtomicBool::new(true)),
+            buffer: Arc::new(Mutex::new(OfflineBuffer::new(
+                buffer,
+                buffer::DEFAULT_CAPACITY,
+                Duration::from_secs(3600),
+            ))),
+            snapshot_every: Duration::from_secs(30),
+            retain: true,
+            polling_groups: Arc::default(),
+            labels: Arc::default(),
+            snapshots: Arc::default(),
+            history: Arc::new(Mutex::new(History::new(10))),
+            dew_rules: watch::channel(dew_rules).1,
+            weather: None,
+            alarms: watch::channel(Arc::from(alarms)).1,
+            battery_alarm: None,
+            current_step: 0.0,
+            language: Language::En,
+            aliases: Arc::new(Mutex::new(Aliases::load(
+                std::env::temp_dir().join(format!("ppba_test_aliases_{}.json", Uuid::new_v4())),
+                HashMap::new(),
+            ))),
+            watchdog: Arc::new(Watchdog::new(Duration::from_secs(30))),
+            #[cfg(feature = "sqlite")]
+            journal: None,
+            #[cfg(feature = "http-api")]
+            live: Arc::default(),
+        };
+        (publisher, eventloop)
+    }
+
+    async fn wait_for_buffered(publisher: &Publisher, count: usize) {
+        let buffered = async {
+            while publisher.buffer.lock().unwrap().len() < count {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), buffered)
+            .await
+            .expect("the polling is blocked");
+    }
+
+    #[tokio::test]
+    async fn polling_goes_on_during_an_outage() {
+        let device = PegasusPowerBox::new_with_port(
+            "PegausPowerBoxAdvanced-SIM0000",
+            "sim://0",
+            transport::DEFAULT_BAUD,
+            Box::new(FakePpbaPort::simulated(0)),
+        )
+        .await
+        .unwrap();
+        // All raised by the first poll, more messages than the client queue takes
+        let alarms = (0..20)
+            .map(|i| Alarm {
+                name: Some(format!("alarm_{}", i)),
+                property: "input_voltage".to_string(),
+                below: None,
+                above: Some(0.0),
+                hysteresis: 0.0,
+                samples: 1,
+                action: None,
+            })
+            .collect();
+        let path = std::env::temp_dir().join(format!("ppba_test_buffer_{}.jsonl", Uuid::new_v4()));
+        let (publisher, _eventloop) = unreachable_broker(alarms, path.clone());
+        let poller = spawn_polling(DeviceHandle::spawn(device), publisher.clone());
+
+        // Not noticed yet, the messages that don't fit are dropped or buffered
+        wait_for_buffered(&publisher, 2).await;
+        // Noticed, nothing is queued anymore
+        publisher.online.store(false, Ordering::Relaxed);
+        let buffered = publisher.buffer.lock().unwrap().len();
+        wait_for_buffered(&publisher, buffered + 2).await;
+
+        poller.abort();
+        let _ = std::fs::remove_file(path);
+    }
+}
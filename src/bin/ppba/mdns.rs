@@ -0,0 +1,439 @@
+//! Advertisement of the driver on the LAN over multicast DNS, as a
+//! `_pegasus._tcp` service, so clients find it without being given its
+//! address.
+//!
+//! Only what DNS-SD browsers ask for is answered: the PTR records of the
+//! service type, and the SRV, TXT and A records of the driver.
+use crate::PegasusDriver;
+use log::{debug, error, info};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::{Notify, RwLock};
+
+pub const SERVICE_TYPE: &str = "_pegasus._tcp.local";
+const SERVICES: &str = "_services._dns-sd._udp.local";
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+/// Seconds the records are cached by the clients
+const TTL: u32 = 120;
+/// How often the devices are checked to announce the new summary
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(10);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on the records only this host answers, the clients replace their cache
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// Endpoints of the driver, in the TXT record
+#[derive(Clone, Debug, Default)]
+pub struct Endpoints {
+    /// Port of the HTTP API and whether it's served over TLS
+    pub http: Option<(u16, bool)>,
+    /// Port of the line delimited JSON endpoint
+    pub tcp: Option<u16>,
+    /// Broker the devices are published on, `host:port`
+    pub mqtt: String,
+}
+
+impl Endpoints {
+    /// Port of the SRV record: the HTTP API, else the TCP endpoint, else the
+    /// one of the broker
+    fn port(&self) -> u16 {
+        self.http
+            .map(|(port, _)| port)
+            .or(self.tcp)
+            .or_else(|| self.mqtt.rsplit(':').next()?.parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+/// The records of the driver
+struct Records {
+    /// `<instance>._pegasus._tcp.local`
+    instance: String,
+    /// `<hostname>.local`
+    host: String,
+    ip: Ipv4Addr,
+    endpoints: Endpoints,
+    /// `key=value` strings of the TXT record
+    txt: Vec<String>,
+}
+
+impl Records {
+    /// Answer to a question, `None` if it's not about the driver
+    fn answer(&self, name: &str, qtype: u16, ttl: u32) -> Option<Message> {
+        let mut message = Message::default();
+        if name.eq_ignore_ascii_case(SERVICES) && matches!(qtype, TYPE_PTR | TYPE_ANY) {
+            message.answers.push(self.ptr(SERVICES, SERVICE_TYPE, ttl));
+        } else if name.eq_ignore_ascii_case(SERVICE_TYPE) && matches!(qtype, TYPE_PTR | TYPE_ANY) {
+            message
+                .answers
+                .push(self.ptr(SERVICE_TYPE, &self.instance, ttl));
+            message.additionals = vec![self.srv(ttl), self.txt(ttl), self.a(ttl)];
+        } else if name.eq_ignore_ascii_case(&self.instance) {
+            match qtype {
+                TYPE_SRV => message.answers.push(self.srv(ttl)),
+                TYPE_TXT => message.answers.push(self.txt(ttl)),
+                TYPE_ANY => message.answers = vec![self.srv(ttl), self.txt(ttl)],
+                _ => return None,
+            }
+            message.additionals.push(self.a(ttl));
+        } else if name.eq_ignore_ascii_case(&self.host) && matches!(qtype, TYPE_A | TYPE_ANY) {
+            message.answers.push(self.a(ttl));
+        } else {
+            return None;
+        }
+        Some(message)
+    }
+
+    /// Every record, sent unsolicited when they change
+    fn announcement(&self, ttl: u32) -> Message {
+        Message {
+            answers: vec![
+                self.ptr(SERVICE_TYPE, &self.instance, ttl),
+                self.srv(ttl),
+                self.txt(ttl),
+                self.a(ttl),
+            ],
+            ..Message::default()
+        }
+    }
+
+    fn ptr(&self, name: &str, target: &str, ttl: u32) -> Record {
+        Record::new(name, TYPE_PTR, CLASS_IN, ttl, encode_name(target))
+    }
+
+    fn srv(&self, ttl: u32) -> Record {
+        let mut data = vec![0, 0, 0, 0];
+        data.extend(self.endpoints.port().to_be_bytes());
+        data.extend(encode_name(&self.host));
+        Record::new(&self.instance, TYPE_SRV, CLASS_IN | CACHE_FLUSH, ttl, data)
+    }
+
+    fn txt(&self, ttl: u32) -> Record {
+        let mut data = Vec::new();
+        for entry in &self.txt {
+            let entry = &entry.as_bytes()[..entry.len().min(255)];
+            data.push(entry.len() as u8);
+            data.extend(entry);
+        }
+        Record::new(&self.instance, TYPE_TXT, CLASS_IN | CACHE_FLUSH, ttl, data)
+    }
+
+    fn a(&self, ttl: u32) -> Record {
+        let data = self.ip.octets().to_vec();
+        Record::new(&self.host, TYPE_A, CLASS_IN | CACHE_FLUSH, ttl, data)
+    }
+}
+
+struct Record {
+    name: String,
+    rtype: u16,
+    class: u16,
+    ttl: u32,
+    data: Vec<u8>,
+}
+
+impl Record {
+    fn new(name: &str, rtype: u16, class: u16, ttl: u32, data: Vec<u8>) -> Self {
+        Self {
+            name: name.to_owned(),
+            rtype,
+            class,
+            ttl,
+            data,
+        }
+    }
+}
+
+/// A response, names aren't compressed
+#[derive(Default)]
+struct Message {
+    id: u16,
+    answers: Vec<Record>,
+    additionals: Vec<Record>,
+}
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        out.extend(self.id.to_be_bytes());
+        // Authoritative response
+        out.extend(0x8400u16.to_be_bytes());
+        out.extend(0u16.to_be_bytes());
+        out.extend((self.answers.len() as u16).to_be_bytes());
+        out.extend(0u16.to_be_bytes());
+        out.extend((self.additionals.len() as u16).to_be_bytes());
+        for record in self.answers.iter().chain(&self.additionals) {
+            out.extend(encode_name(&record.name));
+            out.extend(record.rtype.to_be_bytes());
+            out.extend(record.class.to_be_bytes());
+            out.extend(record.ttl.to_be_bytes());
+            out.extend((record.data.len() as u16).to_be_bytes());
+            out.extend(&record.data);
+        }
+        out
+    }
+}
+
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend(label);
+    }
+    out.push(0);
+    out
+}
+
+/// Id and questions (name and type) of a query, `None` for a response or
+/// a malformed packet
+fn parse_query(packet: &[u8]) -> Option<(u16, Vec<(String, u16)>)> {
+    let id = u16::from_be_bytes([*packet.first()?, *packet.get(1)?]);
+    let flags = u16::from_be_bytes([*packet.get(2)?, *packet.get(3)?]);
+    if flags & 0x8000 != 0 {
+        return None;
+    }
+    let count = u16::from_be_bytes([*packet.get(4)?, *packet.get(5)?]);
+    let mut offset = 12;
+    let mut questions = Vec::new();
+    for _ in 0..count {
+        let (name, end) = parse_name(packet, offset)?;
+        let qtype = u16::from_be_bytes([*packet.get(end)?, *packet.get(end + 1)?]);
+        // The class is skipped, the unicast bit is answered like a multicast query
+        offset = end + 4;
+        questions.push((name, qtype));
+    }
+    Some((id, questions))
+}
+
+/// Name at `offset` following the compression pointers, and the offset after it
+fn parse_name(packet: &[u8], mut offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut end = None;
+    // Bounds the pointers followed, a loop would never end
+    for _ in 0..packet.len() {
+        let len = *packet.get(offset)? as usize;
+        if len & 0xc0 == 0xc0 {
+            let pointer = ((len & 0x3f) << 8) | *packet.get(offset + 1)? as usize;
+            end.get_or_insert(offset + 2);
+            offset = pointer;
+            continue;
+        }
+        if len == 0 {
+            return Some((labels.join("."), end.unwrap_or(offset + 1)));
+        }
+        let label = packet.get(offset + 1..offset + 1 + len)?;
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        offset += 1 + len;
+    }
+    None
+}
+
+/// Name of this machine, the first label only
+fn hostname() -> String {
+    let name = std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .unwrap_or_default();
+    let name: String = name
+        .trim()
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+        .collect();
+    if name.is_empty() {
+        "pegasus".to_owned()
+    } else {
+        name
+    }
+}
+
+/// Address of the interface the multicast traffic goes out of, nothing is
+/// actually sent
+fn local_ip() -> io::Result<Ipv4Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.connect((MDNS_ADDR, MDNS_PORT))?;
+    match socket.local_addr()?.ip() {
+        std::net::IpAddr::V4(ip) if !ip.is_unspecified() => Ok(ip),
+        _ => Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            "no IPv4 interface",
+        )),
+    }
+}
+
+/// Socket on the mDNS port shared with the other responders, e.g. Avahi
+fn mdns_socket(ip: Ipv4Addr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(all(unix, not(any(target_os = "solaris", target_os = "illumos"))))]
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())?;
+    socket.join_multicast_v4(&MDNS_ADDR, &ip)?;
+    socket.set_multicast_if_v4(&ip)?;
+    socket.set_multicast_ttl_v4(255)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// `key=value` strings of the TXT record: the endpoints and the number of
+/// devices of every family
+async fn summary(
+    driver: &RwLock<PegasusDriver>,
+    endpoints: &Endpoints,
+    instance_name: &str,
+) -> Vec<String> {
+    let infos = driver.read().await.infos();
+    let mut families: BTreeMap<&str, usize> = BTreeMap::new();
+    for info in &infos {
+        *families.entry(info.family).or_default() += 1;
+    }
+    let mut txt = vec![
+        "txtvers=1".to_owned(),
+        format!("name={}", instance_name),
+        format!("version={}", env!("CARGO_PKG_VERSION")),
+        format!("mqtt={}", endpoints.mqtt),
+    ];
+    if let Some((port, tls)) = endpoints.http {
+        let scheme = if tls { "https" } else { "http" };
+        txt.push(format!("{}={}", scheme, port));
+    }
+    if let Some(port) = endpoints.tcp {
+        txt.push(format!("tcp={}", port));
+    }
+    txt.push(format!("devices={}", infos.len()));
+    txt.extend(
+        families
+            .iter()
+            .map(|(family, count)| format!("{}={}", family, count)),
+    );
+    txt
+}
+
+/// Advertise the driver until `stop` is notified, the clients are then told
+/// it's gone. The broker is advertised with the address of this machine if
+/// it runs here. The devices are looked at every 10 seconds and announced
+/// again when they change.
+pub async fn advertise(
+    driver: Arc<RwLock<PegasusDriver>>,
+    instance_name: Option<String>,
+    mut endpoints: Endpoints,
+    stop: Arc<Notify>,
+) {
+    let ip = match local_ip() {
+        Ok(ip) => ip,
+        Err(e) => {
+            error!("Cannot advertise the driver over mDNS: {}", e);
+            return;
+        }
+    };
+    let socket = match mdns_socket(ip) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("Cannot listen on the mDNS port: {}", e);
+            return;
+        }
+    };
+    if let Some(port) = endpoints.mqtt.strip_prefix("localhost:") {
+        endpoints.mqtt = format!("{}:{}", ip, port);
+    } else if let Some(port) = endpoints.mqtt.strip_prefix("127.0.0.1:") {
+        endpoints.mqtt = format!("{}:{}", ip, port);
+    }
+    let hostname = hostname();
+    let instance_name = instance_name.unwrap_or_else(|| format!("ppba on {}", hostname));
+    // A label can't have dots
+    let instance_name = instance_name.replace('.', "-");
+    let mut records = Records {
+        instance: format!("{}.{}", instance_name, SERVICE_TYPE),
+        host: format!("{}.local", hostname),
+        ip,
+        txt: summary(&driver, &endpoints, &instance_name).await,
+        endpoints,
+    };
+    info!(
+        "Advertising {} on {}:{} over mDNS",
+        records.instance,
+        records.ip,
+        records.endpoints.port()
+    );
+    let group = SocketAddr::V4(SocketAddrV4::new(MDNS_ADDR, MDNS_PORT));
+    announce(&socket, &records, group).await;
+
+    let mut check = tokio::time::interval(SUMMARY_INTERVAL);
+    check.reset();
+    let mut buf = [0u8; 9000];
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (len, from) = match received {
+                    Ok(received) => received,
+                    Err(e) => {
+                        debug!("Cannot read an mDNS query: {}", e);
+                        continue;
+                    }
+                };
+                let Some((id, questions)) = parse_query(&buf[..len]) else {
+                    continue;
+                };
+                for (name, qtype) in questions {
+                    let Some(mut message) = records.answer(&name, qtype, TTL) else {
+                        continue;
+                    };
+                    // Queries from another port than 5353 are answered to the
+                    // sender only, with their id
+                    let to = if from.port() == MDNS_PORT {
+                        group
+                    } else {
+                        message.id = id;
+                        from
+                    };
+                    debug!("Answering the mDNS query for {} to {}", name, to);
+                    if let Err(e) = socket.send_to(&message.encode(), to).await {
+                        debug!("Cannot answer the mDNS query for {}: {}", name, e);
+                    }
+                }
+            }
+            _ = check.tick() => {
+                let txt = summary(&driver, &records.endpoints, &instance_name).await;
+                if txt != records.txt {
+                    records.txt = txt;
+                    announce(&socket, &records, group).await;
+                }
+            }
+            () = stop.notified() => {
+                let goodbye = records.announcement(0).encode();
+                if let Err(e) = socket.send_to(&goodbye, group).await {
+                    debug!("Cannot withdraw the mDNS advertisement: {}", e);
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Send every record twice, a second apart, as recommended by RFC 6762
+async fn announce(socket: &UdpSocket, records: &Records, group: SocketAddr) {
+    let announcement = records.announcement(TTL).encode();
+    for i in 0..2 {
+        if i > 0 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        if let Err(e) = socket.send_to(&announcement, group).await {
+            error!("Cannot announce the driver over mDNS: {}", e);
+            return;
+        }
+    }
+}
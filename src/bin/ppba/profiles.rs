@@ -0,0 +1,70 @@
+use log::{debug, error, warn};
+use pegasus_astro::ppba::Profile;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// Payload expected on `devices/{id}/profile` and `devices/{id}/profile/save`
+#[derive(Debug, Deserialize)]
+pub struct ProfileRequest {
+    pub name: String,
+}
+
+/// Profiles of the PPBAs, keyed by name.
+///
+/// The `[profiles]` tables of the config file are the defaults, profiles
+/// saved over MQTT replace them and are written to a JSON file.
+pub struct ProfileStore {
+    path: PathBuf,
+    configured: BTreeMap<String, Profile>,
+    saved: BTreeMap<String, Profile>,
+}
+
+/// `~/.pegasus_ppba_profiles.json`, next to the settings
+pub fn default_path() -> PathBuf {
+    crate::settings::default_path().with_file_name(".pegasus_ppba_profiles.json")
+}
+
+impl ProfileStore {
+    pub fn load(path: PathBuf, configured: BTreeMap<String, Profile>) -> Self {
+        let saved = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring corrupted profiles {}: {}", path.display(), e);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        Self {
+            path,
+            configured,
+            saved,
+        }
+    }
+
+    /// Replace the profiles of the config file, e.g. once reloaded
+    pub fn set_configured(&mut self, configured: BTreeMap<String, Profile>) {
+        self.configured = configured;
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.saved.get(name).or_else(|| self.configured.get(name))
+    }
+
+    /// Every profile, the saved ones taking precedence
+    pub fn all(&self) -> BTreeMap<&String, &Profile> {
+        self.configured.iter().chain(self.saved.iter()).collect()
+    }
+
+    /// Save a profile under `name`, replacing the one with the same name.
+    pub fn save(&mut self, name: &str, profile: Profile) {
+        debug!("Saving profile {} to {}", name, self.path.display());
+        self.saved.insert(name.to_owned(), profile);
+
+        let result = serde_json::to_string_pretty(&self.saved)
+            .map_err(|e| e.to_string())
+            .and_then(|content| std::fs::write(&self.path, content).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Cannot write profiles to {}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// A device the driver found once
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RegistryEntry {
+    pub family: String,
+    /// USB serial number, missing for the probed and manually added devices
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<String>,
+    /// Port the device was last seen on
+    pub address: String,
+}
+
+/// Every device found by the driver, keyed by device id.
+///
+/// Devices with a USB serial number get the same id on every run anyway, the
+/// registry keeps the ones without: a device found again on its last port
+/// gets back its id, so clients keep working across restarts.
+pub struct Registry {
+    path: PathBuf,
+    devices: BTreeMap<Uuid, RegistryEntry>,
+}
+
+/// `~/.pegasus_ppba_registry.json`, next to the settings
+pub fn default_path() -> PathBuf {
+    crate::settings::default_path().with_file_name(".pegasus_ppba_registry.json")
+}
+
+impl Registry {
+    pub fn load(path: PathBuf) -> Self {
+        let devices = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring corrupted registry {}: {}", path.display(), e);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        Self { path, devices }
+    }
+
+    /// Id of the device without serial number last seen on `address`
+    pub fn id_of(&self, family: &str, address: &str) -> Option<Uuid> {
+        self.devices
+            .iter()
+            .find(|(_, d)| d.serial_number.is_none() && d.family == family && d.address == address)
+            .map(|(id, _)| *id)
+    }
+
+    /// Remember where a device was found, the file is written only on changes.
+    pub fn record(&mut self, id: Uuid, entry: RegistryEntry) {
+        if self.devices.get(&id) == Some(&entry) {
+            return;
+        }
+        debug!("Registering {} on {}", id, entry.address);
+        // A port is used by a single device
+        if entry.serial_number.is_none() {
+            self.devices.retain(|_, d| {
+                d.serial_number.is_some() || d.family != entry.family || d.address != entry.address
+            });
+        }
+        self.devices.insert(id, entry);
+
+        let result = serde_json::to_string_pretty(&self.devices)
+            .map_err(|e| e.to_string())
+            .and_then(|content| std::fs::write(&self.path, content).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Cannot write registry to {}: {}", self.path.display(), e);
+        }
+    }
+}
@@ -0,0 +1,59 @@
+use serde::Serialize;
+use std::collections::BTreeSet;
+
+/// Tables of the config file applied without a restart, the other ones are
+/// only read when the driver starts
+const LIVE_SECTIONS: [&str; 9] = [
+    "mqtt",
+    "alarms",
+    "dew_rules",
+    "polling_groups",
+    "schedule",
+    "site",
+    "aliases",
+    "labels",
+    "profiles",
+];
+
+/// Outcome of a reload, published on `drivers/pegasus_ppba/reload/result`
+#[derive(Debug, Default, Serialize)]
+pub struct ReloadReport {
+    /// Tables that changed and were applied
+    pub applied: Vec<String>,
+    /// Tables that changed but are only read when the driver starts
+    pub restart_required: Vec<String>,
+    /// Why nothing was applied
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ReloadReport {
+    /// Sort the tables that differ between the applied config file and the
+    /// new one
+    pub fn new(old: &toml::Table, new: &toml::Table) -> Self {
+        let mut report = Self::default();
+        let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+        for key in keys {
+            if old.get(key) == new.get(key) {
+                continue;
+            }
+            if LIVE_SECTIONS.contains(&key.as_str()) {
+                report.applied.push(key.clone());
+            } else {
+                report.restart_required.push(key.clone());
+            }
+        }
+        report
+    }
+
+    pub fn failed(error: String) -> Self {
+        Self {
+            error: Some(error),
+            ..Self::default()
+        }
+    }
+
+    pub fn changed(&self, section: &str) -> bool {
+        self.applied.iter().any(|applied| applied == section)
+    }
+}
@@ -0,0 +1,299 @@
+use crate::events::{self, EventKind};
+use crate::ramp_dew_power;
+use crate::weather::Weather;
+use log::{error, info, warn};
+use pegasus_astro::actor::DeviceHandle;
+use pegasus_astro::ppba::{DewChannel, PegasusPowerBox};
+use pegasus_astro::ramp::DewRamp;
+use rumqttc::AsyncClient;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Temperature and dew point change slowly, no need to check them at every poll
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How the PWM grows as the temperature gets closer to the dew point
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Curve {
+    /// Proportional to how close the dew point is
+    #[default]
+    Linear,
+    /// Gentle at first, steeper near the dew point
+    Quadratic,
+    /// Straight to `max_pwm` once within the margin
+    Step,
+}
+
+/// Raise the PWM of a dew heater when the ambient temperature gets within
+/// `margin` degrees of the dew point, from `min_pwm` at the margin up to
+/// `max_pwm` at the dew point.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DewRule {
+    /// `a` or `b`
+    pub channel: DewChannel,
+    /// Degrees Celsius above the dew point the rule kicks in at
+    #[serde(default = "default_margin")]
+    pub margin: f32,
+    #[serde(default = "default_min_pwm")]
+    pub min_pwm: u8,
+    #[serde(default = "default_max_pwm")]
+    pub max_pwm: u8,
+    #[serde(default)]
+    pub curve: Curve,
+}
+
+fn default_margin() -> f32 {
+    3.0
+}
+
+fn default_min_pwm() -> u8 {
+    64
+}
+
+fn default_max_pwm() -> u8 {
+    255
+}
+
+impl DewRule {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.margin <= 0.0 {
+            return Err(format!("Dew rule margin must be positive: {}", self.margin));
+        }
+        if self.min_pwm > self.max_pwm {
+            return Err(format!(
+                "Dew rule min_pwm {} is above max_pwm {}",
+                self.min_pwm, self.max_pwm
+            ));
+        }
+        Ok(())
+    }
+
+    /// PWM the heater needs from the degrees the temperature is above the dew
+    /// point, `None` when it's far enough or unknown (NaN).
+    pub fn required_pwm(&self, dew_margin: f32) -> Option<u8> {
+        if dew_margin.is_nan() || dew_margin > self.margin {
+            return None;
+        }
+        // 0 at the margin, 1 at the dew point and below
+        let closeness = (1.0 - dew_margin / self.margin).clamp(0.0, 1.0);
+        let factor = match self.curve {
+            Curve::Linear => closeness,
+            Curve::Quadratic => closeness * closeness,
+            Curve::Step => 1.0,
+        };
+        let range = (self.max_pwm - self.min_pwm) as f32;
+        Some(self.min_pwm + (range * factor).round() as u8)
+    }
+
+    fn name(&self) -> &'static str {
+        match self.channel {
+            DewChannel::A => "dew_a",
+            DewChannel::B => "dew_b",
+        }
+    }
+}
+
+/// Published as a `dew_rule` event every time a rule changes a heater
+#[derive(Debug, Serialize)]
+struct RuleEvent {
+    rule: &'static str,
+    /// `activated`, `updated` or `deactivated`
+    event: &'static str,
+    pwm: u8,
+    temperature: f32,
+    dew_point: f32,
+    dew_margin: f32,
+    /// `device` or `weather` when the readings of the external sensor were used
+    source: &'static str,
+}
+
+/// Apply the dew rules to a PPBA until its task goes away.
+///
+/// A rule only ever raises the PWM, the value the heater had when the rule
+/// kicked in is restored once the temperature is back above the margin.
+/// Nothing is done while the device runs its own auto dew. The conditions of
+/// the external weather sensor are preferred over the onboard ones as long as
+/// they are fresh. The rules are replaced when the config file is reloaded.
+pub async fn run(
+    device: DeviceHandle<PegasusPowerBox>,
+    mut configured: watch::Receiver<Arc<[DewRule]>>,
+    client: AsyncClient,
+    weather: Option<Weather>,
+) {
+    let mut rules = configured.borrow_and_update().clone();
+    // PWM to restore for every active rule
+    let mut restore: Vec<Option<u8>> = vec![None; rules.len()];
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+        if configured.has_changed().unwrap_or(false) {
+            let previous = std::mem::replace(&mut rules, configured.borrow_and_update().clone());
+            restore = reassign(&device, &previous, &rules, restore).await;
+        }
+        if rules.is_empty() {
+            continue;
+        }
+        let Ok(snapshot) = device.call(|d| Box::pin(async move { d.snapshot() })).await else {
+            return;
+        };
+        if snapshot.autodew {
+            continue;
+        }
+        let (temperature, dew_point, source) = match weather.as_ref().and_then(Weather::current) {
+            Some(conditions) => (conditions.temperature, conditions.dew_point, "weather"),
+            None => (snapshot.temperature, snapshot.dew_point_computed, "device"),
+        };
+        let dew_margin = temperature - dew_point;
+        // No reading, the heaters stay as they are until there is one
+        if dew_margin.is_nan() {
+            continue;
+        }
+
+        for (rule, restore) in rules.iter().zip(restore.iter_mut()) {
+            let current = match rule.channel {
+                DewChannel::A => snapshot.dew1_power,
+                DewChannel::B => snapshot.dew2_power,
+            };
+            let required = rule.required_pwm(dew_margin);
+
+            let (event, pwm) = match (required, *restore) {
+                (Some(pwm), None) if pwm > current => {
+                    *restore = Some(current);
+                    ("activated", pwm)
+                }
+                (Some(pwm), Some(previous)) if pwm.max(previous) != current => {
+                    ("updated", pwm.max(previous))
+                }
+                (None, Some(previous)) => {
+                    *restore = None;
+                    ("deactivated", previous)
+                }
+                _ => continue,
+            };
+
+            info!("Dew rule {} {}, setting PWM to {}", rule.name(), event, pwm);
+            // Ramped over the `dew_ramp_ms` of the device only
+            match ramp_dew_power(&device, rule.channel, pwm, DewRamp::OFF).await {
+                Ok(true) => (),
+                Ok(false) => {
+                    warn!("Dew rule {} interrupted by another change", rule.name());
+                    continue;
+                }
+                Err(e) => {
+                    error!("Dew rule {} cannot set PWM: {}", rule.name(), e);
+                    continue;
+                }
+            }
+
+            let payload = RuleEvent {
+                rule: rule.name(),
+                event,
+                pwm,
+                temperature,
+                dew_point,
+                dew_margin,
+                source,
+            };
+            events::publish(&client, device.id(), EventKind::DewRule, &payload);
+        }
+    }
+}
+
+/// PWM to restore for every rule of a new set, taken over from the active
+/// rules of the previous set on the same channel. Heaters left without a rule
+/// get their PWM back right away.
+async fn reassign(
+    device: &DeviceHandle<PegasusPowerBox>,
+    previous: &[DewRule],
+    rules: &[DewRule],
+    restore: Vec<Option<u8>>,
+) -> Vec<Option<u8>> {
+    let mut active: Vec<(DewChannel, u8)> = previous
+        .iter()
+        .zip(restore)
+        .filter_map(|(rule, pwm)| pwm.map(|pwm| (rule.channel, pwm)))
+        .collect();
+    let restore = rules
+        .iter()
+        .map(|rule| {
+            let i = active
+                .iter()
+                .position(|(channel, _)| *channel == rule.channel)?;
+            Some(active.swap_remove(i).1)
+        })
+        .collect();
+    for (channel, pwm) in active {
+        info!(
+            "Dew rule on {:?} removed, setting PWM back to {}",
+            channel, pwm
+        );
+        if let Err(e) = ramp_dew_power(device, channel, pwm, DewRamp::OFF).await {
+            error!("Cannot set the PWM back on {:?}: {}", channel, e);
+        }
+    }
+    restore
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(curve: Curve) -> DewRule {
+        DewRule {
+            channel: DewChannel::A,
+            margin: 4.0,
+            min_pwm: 55,
+            max_pwm: 255,
+            curve,
+        }
+    }
+
+    #[test]
+    fn nothing_is_required_past_the_margin() {
+        for curve in [Curve::Linear, Curve::Quadratic, Curve::Step] {
+            assert_eq!(rule(curve).required_pwm(4.1), None);
+            assert_eq!(rule(curve).required_pwm(20.0), None);
+        }
+    }
+
+    #[test]
+    fn pwm_follows_the_curve() {
+        assert_eq!(rule(Curve::Linear).required_pwm(4.0), Some(55));
+        assert_eq!(rule(Curve::Linear).required_pwm(2.0), Some(155));
+        assert_eq!(rule(Curve::Linear).required_pwm(0.0), Some(255));
+        assert_eq!(rule(Curve::Quadratic).required_pwm(2.0), Some(105));
+        assert_eq!(rule(Curve::Quadratic).required_pwm(0.0), Some(255));
+        assert_eq!(rule(Curve::Step).required_pwm(4.0), Some(255));
+        assert_eq!(rule(Curve::Step).required_pwm(3.9), Some(255));
+    }
+
+    #[test]
+    fn below_the_dew_point_is_max_pwm() {
+        assert_eq!(rule(Curve::Linear).required_pwm(-3.0), Some(255));
+        assert_eq!(rule(Curve::Quadratic).required_pwm(-3.0), Some(255));
+    }
+
+    #[test]
+    fn unknown_readings_require_nothing() {
+        for curve in [Curve::Linear, Curve::Quadratic, Curve::Step] {
+            assert_eq!(rule(curve).required_pwm(f32::NAN), None);
+        }
+    }
+
+    #[test]
+    fn invalid_rules_are_refused() {
+        assert!(rule(Curve::Linear).validate().is_ok());
+        let mut zero_margin = rule(Curve::Linear);
+        zero_margin.margin = 0.0;
+        assert!(zero_margin.validate().is_err());
+        let mut inverted = rule(Curve::Linear);
+        inverted.min_pwm = 200;
+        inverted.max_pwm = 100;
+        assert!(inverted.validate().is_err());
+    }
+}
@@ -0,0 +1,389 @@
+use chrono::{DateTime, Datelike, TimeZone, Timelike};
+use log::warn;
+use pegasus_astro::sun::{Site, SunTime};
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// Most minutes run late after the clock jumped forward, e.g. when NTP sets
+/// the clock of a board without RTC or the computer resumes from suspend
+pub const MAX_CATCH_UP: i64 = 60;
+
+/// A cron expression, `minute hour day-of-month month day-of-week`.
+///
+/// Every field accepts `*`, a value, a range `a-b`, a step `*/n` or `a-b/n`
+/// and comma separated lists of those. Days of the week go from 0 (Sunday)
+/// to 6, 7 is Sunday as well.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CronExpr {
+    minutes: u64,
+    hours: u32,
+    days: u32,
+    months: u16,
+    weekdays: u8,
+    /// Both day fields were given, a date matches if any of them does
+    either_day: bool,
+}
+
+/// Bitset of the values a field matches
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step
+                    .parse()
+                    .map_err(|_| format!("Invalid step in cron field {}", field))?;
+                if step == 0 {
+                    return Err(format!("Invalid step in cron field {}", field));
+                }
+                (range, step)
+            }
+            None => (part, 1),
+        };
+        let (from, to) = if range == "*" {
+            (min, max)
+        } else if let Some((from, to)) = range.split_once('-') {
+            let from = from
+                .parse()
+                .map_err(|_| format!("Invalid cron field {}", field))?;
+            let to = to
+                .parse()
+                .map_err(|_| format!("Invalid cron field {}", field))?;
+            (from, to)
+        } else {
+            let value = range
+                .parse()
+                .map_err(|_| format!("Invalid cron field {}", field))?;
+            // `5/15` means from 5 to the end every 15
+            (value, if step > 1 { max } else { value })
+        };
+        if from < min || to > max || from > to {
+            return Err(format!("Cron field {} out of range {}-{}", field, min, max));
+        }
+        for value in (from..=to).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for CronExpr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("Cron expression {} must have 5 fields", s));
+        };
+        let mut weekdays = parse_field(weekday, 0, 7)?;
+        // 7 is Sunday too
+        if weekdays & (1 << 7) != 0 {
+            weekdays = (weekdays | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)? as u32,
+            days: parse_field(day, 1, 31)? as u32,
+            months: parse_field(month, 1, 12)? as u16,
+            weekdays: weekdays as u8,
+            either_day: day != "*" && weekday != "*",
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for CronExpr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl CronExpr {
+    /// Whether the expression matches the minute of the given time
+    pub fn matches<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+        let day = self.days & (1 << time.day()) != 0;
+        let weekday = self.weekdays & (1 << time.weekday().num_days_from_sunday()) != 0;
+        let date = if self.either_day {
+            day || weekday
+        } else {
+            day && weekday
+        };
+        date && self.minutes & (1 << time.minute()) != 0
+            && self.hours & (1 << time.hour()) != 0
+            && self.months & (1 << time.month()) != 0
+    }
+}
+
+/// Property update done by the driver at the times matching `cron`, or
+/// every day at the time of the sun given by `at`
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduledAction {
+    /// Only used in the logs
+    pub name: Option<String>,
+    /// Device the action applies to, every device when missing. Ignored for
+    /// actions received over MQTT, they apply to the device of the topic.
+    pub device: Option<Uuid>,
+    /// Local time, e.g. `0 6 * * *` for every day at 06:00
+    pub cron: Option<CronExpr>,
+    /// Time of the sun at the site, e.g. `sunset-30m` or `nautical_dusk`
+    pub at: Option<SunTime>,
+    pub prop_name: String,
+    pub value: String,
+}
+
+impl ScheduledAction {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.prop_name)
+    }
+
+    /// Exactly one of `cron` and `at` must be given, `at` needs the site
+    pub fn validate(&self, site: Option<&Site>) -> Result<(), String> {
+        match (&self.cron, &self.at) {
+            (Some(_), None) => Ok(()),
+            (None, Some(at)) if site.is_none() => Err(format!(
+                "Scheduled action {} at {} needs the [site] of the config file",
+                self.name(),
+                at
+            )),
+            (None, Some(_)) => Ok(()),
+            _ => Err(format!(
+                "Scheduled action {} needs either a cron expression or a sun time",
+                self.name()
+            )),
+        }
+    }
+
+    /// Whether the action runs in the minute of the given time
+    fn is_due<Tz: TimeZone>(&self, time: &DateTime<Tz>, site: Option<&Site>) -> bool {
+        if let Some(cron) = &self.cron {
+            return cron.matches(time);
+        }
+        let (Some(at), Some(site)) = (&self.at, site) else {
+            return false;
+        };
+        // An offset may move the action to the day before or after the event
+        let date = time.date_naive();
+        let minute = time.timestamp().div_euclid(60);
+        [date.pred_opt(), Some(date), date.succ_opt()]
+            .into_iter()
+            .flatten()
+            .filter_map(|date| at.on(site, date))
+            .any(|due| due.timestamp().div_euclid(60) == minute)
+    }
+}
+
+/// Minutes the schedule went through, in minutes since UNIX epoch, so the
+/// actions of every minute run once whatever the clock does.
+pub struct Minutes {
+    last: i64,
+}
+
+impl Minutes {
+    /// The current minute counts as done, its actions may have run right
+    /// before a restart
+    pub fn starting_at(now: i64) -> Self {
+        Self { last: now }
+    }
+
+    /// Minutes to run up to `now`. The ones skipped by a forward jump of the
+    /// clock are run late, up to [`MAX_CATCH_UP`]. Nothing runs again after a
+    /// backward jump, unless it went back further than that.
+    pub fn advance(&mut self, now: i64) -> RangeInclusive<i64> {
+        let last = self.last;
+        if last - now > MAX_CATCH_UP {
+            warn!("The clock went back {} minutes", last - now);
+            self.last = now;
+        } else {
+            self.last = last.max(now);
+        }
+        if now - last > MAX_CATCH_UP {
+            warn!(
+                "The clock jumped {} minutes forward, only the actions of the last {} run",
+                now - last,
+                MAX_CATCH_UP
+            );
+            return now - MAX_CATCH_UP + 1..=now;
+        }
+        // Empty when the clock didn't move forward
+        last + 1..=now
+    }
+}
+
+/// Actions of the config file and the ones received over MQTT for each device
+#[derive(Default)]
+pub struct Schedule {
+    configured: Vec<ScheduledAction>,
+    received: HashMap<Uuid, Vec<ScheduledAction>>,
+    /// Where the sun times are computed for
+    site: Option<Site>,
+}
+
+impl Schedule {
+    pub fn new(configured: Vec<ScheduledAction>, site: Option<Site>) -> Self {
+        Self {
+            configured,
+            received: HashMap::new(),
+            site,
+        }
+    }
+
+    /// Replace the actions and the site of the config file, e.g. once reloaded
+    pub fn set_configured(&mut self, configured: Vec<ScheduledAction>, site: Option<Site>) {
+        self.configured = configured;
+        self.site = site;
+    }
+
+    /// Replace the actions received for a device, an empty list clears them
+    pub fn set(&mut self, id: Uuid, actions: Vec<ScheduledAction>) -> Result<(), String> {
+        for action in &actions {
+            action.validate(self.site.as_ref())?;
+        }
+        if actions.is_empty() {
+            self.received.remove(&id);
+        } else {
+            self.received.insert(id, actions);
+        }
+        Ok(())
+    }
+
+    /// Actions to run at the given time on the given devices
+    pub fn due<Tz: TimeZone>(
+        &self,
+        time: &DateTime<Tz>,
+        ids: &[Uuid],
+    ) -> Vec<(Uuid, ScheduledAction)> {
+        let mut due = Vec::new();
+        for id in ids {
+            let configured = self
+                .configured
+                .iter()
+                .filter(|a| a.device.is_none_or(|device| device == *id));
+            let received = self.received.get(id).into_iter().flatten();
+            for action in configured.chain(received) {
+                if action.is_due(time, self.site.as_ref()) {
+                    due.push((*id, action.clone()));
+                }
+            }
+        }
+        due
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    /// Values of a parsed field
+    fn values(field: &str, min: u32, max: u32) -> Vec<u32> {
+        let bits = parse_field(field, min, max).unwrap();
+        (min..=max).filter(|v| bits & (1 << v) != 0).collect()
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        // October 2026, the 16th is a Friday
+        Utc.with_ymd_and_hms(2026, 10, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn cron(s: &str) -> CronExpr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn the_minute_of_the_start_is_skipped() {
+        let mut minutes = Minutes::starting_at(1000);
+        assert!(minutes.advance(1000).is_empty());
+        assert_eq!(minutes.advance(1001), 1001..=1001);
+        assert!(minutes.advance(1001).is_empty());
+    }
+
+    #[test]
+    fn minutes_skipped_forward_are_caught_up() {
+        let mut minutes = Minutes::starting_at(1000);
+        assert_eq!(minutes.advance(1005), 1001..=1005);
+        assert_eq!(
+            minutes.advance(1005 + MAX_CATCH_UP),
+            1006..=1005 + MAX_CATCH_UP
+        );
+        // Too late for most of them
+        assert_eq!(minutes.advance(10_000), 10_000 - MAX_CATCH_UP + 1..=10_000);
+    }
+
+    #[test]
+    fn nothing_runs_again_when_the_clock_goes_back() {
+        let mut minutes = Minutes::starting_at(1000);
+        assert!(minutes.advance(990).is_empty());
+        assert!(minutes.advance(1000).is_empty());
+        assert_eq!(minutes.advance(1001), 1001..=1001);
+        // Far back, the schedule starts over from there
+        assert!(minutes.advance(1001 - MAX_CATCH_UP - 1).is_empty());
+        assert_eq!(
+            minutes.advance(1001 - MAX_CATCH_UP),
+            1001 - MAX_CATCH_UP..=1001 - MAX_CATCH_UP
+        );
+    }
+
+    #[test]
+    fn fields_accept_values_ranges_and_lists() {
+        assert_eq!(values("*", 1, 12).len(), 12);
+        assert_eq!(values("5", 0, 59), [5]);
+        assert_eq!(values("1-4", 0, 59), [1, 2, 3, 4]);
+        assert_eq!(values("1,3,10-11", 0, 59), [1, 3, 10, 11]);
+    }
+
+    #[test]
+    fn fields_accept_steps() {
+        assert_eq!(values("*/15", 0, 59), [0, 15, 30, 45]);
+        assert_eq!(values("5/20", 0, 59), [5, 25, 45]);
+        assert_eq!(values("1-10/3", 0, 59), [1, 4, 7, 10]);
+        assert_eq!(values("*/5", 1, 12), [1, 6, 11]);
+    }
+
+    #[test]
+    fn invalid_fields_are_refused() {
+        for field in ["60", "5-2", "*/0", "a", "1-", "*/x", "", "1,,2"] {
+            assert!(parse_field(field, 0, 59).is_err(), "{}", field);
+        }
+        assert!(parse_field("0", 1, 31).is_err());
+        assert!("0 6 * *".parse::<CronExpr>().is_err());
+        assert!("0 6 * * * *".parse::<CronExpr>().is_err());
+        assert!("0 24 * * *".parse::<CronExpr>().is_err());
+    }
+
+    #[test]
+    fn seven_is_sunday() {
+        assert_eq!(cron("0 6 * * 7"), cron("0 6 * * 0"));
+        assert!(cron("0 6 * * 7").matches(&at(18, 6, 0)));
+        assert!(!cron("0 6 * * 7").matches(&at(17, 6, 0)));
+        assert!(cron("0 6 * * 5-7").matches(&at(16, 6, 0)));
+    }
+
+    #[test]
+    fn matches_the_minute_of_the_time() {
+        let every_day = cron("30 21 * * *");
+        assert!(every_day.matches(&at(16, 21, 30)));
+        assert!(every_day.matches(&at(17, 21, 30)));
+        assert!(!every_day.matches(&at(16, 21, 31)));
+        assert!(!every_day.matches(&at(16, 20, 30)));
+        assert!(cron("*/10 * * 10 *").matches(&at(16, 3, 40)));
+        assert!(!cron("*/10 * * 11 *").matches(&at(16, 3, 40)));
+    }
+
+    #[test]
+    fn either_day_field_matches_when_both_are_given() {
+        // The 1st of the month or any Friday
+        let expr = cron("0 12 1 * 5");
+        assert!(expr.matches(&at(1, 12, 0)));
+        assert!(expr.matches(&at(16, 12, 0)));
+        assert!(!expr.matches(&at(17, 12, 0)));
+        // A single day field must match
+        assert!(!cron("0 12 1 * *").matches(&at(16, 12, 0)));
+        assert!(!cron("0 12 * * 6").matches(&at(16, 12, 0)));
+        assert!(cron("0 12 16 * *").matches(&at(16, 12, 0)));
+    }
+}
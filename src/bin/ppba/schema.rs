@@ -0,0 +1,143 @@
+//! Version of the published payloads.
+//!
+//! Every JSON object published by the driver carries `schema_version` and
+//! `driver_version` so consumers can tell what they are reading. The schema
+//! version follows this policy:
+//!
+//! - adding a property, a field or a topic doesn't change it, consumers are
+//!   expected to ignore what they don't know
+//! - removing or renaming a property or a field, changing its type, unit or
+//!   meaning bumps it
+//!
+//! The properties of every device are described on the retained
+//! `devices/{id}/schema` topic, see [`describe`].
+use crate::i18n::{self, Language};
+use log::error;
+use pegasus_astro::device::ReadTimes;
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Bumped on every breaking change of the payloads
+pub const SCHEMA_VERSION: u32 = 1;
+pub const DRIVER_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Add `schema_version` and `driver_version` to an object, anything else is
+/// returned as is
+pub fn with_envelope(mut value: Value) -> Value {
+    if let Some(object) = value.as_object_mut() {
+        object.insert("schema_version".to_string(), json!(SCHEMA_VERSION));
+        object.insert("driver_version".to_string(), json!(DRIVER_VERSION));
+    }
+    value
+}
+
+/// Add to the state of a poll taken at `polled_at` (milliseconds since UNIX
+/// epoch) when every property was last read, `last_updated`, and whether the
+/// device is `stale`: a reading was not read for two polling intervals.
+///
+/// Readings never read have a `null` time, the properties that are not read
+/// from the device, e.g. the settings of the driver, are as of the poll.
+pub fn with_read_times(
+    mut state: Value,
+    read_times: Option<&ReadTimes>,
+    interval: Duration,
+    polled_at: u64,
+) -> Value {
+    let Some(properties) = state.as_object_mut() else {
+        return state;
+    };
+    let last_updated: Map<String, Value> = properties
+        .iter()
+        .filter(|(_, prop)| prop.get("value").is_some())
+        .map(|(name, _)| {
+            let time = match read_times.filter(|times| times.is_reading(name)) {
+                Some(times) => json!(times.last_read(name).map(|at| {
+                    at.duration_since(UNIX_EPOCH)
+                        .map_or(0, |d| d.as_millis() as u64)
+                })),
+                None => json!(polled_at),
+            };
+            (name.clone(), time)
+        })
+        .collect();
+    let stale = read_times.is_some_and(|times| times.is_stale(interval * 2));
+    properties.insert("last_updated".to_string(), Value::Object(last_updated));
+    properties.insert("stale".to_string(), json!(stale));
+    state
+}
+
+/// JSON of a payload to publish, with the envelope when it's an object
+pub fn payload(value: &impl Serialize) -> String {
+    match serde_json::to_value(value) {
+        Ok(value) => with_envelope(value).to_string(),
+        Err(e) => {
+            error!("Cannot serialize a payload: {}", e);
+            Value::Null.to_string()
+        }
+    }
+}
+
+/// Type, unit, permission and display name of every property of a serialized
+/// device state, e.g. `{"input_voltage": {"type": "number", "unit": "V",
+/// "permission": "ReadOnly", "label": "Input voltage", "unit_label": "volts"}}`.
+/// The display names are in `language`.
+pub fn describe(state: &Value, language: Language) -> Value {
+    let mut properties = Map::new();
+    for (name, prop) in state.as_object().into_iter().flatten() {
+        let (value, permission) = match prop.get("value") {
+            Some(value) => (value, prop.get("permission")),
+            None => (prop, None),
+        };
+        let mut description = Map::new();
+        description.insert("type".to_string(), json!(type_of(value)));
+        if let Some(unit) = unit(name) {
+            description.insert("unit".to_string(), json!(unit));
+            if let Some(unit_label) = i18n::unit_label(unit, language) {
+                description.insert("unit_label".to_string(), json!(unit_label));
+            }
+        }
+        if let Some(permission) = permission {
+            description.insert("permission".to_string(), permission.clone());
+        }
+        description.insert("label".to_string(), json!(i18n::label(name, language)));
+        properties.insert(name.clone(), Value::Object(description));
+    }
+    with_envelope(json!({ "language": language.code(), "properties": properties }))
+}
+
+fn type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Unit of a property, from its name
+fn unit(name: &str) -> Option<&'static str> {
+    let unit = match name {
+        "adj_output" | "input_voltage" => "V",
+        "temperature" | "dew_point" | "dew_point_computed" | "dew_margin"
+        | "temperature_offset" => "°C",
+        "humidity" | "battery_soc" | "humidity_offset" => "%",
+        "average_power" => "W",
+        "watt_hours" | "energy_daily" => "Wh",
+        "amps_hours" | "battery_remaining_ah" => "Ah",
+        "uptime" | "polling_interval" => "ms",
+        "uptime_seconds" => "s",
+        "battery_runtime" => "h",
+        "dew1_power" | "dew2_power" | "dew_power" => "pwm",
+        _ if name.ends_with("_pct") => "%",
+        _ if name.ends_with("_ms") => "ms",
+        _ if name.ends_with("_energy") || name.ends_with("_energy_today") => "Wh",
+        _ if name.ends_with("_voltage") => "V",
+        _ if name.contains("current") || name.ends_with("_amps") => "A",
+        _ => return None,
+    };
+    Some(unit)
+}
@@ -0,0 +1,446 @@
+use crate::buffer::now_millis;
+use log::{error, info};
+use pegasus_astro::device::AstronomicalDevice;
+use pegasus_astro::error::PegasusError;
+use pegasus_astro::ppba::{DewChannel, PegasusPowerBox};
+use serde::Serialize;
+use std::fmt::Display;
+use std::ops::RangeInclusive;
+use std::time::{Duration, Instant};
+use tokio::time::sleep;
+
+/// PWM used to pulse the dew heaters, low enough to be harmless for any strap
+const PULSE_PWM: u8 = 30;
+/// How long a dew heater is kept on before reading back its current
+const PULSE_DURATION: Duration = Duration::from_millis(1500);
+const LED_BLINK: Duration = Duration::from_millis(500);
+/// Status commands timed to measure the serial round trip
+const LATENCY_SAMPLES: u32 = 5;
+/// Round trips slower than this make the polling unreliable
+const MAX_LATENCY: Duration = Duration::from_millis(250);
+/// Plausible readings, anything outside points to a bad supply or sensor
+const INPUT_VOLTAGE_RANGE: RangeInclusive<f32> = 10.0..=15.0;
+const TEMPERATURE_RANGE: RangeInclusive<f32> = -40.0..=60.0;
+const HUMIDITY_RANGE: RangeInclusive<f32> = 0.0..=100.0;
+
+#[derive(Debug, Serialize)]
+pub struct SelfTestCheck {
+    pub name: String,
+    pub passed: bool,
+    pub detail: String,
+}
+
+/// Outcome of a self test, `passed` is true only if every check passed.
+#[derive(Debug, Serialize)]
+pub struct SelfTestReport {
+    pub device: String,
+    pub address: String,
+    pub passed: bool,
+    pub checks: Vec<SelfTestCheck>,
+}
+
+impl SelfTestReport {
+    fn record<E: Display>(&mut self, name: &str, result: Result<String, E>) {
+        let (passed, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(e) => (false, e.to_string()),
+        };
+
+        if !passed {
+            error!("Self test {} failed: {}", name, detail);
+            self.passed = false;
+        }
+
+        self.checks.push(SelfTestCheck {
+            name: name.to_owned(),
+            passed,
+            detail,
+        });
+    }
+}
+
+/// Exercise the device without putting anything connected to it at risk.
+///
+/// The routine checks the device answers, reads the firmware version, blinks
+/// the led, and briefly pulses each dew heater at low duty checking that the
+/// measured current responds; every output is restored to its previous value,
+/// autodew is paused during the pulses.
+pub async fn self_test(device: &mut PegasusPowerBox) -> SelfTestReport {
+    info!("Running self test on {}", device.get_name());
+
+    let mut report = SelfTestReport {
+        device: device.get_name().clone(),
+        address: device.get_address().clone(),
+        passed: true,
+        checks: Vec::new(),
+    };
+
+    let status = device.check_status().await;
+    report.record("status", status);
+
+    let fw = device.read_firmware_version().await.and_then(|fw| {
+        if fw.is_empty() {
+            Err(PegasusError::Parse("Empty firmware version".to_string()))
+        } else {
+            Ok(fw)
+        }
+    });
+    report.record("firmware_version", fw);
+
+    let led = device.led();
+    let blink = match device.set_led(!led).await {
+        Ok(_) => {
+            sleep(LED_BLINK).await;
+            device.set_led(led).await
+        }
+        Err(e) => Err(e),
+    };
+    report.record("led", blink.map(|_| "Led blinked".to_string()));
+
+    device.fetch_props().await;
+    let snapshot = device.snapshot();
+    let readings = if snapshot.input_voltage > 0.0 {
+        Ok(format!("Input voltage {:.2}V", snapshot.input_voltage))
+    } else {
+        Err("Input voltage reads 0V".to_string())
+    };
+    report.record("readings", readings);
+
+    check_dew_heaters(device, &mut report, DewCheck::Pulse).await;
+
+    info!(
+        "Self test on {} {}",
+        report.device,
+        if report.passed { "passed" } else { "failed" }
+    );
+    report
+}
+
+#[derive(Clone, Copy)]
+enum DewCheck {
+    /// See [`pulse_dew`]
+    Pulse,
+    /// See [`read_back_dew`]
+    ReadBack,
+}
+
+/// Run `check` on both dew heaters with autodew off, the device would
+/// otherwise override the PWM set by the check
+async fn check_dew_heaters(
+    device: &mut PegasusPowerBox,
+    report: &mut SelfTestReport,
+    check: DewCheck,
+) {
+    let autodew = device.snapshot().autodew;
+    if autodew {
+        if let Err(e) = device.set_autodew(false).await {
+            report.record::<String>(
+                "autodew",
+                Err(format!(
+                    "Cannot pause autodew to check the dew heaters: {}",
+                    e
+                )),
+            );
+            return;
+        }
+        // Back to the PWM set by hand, the ones to restore
+        device.fetch_props().await;
+    }
+
+    for (channel, pulse, read_back) in [
+        (DewChannel::A, "dew1_pulse", "dew1_readback"),
+        (DewChannel::B, "dew2_pulse", "dew2_readback"),
+    ] {
+        match check {
+            DewCheck::Pulse => report.record(pulse, pulse_dew(device, channel).await),
+            DewCheck::ReadBack => report.record(read_back, read_back_dew(device, channel).await),
+        }
+    }
+
+    if autodew {
+        let restored = device.set_autodew(true).await;
+        report.record("autodew", restored.map(|_| "Autodew restored".to_string()));
+    }
+}
+
+/// Set the PWM of a dew heater back to `previous` whatever the outcome of
+/// the check, a failure to do so fails the check
+async fn restore_dew(
+    device: &mut PegasusPowerBox,
+    channel: DewChannel,
+    previous: u8,
+    result: Result<String, String>,
+) -> Result<String, String> {
+    match device.set_dew_power(channel, previous).await {
+        Ok(()) => result,
+        Err(e) => {
+            if let Err(failure) = result {
+                error!("Dew heater {:?} check failed: {}", channel, failure);
+            }
+            Err(format!("Cannot restore the PWM to {}: {}", previous, e))
+        }
+    }
+}
+
+async fn pulse_dew(device: &mut PegasusPowerBox, channel: DewChannel) -> Result<String, String> {
+    let previous = device.dew_power(channel);
+    let result = measure_pulse(device, channel).await;
+    restore_dew(device, channel, previous, result).await
+}
+
+async fn measure_pulse(
+    device: &mut PegasusPowerBox,
+    channel: DewChannel,
+) -> Result<String, String> {
+    let current = |device: &PegasusPowerBox| {
+        let snapshot = device.snapshot();
+        match channel {
+            DewChannel::A => snapshot.dew1_current,
+            DewChannel::B => snapshot.dew2_current,
+        }
+    };
+
+    device
+        .set_dew_power(channel, 0)
+        .await
+        .map_err(|e| e.to_string())?;
+    sleep(PULSE_DURATION).await;
+    device.fetch_props().await;
+    let idle = current(device);
+
+    device
+        .set_dew_power(channel, PULSE_PWM)
+        .await
+        .map_err(|e| e.to_string())?;
+    sleep(PULSE_DURATION).await;
+    device.fetch_props().await;
+    let pulsed = current(device);
+
+    if pulsed > idle {
+        Ok(format!("Current went from {:.2}A to {:.2}A", idle, pulsed))
+    } else {
+        Err(format!(
+            "Current didn't respond ({:.2}A idle, {:.2}A pulsed), is a heater connected?",
+            idle, pulsed
+        ))
+    }
+}
+
+/// Serial round trip of the status command, in milliseconds
+#[derive(Debug, Serialize)]
+pub struct Latency {
+    pub samples: u32,
+    pub min_ms: f64,
+    pub avg_ms: f64,
+    pub max_ms: f64,
+}
+
+/// Outcome of a diagnosis, meant to be attached as is to a bug report.
+#[derive(Debug, Serialize)]
+pub struct DiagnosticReport {
+    /// Milliseconds since epoch
+    pub timestamp: u64,
+    pub serial_number: String,
+    pub firmware_version: String,
+    pub latency: Option<Latency>,
+    #[serde(flatten)]
+    pub report: SelfTestReport,
+}
+
+/// Check the device in depth without pulsing its outputs long enough to
+/// heat anything.
+///
+/// On top of reading the firmware version, the diagnosis times the serial
+/// round trip, checks the sensor readings are plausible and sets each dew
+/// heater reading the PWM back from the device before restoring it.
+pub async fn diagnose(device: &mut PegasusPowerBox) -> DiagnosticReport {
+    info!("Diagnosing {}", device.get_name());
+
+    let mut report = SelfTestReport {
+        device: device.get_name().clone(),
+        address: device.get_address().clone(),
+        passed: true,
+        checks: Vec::new(),
+    };
+
+    let latency = measure_latency(device).await;
+    report.record(
+        "latency",
+        latency.as_ref().map_err(Clone::clone).and_then(|l| {
+            let detail = format!(
+                "{:.1}ms min, {:.1}ms avg, {:.1}ms max",
+                l.min_ms, l.avg_ms, l.max_ms
+            );
+            if l.max_ms > MAX_LATENCY.as_secs_f64() * 1000.0 {
+                Err(format!("Slow round trip: {}", detail))
+            } else {
+                Ok(detail)
+            }
+        }),
+    );
+
+    let fw = device.read_firmware_version().await;
+    report.record(
+        "firmware_version",
+        fw.as_ref().map(Clone::clone).map_err(ToString::to_string),
+    );
+
+    device.fetch_props().await;
+    let snapshot = device.snapshot();
+    for (name, value, range) in [
+        ("input_voltage", snapshot.input_voltage, INPUT_VOLTAGE_RANGE),
+        ("temperature", snapshot.temperature, TEMPERATURE_RANGE),
+        ("humidity", snapshot.humidity, HUMIDITY_RANGE),
+    ] {
+        report.record(name, in_range(value, range));
+    }
+    let dew_point = if snapshot.dew_point <= snapshot.temperature {
+        Ok(format!("{:.1}", snapshot.dew_point))
+    } else {
+        Err(format!(
+            "Dew point {:.1} above the temperature {:.1}",
+            snapshot.dew_point, snapshot.temperature
+        ))
+    };
+    report.record("dew_point", dew_point);
+
+    check_dew_heaters(device, &mut report, DewCheck::ReadBack).await;
+
+    info!(
+        "Diagnosis of {} {}",
+        report.device,
+        if report.passed { "passed" } else { "failed" }
+    );
+    DiagnosticReport {
+        timestamp: now_millis(),
+        serial_number: snapshot.serial_number,
+        firmware_version: fw.unwrap_or_default(),
+        latency: latency.ok(),
+        report,
+    }
+}
+
+async fn measure_latency(device: &mut PegasusPowerBox) -> Result<Latency, String> {
+    let mut samples = Vec::new();
+    for _ in 0..LATENCY_SAMPLES {
+        let start = Instant::now();
+        device.check_status().await.map_err(|e| e.to_string())?;
+        samples.push(start.elapsed().as_secs_f64() * 1000.0);
+    }
+    Ok(Latency {
+        samples: LATENCY_SAMPLES,
+        min_ms: samples.iter().copied().fold(f64::INFINITY, f64::min),
+        avg_ms: samples.iter().sum::<f64>() / samples.len() as f64,
+        max_ms: samples.iter().copied().fold(0.0, f64::max),
+    })
+}
+
+fn in_range(value: f32, range: RangeInclusive<f32>) -> Result<String, String> {
+    if range.contains(&value) {
+        Ok(format!("{:.1}", value))
+    } else {
+        Err(format!(
+            "{:.1} out of the {}..{} range",
+            value,
+            range.start(),
+            range.end()
+        ))
+    }
+}
+
+/// Set the PWM of a dew heater and check the device reports it back
+async fn read_back_dew(
+    device: &mut PegasusPowerBox,
+    channel: DewChannel,
+) -> Result<String, String> {
+    let previous = device.dew_power(channel);
+    let target = if previous == PULSE_PWM { 0 } else { PULSE_PWM };
+
+    let result = match device.set_dew_power_at_once(channel, target).await {
+        Ok(()) => {
+            device.fetch_props().await;
+            let read = device.dew_power(channel);
+            if read == target {
+                Ok(format!("PWM {} read back", target))
+            } else {
+                Err(format!("PWM set to {} but read back {}", target, read))
+            }
+        }
+        Err(e) => Err(e.to_string()),
+    };
+    restore_dew(device, channel, previous, result).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pegasus_astro::sim::FakePpbaPort;
+    use pegasus_astro::transport::{self, RetryPolicies, RetryPolicy};
+
+    async fn simulated(port: &FakePpbaPort) -> PegasusPowerBox {
+        let mut device = PegasusPowerBox::new_with_port(
+            "PegausPowerBoxAdvanced-SIM0000",
+            "sim://0",
+            transport::DEFAULT_BAUD,
+            Box::new(port.clone()),
+        )
+        .await
+        .unwrap();
+        device.set_retry_policies(RetryPolicies::new(RetryPolicy::NONE));
+        device
+    }
+
+    fn check<'a>(report: &'a SelfTestReport, name: &str) -> &'a SelfTestCheck {
+        report.checks.iter().find(|c| c.name == name).unwrap()
+    }
+
+    #[tokio::test]
+    async fn self_test_restores_what_it_changed() {
+        let port = FakePpbaPort::simulated(0);
+        let mut device = simulated(&port).await;
+        device.set_led(false).await.unwrap();
+        device.set_dew_power(DewChannel::B, 60).await.unwrap();
+        let dew = [
+            device.dew_power(DewChannel::A),
+            device.dew_power(DewChannel::B),
+        ];
+        device.set_autodew(true).await.unwrap();
+        device.fetch_props().await;
+
+        let report = self_test(&mut device).await;
+
+        assert!(report.passed, "{:?}", report.checks);
+        assert!(check(&report, "autodew").passed);
+        let sent = port.sent_commands();
+        let leds: Vec<_> = sent.iter().filter(|c| c.starts_with("PL:")).collect();
+        assert_eq!(leds, ["PL:0", "PL:1", "PL:0"]);
+        // Off for the pulses, on again afterwards
+        let autodew = sent.iter().rposition(|c| c == "PD:0").unwrap();
+        let pulse = sent.iter().position(|c| c == "P3:030").unwrap();
+        assert!(autodew < pulse);
+        assert_eq!(sent.last().unwrap(), "PD:1");
+        for (prefix, pwm) in ["P3:", "P4:"].into_iter().zip(dew) {
+            let last = sent.iter().rfind(|c| c.starts_with(prefix)).unwrap();
+            assert_eq!(*last, format!("{}{:03}", prefix, pwm));
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_pulse_restores_the_heater() {
+        let port = FakePpbaPort::simulated(0);
+        let mut device = simulated(&port).await;
+        let previous = device.dew_power(DewChannel::A);
+        port.drop_responses(&format!("P3:{:03}", PULSE_PWM), 1);
+
+        let report = self_test(&mut device).await;
+
+        assert!(!report.passed);
+        assert!(!check(&report, "dew1_pulse").passed);
+        assert!(check(&report, "dew2_pulse").passed);
+        let sent = port.sent_commands();
+        let failed = sent.iter().position(|c| c == "P3:030").unwrap();
+        assert!(sent[failed..].contains(&format!("P3:{:03}", previous)));
+        device.fetch_props().await;
+        assert_eq!(device.dew_power(DewChannel::A), previous);
+    }
+}
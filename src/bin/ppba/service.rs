@@ -0,0 +1,300 @@
+use log::{debug, error, info};
+use std::time::Duration;
+use tokio::signal;
+use tokio::sync::Notify;
+
+/// Name of the systemd unit and of the Windows service
+pub const SERVICE_NAME: &str = "ppba";
+const DESCRIPTION: &str = "Pegasus Astro MQTT driver";
+/// Where `--install-service` writes the systemd unit
+#[cfg(target_os = "linux")]
+const UNIT_PATH: &str = "/etc/systemd/system/ppba.service";
+/// Seconds systemd waits for a watchdog ping before restarting the driver
+#[cfg(target_os = "linux")]
+const WATCHDOG_SEC: u64 = 30;
+
+/// Stop requested by the Windows service manager
+static STOP: Notify = Notify::const_new();
+
+/// Send a state to systemd, nothing happens if it didn't start the driver
+/// with `Type=notify`
+#[cfg(unix)]
+fn sd_notify(state: &str) {
+    use std::os::unix::net::UnixDatagram;
+
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    let res = UnixDatagram::unbound().and_then(|socket| {
+        // A leading `@` is a socket of the abstract namespace
+        #[cfg(target_os = "linux")]
+        if let Some(name) = path.as_encoded_bytes().strip_prefix(b"@") {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            return socket.send_to_addr(state.as_bytes(), &addr);
+        }
+        socket.send_to(state.as_bytes(), &path)
+    });
+    if let Err(e) = res {
+        debug!("Cannot notify systemd of {}: {}", state, e);
+    }
+}
+
+#[cfg(not(unix))]
+fn sd_notify(_state: &str) {}
+
+/// The devices are driven and the broker connection is started
+pub fn notify_ready(devices: usize) {
+    sd_notify(&format!("READY=1\nSTATUS=Driving {} devices", devices));
+}
+
+/// Describe what the driver is doing in `systemctl status`
+pub fn notify_status(status: &str) {
+    sd_notify(&format!("STATUS={}", status));
+}
+
+/// The driver is shutting down, the devices are put in their safe state
+pub fn notify_stopping() {
+    sd_notify("STOPPING=1");
+    #[cfg(windows)]
+    windows::set_state(windows_service::service::ServiceState::StopPending);
+}
+
+/// The driver is about to exit
+pub fn notify_stopped() {
+    #[cfg(windows)]
+    windows::set_state(windows_service::service::ServiceState::Stopped);
+}
+
+/// Ping the systemd watchdog at half its timeout, forever. Returns right
+/// away if it's not enabled.
+pub async fn watchdog() {
+    let Some(timeout) = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .map(Duration::from_micros)
+    else {
+        return;
+    };
+    info!("Pinging the systemd watchdog every {:?}", timeout / 2);
+    let mut interval = tokio::time::interval(timeout / 2);
+    loop {
+        interval.tick().await;
+        sd_notify("WATCHDOG=1");
+    }
+}
+
+/// Wait for a request to stop: ctrl-c, SIGTERM (what `systemctl stop`
+/// sends) or a stop from the Windows service manager
+pub async fn stop_requested() {
+    #[cfg(unix)]
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                error!("Cannot listen to SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        res = signal::ctrl_c() => {
+            if let Err(e) = res {
+                error!("Cannot listen to ctrl-c: {}", e);
+                std::future::pending::<()>().await;
+            }
+            debug!("ctrl-c received!");
+        }
+        () = terminate => debug!("SIGTERM received!"),
+        () = STOP.notified() => debug!("Stop requested by the service manager"),
+    }
+}
+
+/// Arguments to start the driver with at boot, the ones given without
+/// `--install-service`
+#[cfg(any(target_os = "linux", windows))]
+fn service_arguments() -> Vec<std::ffi::OsString> {
+    std::env::args_os()
+        .skip(1)
+        .filter(|arg| arg != "--install-service")
+        .collect()
+}
+
+/// Write a systemd unit starting the driver at boot with the arguments
+/// given, returns what's left to do to enable it
+#[cfg(target_os = "linux")]
+pub fn install() -> Result<String, String> {
+    let exe = std::env::current_exe()
+        .map_err(|e| format!("Cannot find the path of the driver: {}", e))?;
+    let mut exec_start = exe.display().to_string();
+    for arg in service_arguments() {
+        exec_start.push(' ');
+        exec_start.push_str(&systemd_quote(&arg.to_string_lossy()));
+    }
+    let unit = format!(
+        "[Unit]\n\
+         Description={DESCRIPTION}\n\
+         Wants=network-online.target\n\
+         After=network-online.target\n\
+         \n\
+         [Service]\n\
+         Type=notify\n\
+         ExecStart={exec_start}\n\
+         Restart=on-failure\n\
+         RestartSec=5\n\
+         WatchdogSec={WATCHDOG_SEC}\n\
+         SupplementaryGroups=dialout\n\
+         \n\
+         [Install]\n\
+         WantedBy=multi-user.target\n"
+    );
+    std::fs::write(UNIT_PATH, unit).map_err(|e| format!("Cannot write {}: {}", UNIT_PATH, e))?;
+    Ok(format!(
+        "{} written, start it at boot with `systemctl daemon-reload && systemctl enable --now {}`",
+        UNIT_PATH, SERVICE_NAME
+    ))
+}
+
+/// Quote an argument of `ExecStart` if needed
+#[cfg(target_os = "linux")]
+fn systemd_quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || "\"'\\;$%".contains(c)) {
+        return arg.to_owned();
+    }
+    let escaped = arg
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%")
+        .replace('$', "$$");
+    format!("\"{}\"", escaped)
+}
+
+/// Register a Windows service started at boot with the arguments given,
+/// returns what's left to do to start it
+#[cfg(windows)]
+pub fn install() -> Result<String, String> {
+    windows::install(service_arguments())
+        .map_err(|e| format!("Cannot install the service: {}", e))?;
+    Ok(format!(
+        "Service {} installed, start it with `sc start {}`",
+        SERVICE_NAME, SERVICE_NAME
+    ))
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn install() -> Result<String, String> {
+    Err("Installing a service is only supported on Linux and Windows".to_owned())
+}
+
+#[cfg(windows)]
+pub use windows::run_service;
+
+#[cfg(windows)]
+mod windows {
+    use super::{DESCRIPTION, SERVICE_NAME, STOP};
+    use log::error;
+    use std::ffi::OsString;
+    use std::sync::OnceLock;
+    use std::time::Duration;
+    use windows_service::service::{
+        ServiceAccess, ServiceControl, ServiceControlAccept, ServiceErrorControl, ServiceExitCode,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    };
+    use windows_service::service_control_handler::{self, ServiceControlHandlerResult};
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+    use windows_service::{define_windows_service, service_dispatcher};
+
+    /// Argument the service manager starts the driver with, see `Cli::windows_service`
+    const SERVICE_FLAG: &str = "--windows-service";
+
+    /// What the service runs, given to [`run_service`]
+    static RUN: OnceLock<fn()> = OnceLock::new();
+    static STATUS: OnceLock<service_control_handler::ServiceStatusHandle> = OnceLock::new();
+
+    define_windows_service!(ffi_service_main, service_main);
+
+    /// Run `run` under the Windows service manager, until it returns
+    pub fn run_service(run: fn()) -> Result<(), String> {
+        let _ = RUN.set(run);
+        service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+            .map_err(|e| format!("Cannot start the {} service: {}", SERVICE_NAME, e))
+    }
+
+    fn service_main(_arguments: Vec<OsString>) {
+        let handler = |control| match control {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                STOP.notify_one();
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        };
+        match service_control_handler::register(SERVICE_NAME, handler) {
+            Ok(status) => {
+                let _ = STATUS.set(status);
+            }
+            Err(e) => {
+                error!("Cannot register the service control handler: {}", e);
+                return;
+            }
+        }
+        set_state(ServiceState::Running);
+        if let Some(run) = RUN.get() {
+            run();
+        }
+        set_state(ServiceState::Stopped);
+    }
+
+    /// Report the state of the service, if the driver runs as one
+    pub fn set_state(state: ServiceState) {
+        let Some(status) = STATUS.get() else {
+            return;
+        };
+        let controls_accepted = match state {
+            ServiceState::Running => ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN,
+            _ => ServiceControlAccept::empty(),
+        };
+        let res = status.set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted,
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 0,
+            // Covers the shutdown grace period
+            wait_hint: Duration::from_secs(10),
+            process_id: None,
+        });
+        if let Err(e) = res {
+            error!("Cannot report the service state: {}", e);
+        }
+    }
+
+    pub fn install(arguments: Vec<OsString>) -> windows_service::Result<()> {
+        let manager = ServiceManager::local_computer(
+            None::<&str>,
+            ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+        )?;
+        let executable_path = std::env::current_exe().map_err(windows_service::Error::Winapi)?;
+        let info = ServiceInfo {
+            name: SERVICE_NAME.into(),
+            display_name: DESCRIPTION.into(),
+            service_type: ServiceType::OWN_PROCESS,
+            start_type: ServiceStartType::AutoStart,
+            error_control: ServiceErrorControl::Normal,
+            executable_path,
+            launch_arguments: std::iter::once(SERVICE_FLAG.into())
+                .chain(arguments)
+                .collect(),
+            dependencies: Vec::new(),
+            account_name: None,
+            account_password: None,
+        };
+        let service = manager.create_service(&info, ServiceAccess::CHANGE_CONFIG)?;
+        service.set_description(DESCRIPTION)
+    }
+}
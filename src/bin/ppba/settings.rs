@@ -0,0 +1,56 @@
+use log::{debug, error, warn};
+use pegasus_astro::ppba::SavedState;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Last known settings of every PPBA, keyed by device name.
+///
+/// Device names embed the USB serial number so they are stable across
+/// restarts, unlike device ids.
+pub struct SettingsStore {
+    path: PathBuf,
+    states: HashMap<String, SavedState>,
+}
+
+/// `~/.pegasus_ppba_settings.json`, settings must survive a reboot so the temp
+/// dir is used only if there is no home directory.
+pub fn default_path() -> PathBuf {
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join(".pegasus_ppba_settings.json")
+}
+
+impl SettingsStore {
+    pub fn load(path: PathBuf) -> Self {
+        let states = match std::fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("Ignoring corrupted settings {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self { path, states }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&SavedState> {
+        self.states.get(name)
+    }
+
+    /// Remember the settings of a device, the file is written only on changes.
+    pub fn save(&mut self, name: &str, state: SavedState) {
+        if self.states.get(name) == Some(&state) {
+            return;
+        }
+        debug!("Saving settings of {}", name);
+        self.states.insert(name.to_owned(), state);
+
+        let result = serde_json::to_string_pretty(&self.states)
+            .map_err(|e| e.to_string())
+            .and_then(|content| std::fs::write(&self.path, content).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            error!("Cannot write settings to {}: {}", self.path.display(), e);
+        }
+    }
+}
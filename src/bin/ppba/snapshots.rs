@@ -0,0 +1,74 @@
+//! Last state of every device and list of the devices driven, kept for the
+//! read requests of the TCP and HTTP APIs.
+//!
+//! The pollers and the scans replace the snapshots once they have them, the
+//! readers only clone an `Arc`: a request never waits for a poll in progress
+//! on the serial link nor for a scan holding the driver.
+use crate::DeviceInfo;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::watch;
+use uuid::Uuid;
+
+/// State of a device as of its last poll
+pub struct Snapshot {
+    pub state: Value,
+    /// `state` with the envelope, as published on `devices/{id}`
+    pub payload: String,
+}
+
+pub struct Snapshots {
+    devices: watch::Sender<Arc<[DeviceInfo]>>,
+    states: watch::Sender<Arc<HashMap<Uuid, Arc<Snapshot>>>>,
+}
+
+impl Default for Snapshots {
+    fn default() -> Self {
+        Self {
+            devices: watch::channel(Arc::from([])).0,
+            states: watch::channel(Arc::default()).0,
+        }
+    }
+}
+
+impl Snapshots {
+    /// The devices driven, without their alias
+    pub fn devices(&self) -> Arc<[DeviceInfo]> {
+        Arc::clone(&self.devices.borrow())
+    }
+
+    pub fn ids(&self) -> Vec<Uuid> {
+        self.devices.borrow().iter().map(|info| info.id).collect()
+    }
+
+    /// Replace the list of the devices after a scan
+    pub fn set_devices(&self, devices: Vec<DeviceInfo>) {
+        self.devices.send_replace(Arc::from(devices));
+    }
+
+    /// Last state of the device, `None` until its first poll
+    pub fn state(&self, id: &Uuid) -> Option<Arc<Snapshot>> {
+        self.states.borrow().get(id).cloned()
+    }
+
+    /// Last state of every device
+    pub fn states(&self) -> Arc<HashMap<Uuid, Arc<Snapshot>>> {
+        Arc::clone(&self.states.borrow())
+    }
+
+    /// Keep the state of a poll
+    pub fn update(&self, id: Uuid, snapshot: Snapshot) {
+        let snapshot = Arc::new(snapshot);
+        self.states.send_modify(|states| {
+            Arc::make_mut(states).insert(id, snapshot);
+        });
+    }
+
+    /// Forget an unplugged device
+    pub fn remove(&self, id: &Uuid) {
+        self.states.send_modify(|states| {
+            Arc::make_mut(states).remove(id);
+        });
+    }
+}
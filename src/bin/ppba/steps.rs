@@ -0,0 +1,224 @@
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// Default change of a current reported as a step, in A
+pub const DEFAULT_STEP_AMPS: f64 = 0.3;
+/// Polls a change must last to be a step rather than a transient
+const SETTLE_SAMPLES: u32 = 3;
+/// Weight of a new reading in the baseline while the current is steady
+const BASELINE_WEIGHT: f64 = 0.3;
+/// Currents looked at, the ones a device doesn't have are skipped
+const CURRENTS: [&str; 5] = [
+    "current",
+    "total_current",
+    "current_12v_output",
+    "dew1_current",
+    "dew2_current",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepKind {
+    /// The current went up and stayed there, e.g. a camera plugged downstream
+    LoadAdded,
+    /// The current went down and stayed there
+    LoadRemoved,
+    /// The current went back within a few polls, e.g. a mount slew
+    Transient,
+}
+
+/// Published as a `current_step` event on `devices/{id}/events`
+#[derive(Clone, Debug, Serialize)]
+pub struct CurrentStep {
+    pub property: &'static str,
+    pub kind: StepKind,
+    /// Steady current before the change, in A
+    pub before: f64,
+    /// Current once the change settled or went back
+    pub after: f64,
+    /// Furthest reading from `before` during the change
+    pub peak: f64,
+    /// Polls the change lasted
+    pub samples: u32,
+}
+
+/// A change past the threshold waiting to settle or go back
+#[derive(Debug)]
+struct Pending {
+    before: f64,
+    peak: f64,
+    samples: u32,
+}
+
+#[derive(Debug)]
+struct Track {
+    baseline: f64,
+    pending: Option<Pending>,
+}
+
+/// Sudden changes of the currents of a single device.
+///
+/// Every current follows a baseline while it's steady, a reading at least
+/// `threshold` amps away from it starts a change: if the current is back
+/// within [`SETTLE_SAMPLES`] polls it was a transient, otherwise a load was
+/// added or removed and the new current becomes the baseline.
+pub struct StepDetector {
+    threshold: f64,
+    tracks: HashMap<&'static str, Track>,
+}
+
+impl StepDetector {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            tracks: HashMap::new(),
+        }
+    }
+
+    /// Look at the currents of a serialized device state, returns the
+    /// changes that settled or went back with it
+    pub fn check(&mut self, state: &Value) -> Vec<CurrentStep> {
+        let mut steps = Vec::new();
+        for property in CURRENTS {
+            let Some(value) = state
+                .get(property)
+                .and_then(|p| p.get("value"))
+                .and_then(Value::as_f64)
+            else {
+                continue;
+            };
+            let Some(track) = self.tracks.get_mut(property) else {
+                self.tracks.insert(
+                    property,
+                    Track {
+                        baseline: value,
+                        pending: None,
+                    },
+                );
+                continue;
+            };
+
+            let Some(pending) = &mut track.pending else {
+                if (value - track.baseline).abs() >= self.threshold {
+                    track.pending = Some(Pending {
+                        before: track.baseline,
+                        peak: value,
+                        samples: 1,
+                    });
+                } else {
+                    track.baseline += BASELINE_WEIGHT * (value - track.baseline);
+                }
+                continue;
+            };
+
+            let kind = if (value - pending.before).abs() < self.threshold {
+                StepKind::Transient
+            } else {
+                if (value - pending.before).abs() > (pending.peak - pending.before).abs() {
+                    pending.peak = value;
+                }
+                pending.samples += 1;
+                if pending.samples < SETTLE_SAMPLES {
+                    continue;
+                }
+                if value > pending.before {
+                    StepKind::LoadAdded
+                } else {
+                    StepKind::LoadRemoved
+                }
+            };
+            steps.push(CurrentStep {
+                property,
+                kind,
+                before: pending.before,
+                after: value,
+                peak: pending.peak,
+                samples: pending.samples,
+            });
+            track.baseline = value;
+            track.pending = None;
+        }
+        steps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn current(amps: f64) -> Value {
+        json!({"current": {"value": amps}})
+    }
+
+    /// Steps detected over the readings, in order
+    fn steps(detector: &mut StepDetector, readings: &[f64]) -> Vec<CurrentStep> {
+        readings
+            .iter()
+            .flat_map(|amps| detector.check(&current(*amps)))
+            .collect()
+    }
+
+    #[test]
+    fn noise_under_the_threshold_is_no_step() {
+        let mut detector = StepDetector::new(DEFAULT_STEP_AMPS);
+        assert!(steps(&mut detector, &[1.0, 1.1, 0.9, 1.2, 1.0, 1.25, 0.95]).is_empty());
+    }
+
+    #[test]
+    fn a_lasting_rise_is_a_load_added() {
+        let mut detector = StepDetector::new(DEFAULT_STEP_AMPS);
+        let found = steps(&mut detector, &[1.0, 2.0, 2.2, 2.1]);
+        assert_eq!(found.len(), 1);
+        let step = &found[0];
+        assert_eq!(step.property, "current");
+        assert_eq!(step.kind, StepKind::LoadAdded);
+        assert_eq!(step.before, 1.0);
+        assert_eq!(step.after, 2.1);
+        assert_eq!(step.peak, 2.2);
+        assert_eq!(step.samples, SETTLE_SAMPLES);
+        // The new current is the baseline
+        assert!(steps(&mut detector, &[2.1, 2.0, 2.2]).is_empty());
+    }
+
+    #[test]
+    fn a_lasting_drop_is_a_load_removed() {
+        let mut detector = StepDetector::new(DEFAULT_STEP_AMPS);
+        let found = steps(&mut detector, &[3.0, 1.0, 1.0, 1.0]);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].kind, StepKind::LoadRemoved);
+        assert_eq!(found[0].before, 3.0);
+        assert_eq!(found[0].after, 1.0);
+    }
+
+    #[test]
+    fn a_spike_going_back_is_a_transient() {
+        let mut detector = StepDetector::new(DEFAULT_STEP_AMPS);
+        let found = steps(&mut detector, &[1.0, 4.0, 3.0, 1.1]);
+        assert_eq!(found.len(), 1);
+        let step = &found[0];
+        assert_eq!(step.kind, StepKind::Transient);
+        assert_eq!(step.before, 1.0);
+        assert_eq!(step.after, 1.1);
+        assert_eq!(step.peak, 4.0);
+        assert_eq!(step.samples, 2);
+        assert!(steps(&mut detector, &[1.0, 1.1]).is_empty());
+    }
+
+    #[test]
+    fn every_current_is_tracked_on_its_own() {
+        let mut detector = StepDetector::new(DEFAULT_STEP_AMPS);
+        let state = |total: f64, dew1: f64| json!({"total_current": {"value": total}, "dew1_current": {"value": dew1}});
+        let mut found = Vec::new();
+        for (total, dew1) in [(1.0, 0.0), (1.0, 2.0), (1.0, 2.0), (1.0, 2.0)] {
+            found.extend(detector.check(&state(total, dew1)));
+        }
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].property, "dew1_current");
+        // Readings without a value are skipped
+        assert!(detector
+            .check(&json!({"total_current": {"value": null}}))
+            .is_empty());
+    }
+}
@@ -0,0 +1,190 @@
+//! Line delimited JSON over TCP, for sequencers that can only script raw
+//! sockets (N.I.N.A. Advanced Sequencer, shell scripts with `nc`, ...).
+//!
+//! Every request is a JSON object on its own line, answered by a single line:
+//!
+//! ```text
+//! {"cmd": "set", "prop": "dew_a_power", "value": 120}
+//! {"ok": true}
+//! {"cmd": "get", "prop": "input_voltage"}
+//! {"ok": true, "value": 12.4}
+//! ```
+use crate::aliases::Aliases;
+use crate::snapshots::Snapshots;
+use crate::{setting_value, DeviceInfo, PegasusDriver, UpdateError, UpdatePropertyRequest};
+use log::{debug, error, info};
+use pegasus_astro::ramp::DewRamp;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::RwLock;
+use uuid::Uuid;
+
+#[derive(Clone)]
+pub struct TcpState {
+    pub driver: Arc<RwLock<PegasusDriver>>,
+    /// Answers the reads without waiting for the devices
+    pub snapshots: Arc<Snapshots>,
+    pub aliases: Arc<Mutex<Aliases>>,
+    pub ramp: DewRamp,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+enum Request {
+    /// Every device, as on `GET /devices`
+    List,
+    /// A property of a device, or its whole state without `prop`
+    Get {
+        #[serde(default)]
+        device: Option<String>,
+        #[serde(default)]
+        prop: Option<String>,
+    },
+    /// Update a property, answered once the device applied it
+    Set {
+        #[serde(default)]
+        device: Option<String>,
+        prop: String,
+        /// As accepted on `devices/{id}/update`, numbers and booleans are taken as is
+        value: Value,
+        #[serde(default)]
+        immediate: bool,
+    },
+}
+
+/// Error answered to a request, `{"ok": false, "error": ...}`
+fn failure(error: String) -> Value {
+    json!({ "ok": false, "error": error })
+}
+
+/// Id of the device given by id or alias, the only device if none is given
+fn resolve(state: &TcpState, device: Option<&str>) -> Result<Uuid, Value> {
+    let ids = state.snapshots.ids();
+    let Some(device) = device else {
+        return match ids[..] {
+            [id] => Ok(id),
+            _ => Err(failure(format!(
+                "{} devices are driven, which one is to be given",
+                ids.len()
+            ))),
+        };
+    };
+    let aliases = state.aliases.lock().unwrap();
+    ids.into_iter()
+        .find(|id| id.to_string() == device || aliases.get(id) == Some(device))
+        .ok_or_else(|| failure(format!("No device {}", device)))
+}
+
+async fn handle(state: &TcpState, request: Request) -> Value {
+    match request {
+        Request::List => {
+            let mut infos: Vec<DeviceInfo> = state.snapshots.devices().to_vec();
+            let aliases = state.aliases.lock().unwrap();
+            for info in infos.iter_mut() {
+                info.alias = aliases.get(&info.id).map(str::to_owned);
+            }
+            json!({ "ok": true, "devices": infos })
+        }
+        Request::Get { device, prop } => {
+            let id = match resolve(state, device.as_deref()) {
+                Ok(id) => id,
+                Err(e) => return e,
+            };
+            // The state of the last poll, the device may be busy with the next one
+            let Some(snapshot) = state.snapshots.state(&id) else {
+                return failure(format!("{} was not read yet", id));
+            };
+            let device_state = &snapshot.state;
+            match prop {
+                None => json!({ "ok": true, "id": id, "state": device_state }),
+                Some(prop) => match device_state.get(&prop) {
+                    Some(property) => json!({
+                        "ok": true,
+                        "id": id,
+                        "value": property.get("value").unwrap_or(property),
+                    }),
+                    None => failure(format!("No property {}", prop)),
+                },
+            }
+        }
+        Request::Set {
+            device,
+            prop,
+            value,
+            immediate,
+        } => {
+            let id = match resolve(state, device.as_deref()) {
+                Ok(id) => id,
+                Err(e) => return e,
+            };
+            let Some(device) = state.driver.read().await.find_any(&id) else {
+                return failure(format!("No device {}", id));
+            };
+            let request = UpdatePropertyRequest {
+                prop_name: prop,
+                value: setting_value(value),
+                immediate,
+            };
+            match device.update(&request, state.ramp).await {
+                Ok(()) => json!({ "ok": true, "id": id }),
+                Err(e) => {
+                    let error = UpdateError::new(&request, &e);
+                    json!({ "ok": false, "id": id, "kind": error.kind, "error": error.error })
+                }
+            }
+        }
+    }
+}
+
+async fn serve_client(state: TcpState, stream: TcpStream, peer: SocketAddr) {
+    debug!("TCP client {} connected", peer);
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                debug!("TCP client {} dropped: {}", peer, e);
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => handle(&state, request).await,
+            Err(e) => failure(format!("Invalid request: {}", e)),
+        };
+        let mut response = response.to_string();
+        response.push('\n');
+        if writer.write_all(response.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+    debug!("TCP client {} disconnected", peer);
+}
+
+pub async fn serve(addr: SocketAddr, state: TcpState) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Cannot listen for TCP clients on {}: {}", addr, e);
+            return;
+        }
+    };
+    info!("TCP JSON server listening on {}", addr);
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(serve_client(state.clone(), stream, peer));
+            }
+            Err(e) => error!("Cannot accept a TCP client: {}", e),
+        }
+    }
+}
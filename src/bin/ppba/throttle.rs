@@ -0,0 +1,197 @@
+use crate::UpdatePropertyRequest;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Default number of updates a device accepts per second
+pub const DEFAULT_RATE: f64 = 5.0;
+/// Default number of updates a device accepts at once before being limited
+pub const DEFAULT_BURST: u32 = 10;
+/// Default delay to wait for the next value of a dew heater slider
+pub const DEFAULT_DEBOUNCE_MS: u64 = 200;
+
+/// Updates left to a device, refilled at the rate of the [`Throttle`]
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Limits on the updates received on MQTT, a client flooding
+/// `devices/{id}/update` would otherwise saturate the serial link.
+///
+/// Every device gets its own token bucket: `burst` updates at once, then
+/// `rate` per second, the updates over the limit are dropped. Dew heater
+/// updates are debounced first, only the last value received while dragging a
+/// slider is sent.
+#[derive(Debug)]
+pub struct Throttle {
+    rate: f64,
+    burst: f64,
+    debounce: Duration,
+    buckets: Mutex<HashMap<Uuid, TokenBucket>>,
+    /// Latest dew heater update of every device waiting for the debounce delay
+    pending: Mutex<HashMap<(Uuid, String), UpdatePropertyRequest>>,
+    dropped: AtomicU64,
+    coalesced: AtomicU64,
+}
+
+impl Throttle {
+    /// A `rate` of 0 disables the rate limiting, a `debounce` of 0 the coalescing
+    pub fn new(rate: f64, burst: u32, debounce: Duration) -> Self {
+        Self {
+            rate,
+            burst: f64::from(burst.max(1)),
+            debounce,
+            buckets: Mutex::default(),
+            pending: Mutex::default(),
+            dropped: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+        }
+    }
+
+    /// Take a token from the bucket of `id`, false if the update must be dropped
+    pub fn allow(&self, id: Uuid) -> bool {
+        if self.rate <= 0.0 {
+            return true;
+        }
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.entry(id).or_insert(TokenBucket {
+            tokens: self.burst,
+            refilled: now,
+        });
+        let elapsed = now.duration_since(bucket.refilled).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.refilled = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            false
+        }
+    }
+
+    pub fn debounce(&self) -> Duration {
+        self.debounce
+    }
+
+    /// Dew heater PWM updates are coalesced, e.g. `dew1_power_pct` or `dew_power_2`
+    pub fn debounces(&self, prop_name: &str) -> bool {
+        !self.debounce.is_zero() && prop_name.starts_with("dew") && prop_name.contains("_power")
+    }
+
+    /// Keep `request` until the end of the debounce delay, replacing the one
+    /// pending for the same property. Returns true for the first request of a
+    /// burst, the caller then [takes](Self::take) the last one after the delay.
+    pub fn coalesce(&self, id: Uuid, request: UpdatePropertyRequest) -> bool {
+        let key = (id, request.prop_name.clone());
+        let replaced = self.pending.lock().unwrap().insert(key, request);
+        if replaced.is_some() {
+            self.coalesced.fetch_add(1, Ordering::Relaxed);
+        }
+        replaced.is_none()
+    }
+
+    /// Last update of `prop_name` received during the debounce delay
+    pub fn take(&self, id: Uuid, prop_name: &str) -> Option<UpdatePropertyRequest> {
+        self.pending
+            .lock()
+            .unwrap()
+            .remove(&(id, prop_name.to_owned()))
+    }
+
+    /// Updates dropped for going over the rate limit
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Dew heater updates replaced by a newer value before being sent
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(prop_name: &str, value: &str) -> UpdatePropertyRequest {
+        UpdatePropertyRequest {
+            prop_name: prop_name.to_string(),
+            value: value.to_string(),
+            immediate: false,
+        }
+    }
+
+    #[test]
+    fn a_burst_is_allowed_then_updates_are_dropped() {
+        let throttle = Throttle::new(1.0, 3, Duration::ZERO);
+        let id = Uuid::new_v4();
+        for _ in 0..3 {
+            assert!(throttle.allow(id));
+        }
+        assert!(!throttle.allow(id));
+        assert!(!throttle.allow(id));
+        assert_eq!(throttle.dropped(), 2);
+        // Every device has its own bucket
+        assert!(throttle.allow(Uuid::new_v4()));
+    }
+
+    #[test]
+    fn tokens_are_refilled_at_the_rate() {
+        let throttle = Throttle::new(20.0, 1, Duration::ZERO);
+        let id = Uuid::new_v4();
+        assert!(throttle.allow(id));
+        assert!(!throttle.allow(id));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(throttle.allow(id));
+        // Never more than the burst, however long the device was quiet
+        assert!(!throttle.allow(id));
+    }
+
+    #[test]
+    fn no_rate_disables_the_limit() {
+        let throttle = Throttle::new(0.0, 1, Duration::ZERO);
+        let id = Uuid::new_v4();
+        for _ in 0..100 {
+            assert!(throttle.allow(id));
+        }
+        assert_eq!(throttle.dropped(), 0);
+    }
+
+    #[test]
+    fn only_dew_heater_updates_are_debounced() {
+        let throttle = Throttle::new(DEFAULT_RATE, DEFAULT_BURST, Duration::from_millis(200));
+        assert!(throttle.debounces("dew1_power"));
+        assert!(throttle.debounces("dew1_power_pct"));
+        assert!(throttle.debounces("dew_power_2"));
+        assert!(!throttle.debounces("autodew"));
+        assert!(!throttle.debounces("quadport_status"));
+        let disabled = Throttle::new(DEFAULT_RATE, DEFAULT_BURST, Duration::ZERO);
+        assert!(!disabled.debounces("dew1_power"));
+    }
+
+    #[test]
+    fn the_last_value_of_a_burst_is_kept() {
+        let throttle = Throttle::new(DEFAULT_RATE, DEFAULT_BURST, Duration::from_millis(200));
+        let id = Uuid::new_v4();
+        assert!(throttle.coalesce(id, request("dew1_power", "10")));
+        assert!(!throttle.coalesce(id, request("dew1_power", "20")));
+        assert!(!throttle.coalesce(id, request("dew1_power", "30")));
+        // Other properties and devices have their own burst
+        assert!(throttle.coalesce(id, request("dew2_power", "40")));
+        assert!(throttle.coalesce(Uuid::new_v4(), request("dew1_power", "50")));
+        assert_eq!(throttle.coalesced(), 2);
+
+        assert_eq!(throttle.take(id, "dew1_power").unwrap().value, "30");
+        assert!(throttle.take(id, "dew1_power").is_none());
+        assert_eq!(throttle.take(id, "dew2_power").unwrap().value, "40");
+        // A new burst starts once taken
+        assert!(throttle.coalesce(id, request("dew1_power", "60")));
+    }
+}
@@ -0,0 +1,114 @@
+//! Topics of the devices.
+//!
+//! Every device has its topics under `devices/{id}`. With `--family-topics`
+//! they're under the namespace of its family instead, e.g. `upb/{id}/update`,
+//! so a consumer of a single family only subscribes to its own namespace.
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+/// Namespace of the topics of every device without `--family-topics`
+const SHARED: &str = "devices";
+
+static BY_FAMILY: AtomicBool = AtomicBool::new(false);
+/// Family of every device driven so far
+static FAMILIES: Mutex<BTreeMap<Uuid, &'static str>> = Mutex::new(BTreeMap::new());
+
+/// Put the topics of every device under the namespace of its family
+pub fn use_family_namespaces(on: bool) {
+    BY_FAMILY.store(on, Ordering::Relaxed);
+}
+
+/// Remember the family of a device, before anything is published for it
+pub fn register(id: Uuid, family: &'static str) {
+    FAMILIES.lock().unwrap().insert(id, family);
+}
+
+/// Root of the topics of the device `id`, e.g. `devices/{id}`
+pub fn device(id: &Uuid) -> String {
+    let family = FAMILIES.lock().unwrap().get(id).copied();
+    root(namespace(BY_FAMILY.load(Ordering::Relaxed), family), id)
+}
+
+/// Id and action of a `{namespace}/{id}/{action}` topic of a device driven,
+/// `None` for anything else
+pub fn parse(topic: &str) -> Option<(Uuid, &str)> {
+    let (namespace, id, action) = split(topic)?;
+    (root(namespace, &id) == device(&id)).then_some((id, action))
+}
+
+fn namespace(by_family: bool, family: Option<&'static str>) -> &'static str {
+    match family {
+        Some(family) if by_family => family,
+        _ => SHARED,
+    }
+}
+
+fn root(namespace: &str, id: &Uuid) -> String {
+    format!("{}/{}", namespace, id)
+}
+
+fn split(topic: &str) -> Option<(&str, Uuid, &str)> {
+    let (namespace, rest) = topic.split_once('/')?;
+    let (id, action) = rest.split_once('/')?;
+    Some((namespace, Uuid::parse_str(id).ok()?, action))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn namespaces_follow_the_layout() {
+        let id = Uuid::new_v4();
+        assert_eq!(
+            root(namespace(false, Some("upb")), &id),
+            format!("devices/{}", id)
+        );
+        assert_eq!(
+            root(namespace(true, Some("upb")), &id),
+            format!("upb/{}", id)
+        );
+        // Not driven, nothing to tell its family
+        assert_eq!(root(namespace(true, None), &id), format!("devices/{}", id));
+    }
+
+    #[test]
+    fn device_topics_are_split() {
+        let id = Uuid::new_v4();
+        assert_eq!(
+            split(&format!("devices/{}/history/get", id)),
+            Some(("devices", id, "history/get"))
+        );
+        assert_eq!(
+            split(&format!("ppbm/{}/update", id)),
+            Some(("ppbm", id, "update"))
+        );
+        assert_eq!(
+            split(&format!("devices/{}/", id)),
+            Some(("devices", id, ""))
+        );
+        for topic in [
+            "devices",
+            "devices/",
+            "devices/short",
+            &format!("devices/{}", id),
+            "devices/not-a-uuid-but-just-as-long-as-one-abc/update",
+            "dévices/ü",
+        ] {
+            assert_eq!(split(topic), None, "{}", topic);
+        }
+    }
+
+    #[test]
+    fn topics_of_unknown_devices_are_in_the_shared_namespace() {
+        let id = Uuid::new_v4();
+        assert_eq!(
+            parse(&format!("devices/{}/update", id)),
+            Some((id, "update"))
+        );
+        assert_eq!(parse(&format!("upb/{}/update", id)), None);
+        assert_eq!(parse(&format!("driver/{}/update", id)), None);
+    }
+}
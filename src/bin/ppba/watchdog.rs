@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Default seconds a polling task may be late before it is considered stalled
+pub const DEFAULT_STALL_TIMEOUT: u64 = 30;
+
+/// What to do with a stalled polling task
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Recovery {
+    /// Abort the request the device is serving, its port is reopened
+    Reset,
+    /// The reset didn't help, start the polling task again
+    Restart,
+}
+
+#[derive(Debug)]
+struct Deadline {
+    at: Instant,
+    /// Recoveries since the task last made progress
+    recoveries: u32,
+}
+
+/// Last sign of life of every polling task.
+///
+/// Some USB adapters never answer nor fail a read, the polling task then
+/// waits forever and the device silently stops updating. Every task feeds the
+/// watchdog when it makes progress with the time it needs until the next
+/// time, the ones later than that by more than the stall timeout are reset.
+#[derive(Debug)]
+pub struct Watchdog {
+    stall_after: Duration,
+    deadlines: Mutex<HashMap<Uuid, Deadline>>,
+}
+
+impl Watchdog {
+    pub fn new(stall_after: Duration) -> Self {
+        Self {
+            stall_after,
+            deadlines: Mutex::default(),
+        }
+    }
+
+    /// How often the deadlines are checked
+    pub fn period(&self) -> Duration {
+        (self.stall_after / 4).max(Duration::from_secs(1))
+    }
+
+    /// The task polling `id` is alive and makes progress again within `next_in`
+    pub fn feed(&self, id: Uuid, next_in: Duration) {
+        let deadline = Deadline {
+            at: Instant::now() + next_in + self.stall_after,
+            recoveries: 0,
+        };
+        self.deadlines.lock().unwrap().insert(id, deadline);
+    }
+
+    /// `id` is not polled anymore
+    pub fn forget(&self, id: &Uuid) {
+        self.deadlines.lock().unwrap().remove(id);
+    }
+
+    /// The tasks past their deadline, a reset is tried first and the task is
+    /// restarted if it's still stalled a stall timeout later
+    pub fn overdue(&self) -> Vec<(Uuid, Recovery)> {
+        let now = Instant::now();
+        let mut deadlines = self.deadlines.lock().unwrap();
+        deadlines
+            .iter_mut()
+            .filter(|(_, deadline)| deadline.at <= now)
+            .map(|(id, deadline)| {
+                let recovery = match deadline.recoveries {
+                    0 => Recovery::Reset,
+                    _ => Recovery::Restart,
+                };
+                deadline.at = now + self.stall_after;
+                deadline.recoveries += 1;
+                (*id, recovery)
+            })
+            .collect()
+    }
+}
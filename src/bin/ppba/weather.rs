@@ -0,0 +1,118 @@
+use pegasus_astro::dewpoint::Magnus;
+use serde::Deserialize;
+use serde_json::Value;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// External sensor publishing the ambient conditions, from the `[weather]`
+/// table of the config file
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WeatherConfig {
+    /// MQTT topic the sensor publishes a JSON object on
+    pub topic: String,
+    /// Key of the temperature in Celsius
+    #[serde(default = "default_temperature_key")]
+    pub temperature_key: String,
+    /// Key of the relative humidity in %
+    #[serde(default = "default_humidity_key")]
+    pub humidity_key: String,
+    /// Readings older than this are ignored, the onboard sensor is used again
+    #[serde(default = "default_max_age")]
+    pub max_age_s: u64,
+}
+
+fn default_temperature_key() -> String {
+    "temperature".to_string()
+}
+
+fn default_humidity_key() -> String {
+    "humidity".to_string()
+}
+
+fn default_max_age() -> u64 {
+    300
+}
+
+impl WeatherConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.topic.is_empty() || self.topic.contains(['+', '#']) {
+            return Err(format!("Invalid weather topic: {:?}", self.topic));
+        }
+        if self.max_age_s == 0 {
+            return Err("Weather max_age_s must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Ambient conditions reported by the external sensor
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Conditions {
+    pub temperature: f32,
+    pub humidity: f32,
+    pub dew_point: f32,
+}
+
+impl Conditions {
+    pub fn new(temperature: f32, humidity: f32, formula: &Magnus) -> Self {
+        Self {
+            temperature,
+            humidity,
+            dew_point: formula.dew_point(temperature, humidity),
+        }
+    }
+}
+
+/// Last conditions received on the weather topic, shared with the dew rules
+#[derive(Clone)]
+pub struct Weather {
+    config: WeatherConfig,
+    /// Same dew point as the one computed by the devices
+    formula: Magnus,
+    last: Arc<Mutex<Option<(Instant, Conditions)>>>,
+}
+
+impl Weather {
+    pub fn new(config: WeatherConfig, formula: Magnus) -> Self {
+        Self {
+            config,
+            formula,
+            last: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn topic(&self) -> &str {
+        &self.config.topic
+    }
+
+    /// Take the conditions published by the sensor
+    pub fn update(&self, payload: &[u8]) -> Result<Conditions, String> {
+        let payload: Value = serde_json::from_slice(payload).map_err(|e| e.to_string())?;
+        let reading = |key: &str| {
+            payload
+                .get(key)
+                .and_then(Value::as_f64)
+                .map(|v| v as f32)
+                .ok_or_else(|| format!("No numeric {} in the weather payload", key))
+        };
+        let temperature = reading(&self.config.temperature_key)?;
+        let humidity = reading(&self.config.humidity_key)?;
+        if !(0.0..=100.0).contains(&humidity) {
+            return Err(format!("Humidity out of range: {}", humidity));
+        }
+        let conditions = Conditions::new(temperature, humidity, &self.formula);
+        *self.last.lock().unwrap() = Some((Instant::now(), conditions));
+        Ok(conditions)
+    }
+
+    /// Last conditions, `None` when nothing was received for `max_age_s`
+    pub fn current(&self) -> Option<Conditions> {
+        let max_age = Duration::from_secs(self.config.max_age_s);
+        self.last
+            .lock()
+            .unwrap()
+            .filter(|(at, _)| at.elapsed() <= max_age)
+            .map(|(_, conditions)| conditions)
+    }
+}
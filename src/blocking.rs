@@ -0,0 +1,226 @@
+//! Blocking facade of the PPBA, for scripts that don't want to deal with an
+//! async runtime:
+//!
+//! ```no_run
+//! # fn run() -> Result<(), pegasus_astro::error::PegasusError> {
+//! use pegasus_astro::blocking::Ppba;
+//!
+//! let ppba = Ppba::open_first()?;
+//! ppba.set_dew_a_percent(50.0)?;
+//! println!("{}V", ppba.refresh()?.input_voltage);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Every call runs the matching method of [`PegasusPowerBox`] to completion
+//! on a runtime owned by the [`Ppba`], so it must not be used from async
+//! code, use the device directly there.
+use crate::device::AstronomicalDevice;
+use crate::discovery::{self, DeviceFamily};
+use crate::error::PegasusError;
+use crate::ppba::{
+    AdjustableVoltage, BootPowerConfig, DewChannel, PegasusPowerBox, PowerBoxSnapshot, Profile,
+};
+use crate::transport::{self, SerialConfig, SerialTransport};
+use tokio::runtime::{Builder, Runtime};
+use tokio::sync::Mutex;
+
+/// A PPBA driven with blocking calls
+#[derive(Debug)]
+pub struct Ppba {
+    runtime: Runtime,
+    device: Mutex<PegasusPowerBox>,
+}
+
+impl Ppba {
+    /// Connect to the first PPBA plugged, by port name
+    pub fn open_first() -> Result<Self, PegasusError> {
+        let mut found = discovery::find(DeviceFamily::Ppba)?;
+        found.sort_by(|a, b| a.port.cmp(&b.port));
+        let found = found.into_iter().next().ok_or(PegasusError::NotConnected)?;
+        let ppba = Self::open(&found.port)?;
+        if let Some(serial) = &found.serial_number {
+            ppba.runtime
+                .block_on(ppba.device.lock())
+                .set_serial_number(serial);
+        }
+        Ok(ppba)
+    }
+
+    /// Connect to the PPBA on `address`, a serial port or a `tcp://` or
+    /// `rfc2217://` bridge, see [`transport::open`]
+    pub fn open(address: &str) -> Result<Self, PegasusError> {
+        Self::open_with_config(address, &SerialConfig::default())
+    }
+
+    /// Same as [`open`](Self::open) with every setting of the serial link
+    pub fn open_with_config(address: &str, config: &SerialConfig) -> Result<Self, PegasusError> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let device = runtime.block_on(PegasusPowerBox::try_with_config(
+            "PegausPowerBoxAdvanced",
+            address,
+            config,
+        ))?;
+        Ok(Self {
+            runtime,
+            device: Mutex::new(device),
+        })
+    }
+
+    /// Connect through an already open transport, e.g. a
+    /// [`FakePpbaPort`](crate::sim::FakePpbaPort)
+    pub fn with_port(address: &str, port: Box<dyn SerialTransport>) -> Result<Self, PegasusError> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let device = runtime.block_on(PegasusPowerBox::new_with_port(
+            "PegausPowerBoxAdvanced",
+            address,
+            transport::DEFAULT_BAUD,
+            port,
+        ))?;
+        Ok(Self {
+            runtime,
+            device: Mutex::new(device),
+        })
+    }
+
+    /// Give the async device back, e.g. to move a script to async code
+    pub fn into_inner(self) -> PegasusPowerBox {
+        self.device.into_inner()
+    }
+
+    /// Readings and settings as of the last [`refresh`](Self::refresh)
+    pub fn snapshot(&self) -> PowerBoxSnapshot {
+        self.runtime
+            .block_on(async { self.device.lock().await.snapshot() })
+    }
+
+    /// Read every reading and setting from the device
+    pub fn refresh(&self) -> Result<PowerBoxSnapshot, PegasusError> {
+        self.runtime.block_on(async {
+            let mut device = self.device.lock().await;
+            device.fetch_props().await;
+            if !device.is_connected() {
+                return Err(PegasusError::NotConnected);
+            }
+            Ok(device.snapshot())
+        })
+    }
+
+    /// Every property as published by the MQTT driver on `devices/{id}`, as of
+    /// the last [`refresh`](Self::refresh)
+    pub fn state(&self) -> serde_json::Value {
+        self.runtime
+            .block_on(async { serde_json::to_value(&*self.device.lock().await) })
+            .unwrap_or_default()
+    }
+
+    pub fn firmware_version(&self) -> Result<String, PegasusError> {
+        self.runtime
+            .block_on(async { self.device.lock().await.read_firmware_version().await })
+    }
+
+    /// PWM (0-255) of dew heater A
+    pub fn set_dew_a(&self, pwm: u8) -> Result<(), PegasusError> {
+        self.set_dew(DewChannel::A, pwm)
+    }
+
+    /// PWM (0-255) of dew heater B
+    pub fn set_dew_b(&self, pwm: u8) -> Result<(), PegasusError> {
+        self.set_dew(DewChannel::B, pwm)
+    }
+
+    pub fn set_dew_a_percent(&self, pct: f32) -> Result<(), PegasusError> {
+        self.set_dew_percent(DewChannel::A, pct)
+    }
+
+    pub fn set_dew_b_percent(&self, pct: f32) -> Result<(), PegasusError> {
+        self.set_dew_percent(DewChannel::B, pct)
+    }
+
+    pub fn set_dew(&self, channel: DewChannel, pwm: u8) -> Result<(), PegasusError> {
+        self.runtime
+            .block_on(async { self.device.lock().await.set_dew_power(channel, pwm).await })
+    }
+
+    pub fn set_dew_percent(&self, channel: DewChannel, pct: f32) -> Result<(), PegasusError> {
+        self.runtime.block_on(async {
+            self.device
+                .lock()
+                .await
+                .set_dew_power_percent(channel, pct)
+                .await
+        })
+    }
+
+    /// Spread the dew heater changes over `ms` milliseconds, 0 applies them at once
+    pub fn set_dew_ramp(&self, ms: u64) -> Result<(), PegasusError> {
+        self.runtime
+            .block_on(async { self.device.lock().await.set_dew_ramp(ms) })
+    }
+
+    pub fn set_autodew(&self, on: bool) -> Result<(), PegasusError> {
+        self.runtime
+            .block_on(async { self.device.lock().await.set_autodew(on).await })
+    }
+
+    /// Switch the quad 12V output
+    pub fn set_quadport(&self, on: bool) -> Result<(), PegasusError> {
+        self.runtime
+            .block_on(async { self.device.lock().await.set_quadport(on).await })
+    }
+
+    /// Switch the adjustable output, keeping its voltage
+    pub fn set_adj_output(&self, on: bool) -> Result<(), PegasusError> {
+        self.runtime
+            .block_on(async { self.device.lock().await.set_adj_output_status(on).await })
+    }
+
+    pub fn set_adj_voltage(&self, voltage: AdjustableVoltage) -> Result<(), PegasusError> {
+        self.runtime.block_on(async {
+            self.device
+                .lock()
+                .await
+                .set_adjustable_voltage(voltage)
+                .await
+        })
+    }
+
+    pub fn set_led(&self, on: bool) -> Result<(), PegasusError> {
+        self.runtime
+            .block_on(async { self.device.lock().await.set_led(on).await })
+    }
+
+    /// 12V outputs switched on when the device powers up
+    pub fn power_on_boot(&self) -> Result<BootPowerConfig, PegasusError> {
+        self.runtime
+            .block_on(async { self.device.lock().await.read_power_on_boot().await })
+    }
+
+    pub fn set_power_on_boot(&self, config: BootPowerConfig) -> Result<(), PegasusError> {
+        self.runtime
+            .block_on(async { self.device.lock().await.set_power_on_boot(config).await })
+    }
+
+    /// Settings of the device as a profile, to apply it again later
+    pub fn capture_profile(&self) -> Profile {
+        self.runtime
+            .block_on(async { self.device.lock().await.capture_profile() })
+    }
+
+    pub fn apply_profile(&self, profile: &Profile) -> Result<(), PegasusError> {
+        self.runtime
+            .block_on(async { self.device.lock().await.apply_profile(profile).await })
+    }
+
+    /// Any property of the MQTT driver, e.g. `("dew1_power_pct", "60")`
+    pub fn set_property(&self, name: &str, value: &str) -> Result<(), PegasusError> {
+        self.runtime
+            .block_on(async { self.device.lock().await.update_property(name, value).await })
+    }
+
+    /// Reboot the device, it's unreachable until it's back up
+    pub fn reboot(&self) -> Result<(), PegasusError> {
+        self.runtime
+            .block_on(async { self.device.lock().await.reboot().await })
+    }
+}
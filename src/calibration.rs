@@ -0,0 +1,76 @@
+//! Offsets correcting the temperature and humidity sensor of the powerboxes.
+//!
+//! The onboard sensor often reads a degree or two off. The offsets are added
+//! to every reading and the dew point is computed again from the corrected
+//! readings, the one of the device is only kept while there is no offset.
+use crate::dewpoint;
+use crate::error::PegasusError;
+use serde::{Deserialize, Serialize};
+
+/// Largest temperature offset accepted, in °C
+pub const MAX_TEMPERATURE_OFFSET: f32 = 10.0;
+/// Largest humidity offset accepted, in %
+pub const MAX_HUMIDITY_OFFSET: f32 = 30.0;
+
+/// Offsets of the sensor of a device, `temperature_offset` and
+/// `humidity_offset` of its properties
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SensorCalibration {
+    /// Added to the temperature, in °C
+    pub temperature: f32,
+    /// Added to the relative humidity, in %
+    pub humidity: f32,
+}
+
+impl SensorCalibration {
+    pub fn validate(&self) -> Result<(), PegasusError> {
+        let check = |name: &str, offset: f32, max: f32| {
+            if offset.is_finite() && offset.abs() <= max {
+                Ok(())
+            } else {
+                Err(PegasusError::Validation(format!(
+                    "Invalid {} offset {}, at most {} either way",
+                    name, offset, max
+                )))
+            }
+        };
+        check("temperature", self.temperature, MAX_TEMPERATURE_OFFSET)?;
+        check("humidity", self.humidity, MAX_HUMIDITY_OFFSET)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.temperature == 0.0 && self.humidity == 0.0
+    }
+
+    /// Same calibration with the offset of `prop_name` changed, `None` if it's
+    /// not `temperature_offset` nor `humidity_offset`
+    pub fn with(&self, prop_name: &str, offset: f32) -> Option<Self> {
+        let mut calibration = *self;
+        match prop_name {
+            "temperature_offset" => calibration.temperature = offset,
+            "humidity_offset" => calibration.humidity = offset,
+            _ => return None,
+        }
+        Some(calibration)
+    }
+
+    pub fn temperature(&self, raw: f32) -> f32 {
+        raw + self.temperature
+    }
+
+    /// Corrected humidity, kept within 0-100 %
+    pub fn humidity(&self, raw: f32) -> f32 {
+        (raw + self.humidity).clamp(0.0, 100.0)
+    }
+
+    /// Dew point of the corrected `temperature` and `humidity`, the one the
+    /// device computed if there is no offset
+    pub fn dew_point(&self, raw: f32, temperature: f32, humidity: f32) -> f32 {
+        if self.is_zero() {
+            raw
+        } else {
+            dewpoint::dew_point(temperature, humidity)
+        }
+    }
+}
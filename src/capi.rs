@@ -0,0 +1,210 @@
+//! C API, so C and C++ driver frameworks (ASCOM, INDIGO, ...) can reuse the
+//! protocol implementation.
+//!
+//! Built with the `capi` feature, which also writes the matching header to
+//! `include/pegasus_astro.h`. The library is then available as a shared
+//! (`cdylib`) and a static library. A PPBA is opened with
+//! [`pegasus_ppba_open`] and must be released with [`pegasus_ppba_close`];
+//! strings returned by the library must be released with
+//! [`pegasus_string_free`]. Calls on the same device must not run
+//! concurrently, distinct devices can be used from distinct threads.
+//!
+//! Failing functions return a negative [`PegasusStatus`] or `NULL`, the
+//! message of the last error of the calling thread is returned by
+//! [`pegasus_last_error`].
+use crate::blocking::Ppba;
+use crate::error::PegasusError;
+use std::cell::RefCell;
+use std::ffi::{c_char, CStr, CString};
+use std::ptr;
+
+/// Outcome of a call, 0 on success
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PegasusStatus {
+    Ok = 0,
+    /// A `NULL` pointer or a string that isn't UTF-8
+    InvalidArgument = -1,
+    Serial = -2,
+    Parse = -3,
+    Protocol = -4,
+    Validation = -5,
+    NotConnected = -6,
+    Unsupported = -7,
+    PermissionDenied = -8,
+    ReadOnly = -9,
+    Broker = -10,
+    /// The port is used by another program
+    DeviceBusy = -11,
+}
+
+impl From<&PegasusError> for PegasusStatus {
+    fn from(e: &PegasusError) -> Self {
+        match e {
+            PegasusError::Serial(_) => Self::Serial,
+            PegasusError::Parse(_) => Self::Parse,
+            PegasusError::Protocol(_) => Self::Protocol,
+            PegasusError::Validation(_) => Self::Validation,
+            PegasusError::NotConnected => Self::NotConnected,
+            PegasusError::Unsupported(_) => Self::Unsupported,
+            PegasusError::PermissionDenied(_) => Self::PermissionDenied,
+            PegasusError::ReadOnly(_) => Self::ReadOnly,
+            PegasusError::Broker(_) => Self::Broker,
+            PegasusError::DeviceBusy(_) => Self::DeviceBusy,
+        }
+    }
+}
+
+/// A PPBA opened with [`pegasus_ppba_open`], opaque to C
+pub struct PegasusPpba(Ppba);
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+fn set_last_error(message: &str) {
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+}
+
+fn fail(e: &PegasusError) -> PegasusStatus {
+    set_last_error(&e.to_string());
+    PegasusStatus::from(e)
+}
+
+fn invalid_argument(name: &str) -> PegasusStatus {
+    set_last_error(&format!("Invalid {}", name));
+    PegasusStatus::InvalidArgument
+}
+
+/// Read a C string, `None` for `NULL` or invalid UTF-8
+///
+/// # Safety
+///
+/// `s` is `NULL` or a valid NUL terminated string.
+unsafe fn to_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    CStr::from_ptr(s).to_str().ok()
+}
+
+/// Version of the library, static, not to be freed
+#[no_mangle]
+pub extern "C" fn pegasus_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr().cast()
+}
+
+/// Message of the last error of the calling thread, empty if none. Valid
+/// until the next failing call of the thread, not to be freed.
+#[no_mangle]
+pub extern "C" fn pegasus_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// Connect to the PPBA on `address` (a serial port, `tcp://host:port` or
+/// `rfc2217://host:port`), to the first one plugged if `address` is `NULL`.
+/// Returns `NULL` on failure.
+///
+/// # Safety
+///
+/// `address` is `NULL` or a valid NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn pegasus_ppba_open(address: *const c_char) -> *mut PegasusPpba {
+    let ppba = if address.is_null() {
+        Ppba::open_first()
+    } else {
+        match to_str(address) {
+            Some(address) => Ppba::open(address),
+            None => {
+                invalid_argument("address");
+                return ptr::null_mut();
+            }
+        }
+    };
+    match ppba {
+        Ok(ppba) => Box::into_raw(Box::new(PegasusPpba(ppba))),
+        Err(e) => {
+            fail(&e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Disconnect from the PPBA and release it, `NULL` is ignored
+///
+/// # Safety
+///
+/// `ppba` is `NULL` or was returned by [`pegasus_ppba_open`] and not closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn pegasus_ppba_close(ppba: *mut PegasusPpba) {
+    if !ppba.is_null() {
+        drop(Box::from_raw(ppba));
+    }
+}
+
+/// Read every property from the device, returns them as a JSON object, the
+/// same as published by the MQTT driver on `devices/{id}`, or `NULL` on
+/// failure. The string must be freed with [`pegasus_string_free`].
+///
+/// # Safety
+///
+/// `ppba` was returned by [`pegasus_ppba_open`] and not closed yet.
+#[no_mangle]
+pub unsafe extern "C" fn pegasus_ppba_state_json(ppba: *mut PegasusPpba) -> *mut c_char {
+    let Some(PegasusPpba(ppba)) = ppba.as_ref() else {
+        invalid_argument("device");
+        return ptr::null_mut();
+    };
+    if let Err(e) = ppba.refresh() {
+        fail(&e);
+        return ptr::null_mut();
+    }
+    match CString::new(ppba.state().to_string()) {
+        Ok(json) => json.into_raw(),
+        Err(e) => {
+            set_last_error(&e.to_string());
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Change a property, with the names and values of the MQTT driver, e.g.
+/// `dew1_power_pct` and `60`
+///
+/// # Safety
+///
+/// `ppba` was returned by [`pegasus_ppba_open`] and not closed yet, `name`
+/// and `value` are valid NUL terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn pegasus_ppba_set_property(
+    ppba: *mut PegasusPpba,
+    name: *const c_char,
+    value: *const c_char,
+) -> PegasusStatus {
+    let Some(PegasusPpba(ppba)) = ppba.as_ref() else {
+        return invalid_argument("device");
+    };
+    let Some(name) = to_str(name) else {
+        return invalid_argument("property name");
+    };
+    let Some(value) = to_str(value) else {
+        return invalid_argument("property value");
+    };
+    match ppba.set_property(name, value) {
+        Ok(()) => PegasusStatus::Ok,
+        Err(e) => fail(&e),
+    }
+}
+
+/// Release a string returned by the library, `NULL` is ignored
+///
+/// # Safety
+///
+/// `s` is `NULL` or was returned by the library and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn pegasus_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
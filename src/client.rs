@@ -0,0 +1,253 @@
+//! Async client of the MQTT driver (`ppba`), for applications driving the
+//! devices from another process, e.g. a GUI or an automation script.
+//!
+//! It hides the topics and the JSON payloads of the driver:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), pegasus_astro::error::PegasusError> {
+//! use pegasus_astro::client::PegasusClient;
+//! use pegasus_astro::ppba::DewChannel;
+//!
+//! let client = PegasusClient::connect("127.0.0.1", 1883).await?;
+//! let mut events = client.watch();
+//! for device in client.list_devices() {
+//!     client.set_dew(device.id, DewChannel::A, 60).await?;
+//! }
+//! while let Ok(event) = events.recv().await {
+//!     println!("{} {} = {}", event.id, event.name, event.value);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use crate::error::PegasusError;
+use crate::ppba::DewChannel;
+use log::{debug, error, warn};
+use rumqttc::{AsyncClient, ClientError, Event, EventLoop, MqttOptions, Packet, QoS};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+/// Events buffered for every [`PegasusClient::watch`] receiver before the
+/// slowest ones start lagging
+const EVENTS_CAPACITY: usize = 256;
+
+/// Last known state of a device, as published by the driver
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceState {
+    pub id: Uuid,
+    /// Every property, `{"value": ..., "permission": ...}`
+    pub properties: Map<String, Value>,
+}
+
+impl DeviceState {
+    /// Value of a property, e.g. `state.value("input_voltage")`
+    pub fn value(&self, name: &str) -> Option<&Value> {
+        self.properties.get(name).and_then(|p| p.get("value"))
+    }
+}
+
+/// A property of a device that changed
+#[derive(Clone, Debug, PartialEq)]
+pub struct PropertyEvent {
+    pub id: Uuid,
+    pub name: String,
+    pub value: Value,
+}
+
+type Devices = Arc<Mutex<BTreeMap<Uuid, DeviceState>>>;
+
+/// Connection to the broker the driver publishes to
+pub struct PegasusClient {
+    client: AsyncClient,
+    devices: Devices,
+    events: broadcast::Sender<PropertyEvent>,
+    task: JoinHandle<()>,
+}
+
+impl From<ClientError> for PegasusError {
+    fn from(e: ClientError) -> Self {
+        Self::Broker(e.to_string())
+    }
+}
+
+impl PegasusClient {
+    /// Connect to the MQTT broker on `host:port`, the devices are known once
+    /// the driver snapshots, retained by the broker, are received.
+    pub async fn connect(host: &str, port: u16) -> Result<Self, PegasusError> {
+        let client_id = format!("pegasus_client_{}", Uuid::new_v4().simple());
+        let mut options = MqttOptions::new(client_id, host, port);
+        options.set_keep_alive(Duration::from_secs(5));
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => break,
+                Ok(_) => {}
+                Err(e) => return Err(PegasusError::Broker(e.to_string())),
+            }
+        }
+        subscribe(&client).await?;
+
+        let devices = Devices::default();
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        let task = tokio::spawn(run(
+            eventloop,
+            client.clone(),
+            Arc::clone(&devices),
+            events.clone(),
+        ));
+
+        Ok(Self {
+            client,
+            devices,
+            events,
+            task,
+        })
+    }
+
+    /// Every device the driver published, sorted by id
+    pub fn list_devices(&self) -> Vec<DeviceState> {
+        self.devices.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn device(&self, id: Uuid) -> Option<DeviceState> {
+        self.devices.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Change a property of a device, `value` as accepted on `devices/{id}/update`
+    pub async fn set_property(
+        &self,
+        id: Uuid,
+        name: &str,
+        value: &str,
+    ) -> Result<(), PegasusError> {
+        let payload = json!({ "prop_name": name, "value": value });
+        self.client
+            .publish(
+                format!("devices/{}/update", id),
+                QoS::AtLeastOnce,
+                false,
+                payload.to_string(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    /// Power a dew heater of a PPBA, from 0 to 100 %
+    pub async fn set_dew(
+        &self,
+        id: Uuid,
+        channel: DewChannel,
+        pct: u8,
+    ) -> Result<(), PegasusError> {
+        if pct > 100 {
+            return Err(PegasusError::Validation(format!(
+                "Dew heater power must be between 0 and 100 %: {}",
+                pct
+            )));
+        }
+        let name = match channel {
+            DewChannel::A => "dew1_power_pct",
+            DewChannel::B => "dew2_power_pct",
+        };
+        self.set_property(id, name, &pct.to_string()).await
+    }
+
+    /// Every property change from now on, of every device
+    pub fn watch(&self) -> broadcast::Receiver<PropertyEvent> {
+        self.events.subscribe()
+    }
+
+    pub async fn disconnect(self) -> Result<(), PegasusError> {
+        self.client.disconnect().await?;
+        Ok(())
+    }
+}
+
+impl Drop for PegasusClient {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn subscribe(client: &AsyncClient) -> Result<(), ClientError> {
+    for topic in ["devices/+", "devices/+/properties/+", "devices/+/delete"] {
+        client.subscribe(topic, QoS::AtLeastOnce).await?;
+    }
+    Ok(())
+}
+
+/// Keep the devices up to date with the messages of the driver
+async fn run(
+    mut eventloop: EventLoop,
+    client: AsyncClient,
+    devices: Devices,
+    events: broadcast::Sender<PropertyEvent>,
+) {
+    loop {
+        match eventloop.poll().await {
+            // Subscriptions are lost when the broker drops the session
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                if let Err(e) = subscribe(&client).await {
+                    error!("Cannot subscribe to the driver topics: {}", e);
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(p))) => {
+                let Ok(payload) = serde_json::from_slice::<Value>(&p.payload) else {
+                    debug!("Ignoring message on {}", p.topic);
+                    continue;
+                };
+                handle_message(&p.topic, payload, &devices, &events);
+            }
+            Ok(_) => {}
+            Err(e) => {
+                warn!("Connection to the broker lost: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    }
+}
+
+fn handle_message(
+    topic: &str,
+    payload: Value,
+    devices: &Mutex<BTreeMap<Uuid, DeviceState>>,
+    events: &broadcast::Sender<PropertyEvent>,
+) {
+    let mut levels = topic.split('/').skip(1);
+    let Some(Ok(id)) = levels.next().map(Uuid::parse_str) else {
+        return;
+    };
+    let mut devices = devices.lock().unwrap();
+
+    match (levels.next(), levels.next()) {
+        (None, _) => {
+            if let Value::Object(mut properties) = payload {
+                // Envelope of the payload, not properties
+                properties.remove("schema_version");
+                properties.remove("driver_version");
+                devices.insert(id, DeviceState { id, properties });
+            }
+        }
+        (Some("delete"), _) => {
+            devices.remove(&id);
+        }
+        (Some("properties"), Some(name)) => {
+            let value = payload.get("value").cloned().unwrap_or(payload.clone());
+            if let Some(device) = devices.get_mut(&id) {
+                device.properties.insert(name.to_owned(), payload);
+            }
+            // Nobody watching is fine
+            let _ = events.send(PropertyEvent {
+                id,
+                name: name.to_owned(),
+                value,
+            });
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,160 @@
+//! Frames of the line based protocol spoken by every Pegasus device.
+//!
+//! A command is an ASCII code, e.g. `P3:` for the DewA power of a PPBA,
+//! followed by an optional value and a newline. The devices answer with a
+//! single line, SET commands echo their code and the value applied, most
+//! queries start their answer with a known prefix, e.g. `PS:` for `PS`.
+use crate::error::PegasusError;
+use std::fmt;
+
+/// Value sent after the code of a SET command
+#[derive(Clone, Debug, PartialEq)]
+pub enum Payload {
+    /// On/off switches, sent as `1` or `0`
+    Flag(bool),
+    /// PWM duty cycle from 0 to 255, always sent on 3 digits
+    Pwm(u8),
+    /// Positions, steps, volts, ...
+    Number(i64),
+    /// Anything else, sent as is, e.g. the power on boot mask `1011`
+    Text(String),
+}
+
+impl fmt::Display for Payload {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Flag(on) => write!(f, "{}", u8::from(*on)),
+            Self::Pwm(pwm) => write!(f, "{:03}", pwm),
+            Self::Number(n) => write!(f, "{}", n),
+            Self::Text(text) => f.write_str(text),
+        }
+    }
+}
+
+/// A command and its value, displayed as sent without the newline (`P3:128`)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Command {
+    code: u32,
+    payload: Option<Payload>,
+    /// Start of the response of a query, SET commands echo their code
+    prefix: Option<&'static str>,
+}
+
+impl Command {
+    /// `code` holds the ASCII characters of the command, e.g. `0x50333a` for `P3:`
+    pub fn new(code: u32, payload: Option<Payload>) -> Self {
+        Self {
+            code,
+            payload,
+            prefix: None,
+        }
+    }
+
+    /// Only accept responses starting with `prefix`, e.g. `PPBA:` for `PA`
+    pub fn expecting(mut self, prefix: Option<&'static str>) -> Self {
+        self.prefix = prefix;
+        self
+    }
+
+    /// The command without its value, e.g. `P3:`
+    pub fn code(&self) -> String {
+        String::from_utf8_lossy(&self.code_bytes()).into_owned()
+    }
+
+    pub fn payload(&self) -> Option<&Payload> {
+        self.payload.as_ref()
+    }
+
+    /// SET commands have a `:` between the code and the value
+    pub fn is_set(&self) -> bool {
+        self.code_bytes().ends_with(b":")
+    }
+
+    fn code_bytes(&self) -> Vec<u8> {
+        self.code
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|&b| b == 0)
+            .collect()
+    }
+
+    /// Whether `response` can be the answer to this command and not a late
+    /// answer to another one. Errors are always taken, they don't tell which
+    /// command they refuse.
+    pub fn answers(&self, response: &str) -> bool {
+        if response.split(':').nth(1) == Some("ERR") {
+            return true;
+        }
+        match self.prefix {
+            Some(prefix) => response.starts_with(prefix),
+            None => !self.is_set() || response.starts_with(&self.code()),
+        }
+    }
+
+    /// The frame written on the serial link, `P3:128\n`
+    ///
+    /// Values are sent as printable ASCII, a [`Payload::Text`] with anything
+    /// else, e.g. a newline ending the frame early, is rejected.
+    pub fn encode(&self) -> Result<Vec<u8>, PegasusError> {
+        let mut frame = self.code_bytes();
+        if let Some(payload) = &self.payload {
+            let value = payload.to_string();
+            if !value.bytes().all(|b| b.is_ascii_graphic()) {
+                return Err(PegasusError::Validation(format!(
+                    "Cannot send {:?} to the device, only printable ASCII is allowed",
+                    value
+                )));
+            }
+            frame.extend(value.bytes());
+        }
+        frame.push(b'\n');
+        Ok(frame)
+    }
+
+    /// Check the line read back answers this command, returns it without the
+    /// line ending.
+    ///
+    /// A response ending with `:ERR` is the device refusing the command, one
+    /// not [answering](Self::answers) it is meant for another command.
+    pub fn decode(&self, line: &[u8]) -> Result<String, PegasusError> {
+        let response = std::str::from_utf8(line)
+            .map_err(|_| PegasusError::Parse(String::from_utf8_lossy(line).into_owned()))?
+            .trim_end_matches(['\r', '\n']);
+
+        if response.split(':').nth(1) == Some("ERR") {
+            return Err(PegasusError::Protocol(response.to_owned()));
+        }
+        if !self.answers(response) {
+            return Err(PegasusError::Parse(format!(
+                "{} doesn't answer {}",
+                response, self
+            )));
+        }
+        Ok(response.to_owned())
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.code())?;
+        match &self.payload {
+            Some(payload) => write!(f, "{}", payload),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Split a frame read by a device into the code of the command and its
+/// value, `P3:128\n` gives `("P3:", Some("128"))`. The reverse of
+/// [`Command::encode`].
+pub fn split_frame(frame: &[u8]) -> Result<(&str, Option<&str>), PegasusError> {
+    let line = std::str::from_utf8(frame)
+        .ok()
+        .filter(|line| line.is_ascii())
+        .ok_or_else(|| PegasusError::Parse(String::from_utf8_lossy(frame).into_owned()))?
+        .trim_end_matches(['\r', '\n']);
+    Ok(match line.find(':') {
+        Some(idx) if idx + 1 < line.len() => (&line[..=idx], Some(&line[idx + 1..])),
+        _ => (line, None),
+    })
+}
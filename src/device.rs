@@ -0,0 +1,237 @@
+use crate::codec::Command;
+use crate::error::PegasusError;
+use crate::limits::CurrentTrip;
+use async_trait::async_trait;
+use log::info;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+/// Default pause between two polls of a device, in milliseconds
+pub const DEFAULT_POLLING_INTERVAL_MS: u64 = 500;
+/// Shortest polling interval accepted, polling faster floods the serial link
+pub const MIN_POLLING_INTERVAL_MS: u64 = 250;
+
+/// Reject polling intervals (in milliseconds) below the minimum
+pub fn check_polling_interval(ms: u64) -> Result<u64, PegasusError> {
+    if ms < MIN_POLLING_INTERVAL_MS {
+        return Err(PegasusError::Validation(format!(
+            "Polling interval {}ms is below the minimum of {}ms",
+            ms, MIN_POLLING_INTERVAL_MS
+        )));
+    }
+    Ok(ms)
+}
+
+/// Parse a polling interval in milliseconds, see [`check_polling_interval`]
+pub fn parse_polling_interval(val: &str) -> Result<u64, PegasusError> {
+    val.parse()
+        .map_err(|_| {
+            PegasusError::Validation(format!("Invalid value for polling_interval: {}", val))
+        })
+        .and_then(check_polling_interval)
+}
+
+/// Log a command not sent to a device in read-only mode, returns the error
+/// telling the caller it was valid but not applied.
+pub fn not_sent(name: &str, command: &Command) -> PegasusError {
+    info!("Read-only mode, {} not sent to {}", command, name);
+    PegasusError::ReadOnly(command.to_string())
+}
+
+/// Namespace of the device ids derived from USB serial numbers
+pub const DEVICE_ID_NAMESPACE: Uuid = Uuid::from_u128(0x6f3a_91c2_5d7e_4b08_a1f4_2e9c_7b05_d863);
+
+/// Id of the device with the given USB serial number, the same across
+/// restarts and machines so clients can keep it around.
+pub fn device_id(serial_number: &str) -> Uuid {
+    Uuid::new_v5(&DEVICE_ID_NAMESPACE, serial_number.as_bytes())
+}
+
+/// When the readings of a device were last read from it, by property name, so
+/// a `0.0` that was never read can be told from a real one.
+#[derive(Clone, Debug, Default)]
+pub struct ReadTimes {
+    times: HashMap<&'static str, Option<SystemTime>>,
+    /// Readings the firmware of the device doesn't send
+    not_sent: HashSet<&'static str>,
+}
+
+impl ReadTimes {
+    /// Readings expected at every poll, none read yet
+    pub fn new(names: &[&'static str]) -> Self {
+        Self {
+            times: names.iter().map(|&name| (name, None)).collect(),
+            not_sent: HashSet::new(),
+        }
+    }
+
+    /// The readings were just read from the device
+    pub fn touch(&mut self, names: &[&'static str]) {
+        let now = SystemTime::now();
+        for &name in names {
+            self.times.insert(name, Some(now));
+            self.not_sent.remove(name);
+        }
+    }
+
+    /// The device answered without the readings, e.g. an older firmware with
+    /// fewer fields. They keep their last read time but don't make the
+    /// device stale.
+    pub fn mark_not_sent(&mut self, names: &[&'static str]) {
+        self.not_sent.extend(names);
+    }
+
+    pub fn is_reading(&self, name: &str) -> bool {
+        self.times.contains_key(name)
+    }
+
+    /// When the reading was last read, `None` if it was not yet
+    pub fn last_read(&self, name: &str) -> Option<SystemTime> {
+        self.times.get(name).copied().flatten()
+    }
+
+    /// A reading sent by the device was never read or not for longer than `max_age`
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        self.times
+            .iter()
+            .filter(|(name, _)| !self.not_sent.contains(*name))
+            .any(|(_, read)| {
+                read.is_none_or(|at| at.elapsed().is_ok_and(|elapsed| elapsed > max_age))
+            })
+    }
+}
+
+/// Operations the driver needs from every kind of Pegasus device.
+#[async_trait]
+pub trait AstronomicalDevice {
+    fn get_id(&self) -> Uuid;
+
+    fn get_name(&self) -> &String;
+
+    /// OS address of the device (/dev/ttyUSB0 or COM6 for example)
+    fn get_address(&self) -> &String;
+
+    /// Refresh the cached properties reading them from the device.
+    async fn fetch_props(&mut self);
+
+    /// Entrypoint for property updates requested by clients.
+    async fn update_property(&mut self, prop_name: &str, val: &str) -> Result<(), PegasusError>;
+
+    /// False once the device stopped answering, until [`reconnect`] succeeds.
+    ///
+    /// [`reconnect`]: AstronomicalDevice::reconnect
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    /// Reopen the link with a device that stopped answering and check it is back.
+    async fn reconnect(&mut self) -> Result<(), PegasusError> {
+        Ok(())
+    }
+
+    /// Pause between two polls of the device, can be changed at runtime.
+    fn polling_interval(&self) -> Duration {
+        Duration::from_millis(DEFAULT_POLLING_INTERVAL_MS)
+    }
+
+    /// When the readings were last read, devices that don't keep track have none
+    fn read_times(&self) -> Option<&ReadTimes> {
+        None
+    }
+
+    /// Check the last fetched readings against the software current limits,
+    /// devices without such protection don't need to implement this.
+    async fn enforce_current_limits(&mut self) -> Vec<CurrentTrip> {
+        Vec::new()
+    }
+}
+
+/// A device whose settings can be changed with typed values.
+///
+/// Text based clients (MQTT, INDI) go through [`AstronomicalDevice::update_property`],
+/// which parses the value with [`parse_setting`] and then [`apply`]s it, other
+/// frontends can build the setting directly.
+///
+/// [`parse_setting`]: PegasusDevice::parse_setting
+/// [`apply`]: PegasusDevice::apply
+#[async_trait]
+pub trait PegasusDevice: AstronomicalDevice {
+    /// Every setting a client can change on this kind of device
+    type Setting: Send;
+
+    /// Turn a property name and its textual value into a setting.
+    fn parse_setting(prop_name: &str, val: &str) -> Result<Self::Setting, PegasusError>;
+
+    /// Send the setting to the device and update the cached properties.
+    async fn apply(&mut self, setting: Self::Setting) -> Result<(), PegasusError>;
+
+    /// Refuse a change of a property clients can only read, e.g. `input_voltage`.
+    ///
+    /// The permission is the one published with the property, names that are
+    /// not published (`halt`, `reset_trip`, ...) are left to [`parse_setting`].
+    ///
+    /// [`parse_setting`]: PegasusDevice::parse_setting
+    fn check_writable(&self, prop_name: &str) -> Result<(), PegasusError>
+    where
+        Self: Serialize,
+    {
+        let state = serde_json::to_value(self).unwrap_or_default();
+        match state.get(prop_name).and_then(|p| p.get("permission")) {
+            Some(permission) if permission == "ReadOnly" => {
+                Err(PegasusError::PermissionDenied(prop_name.to_owned()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Change several properties at once, e.g. both dew heaters and the quad
+    /// port when a session starts, returns the result of every update in order.
+    ///
+    /// Every value is parsed first, nothing is sent if one is invalid or
+    /// [read-only](PegasusDevice::check_writable). The
+    /// settings are then applied in order, the ones following a setting the
+    /// device failed to apply are not sent. On a read-only device every
+    /// setting fails with [`PegasusError::ReadOnly`].
+    async fn update_properties(
+        &mut self,
+        updates: &[(String, String)],
+    ) -> Vec<Result<(), PegasusError>>
+    where
+        Self: Send + Serialize,
+    {
+        let parsed: Vec<_> = updates
+            .iter()
+            .map(|(prop_name, val)| {
+                self.check_writable(prop_name)
+                    .and_then(|_| Self::parse_setting(prop_name, val))
+            })
+            .collect();
+        if let Some(invalid) = parsed.iter().position(Result::is_err) {
+            let skipped = format!("Not applied, {} is invalid", updates[invalid].0);
+            return parsed
+                .into_iter()
+                .map(|res| res.and(Err(PegasusError::Validation(skipped.clone()))))
+                .collect();
+        }
+
+        let mut results = Vec::with_capacity(updates.len());
+        let mut failed = None;
+        for (setting, (prop_name, _)) in parsed.into_iter().flatten().zip(updates) {
+            let res = match &failed {
+                Some(failed) => Err(PegasusError::Validation(format!(
+                    "Not applied, {} failed",
+                    failed
+                ))),
+                None => self.apply(setting).await,
+            };
+            // Nothing reaches a read-only device, the next settings are checked too
+            if res.as_ref().is_err_and(|e| !e.is_read_only()) && failed.is_none() {
+                failed = Some(prop_name.clone());
+            }
+            results.push(res);
+        }
+        results
+    }
+}
@@ -0,0 +1,69 @@
+//! Dew point computed from the temperature and the relative humidity.
+//!
+//! The firmwares report their own dew point, the one computed here uses the
+//! same Magnus formula everywhere, with the coefficients chosen by the user,
+//! so the readings of different devices and weather stations compare.
+use crate::error::PegasusError;
+use serde::{Deserialize, Serialize};
+
+/// Coefficients of the Magnus formula, `b` and `c` in °C
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Magnus {
+    pub b: f32,
+    pub c: f32,
+}
+
+impl Default for Magnus {
+    fn default() -> Self {
+        MagnusPreset::default().coefficients()
+    }
+}
+
+impl Magnus {
+    pub fn validate(&self) -> Result<(), PegasusError> {
+        if !(self.b.is_finite() && self.b > 0.0 && self.c.is_finite() && self.c > 0.0) {
+            return Err(PegasusError::Validation(format!(
+                "Invalid Magnus coefficients b = {}, c = {}",
+                self.b, self.c
+            )));
+        }
+        Ok(())
+    }
+
+    /// Dew point in °C, `humidity` in %
+    pub fn dew_point(&self, temperature: f32, humidity: f32) -> f32 {
+        let gamma =
+            (humidity.max(1.0) / 100.0).ln() + self.b * temperature / (self.c + temperature);
+        self.c * gamma / (self.b - gamma)
+    }
+}
+
+/// Published sets of coefficients
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MagnusPreset {
+    /// Sonntag (1990), within 0.35 °C from -45 to 60 °C
+    #[default]
+    Sonntag,
+    /// Alduchov and Eskridge (1996), the one of the WMO
+    AlduchovEskridge,
+    /// Tetens (1930), used by older instruments
+    Tetens,
+}
+
+impl MagnusPreset {
+    pub fn coefficients(self) -> Magnus {
+        let (b, c) = match self {
+            Self::Sonntag => (17.62, 243.12),
+            Self::AlduchovEskridge => (17.625, 243.04),
+            Self::Tetens => (17.27, 237.7),
+        };
+        Magnus { b, c }
+    }
+}
+
+/// Dew point in °C with the default coefficients
+pub fn dew_point(temperature: f32, humidity: f32) -> f32 {
+    Magnus::default().dew_point(temperature, humidity)
+}
@@ -0,0 +1,121 @@
+//! Pegasus devices plugged on the USB ports.
+//!
+//! Devices are told apart by the serial number of their USB chip, `PPBA1234`
+//! for a PPBA. Ports of a chip used by Pegasus whose serial number is missing
+//! or unknown, e.g. on Windows where it may not be reported, are listed
+//! without a family, only a [`probe`](crate::utils::probe) can tell what they are.
+use crate::error::PegasusError;
+use crate::utils::USB_IDS;
+use serde::Serialize;
+use serialport::{available_ports, SerialPortType};
+use std::fmt;
+
+/// Kinds of Pegasus devices
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceFamily {
+    /// Pocket Powerbox Advance
+    Ppba,
+    /// Ultimate Powerbox v2
+    Upb,
+    /// Pocket Powerbox Micro
+    Ppbm,
+    Dmfc,
+    FocusCube,
+}
+
+impl DeviceFamily {
+    pub const ALL: [Self; 5] = [
+        Self::Ppba,
+        Self::Upb,
+        Self::Ppbm,
+        Self::Dmfc,
+        Self::FocusCube,
+    ];
+
+    /// Start of the USB serial number of the devices of the family
+    pub fn serial_prefix(self) -> &'static str {
+        match self {
+            Self::Ppba => "PPBA",
+            Self::Upb => "UPB",
+            Self::Ppbm => "PPBM",
+            Self::Dmfc => "DMFC",
+            Self::FocusCube => "FC",
+        }
+    }
+
+    /// Family of a device from its USB serial number
+    pub fn from_serial(serial: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|family| serial.starts_with(family.serial_prefix()))
+    }
+
+    /// Both focusers are driven the same way
+    pub fn is_focuser(self) -> bool {
+        matches!(self, Self::Dmfc | Self::FocusCube)
+    }
+}
+
+impl fmt::Display for DeviceFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.serial_prefix())
+    }
+}
+
+/// A USB serial port that is, or may be, a Pegasus device
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DiscoveredDevice {
+    /// Path of the port, e.g. `/dev/ttyUSB0` or `COM6`
+    pub port: String,
+    pub serial_number: Option<String>,
+    pub vid: u16,
+    pub pid: u16,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    /// Guessed from the serial number, `None` if only the USB chip is one
+    /// used by Pegasus devices
+    pub family: Option<DeviceFamily>,
+}
+
+/// Every Pegasus device plugged, and the ports that may be one
+pub fn discover() -> Result<Vec<DiscoveredDevice>, PegasusError> {
+    let ports = available_ports().map_err(|e| {
+        PegasusError::Serial(std::io::Error::other(format!(
+            "Cannot list the serial ports: {}",
+            e
+        )))
+    })?;
+    Ok(ports
+        .into_iter()
+        .filter_map(|port| {
+            let SerialPortType::UsbPort(info) = port.port_type else {
+                return None;
+            };
+            let family = info
+                .serial_number
+                .as_deref()
+                .and_then(DeviceFamily::from_serial);
+            if family.is_none() && !USB_IDS.contains(&(info.vid, info.pid)) {
+                return None;
+            }
+            Some(DiscoveredDevice {
+                port: port.port_name,
+                serial_number: info.serial_number,
+                vid: info.vid,
+                pid: info.pid,
+                manufacturer: info.manufacturer,
+                product: info.product,
+                family,
+            })
+        })
+        .collect())
+}
+
+/// The devices of `family` plugged, identified by their serial number
+pub fn find(family: DeviceFamily) -> Result<Vec<DiscoveredDevice>, PegasusError> {
+    Ok(discover()?
+        .into_iter()
+        .filter(|dev| dev.family == Some(family))
+        .collect())
+}
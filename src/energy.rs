@@ -0,0 +1,136 @@
+use astrotools::properties::{Permission, Property};
+use chrono::NaiveDate;
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// Days of totals kept in `energy_daily`, today included
+pub const DAILY_TOTALS: usize = 7;
+/// Readings further apart are not integrated, the device was likely unplugged
+const MAX_GAP: Duration = Duration::from_secs(60);
+
+/// Outputs of a PPBA whose energy is accounted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnergyOutput {
+    Quadport,
+    Dew1,
+    Dew2,
+    /// Whatever the other outputs don't draw, the PPBA doesn't measure it alone
+    Adjustable,
+}
+
+impl EnergyOutput {
+    pub const ALL: [EnergyOutput; 4] = [
+        EnergyOutput::Quadport,
+        EnergyOutput::Dew1,
+        EnergyOutput::Dew2,
+        EnergyOutput::Adjustable,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Quadport => "quadport",
+            Self::Dew1 => "dew1",
+            Self::Dew2 => "dew2",
+            Self::Adjustable => "adj_output",
+        }
+    }
+}
+
+/// Energy drawn by every output, integrated from the input voltage and the
+/// current of each output at every poll.
+///
+/// Flattened in the serialized device, every output has a read only
+/// `{output}_energy` property in Wh since the last [reset](Self::reset) and
+/// `{output}_energy_today` since midnight, `energy_daily` holds the totals of
+/// every output for the last days.
+#[derive(Clone, Debug, Default)]
+pub struct EnergyMeter {
+    last: Option<Instant>,
+    /// Wh since the last reset, in the order of [`EnergyOutput::ALL`]
+    total: [f64; 4],
+    /// Wh since midnight
+    today: [f64; 4],
+    /// Wh drawn by all the outputs, by day
+    daily: BTreeMap<NaiveDate, f64>,
+}
+
+impl EnergyMeter {
+    /// Integrate the readings taken at `now` on `date`, `amps` in the order of
+    /// [`EnergyOutput::ALL`]
+    pub fn record(&mut self, now: Instant, date: NaiveDate, volts: f32, amps: [f32; 4]) {
+        if self
+            .daily
+            .last_key_value()
+            .is_some_and(|(day, _)| *day != date)
+        {
+            self.today = [0.0; 4];
+        }
+        let elapsed = self.last.map(|last| now.saturating_duration_since(last));
+        self.last = Some(now);
+
+        let hours = match elapsed {
+            Some(elapsed) if elapsed <= MAX_GAP => elapsed.as_secs_f64() / 3600.0,
+            _ => 0.0,
+        };
+        let mut drawn = 0.0;
+        for (i, amps) in amps.into_iter().enumerate() {
+            let wh = f64::from(volts) * f64::from(amps.max(0.0)) * hours;
+            self.total[i] += wh;
+            self.today[i] += wh;
+            drawn += wh;
+        }
+        *self.daily.entry(date).or_default() += drawn;
+        while self.daily.len() > DAILY_TOTALS {
+            self.daily.pop_first();
+        }
+    }
+
+    /// Wh drawn by `output` since the last reset
+    pub fn total(&self, output: EnergyOutput) -> f64 {
+        self.total[Self::index(output)]
+    }
+
+    /// Wh drawn by `output` since midnight
+    pub fn today(&self, output: EnergyOutput) -> f64 {
+        self.today[Self::index(output)]
+    }
+
+    /// Wh drawn by all the outputs on every day kept
+    pub fn daily(&self) -> &BTreeMap<NaiveDate, f64> {
+        &self.daily
+    }
+
+    /// Start the counters since the last reset from 0, the daily totals are kept
+    pub fn reset(&mut self) {
+        self.total = [0.0; 4];
+    }
+
+    fn index(output: EnergyOutput) -> usize {
+        EnergyOutput::ALL.iter().position(|o| *o == output).unwrap()
+    }
+}
+
+impl Serialize for EnergyMeter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for output in EnergyOutput::ALL {
+            map.serialize_entry(
+                &format!("{}_energy", output.name()),
+                &Property::new(self.total(output) as f32, Permission::ReadOnly),
+            )?;
+            map.serialize_entry(
+                &format!("{}_energy_today", output.name()),
+                &Property::new(self.today(output) as f32, Permission::ReadOnly),
+            )?;
+        }
+        let daily: BTreeMap<String, f32> = self
+            .daily
+            .iter()
+            .map(|(day, wh)| (day.to_string(), *wh as f32))
+            .collect();
+        map.serialize_entry("energy_daily", &Property::new(daily, Permission::ReadOnly))?;
+        map.end()
+    }
+}
@@ -0,0 +1,84 @@
+//! Errors reported by the devices and the transports.
+//!
+//! Every fallible operation of the library returns a [`PegasusError`] so
+//! callers can tell a device that stopped answering from a value it refused.
+use crate::parser::ParseError;
+use std::io;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PegasusError {
+    /// The serial link failed, `io::ErrorKind::TimedOut` when the device stayed silent
+    #[error("Serial link error: {0}")]
+    Serial(io::Error),
+    /// Another process, or another driver of this one, has the port open
+    #[error("Device busy: {0}")]
+    DeviceBusy(String),
+    /// The device answered something that cannot be understood
+    #[error("Invalid response: {0}")]
+    Parse(String),
+    /// The device refused the command, the payload is its response
+    #[error("Command refused by the device: {0}")]
+    Protocol(String),
+    /// A value or a setting rejected before reaching the device
+    #[error("{0}")]
+    Validation(String),
+    /// The device is gone, e.g. unplugged or its task stopped
+    #[error("Device not connected")]
+    NotConnected,
+    /// The device doesn't have the property or the operation
+    #[error("{0}")]
+    Unsupported(String),
+    /// The property can only be read, e.g. a reading like `input_voltage`
+    #[error("Property {0} is read-only")]
+    PermissionDenied(String),
+    /// The command is valid but was not sent, the device is in read-only mode
+    #[error("Read-only mode, {0} was not sent")]
+    ReadOnly(String),
+    /// The MQTT broker of a [`PegasusClient`](crate::client::PegasusClient)
+    /// cannot be reached
+    #[error("MQTT broker error: {0}")]
+    Broker(String),
+}
+
+impl PegasusError {
+    /// The device stayed silent
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, Self::Serial(e) if e.kind() == io::ErrorKind::TimedOut)
+    }
+
+    /// The command was only validated, see [`PegasusError::ReadOnly`]
+    pub fn is_read_only(&self) -> bool {
+        matches!(self, Self::ReadOnly(_))
+    }
+
+    /// The device answered, even if to refuse the command, so the link is fine
+    pub fn device_answered(&self) -> bool {
+        matches!(self, Self::Protocol(_))
+    }
+
+    /// The link to the device failed: it stayed silent, answered garbage or
+    /// is gone. A refused command, or one stopped before reaching the device,
+    /// says nothing of the link.
+    pub fn link_failed(&self) -> bool {
+        matches!(
+            self,
+            Self::Serial(_) | Self::DeviceBusy(_) | Self::Parse(_) | Self::NotConnected
+        )
+    }
+}
+
+impl From<io::Error> for PegasusError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::ResourceBusy => Self::DeviceBusy(e.to_string()),
+            _ => Self::Serial(e),
+        }
+    }
+}
+
+impl From<ParseError> for PegasusError {
+    fn from(e: ParseError) -> Self {
+        Self::Parse(e.to_string())
+    }
+}
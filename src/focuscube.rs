@@ -0,0 +1,400 @@
+use crate::codec::{self, Payload};
+use crate::device::{self, AstronomicalDevice, PegasusDevice, ReadTimes};
+use crate::error::PegasusError;
+use crate::transport::{self, RetryPolicies, SerialConfig, SerialTransport};
+use astrotools::properties::{Permission, Prop, Property};
+use async_trait::async_trait;
+use log::{debug, error, info};
+use serde::Serialize;
+use std::str::FromStr;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Focus Cube and Dual Motor Focus Controller (DMFC), they share the same
+/// motor command set.
+#[derive(Debug, Serialize)]
+pub struct FocusCube {
+    #[serde(skip)]
+    pub id: Uuid,
+    name: String,
+    address: String,
+    pub baud: u32,
+    #[serde(skip)]
+    pub port: Box<dyn SerialTransport>,
+    /// USB serial number, `UNKNOWN` if the device was opened without it
+    serial_number: Property<String>,
+    fw_version: Property<String>,
+    temperature: Property<f32>,
+    /// Writing it moves the motor to the given absolute position
+    position: Property<i32>,
+    moving: Property<bool>,
+    /// Steps added when the motor changes direction
+    backlash: Property<u32>,
+    reverse: Property<bool>,
+    /// Milliseconds between two polls
+    polling_interval: Property<u64>,
+    /// Set commands are validated and logged but never sent
+    read_only: Property<bool>,
+    #[serde(skip)]
+    retry: RetryPolicies,
+    #[serde(skip)]
+    read_times: ReadTimes,
+    /// False once the device stopped answering
+    #[serde(skip)]
+    connected: bool,
+}
+
+// The whole protocol is mapped here even if not every command is issued yet
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+enum Command {
+    /// Status command serial code is #
+    Status = 0x23,
+    /// Firmware version command serial code is V
+    FirmwareVersion = 0x56,
+    /// Temperature command serial code is T
+    Temperature = 0x54,
+    /// Position command serial code is P
+    Position = 0x50,
+    /// Motor moving command serial code is I, answers 1 while moving
+    Moving = 0x49,
+    /// Move to an absolute position SET command is M:
+    MoveAbsolute = 0x4d3a,
+    /// Move by a number of steps SET command is G:
+    MoveRelative = 0x473a,
+    /// Stop the motor command is H
+    Halt = 0x48,
+    /// Set the current position without moving SET command is W:
+    SyncPosition = 0x573a,
+    /// Backlash SET command is C:
+    Backlash = 0x433a,
+    /// Reverse direction SET command is N:
+    Reverse = 0x4e3a,
+    /// Led indicator SET command is L:
+    Led = 0x4c3a,
+}
+
+/// Settings a client can change on a focuser
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Setting {
+    /// Move to an absolute position, `position`
+    MoveTo(i32),
+    /// Move by a number of steps, negative to move inward, `move_relative`
+    MoveBy(i32),
+    /// Stop the motor, `halt`
+    Halt,
+    /// Declare the current position without moving, `sync_position`
+    Sync(i32),
+    /// Backlash compensation in steps, `backlash`
+    Backlash(u32),
+    /// Reverse the motor direction, `reverse`
+    Reverse(bool),
+    /// Milliseconds between two polls, `polling_interval`
+    PollingInterval(u64),
+}
+
+/// Parse a query response, with or without its `X:` prefix
+fn value<T: FromStr>(response: &str) -> Option<T> {
+    response
+        .rsplit(':')
+        .next()
+        .and_then(|v| v.trim().parse().ok())
+}
+
+impl FocusCube {
+    pub async fn try_new(
+        name: &str,
+        address: &str,
+        baud: u32,
+        timeout_ms: u64,
+    ) -> Result<Self, PegasusError> {
+        Self::try_with_config(name, address, &SerialConfig::new(baud, timeout_ms)).await
+    }
+
+    /// Same as [`FocusCube::try_new`] with every setting of the serial link,
+    /// e.g. flow control or a pause between commands.
+    pub async fn try_with_config(
+        name: &str,
+        address: &str,
+        config: &SerialConfig,
+    ) -> Result<Self, PegasusError> {
+        let port = transport::open_with_config(address, config).await?;
+        Self::new_with_port(name, address, config.baud, port).await
+    }
+
+    /// Build a device on top of an already open transport, e.g. a
+    /// [`FakePpbaPort`](crate::sim::FakePpbaPort) to run without hardware.
+    pub async fn new_with_port(
+        name: &str,
+        address: &str,
+        baud: u32,
+        port: Box<dyn SerialTransport>,
+    ) -> Result<Self, PegasusError> {
+        let mut dev = Self {
+            id: Uuid::new_v4(),
+            name: name.to_owned(),
+            address: address.to_owned(),
+            baud,
+            port,
+            serial_number: Property::<String>::new("UNKNOWN".to_string(), Permission::ReadOnly),
+            fw_version: Property::<String>::new("UNKNOWN".to_string(), Permission::ReadOnly),
+            temperature: Property::<f32>::new(0.0, Permission::ReadOnly),
+            position: Property::<i32>::new(0, Permission::ReadWrite),
+            moving: Property::<bool>::new(false, Permission::ReadOnly),
+            backlash: Property::<u32>::new(0, Permission::ReadWrite),
+            reverse: Property::<bool>::new(false, Permission::ReadWrite),
+            polling_interval: Property::<u64>::new(
+                device::DEFAULT_POLLING_INTERVAL_MS,
+                Permission::ReadWrite,
+            ),
+            read_only: Property::new(false, Permission::ReadOnly),
+            retry: RetryPolicies::default(),
+            read_times: ReadTimes::new(&["temperature", "position", "moving"]),
+            connected: true,
+        };
+
+        // The Focus Cube answers OK_FC, the DMFC OK_DMFCN or OK_DMFCS
+        let status = dev.send_command(Command::Status, None).await?;
+        if !status.starts_with("OK_") {
+            return Err(PegasusError::Unsupported(format!(
+                "Not a Pegasus focuser: {}",
+                status
+            )));
+        }
+        if let Ok(fw) = dev.send_command(Command::FirmwareVersion, None).await {
+            dev.fw_version.update_int(fw);
+        }
+        dev.fetch_props().await;
+        Ok(dev)
+    }
+
+    /// Move the motor to an absolute position.
+    pub async fn move_to(&mut self, position: i32) -> Result<(), PegasusError> {
+        self.send_command(
+            Command::MoveAbsolute,
+            Some(Payload::Number(position.into())),
+        )
+        .await?;
+        self.moving.update_int(true);
+        Ok(())
+    }
+
+    /// Move the motor by `steps`, negative values move inward.
+    pub async fn move_by(&mut self, steps: i32) -> Result<(), PegasusError> {
+        self.send_command(Command::MoveRelative, Some(Payload::Number(steps.into())))
+            .await?;
+        self.moving.update_int(true);
+        Ok(())
+    }
+
+    /// Stop the motor right away.
+    pub async fn halt(&mut self) -> Result<(), PegasusError> {
+        if self.is_read_only() {
+            return Err(device::not_sent(
+                &self.name,
+                &codec::Command::new(Command::Halt as u32, None),
+            ));
+        }
+        self.send_command(Command::Halt, None).await?;
+        self.moving.update_int(false);
+        Ok(())
+    }
+
+    /// Declare the current position of the motor without moving it.
+    pub async fn sync_position(&mut self, position: i32) -> Result<(), PegasusError> {
+        self.send_command(
+            Command::SyncPosition,
+            Some(Payload::Number(position.into())),
+        )
+        .await?;
+        self.position.update_int(position);
+        Ok(())
+    }
+
+    pub async fn set_backlash(&mut self, steps: u32) -> Result<(), PegasusError> {
+        self.send_command(Command::Backlash, Some(Payload::Number(steps.into())))
+            .await?;
+        self.backlash.update_int(steps);
+        Ok(())
+    }
+
+    pub async fn set_reverse(&mut self, on: bool) -> Result<(), PegasusError> {
+        self.send_command(Command::Reverse, Some(Payload::Flag(on)))
+            .await?;
+        self.reverse.update_int(on);
+        Ok(())
+    }
+
+    pub fn position(&self) -> i32 {
+        *self.position.value()
+    }
+
+    pub fn is_moving(&self) -> bool {
+        *self.moving.value()
+    }
+
+    /// Change the pause between two polls, at least
+    /// [`MIN_POLLING_INTERVAL_MS`](device::MIN_POLLING_INTERVAL_MS).
+    pub fn set_polling_interval(&mut self, ms: u64) -> Result<(), PegasusError> {
+        let ms = device::check_polling_interval(ms)?;
+        self.polling_interval.update_int(ms);
+        Ok(())
+    }
+
+    /// Identify the device by its USB serial number, its id is derived from it
+    /// with [`device_id`](device::device_id) instead of being random.
+    pub fn set_serial_number(&mut self, serial_number: &str) {
+        self.id = device::device_id(serial_number);
+        self.serial_number.update_int(serial_number.to_owned());
+    }
+
+    /// Reuse the id a device without serial number had, e.g. before a restart
+    pub fn set_id(&mut self, id: Uuid) {
+        self.id = id;
+    }
+
+    /// Change how commands the device doesn't answer are retried.
+    pub fn set_retry_policies(&mut self, retry: RetryPolicies) {
+        self.retry = retry;
+    }
+
+    /// In read-only mode the settings are validated but not sent, changing
+    /// one fails with [`PegasusError::ReadOnly`].
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only.update_int(read_only);
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        *self.read_only.value()
+    }
+
+    async fn send_command(
+        &mut self,
+        comm: Command,
+        payload: Option<Payload>,
+    ) -> Result<String, PegasusError> {
+        let command = codec::Command::new(comm as u32, payload);
+        // Only the set commands have a value
+        if self.is_read_only() && command.payload().is_some() {
+            return Err(device::not_sent(&self.name, &command));
+        }
+        let res =
+            transport::send_command_with_retry(self.port.as_mut(), &command, &self.retry).await;
+        // A device answering with an error is still there, anything else means the link is gone
+        self.connected = !matches!(&res, Err(e) if e.link_failed());
+        res
+    }
+
+    /// Send a query and parse its response, logging what can't be read
+    async fn query<T: FromStr>(&mut self, comm: Command, what: &str) -> Option<T> {
+        match self.send_command(comm, None).await {
+            Ok(response) => {
+                debug!("{}: {}", what, response);
+                let parsed = value(&response);
+                if parsed.is_none() {
+                    error!("Invalid {} from {}: {}", what, self.name, response);
+                }
+                parsed
+            }
+            Err(_) => {
+                error!("Couldn't read {}", what);
+                None
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl AstronomicalDevice for FocusCube {
+    fn get_id(&self) -> Uuid {
+        self.id
+    }
+
+    fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    fn get_address(&self) -> &String {
+        &self.address
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn polling_interval(&self) -> Duration {
+        Duration::from_millis(*self.polling_interval.value())
+    }
+
+    fn read_times(&self) -> Option<&ReadTimes> {
+        Some(&self.read_times)
+    }
+
+    async fn reconnect(&mut self) -> Result<(), PegasusError> {
+        self.port.reopen().await?;
+        self.send_command(Command::Status, None).await?;
+        info!("{} is back on {}", self.name, self.address);
+        Ok(())
+    }
+
+    async fn fetch_props(&mut self) {
+        info!("Fetching properties for device {}", self.name);
+        if let Some(v) = self.query(Command::Temperature, "temperature").await {
+            self.temperature.update_int(v);
+            self.read_times.touch(&["temperature"]);
+        }
+        if let Some(v) = self.query(Command::Position, "position").await {
+            self.position.update_int(v);
+            self.read_times.touch(&["position"]);
+        }
+        if let Some(v) = self.query::<u8>(Command::Moving, "motor status").await {
+            self.moving.update_int(v == 1);
+            self.read_times.touch(&["moving"]);
+        }
+    }
+
+    async fn update_property(&mut self, prop_name: &str, val: &str) -> Result<(), PegasusError> {
+        let setting = Self::parse_setting(prop_name, val)?;
+        self.apply(setting).await
+    }
+}
+
+#[async_trait]
+impl PegasusDevice for FocusCube {
+    type Setting = Setting;
+
+    fn parse_setting(prop_name: &str, val: &str) -> Result<Setting, PegasusError> {
+        let invalid =
+            || PegasusError::Validation(format!("Invalid value for {}: {}", prop_name, val));
+
+        match prop_name {
+            "position" => val.parse().map(Setting::MoveTo).map_err(|_| invalid()),
+            "move_relative" => val.parse().map(Setting::MoveBy).map_err(|_| invalid()),
+            "halt" => Ok(Setting::Halt),
+            "sync_position" => val.parse().map(Setting::Sync).map_err(|_| invalid()),
+            "backlash" => val.parse().map(Setting::Backlash).map_err(|_| invalid()),
+            "reverse" => match val {
+                "1" | "true" => Ok(Setting::Reverse(true)),
+                "0" | "false" => Ok(Setting::Reverse(false)),
+                _ => Err(invalid()),
+            },
+            "polling_interval" => device::parse_polling_interval(val).map(Setting::PollingInterval),
+            _ => Err(PegasusError::Unsupported(format!(
+                "Property {} cannot be updated",
+                prop_name
+            ))),
+        }
+    }
+
+    async fn apply(&mut self, setting: Setting) -> Result<(), PegasusError> {
+        match setting {
+            Setting::MoveTo(position) => self.move_to(position).await,
+            Setting::MoveBy(steps) => self.move_by(steps).await,
+            Setting::Halt => self.halt().await,
+            Setting::Sync(position) => self.sync_position(position).await,
+            Setting::Backlash(steps) => self.set_backlash(steps).await,
+            Setting::Reverse(on) => self.set_reverse(on).await,
+            Setting::PollingInterval(ms) => self.set_polling_interval(ms),
+        }
+    }
+}
@@ -1 +1,29 @@
+#[cfg(feature = "actor")]
+pub mod actor;
+pub mod battery;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod calibration;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "mqtt")]
+pub mod client;
+pub mod codec;
+pub mod device;
+pub mod dewpoint;
+pub mod discovery;
+pub mod energy;
+pub mod error;
+pub mod focuscube;
+pub mod limits;
+pub mod parser;
+pub mod ppba;
+pub mod ppbm;
+pub mod ramp;
+pub mod sim;
+pub mod smoothing;
+pub mod sun;
+pub mod trace;
+pub mod transport;
+pub mod upbv2;
 pub mod utils;
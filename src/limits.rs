@@ -0,0 +1,309 @@
+use log::warn;
+use serde::{Serialize, Serializer};
+use std::collections::{HashMap, HashSet};
+
+/// Default number of consecutive samples over the limit before tripping.
+pub const DEFAULT_TRIP_SAMPLES: u32 = 3;
+
+/// Outputs whose current is monitored by the PPBA
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Output {
+    Quadport,
+    Dew1,
+    Dew2,
+}
+
+impl Output {
+    pub const ALL: [Output; 3] = [Output::Quadport, Output::Dew1, Output::Dew2];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "quadport" => Some(Self::Quadport),
+            "dew1" => Some(Self::Dew1),
+            "dew2" => Some(Self::Dew2),
+            _ => None,
+        }
+    }
+
+    fn env_var(&self) -> &'static str {
+        match self {
+            Self::Quadport => "PPBA_QUADPORT_MAX_CURRENT",
+            Self::Dew1 => "PPBA_DEW1_MAX_CURRENT",
+            Self::Dew2 => "PPBA_DEW2_MAX_CURRENT",
+        }
+    }
+
+    fn action_env_var(&self) -> &'static str {
+        match self {
+            Self::Quadport => "PPBA_QUADPORT_LIMIT_ACTION",
+            Self::Dew1 => "PPBA_DEW1_LIMIT_ACTION",
+            Self::Dew2 => "PPBA_DEW2_LIMIT_ACTION",
+        }
+    }
+
+    /// Only the dew heaters have a PWM to reduce
+    fn can_reduce(&self) -> bool {
+        *self != Self::Quadport
+    }
+}
+
+/// What is done to an output over its limit for too long
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitAction {
+    /// Switch the output off until it is re-armed
+    #[default]
+    Cut,
+    /// Lower the PWM of a dew heater to get under the limit, the output stays on
+    Reduce,
+}
+
+impl LimitAction {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "cut" => Some(Self::Cut),
+            "reduce" => Some(Self::Reduce),
+            _ => None,
+        }
+    }
+}
+
+/// Maximum current (in Amps) allowed on each output, outputs without a limit
+/// are not monitored.
+#[derive(Clone, Debug, Default)]
+pub struct CurrentLimits {
+    pub max_amps: HashMap<Output, f32>,
+    /// How many consecutive samples must exceed the limit before tripping
+    pub trip_samples: u32,
+    /// [`LimitAction::Cut`] for the outputs missing
+    pub actions: HashMap<Output, LimitAction>,
+}
+
+impl CurrentLimits {
+    /// Read the limits from `PPBA_QUADPORT_MAX_CURRENT`, `PPBA_DEW1_MAX_CURRENT`,
+    /// `PPBA_DEW2_MAX_CURRENT` and `PPBA_CURRENT_TRIP_SAMPLES`, what is done
+    /// over the limit from `PPBA_DEW1_LIMIT_ACTION` and `PPBA_DEW2_LIMIT_ACTION`.
+    pub fn from_env() -> Self {
+        let mut max_amps = HashMap::new();
+        let mut actions = HashMap::new();
+
+        for output in Output::ALL {
+            if let Some(amps) = std::env::var(output.env_var())
+                .ok()
+                .and_then(|v| v.parse().ok())
+            {
+                max_amps.insert(output, amps);
+            }
+            let Ok(name) = std::env::var(output.action_env_var()) else {
+                continue;
+            };
+            match LimitAction::from_name(&name) {
+                Some(LimitAction::Reduce) if !output.can_reduce() => {
+                    warn!("{:?} can only be cut, ignoring {}", output, name)
+                }
+                Some(action) => {
+                    actions.insert(output, action);
+                }
+                None => warn!(
+                    "Ignoring unknown limit action {} of {:?}, `cut` or `reduce` expected",
+                    name, output
+                ),
+            }
+        }
+
+        let trip_samples = std::env::var("PPBA_CURRENT_TRIP_SAMPLES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_TRIP_SAMPLES);
+
+        Self {
+            max_amps,
+            trip_samples,
+            actions,
+        }
+    }
+
+    pub fn action(&self, output: Output) -> LimitAction {
+        self.actions.get(&output).copied().unwrap_or_default()
+    }
+}
+
+/// Emitted when an output is switched off or reduced because it drew too
+/// much current
+#[derive(Clone, Debug, Serialize)]
+pub struct CurrentTrip {
+    pub output: Output,
+    pub current: f32,
+    pub limit: f32,
+    pub action: LimitAction,
+    /// PWM the dew heater was reduced to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pwm: Option<u8>,
+}
+
+/// Keeps track of the samples over the limit and of the tripped outputs.
+///
+/// A tripped output stays latched until it is explicitly re-armed, this way a
+/// client can't turn it on again by mistake while the cause is still there.
+/// Reduced outputs stay on and keep being monitored.
+#[derive(Debug, Default)]
+pub struct CurrentGuard {
+    limits: CurrentLimits,
+    over_limit: HashMap<Output, u32>,
+    tripped: HashSet<Output>,
+}
+
+impl CurrentGuard {
+    pub fn new(limits: CurrentLimits) -> Self {
+        Self {
+            limits,
+            ..Default::default()
+        }
+    }
+
+    /// Record a current sample for an output, returns a trip if the output
+    /// should be switched off or reduced right now.
+    pub fn check(&mut self, output: Output, current: f32) -> Option<CurrentTrip> {
+        let limit = *self.limits.max_amps.get(&output)?;
+
+        if self.tripped.contains(&output) {
+            return None;
+        }
+
+        if current <= limit {
+            self.over_limit.remove(&output);
+            return None;
+        }
+
+        let count = self.over_limit.entry(output).or_insert(0);
+        *count += 1;
+        warn!(
+            "{:?} is drawing {:.2}A, over the limit of {:.2}A ({}/{})",
+            output, current, limit, count, self.limits.trip_samples
+        );
+
+        if *count >= self.limits.trip_samples {
+            self.over_limit.remove(&output);
+            let action = self.limits.action(output);
+            if action == LimitAction::Cut {
+                self.tripped.insert(output);
+            }
+            Some(CurrentTrip {
+                output,
+                current,
+                limit,
+                action,
+                pwm: None,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_tripped(&self, output: Output) -> bool {
+        self.tripped.contains(&output)
+    }
+
+    pub fn tripped(&self) -> Vec<Output> {
+        let mut outputs: Vec<Output> = self.tripped.iter().copied().collect();
+        outputs.sort_by_key(|o| *o as u8);
+        outputs
+    }
+
+    /// Allow a tripped output to be switched on again, returns false if the
+    /// output wasn't tripped.
+    pub fn rearm(&mut self, output: Output) -> bool {
+        self.tripped.remove(&output)
+    }
+}
+
+/// Only the tripped outputs are part of the device state
+impl Serialize for CurrentGuard {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.tripped().serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard(action: LimitAction) -> CurrentGuard {
+        CurrentGuard::new(CurrentLimits {
+            max_amps: HashMap::from([(Output::Dew1, 2.0)]),
+            trip_samples: 3,
+            actions: HashMap::from([(Output::Dew1, action)]),
+        })
+    }
+
+    #[test]
+    fn trips_after_consecutive_samples_over_the_limit() {
+        let mut guard = guard(LimitAction::Cut);
+        assert!(guard.check(Output::Dew1, 2.5).is_none());
+        assert!(guard.check(Output::Dew1, 2.5).is_none());
+        let trip = guard.check(Output::Dew1, 3.0).unwrap();
+        assert_eq!(trip.output, Output::Dew1);
+        assert_eq!(trip.current, 3.0);
+        assert_eq!(trip.limit, 2.0);
+        assert_eq!(trip.action, LimitAction::Cut);
+        assert!(guard.is_tripped(Output::Dew1));
+    }
+
+    #[test]
+    fn a_sample_within_the_limit_resets_the_count() {
+        let mut guard = guard(LimitAction::Cut);
+        guard.check(Output::Dew1, 2.5);
+        guard.check(Output::Dew1, 2.5);
+        // The limit itself is allowed
+        assert!(guard.check(Output::Dew1, 2.0).is_none());
+        assert!(guard.check(Output::Dew1, 2.5).is_none());
+        assert!(guard.check(Output::Dew1, 2.5).is_none());
+        assert!(guard.check(Output::Dew1, 2.5).is_some());
+    }
+
+    #[test]
+    fn cut_outputs_stay_latched_until_rearmed() {
+        let mut guard = guard(LimitAction::Cut);
+        for _ in 0..3 {
+            guard.check(Output::Dew1, 5.0);
+        }
+        for _ in 0..5 {
+            assert!(guard.check(Output::Dew1, 5.0).is_none());
+        }
+        assert_eq!(guard.tripped(), [Output::Dew1]);
+        assert!(guard.rearm(Output::Dew1));
+        assert!(!guard.rearm(Output::Dew1));
+        assert!(guard.tripped().is_empty());
+        for _ in 0..2 {
+            assert!(guard.check(Output::Dew1, 5.0).is_none());
+        }
+        assert!(guard.check(Output::Dew1, 5.0).is_some());
+    }
+
+    #[test]
+    fn reduced_outputs_keep_being_monitored() {
+        let mut guard = guard(LimitAction::Reduce);
+        for _ in 0..2 {
+            assert!(guard.check(Output::Dew1, 5.0).is_none());
+        }
+        assert_eq!(
+            guard.check(Output::Dew1, 5.0).unwrap().action,
+            LimitAction::Reduce
+        );
+        assert!(!guard.is_tripped(Output::Dew1));
+        for _ in 0..2 {
+            assert!(guard.check(Output::Dew1, 5.0).is_none());
+        }
+        assert!(guard.check(Output::Dew1, 5.0).is_some());
+    }
+
+    #[test]
+    fn outputs_without_a_limit_are_not_monitored() {
+        let mut guard = guard(LimitAction::Cut);
+        for _ in 0..10 {
+            assert!(guard.check(Output::Quadport, 100.0).is_none());
+        }
+        assert!(!guard.is_tripped(Output::Quadport));
+    }
+}
@@ -0,0 +1,385 @@
+//! Parsing of the PPBA responses to the `PV`, `PA`, `PS` and `PC` queries.
+//!
+//! Responses are `:` separated fields after a header, malformed or truncated
+//! responses are reported as a [`ParseError`] instead of panicking. The fields
+//! sent depend on the firmware, see [`PpbaLayout`].
+use crate::calibration::SensorCalibration;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ParseError {
+    /// The response doesn't start with the header of the query
+    UnexpectedHeader {
+        expected: &'static str,
+        found: String,
+    },
+    /// The response is too short to contain `field`
+    MissingField { field: &'static str, index: usize },
+    /// `field` is there but its value can't be parsed
+    InvalidField { field: &'static str, value: String },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnexpectedHeader { expected, found } => {
+                write!(f, "expected a {} response, got {:?}", expected, found)
+            }
+            Self::MissingField { field, index } => {
+                write!(f, "missing {} (field {})", field, index)
+            }
+            Self::InvalidField { field, value } => write!(f, "invalid {}: {:?}", field, value),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Fields of a response, the header being field 0
+struct Fields<'a> {
+    chunks: Vec<&'a str>,
+}
+
+impl<'a> Fields<'a> {
+    /// Split `response` checking it starts with one of `headers`
+    fn new(response: &'a str, headers: &[&'static str]) -> Result<Self, ParseError> {
+        let chunks: Vec<&str> = response.trim().split(':').collect();
+        if !headers.contains(&chunks[0]) {
+            return Err(ParseError::UnexpectedHeader {
+                expected: headers[0],
+                found: response.trim().to_owned(),
+            });
+        }
+        Ok(Self { chunks })
+    }
+
+    fn get<T: FromStr>(&self, index: usize, field: &'static str) -> Result<T, ParseError> {
+        self.optional(index, field)?
+            .ok_or(ParseError::MissingField { field, index })
+    }
+
+    /// Same as `get` for fields older firmwares don't send
+    fn optional<T: FromStr>(
+        &self,
+        index: usize,
+        field: &'static str,
+    ) -> Result<Option<T>, ParseError> {
+        match self.chunks.get(index) {
+            Some(value) => value
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|_| ParseError::InvalidField {
+                    field,
+                    value: value.to_string(),
+                }),
+            None => Ok(None),
+        }
+    }
+
+    /// Field sent only by some firmwares: required when `expected` is true,
+    /// ignored when false and optional when the firmware is unknown
+    fn versioned<T: FromStr>(
+        &self,
+        index: usize,
+        field: &'static str,
+        expected: Option<bool>,
+    ) -> Result<Option<T>, ParseError> {
+        match expected {
+            Some(true) => self.get(index, field).map(Some),
+            Some(false) => Ok(None),
+            None => self.optional(index, field),
+        }
+    }
+
+    /// Booleans are sent as `0` or `1`
+    fn flag(&self, index: usize, field: &'static str) -> Result<bool, ParseError> {
+        self.optional_flag(index, field)?
+            .ok_or(ParseError::MissingField { field, index })
+    }
+
+    /// Same as `flag` for fields older firmwares don't send
+    fn optional_flag(&self, index: usize, field: &'static str) -> Result<Option<bool>, ParseError> {
+        match self.optional::<u8>(index, field)? {
+            None => Ok(None),
+            Some(0) => Ok(Some(false)),
+            Some(1) => Ok(Some(true)),
+            Some(v) => Err(ParseError::InvalidField {
+                field,
+                value: v.to_string(),
+            }),
+        }
+    }
+}
+
+/// Firmware version, answer to `PV`, e.g. `1.4` or `2.1.3`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FirmwareVersion {
+    pub major: u16,
+    pub minor: u16,
+    pub patch: u16,
+}
+
+impl FirmwareVersion {
+    pub const fn new(major: u16, minor: u16, patch: u16) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl FromStr for FirmwareVersion {
+    type Err = ParseError;
+
+    fn from_str(response: &str) -> Result<Self, ParseError> {
+        let version = response.trim();
+        let invalid = || ParseError::InvalidField {
+            field: "firmware version",
+            value: version.to_owned(),
+        };
+        let numbers = version
+            .strip_prefix("PV:")
+            .unwrap_or(version)
+            .split('.')
+            .map(|n| n.trim().parse::<u16>().map_err(|_| invalid()))
+            .collect::<Result<Vec<u16>, ParseError>>()?;
+        // Missing minor or patch numbers are 0
+        match numbers[..] {
+            [major] => Ok(Self::new(major, 0, 0)),
+            [major, minor] => Ok(Self::new(major, minor, 0)),
+            [major, minor, patch] => Ok(Self::new(major, minor, patch)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Fields of the PPBA responses that depend on the firmware
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PpbaLayout {
+    /// `PA` ends with the voltage of the adjustable output
+    pub adj_output_voltage: bool,
+    /// `PC` ends with the uptime
+    pub metrics_uptime: bool,
+}
+
+impl PpbaLayout {
+    /// First firmware sending the adjustable output voltage and the `PC` uptime
+    pub const EXTENDED_SINCE: FirmwareVersion = FirmwareVersion::new(1, 4, 0);
+
+    pub fn for_firmware(version: &FirmwareVersion) -> Self {
+        let extended = *version >= Self::EXTENDED_SINCE;
+        Self {
+            adj_output_voltage: extended,
+            metrics_uptime: extended,
+        }
+    }
+}
+
+/// Power and sensor readings, answer to `PA`:
+/// `PPBA:voltage:current_12V:temp:humidity:dewpoint:quadport:adj_output_status:dewA:dewB:autodew:pwr_warn:pwradj`
+///
+/// Firmwares 1.x stop after fewer fields, the ones after the sensors are
+/// `None` when they're not sent.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PpbaStatus {
+    pub input_voltage: f32,
+    pub current_12v_output: f32,
+    pub temperature: f32,
+    pub humidity: f32,
+    pub dew_point: f32,
+    pub quadport_status: Option<bool>,
+    pub adj_output_status: Option<bool>,
+    pub dew1_power: Option<u8>,
+    pub dew2_power: Option<u8>,
+    pub autodew: Option<bool>,
+    pub pwr_warn: Option<bool>,
+    /// Voltage of the adjustable output, not sent by older firmwares
+    pub adj_output: Option<u8>,
+}
+
+impl FromStr for PpbaStatus {
+    type Err = ParseError;
+
+    fn from_str(response: &str) -> Result<Self, ParseError> {
+        Self::parse_with(response, None)
+    }
+}
+
+impl PpbaStatus {
+    /// Parse a response of a firmware with the given layout, fields the layout
+    /// doesn't have are ignored and the ones it has are required. Without a
+    /// layout the optional fields are read when sent. The readings of the
+    /// sensors are always required, the fields after them are read when sent.
+    pub fn parse_with(response: &str, layout: Option<PpbaLayout>) -> Result<Self, ParseError> {
+        let f = Fields::new(response, &["PPBA"])?;
+        Ok(Self {
+            input_voltage: f.get(1, "input voltage")?,
+            current_12v_output: f.get(2, "12V outputs current")?,
+            temperature: f.get(3, "temperature")?,
+            humidity: f.get(4, "humidity")?,
+            dew_point: f.get(5, "dew point")?,
+            quadport_status: f.optional_flag(6, "quadport status")?,
+            adj_output_status: f.optional_flag(7, "adjustable output status")?,
+            dew1_power: f.optional(8, "dew A power")?,
+            dew2_power: f.optional(9, "dew B power")?,
+            autodew: f.optional_flag(10, "autodew")?,
+            pwr_warn: f.optional_flag(11, "power warning")?,
+            adj_output: f.versioned(
+                12,
+                "adjustable output voltage",
+                layout.map(|l| l.adj_output_voltage),
+            )?,
+        })
+    }
+
+    /// Correct the sensor readings with the offsets of the device
+    pub fn calibrate(&mut self, calibration: &SensorCalibration) {
+        self.temperature = calibration.temperature(self.temperature);
+        self.humidity = calibration.humidity(self.humidity);
+        self.dew_point = calibration.dew_point(self.dew_point, self.temperature, self.humidity);
+    }
+}
+
+/// Power and sensor readings of a Pocket Powerbox Micro, answer to `PA`:
+/// `PPBM:voltage:current:temp:humidity:dewpoint:dew_power:autodew:pwr_warn`
+#[derive(Clone, Debug, PartialEq)]
+pub struct PpbmStatus {
+    pub input_voltage: f32,
+    pub current: f32,
+    pub temperature: f32,
+    pub humidity: f32,
+    pub dew_point: f32,
+    pub dew_power: u8,
+    pub autodew: bool,
+    pub pwr_warn: bool,
+}
+
+impl FromStr for PpbmStatus {
+    type Err = ParseError;
+
+    fn from_str(response: &str) -> Result<Self, ParseError> {
+        let f = Fields::new(response, &["PPBM"])?;
+        Ok(Self {
+            input_voltage: f.get(1, "input voltage")?,
+            current: f.get(2, "current")?,
+            temperature: f.get(3, "temperature")?,
+            humidity: f.get(4, "humidity")?,
+            dew_point: f.get(5, "dew point")?,
+            dew_power: f.get(6, "dew power")?,
+            autodew: f.flag(7, "autodew")?,
+            pwr_warn: f.flag(8, "power warning")?,
+        })
+    }
+}
+
+impl PpbmStatus {
+    /// Correct the sensor readings with the offsets of the device
+    pub fn calibrate(&mut self, calibration: &SensorCalibration) {
+        self.temperature = calibration.temperature(self.temperature);
+        self.humidity = calibration.humidity(self.humidity);
+        self.dew_point = calibration.dew_point(self.dew_point, self.temperature, self.humidity);
+    }
+}
+
+/// Power consumption and stats, answer to `PS`:
+/// `PS:averageAmps:ampHours:wattHours:uptime_in_milliseconds`
+#[derive(Clone, Debug, PartialEq)]
+pub struct PowerStats {
+    pub average_amps: f32,
+    pub amps_hours: f32,
+    pub watt_hours: f32,
+    pub uptime: u32,
+}
+
+impl FromStr for PowerStats {
+    type Err = ParseError;
+
+    fn from_str(response: &str) -> Result<Self, ParseError> {
+        let f = Fields::new(response, &["PS"])?;
+        Ok(Self {
+            average_amps: f.get(1, "average current")?,
+            amps_hours: f.get(2, "amp hours")?,
+            watt_hours: f.get(3, "watt hours")?,
+            uptime: f.get(4, "uptime")?,
+        })
+    }
+}
+
+impl PowerStats {
+    pub fn uptime_seconds(&self) -> u32 {
+        self.uptime / 1000
+    }
+
+    /// Uptime as `[Nd ]HH:MM:SS`
+    pub fn uptime_human(&self) -> String {
+        format_uptime(self.uptime)
+    }
+
+    /// Average power in W since the device was powered up
+    pub fn average_power(&self) -> f32 {
+        average_power(self.watt_hours, self.uptime)
+    }
+}
+
+/// Format an uptime in milliseconds as `[Nd ]HH:MM:SS`, e.g. `1d 02:03:04`
+pub fn format_uptime(uptime_ms: u32) -> String {
+    let seconds = uptime_ms / 1000;
+    let (days, hours) = (seconds / 86400, seconds / 3600 % 24);
+    let time = format!("{:02}:{:02}:{:02}", hours, seconds / 60 % 60, seconds % 60);
+    if days > 0 {
+        format!("{}d {}", days, time)
+    } else {
+        time
+    }
+}
+
+/// Energy used per hour, in W, from the watt hours used in `uptime_ms`
+pub fn average_power(watt_hours: f32, uptime_ms: u32) -> f32 {
+    if uptime_ms == 0 {
+        return 0.0;
+    }
+    watt_hours / (uptime_ms as f32 / 3_600_000.0)
+}
+
+/// Power metrics, answer to `PC`:
+/// `PC:total_current:current_12V_outputs:current_dewA:current_dewB:uptime_in_milliseconds`
+#[derive(Clone, Debug, PartialEq)]
+pub struct PowerMetrics {
+    pub total_current: f32,
+    pub current_12v_output: f32,
+    pub dew1_current: f32,
+    pub dew2_current: f32,
+    /// Not sent by older firmwares
+    pub uptime: Option<u32>,
+}
+
+impl FromStr for PowerMetrics {
+    type Err = ParseError;
+
+    fn from_str(response: &str) -> Result<Self, ParseError> {
+        Self::parse_with(response, None)
+    }
+}
+
+impl PowerMetrics {
+    /// Same as [`PpbaStatus::parse_with`] for the `PC` response
+    pub fn parse_with(response: &str, layout: Option<PpbaLayout>) -> Result<Self, ParseError> {
+        let f = Fields::new(response, &["PC"])?;
+        Ok(Self {
+            total_current: f.get(1, "total current")?,
+            current_12v_output: f.get(2, "12V outputs current")?,
+            dew1_current: f.get(3, "dew A current")?,
+            dew2_current: f.get(4, "dew B current")?,
+            uptime: f.versioned(5, "uptime", layout.map(|l| l.metrics_uptime))?,
+        })
+    }
+}
@@ -0,0 +1,1626 @@
+use crate::battery::{BatteryConfig, BatteryModel};
+use crate::calibration::SensorCalibration;
+use crate::codec::{self, Payload};
+use crate::device::{self, AstronomicalDevice, PegasusDevice, ReadTimes};
+use crate::dewpoint::Magnus;
+use crate::energy::EnergyMeter;
+use crate::error::PegasusError;
+use crate::limits::{CurrentGuard, CurrentLimits, CurrentTrip, LimitAction, Output};
+use crate::parser::{self, FirmwareVersion, PowerMetrics, PowerStats, PpbaLayout, PpbaStatus};
+use crate::ramp::DewRamp;
+use crate::smoothing::{Filter, Smoothing};
+use crate::transport::{self, RetryPolicies, SerialConfig, SerialTransport};
+use astrotools::properties::{Permission, Prop, Property};
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize, Serializer};
+use std::fmt;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// Longest `dew_ramp_ms`
+pub const MAX_DEW_RAMP_MS: u64 = 10_000;
+
+/// Properties read with `PS`
+const STATS_READINGS: &[&str] = &[
+    "current",
+    "average_amps",
+    "amps_hours",
+    "watt_hours",
+    "uptime",
+    "uptime_seconds",
+    "uptime_human",
+    "average_power",
+];
+/// Properties read with `PC`
+const METRICS_READINGS: &[&str] = &[
+    "total_current",
+    "current_12v_output",
+    "dew1_current",
+    "dew2_current",
+];
+/// Properties read with `PA`, always sent
+const STATUS_READINGS: &[&str] = &[
+    "input_voltage",
+    "current_12v_output",
+    "temperature",
+    "humidity",
+    "dew_point",
+    "dew_point_computed",
+    "dew_margin",
+];
+/// Properties read with `PA` that firmwares 1.x may not send, `adj_output`
+/// is only a reading once the firmware sent it
+const STATUS_OPTIONAL_READINGS: &[&str] = &[
+    "quadport_status",
+    "adj_output_status",
+    "dew1_power",
+    "dew1_power_pct",
+    "dew2_power",
+    "dew2_power_pct",
+    "autodew",
+    "pwr_warn",
+];
+
+/// Serializes into the values of its [`snapshot`](Self::snapshot), every
+/// one with the permission of its property
+#[derive(Debug)]
+pub struct PegasusPowerBox {
+    pub id: Uuid,
+    name: String,
+    address: String,
+    pub baud: u32,
+    pub port: Box<dyn SerialTransport>,
+    /// USB serial number, `UNKNOWN` if the device was opened without it
+    serial_number: Property<String>,
+    fw_version: Property<String>,
+    /// Parsed `fw_version`, `None` if the device answered something else
+    firmware: Option<FirmwareVersion>,
+    /// Optional properties the device is known to support, the others are always there
+    capabilities: Property<Vec<String>>,
+    reboot: Property<bool>,
+    input_voltage: Property<f32>,
+    current: Property<f32>,
+    temperature: Property<f32>,
+    humidity: Property<f32>,
+    dew_point: Property<f32>,
+    /// Added to the temperature read by the sensor, in °C
+    temperature_offset: Property<f32>,
+    /// Added to the humidity read by the sensor, in %
+    humidity_offset: Property<f32>,
+    /// Dew point computed with `dew_formula`, `dew_point` is the one of the firmware
+    dew_point_computed: Property<f32>,
+    /// Degrees the temperature is above `dew_point_computed`
+    dew_margin: Property<f32>,
+    dew_formula: Magnus,
+    quadport_status: Property<bool>,
+    adj_output_status: Property<bool>,
+    dew1_power: Property<u8>,
+    dew1_power_pct: Property<f32>,
+    dew1_current: Property<f32>,
+    dew2_power: Property<u8>,
+    dew2_power_pct: Property<f32>,
+    dew2_current: Property<f32>,
+    autodew: Property<bool>,
+    pwr_warn: Property<bool>,
+    adj_output: Property<u8>,
+    /// Voltage chosen while the adjustable output was off, sent when it's switched on
+    adj_output_pending: Option<AdjustableVoltage>,
+    average_amps: Property<f32>,
+    amps_hours: Property<f32>,
+    watt_hours: Property<f32>,
+    uptime: Property<u32>,
+    uptime_seconds: Property<u32>,
+    /// Uptime as `[Nd ]HH:MM:SS`
+    uptime_human: Property<String>,
+    /// Average power in W since the device was powered up
+    average_power: Property<f32>,
+    total_current: Property<f32>,
+    current_12v_output: Property<f32>,
+    current_guard: CurrentGuard,
+    power_status_on_boot: Property<Option<BootPowerConfig>>,
+    /// Milliseconds between two polls
+    polling_interval: Property<u64>,
+    /// Milliseconds a dew heater change is ramped over, see
+    /// [`set_dew_power`](Self::set_dew_power), 0 applies it at once
+    dew_ramp_ms: Property<u64>,
+    smoothing: Smoothing,
+    energy: EnergyMeter,
+    /// Battery powering the device, if configured
+    battery: Option<BatteryModel>,
+    /// Set commands are validated and logged but never sent
+    read_only: Property<bool>,
+    retry: RetryPolicies,
+    read_times: ReadTimes,
+    /// False once the device stopped answering
+    connected: bool,
+    /// Last state sent to the led indicator, the PPBA cannot be asked for
+    /// it and switches it on at power up
+    led: bool,
+}
+
+/// Plain copy of all the readings and settings of a PPBA at a given time.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct PowerBoxSnapshot {
+    pub id: Uuid,
+    pub name: String,
+    pub address: String,
+    pub serial_number: String,
+    pub fw_version: String,
+    pub capabilities: Vec<String>,
+    pub input_voltage: f32,
+    pub current: f32,
+    pub temperature: f32,
+    pub humidity: f32,
+    pub dew_point: f32,
+    /// Computed with the formula of the device
+    pub dew_point_computed: f32,
+    /// Degrees the temperature is above `dew_point_computed`
+    pub dew_margin: f32,
+    pub quadport_status: bool,
+    pub adj_output_status: bool,
+    pub adj_output: u8,
+    pub dew1_power: u8,
+    pub dew1_power_pct: f32,
+    pub dew1_current: f32,
+    pub dew2_power: u8,
+    pub dew2_power_pct: f32,
+    pub dew2_current: f32,
+    pub autodew: bool,
+    pub pwr_warn: bool,
+    pub average_amps: f32,
+    pub amps_hours: f32,
+    pub watt_hours: f32,
+    pub uptime: u32,
+    pub total_current: f32,
+    pub current_12v_output: f32,
+    pub tripped_outputs: Vec<Output>,
+    /// None until read back from or sent to the device
+    pub power_status_on_boot: Option<BootPowerConfig>,
+}
+
+/// Which of the four 12V outputs the PPBA switches on at power up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BootPowerConfig {
+    pub port1: bool,
+    pub port2: bool,
+    pub port3: bool,
+    pub port4: bool,
+}
+
+impl BootPowerConfig {
+    pub fn ports(&self) -> [bool; 4] {
+        [self.port1, self.port2, self.port3, self.port4]
+    }
+
+    /// Mask sent to the device, one digit per output (`1101`)
+    pub fn to_mask(&self) -> String {
+        self.ports()
+            .iter()
+            .map(|on| if *on { '1' } else { '0' })
+            .collect()
+    }
+
+    /// Parse a mask like `1101`, None unless it has exactly 4 binary digits
+    pub fn from_mask(mask: &str) -> Option<Self> {
+        let mut ports = [false; 4];
+        if mask.len() != ports.len() {
+            return None;
+        }
+        for (port, digit) in ports.iter_mut().zip(mask.chars()) {
+            *port = match digit {
+                '1' => true,
+                '0' => false,
+                _ => return None,
+            };
+        }
+        let [port1, port2, port3, port4] = ports;
+        Some(Self {
+            port1,
+            port2,
+            port3,
+            port4,
+        })
+    }
+}
+
+/// Settings of a PPBA worth restoring after a power cycle.
+///
+/// Field names match the published device state, so a state read back from
+/// `devices/{id}` deserializes into a `SavedState` too.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SavedState {
+    #[serde(deserialize_with = "prop_value")]
+    pub quadport_status: bool,
+    #[serde(deserialize_with = "prop_value")]
+    pub adj_output_status: bool,
+    #[serde(deserialize_with = "prop_value")]
+    pub adj_output: u8,
+    #[serde(deserialize_with = "prop_value")]
+    pub dew1_power: u8,
+    #[serde(deserialize_with = "prop_value")]
+    pub dew2_power: u8,
+    #[serde(deserialize_with = "prop_value")]
+    pub autodew: bool,
+    /// Missing from the files saved by older versions
+    #[serde(deserialize_with = "prop_value", default = "default_polling_interval")]
+    pub polling_interval: u64,
+    #[serde(deserialize_with = "prop_value", default)]
+    pub dew_ramp_ms: u64,
+}
+
+fn default_polling_interval() -> u64 {
+    device::DEFAULT_POLLING_INTERVAL_MS
+}
+
+impl Default for SavedState {
+    fn default() -> Self {
+        Self {
+            quadport_status: false,
+            adj_output_status: false,
+            adj_output: 0,
+            dew1_power: 0,
+            dew2_power: 0,
+            autodew: false,
+            polling_interval: default_polling_interval(),
+            dew_ramp_ms: 0,
+        }
+    }
+}
+
+/// Named set of settings applied in one go, e.g. `imaging` or `storage`.
+///
+/// Settings left out are not changed when the profile is applied.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub quadport_status: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adj_output_status: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adj_output: Option<AdjustableVoltage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dew1_power: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dew2_power: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub autodew: Option<bool>,
+}
+
+/// Accept both a bare value and a serialized `Property` (`{"value": ..}`)
+fn prop_value<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value<T> {
+        Bare(T),
+        Prop { value: T },
+    }
+
+    Ok(match Value::deserialize(deserializer)? {
+        Value::Bare(v) => v,
+        Value::Prop { value } => value,
+    })
+}
+
+/// Voltages supported by the adjustable output
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "u8", try_from = "u8")]
+pub enum AdjustableVoltage {
+    V3 = 3,
+    V5 = 5,
+    V8 = 8,
+    V9 = 9,
+    V12 = 12,
+}
+
+impl AdjustableVoltage {
+    pub const ALL: [Self; 5] = [Self::V3, Self::V5, Self::V8, Self::V9, Self::V12];
+
+    pub fn volts(self) -> u8 {
+        self as u8
+    }
+}
+
+impl From<AdjustableVoltage> for u8 {
+    fn from(voltage: AdjustableVoltage) -> u8 {
+        voltage.volts()
+    }
+}
+
+impl TryFrom<u8> for AdjustableVoltage {
+    type Error = InvalidVoltage;
+
+    fn try_from(volts: u8) -> Result<Self, InvalidVoltage> {
+        Self::ALL
+            .into_iter()
+            .find(|v| v.volts() == volts)
+            .ok_or(InvalidVoltage(volts))
+    }
+}
+
+/// Voltage not supported by the adjustable output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidVoltage(pub u8);
+
+impl fmt::Display for InvalidVoltage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid adjustable output voltage: {}, must be one of {:?}",
+            self.0,
+            AdjustableVoltage::ALL.map(u8::from)
+        )
+    }
+}
+
+impl std::error::Error for InvalidVoltage {}
+
+/// What a [`PegasusPowerBox`] serializes into: the values of its
+/// [`PowerBoxSnapshot`] with the permissions of its properties, followed by
+/// the settings and counters the snapshot doesn't have
+#[derive(Serialize)]
+struct PublishedState<'a> {
+    name: String,
+    address: String,
+    baud: u32,
+    serial_number: Property<String>,
+    fw_version: Property<String>,
+    capabilities: Property<Vec<String>>,
+    reboot: &'a Property<bool>,
+    input_voltage: Property<f32>,
+    current: Property<f32>,
+    temperature: Property<f32>,
+    humidity: Property<f32>,
+    dew_point: Property<f32>,
+    temperature_offset: &'a Property<f32>,
+    humidity_offset: &'a Property<f32>,
+    dew_point_computed: Property<f32>,
+    dew_margin: Property<f32>,
+    quadport_status: Property<bool>,
+    adj_output_status: Property<bool>,
+    dew1_power: Property<u8>,
+    dew1_power_pct: Property<f32>,
+    dew1_current: Property<f32>,
+    dew2_power: Property<u8>,
+    dew2_power_pct: Property<f32>,
+    dew2_current: Property<f32>,
+    autodew: Property<bool>,
+    pwr_warn: Property<bool>,
+    #[serde(serialize_with = "with_voltage_choices")]
+    adj_output: Property<u8>,
+    average_amps: Property<f32>,
+    amps_hours: Property<f32>,
+    watt_hours: Property<f32>,
+    uptime: Property<u32>,
+    uptime_seconds: &'a Property<u32>,
+    uptime_human: &'a Property<String>,
+    average_power: &'a Property<f32>,
+    total_current: Property<f32>,
+    current_12v_output: Property<f32>,
+    tripped_outputs: Vec<Output>,
+    power_status_on_boot: Property<Option<BootPowerConfig>>,
+    polling_interval: &'a Property<u64>,
+    dew_ramp_ms: &'a Property<u64>,
+    #[serde(flatten)]
+    smoothing: &'a Smoothing,
+    #[serde(flatten)]
+    energy: &'a EnergyMeter,
+    #[serde(flatten)]
+    battery: &'a Option<BatteryModel>,
+    read_only: &'a Property<bool>,
+}
+
+/// `prop` holding `value` instead of its own
+fn with_value<T: Clone>(prop: &Property<T>, value: T) -> Property<T> {
+    let mut prop = prop.clone();
+    prop.update_int(value);
+    prop
+}
+
+impl Serialize for PegasusPowerBox {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let snapshot = self.snapshot();
+        PublishedState {
+            name: snapshot.name,
+            address: snapshot.address,
+            baud: self.baud,
+            serial_number: with_value(&self.serial_number, snapshot.serial_number),
+            fw_version: with_value(&self.fw_version, snapshot.fw_version),
+            capabilities: with_value(&self.capabilities, snapshot.capabilities),
+            reboot: &self.reboot,
+            input_voltage: with_value(&self.input_voltage, snapshot.input_voltage),
+            current: with_value(&self.current, snapshot.current),
+            temperature: with_value(&self.temperature, snapshot.temperature),
+            humidity: with_value(&self.humidity, snapshot.humidity),
+            dew_point: with_value(&self.dew_point, snapshot.dew_point),
+            temperature_offset: &self.temperature_offset,
+            humidity_offset: &self.humidity_offset,
+            dew_point_computed: with_value(&self.dew_point_computed, snapshot.dew_point_computed),
+            dew_margin: with_value(&self.dew_margin, snapshot.dew_margin),
+            quadport_status: with_value(&self.quadport_status, snapshot.quadport_status),
+            adj_output_status: with_value(&self.adj_output_status, snapshot.adj_output_status),
+            dew1_power: with_value(&self.dew1_power, snapshot.dew1_power),
+            dew1_power_pct: with_value(&self.dew1_power_pct, snapshot.dew1_power_pct),
+            dew1_current: with_value(&self.dew1_current, snapshot.dew1_current),
+            dew2_power: with_value(&self.dew2_power, snapshot.dew2_power),
+            dew2_power_pct: with_value(&self.dew2_power_pct, snapshot.dew2_power_pct),
+            dew2_current: with_value(&self.dew2_current, snapshot.dew2_current),
+            autodew: with_value(&self.autodew, snapshot.autodew),
+            pwr_warn: with_value(&self.pwr_warn, snapshot.pwr_warn),
+            adj_output: with_value(&self.adj_output, snapshot.adj_output),
+            average_amps: with_value(&self.average_amps, snapshot.average_amps),
+            amps_hours: with_value(&self.amps_hours, snapshot.amps_hours),
+            watt_hours: with_value(&self.watt_hours, snapshot.watt_hours),
+            uptime: with_value(&self.uptime, snapshot.uptime),
+            uptime_seconds: &self.uptime_seconds,
+            uptime_human: &self.uptime_human,
+            average_power: &self.average_power,
+            total_current: with_value(&self.total_current, snapshot.total_current),
+            current_12v_output: with_value(&self.current_12v_output, snapshot.current_12v_output),
+            tripped_outputs: snapshot.tripped_outputs,
+            power_status_on_boot: with_value(
+                &self.power_status_on_boot,
+                snapshot.power_status_on_boot,
+            ),
+            polling_interval: &self.polling_interval,
+            dew_ramp_ms: &self.dew_ramp_ms,
+            smoothing: &self.smoothing,
+            energy: &self.energy,
+            battery: &self.battery,
+            read_only: &self.read_only,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Publish the adjustable output with the voltages it accepts
+fn with_voltage_choices<S>(prop: &Property<u8>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    #[derive(Serialize)]
+    struct WithChoices<'a> {
+        #[serde(flatten)]
+        prop: &'a Property<u8>,
+        choices: [AdjustableVoltage; 5],
+    }
+
+    WithChoices {
+        prop,
+        choices: AdjustableVoltage::ALL,
+    }
+    .serialize(serializer)
+}
+
+/// The two PWM controlled dew heater outputs
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DewChannel {
+    /// Dew heater A, exposed as `dew1_power` (or `dew_a_power`)
+    A,
+    /// Dew heater B, exposed as `dew2_power` (or `dew_b_power`)
+    B,
+}
+
+impl DewChannel {
+    /// Channel of a PWM (0-255) property name
+    pub fn from_prop_name(prop_name: &str) -> Option<Self> {
+        match prop_name {
+            "dew1_power" | "dew_a_power" => Some(Self::A),
+            "dew2_power" | "dew_b_power" => Some(Self::B),
+            _ => None,
+        }
+    }
+
+    /// Channel of a percentage (0-100) property name
+    pub fn from_pct_prop_name(prop_name: &str) -> Option<Self> {
+        prop_name
+            .strip_suffix("_pct")
+            .and_then(Self::from_prop_name)
+    }
+}
+
+/// Convert a dew heater power percentage (0-100) to the PWM duty cycle the device expects
+pub fn pct_to_pwm(pct: f32) -> Result<u8, PegasusError> {
+    if !(0.0..=100.0).contains(&pct) {
+        return Err(PegasusError::Validation(format!(
+            "Invalid dew heater power: {}%",
+            pct
+        )));
+    }
+    Ok((pct * 255.0 / 100.0).round() as u8)
+}
+
+pub fn pwm_to_pct(pwm: u8) -> f32 {
+    pwm as f32 * 100.0 / 255.0
+}
+
+/// PWM bringing a dew heater drawing `current` at `pwm` 10 % under `limit`,
+/// the current of a heater is about proportional to its PWM
+fn reduced_pwm(pwm: u8, current: f32, limit: f32) -> u8 {
+    let ratio = (limit / current * 0.9).clamp(0.0, 1.0);
+    (f32::from(pwm) * ratio).floor() as u8
+}
+
+/// Settings a client can change on a PPBA
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Setting {
+    /// Quad 12V output on or off, `quadport_status`
+    Quadport(bool),
+    /// Adjustable output on or off, `adj_output_status`
+    AdjOutputStatus(bool),
+    /// Voltage of the adjustable output, `adj_output`, applied when the
+    /// output is switched on
+    AdjOutput(AdjustableVoltage),
+    /// PWM duty cycle of a dew heater, `dew1_power` and `dew2_power` or
+    /// `dew1_power_pct` and `dew2_power_pct` as a percentage
+    DewPower(DewChannel, u8),
+    /// Automatic dew heaters control, `autodew`
+    Autodew(bool),
+    /// Re-arm an output tripped for over current, `reset_trip`
+    ResetTrip(Output),
+    /// Start the energy counters from 0, `reset_energy`
+    ResetEnergy,
+    /// Outputs switched on at power up, `power_status_on_boot`
+    PowerOnBoot(BootPowerConfig),
+    /// Milliseconds between two polls, `polling_interval`
+    PollingInterval(u64),
+    /// Milliseconds dew heater changes are ramped over, `dew_ramp_ms`
+    DewRamp(u64),
+    /// Offsets of the sensor, `temperature_offset` and `humidity_offset`
+    TemperatureOffset(f32),
+    HumidityOffset(f32),
+}
+
+// The whole protocol is mapped here even if not every command is issued yet
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+enum Command {
+    /// Adjustable 12V Output SET command is P2:
+    Adj12VOutput = 0x50323a,
+    /// DewA power SET command is P3:
+    Dew1Power = 0x50333a,
+    /// DewB power SET command is P4:
+    Dew2Power = 0x50343a,
+    /// Status command serial code is P#
+    Status = 0x5023,
+    /// Firmware version command serial code is PV
+    FirmwareVersion = 0x5056,
+    /// Power consumption and stats serial code is PS
+    PowerConsumAndStats = 0x5053,
+    /// Power metrics serial code is PC
+    PowerMetrics = 0x5043,
+    /// Power and sensor reading serial code is PA
+    PowerAndSensorReadings = 0x5041,
+    /// Power status on boot SET command is PE:
+    PowerStatusOnBoot = 0x50453a,
+    /// Power status on boot query is PE, not supported by older firmwares
+    PowerStatusOnBootQuery = 0x5045,
+    /// Quad port boot status SET command is P1:
+    QuadPortStatus = 0x50313a,
+    /// Reboot command is PF
+    Reboot = 0x5046,
+    /// Led indicator SET command is PL:
+    Led = 0x504c3a,
+    /// Auto dew SET command is PD:
+    AutoDew = 0x50443a,
+}
+
+impl Command {
+    /// Start of the response of the queries, to tell it from a late answer
+    /// to another command
+    fn response_prefix(self) -> Option<&'static str> {
+        match self {
+            Self::PowerAndSensorReadings => Some("PPBA:"),
+            Self::PowerConsumAndStats => Some("PS:"),
+            Self::PowerMetrics => Some("PC:"),
+            Self::PowerStatusOnBootQuery => Some("PE:"),
+            _ => None,
+        }
+    }
+}
+
+trait Pegasus {
+    async fn update_firmware_version(&mut self);
+    async fn update_power_consumption_and_stats(&mut self);
+    async fn update_power_metrics(&mut self);
+    async fn update_power_and_sensor_readings(&mut self);
+}
+
+impl PegasusPowerBox {
+    /// Open the device at `address`, fails if it cannot be reached
+    pub async fn try_new(
+        name: &str,
+        address: &str,
+        baud: u32,
+        timeout_ms: u64,
+    ) -> Result<Self, PegasusError> {
+        Self::try_with_config(name, address, &SerialConfig::new(baud, timeout_ms)).await
+    }
+
+    /// Same as [`PegasusPowerBox::try_new`] with every setting of the serial link,
+    /// e.g. flow control or a pause between commands.
+    pub async fn try_with_config(
+        name: &str,
+        address: &str,
+        config: &SerialConfig,
+    ) -> Result<Self, PegasusError> {
+        let port = transport::open_with_config(address, config).await?;
+        Self::new_with_port(name, address, config.baud, port).await
+    }
+
+    /// Build a device on top of an already open transport, e.g. a
+    /// [`FakePpbaPort`](crate::sim::FakePpbaPort) to run without hardware.
+    pub async fn new_with_port(
+        name: &str,
+        address: &str,
+        baud: u32,
+        port: Box<dyn SerialTransport>,
+    ) -> Result<Self, PegasusError> {
+        let mut dev = Self {
+            id: Uuid::new_v4(),
+            name: name.to_owned(),
+            address: address.to_owned(),
+            baud,
+            port,
+            serial_number: Property::<String>::new("UNKNOWN".to_string(), Permission::ReadOnly),
+            fw_version: Property::<String>::new("UNKNOWN".to_string(), Permission::ReadOnly),
+            firmware: None,
+            capabilities: Property::<Vec<String>>::new(Vec::new(), Permission::ReadOnly),
+            reboot: Property::<bool>::new(false, Permission::ReadWrite),
+            input_voltage: Property::<f32>::new(0.0, Permission::ReadOnly),
+            current: Property::<f32>::new(0.0, Permission::ReadOnly),
+            temperature: Property::<f32>::new(0.0, Permission::ReadOnly),
+            humidity: Property::<f32>::new(0.0, Permission::ReadOnly),
+            dew_point: Property::<f32>::new(0.0, Permission::ReadOnly),
+            temperature_offset: Property::<f32>::new(0.0, Permission::ReadWrite),
+            humidity_offset: Property::<f32>::new(0.0, Permission::ReadWrite),
+            dew_point_computed: Property::<f32>::new(0.0, Permission::ReadOnly),
+            dew_margin: Property::<f32>::new(0.0, Permission::ReadOnly),
+            dew_formula: Magnus::default(),
+            quadport_status: Property::<bool>::new(false, Permission::ReadWrite),
+            adj_output: Property::<u8>::new(0, Permission::ReadWrite),
+            adj_output_status: Property::<bool>::new(false, Permission::ReadWrite),
+            adj_output_pending: None,
+            dew1_power: Property::<u8>::new(0, Permission::ReadWrite),
+            dew1_power_pct: Property::<f32>::new(0.0, Permission::ReadWrite),
+            dew1_current: Property::<f32>::new(0.0, Permission::ReadOnly),
+            dew2_power: Property::<u8>::new(0, Permission::ReadWrite),
+            dew2_power_pct: Property::<f32>::new(0.0, Permission::ReadWrite),
+            dew2_current: Property::<f32>::new(0.0, Permission::ReadOnly),
+            autodew: Property::<bool>::new(false, Permission::ReadWrite),
+            pwr_warn: Property::<bool>::new(false, Permission::ReadOnly),
+            average_amps: Property::<f32>::new(0.0, Permission::ReadOnly),
+            amps_hours: Property::<f32>::new(0.0, Permission::ReadOnly),
+            watt_hours: Property::<f32>::new(0.0, Permission::ReadOnly),
+            uptime: Property::<u32>::new(0, Permission::ReadOnly),
+            uptime_seconds: Property::<u32>::new(0, Permission::ReadOnly),
+            uptime_human: Property::<String>::new(parser::format_uptime(0), Permission::ReadOnly),
+            average_power: Property::<f32>::new(0.0, Permission::ReadOnly),
+            total_current: Property::<f32>::new(0.0, Permission::ReadOnly),
+            current_12v_output: Property::<f32>::new(0.0, Permission::ReadOnly),
+            current_guard: CurrentGuard::default(),
+            power_status_on_boot: Property::<Option<BootPowerConfig>>::new(
+                None,
+                Permission::ReadWrite,
+            ),
+            polling_interval: Property::<u64>::new(
+                device::DEFAULT_POLLING_INTERVAL_MS,
+                Permission::ReadWrite,
+            ),
+            dew_ramp_ms: Property::<u64>::new(0, Permission::ReadWrite),
+            smoothing: Smoothing::default(),
+            energy: EnergyMeter::default(),
+            battery: None,
+            read_only: Property::new(false, Permission::ReadOnly),
+            retry: RetryPolicies::default(),
+            read_times: ReadTimes::new(
+                &[
+                    STATS_READINGS,
+                    METRICS_READINGS,
+                    STATUS_READINGS,
+                    STATUS_OPTIONAL_READINGS,
+                ]
+                .concat(),
+            ),
+            connected: true,
+            led: true,
+        };
+
+        dev.send_command(Command::Status, None).await?;
+        dev.update_firmware_version().await;
+        if let Err(e) = dev.read_power_on_boot().await {
+            debug!(
+                "Cannot read the power on boot config of {}: {}",
+                dev.name, e
+            );
+        }
+        dev.fetch_props().await;
+        Ok(dev)
+    }
+
+    async fn send_command(
+        &mut self,
+        comm: Command,
+        payload: Option<Payload>,
+    ) -> Result<String, PegasusError> {
+        let command = codec::Command::new(comm as u32, payload).expecting(comm.response_prefix());
+        // Only the set commands have a value
+        if self.is_read_only() && command.payload().is_some() {
+            return Err(device::not_sent(&self.name, &command));
+        }
+        let res =
+            transport::send_command_with_retry(self.port.as_mut(), &command, &self.retry).await;
+        // A device answering with an error is still there, anything else means the link is gone
+        self.connected = !matches!(&res, Err(e) if e.link_failed());
+        res
+    }
+
+    /// Ask the device for its status, a healthy PPBA answers `PPBA_OK`.
+    pub async fn check_status(&mut self) -> Result<String, PegasusError> {
+        self.send_command(Command::Status, None).await
+    }
+
+    /// Read again the firmware version from the device.
+    pub async fn read_firmware_version(&mut self) -> Result<String, PegasusError> {
+        let fw = self.send_command(Command::FirmwareVersion, None).await?;
+        self.store_firmware_version(&fw);
+        Ok(fw)
+    }
+
+    /// Firmware version parsed from `fw_version`
+    pub fn firmware(&self) -> Option<FirmwareVersion> {
+        self.firmware
+    }
+
+    /// Layout of the responses of the firmware, `None` while it's unknown
+    fn layout(&self) -> Option<PpbaLayout> {
+        self.firmware.as_ref().map(PpbaLayout::for_firmware)
+    }
+
+    fn store_firmware_version(&mut self, fw: &str) {
+        self.fw_version.update_int(fw.to_owned());
+        self.firmware = match fw.parse() {
+            Ok(version) => Some(version),
+            Err(e) => {
+                warn!(
+                    "Unknown firmware of {}, reading every field: {}",
+                    self.name, e
+                );
+                None
+            }
+        };
+        if let Some(layout) = self.layout() {
+            if layout.adj_output_voltage {
+                self.add_capability("adj_output");
+            }
+        }
+    }
+
+    fn add_capability(&mut self, name: &str) {
+        if !self.capabilities.value().iter().any(|c| c == name) {
+            let mut capabilities = self.capabilities.value().clone();
+            capabilities.push(name.to_owned());
+            self.capabilities.update_int(capabilities);
+        }
+    }
+
+    /// Choose which 12V outputs are switched on when the device powers up.
+    pub async fn set_power_on_boot(&mut self, config: BootPowerConfig) -> Result<(), PegasusError> {
+        self.send_command(
+            Command::PowerStatusOnBoot,
+            Some(Payload::Text(config.to_mask())),
+        )
+        .await?;
+        self.power_status_on_boot.update_int(Some(config));
+        Ok(())
+    }
+
+    /// Read back the power on boot config, only recent firmwares answer the `PE` query.
+    pub async fn read_power_on_boot(&mut self) -> Result<BootPowerConfig, PegasusError> {
+        // Not going through self.send_command, a firmware ignoring the query
+        // must not flag the device as disconnected
+        let query = codec::Command::new(Command::PowerStatusOnBootQuery as u32, None)
+            .expecting(Command::PowerStatusOnBootQuery.response_prefix());
+        let res = transport::send_command(self.port.as_mut(), &query).await?;
+        let config = BootPowerConfig::from_mask(res.trim_start_matches("PE:"))
+            .ok_or(PegasusError::Parse(res))?;
+        self.power_status_on_boot.update_int(Some(config));
+        self.add_capability("power_status_on_boot");
+        Ok(config)
+    }
+
+    /// Reboot the device, it doesn't answer and is unreachable until it's back up.
+    pub async fn reboot(&mut self) -> Result<(), PegasusError> {
+        let reboot = codec::Command::new(Command::Reboot as u32, None);
+        if self.is_read_only() {
+            return Err(device::not_sent(&self.name, &reboot));
+        }
+        // Not retried, every attempt would reboot the device once more
+        match transport::send_command(self.port.as_mut(), &reboot).await {
+            Ok(_) => (),
+            Err(e) if e.is_timeout() => (),
+            Err(e) => return Err(e),
+        }
+        self.connected = false;
+        Ok(())
+    }
+
+    /// Switch the led indicator on or off.
+    pub async fn set_led(&mut self, on: bool) -> Result<(), PegasusError> {
+        self.send_command(Command::Led, Some(Payload::Flag(on)))
+            .await?;
+        self.led = on;
+        Ok(())
+    }
+
+    /// Whether the led indicator is on, as last set by the driver
+    pub fn led(&self) -> bool {
+        self.led
+    }
+
+    /// Set the voltage of the adjustable output.
+    ///
+    /// `P2:nn` also switches the output on, so a switched off output only
+    /// keeps the voltage, it's sent when the output is switched on.
+    pub async fn set_adjustable_voltage(
+        &mut self,
+        voltage: AdjustableVoltage,
+    ) -> Result<(), PegasusError> {
+        let payload = Some(Payload::Number(voltage.volts().into()));
+        if *self.adj_output_status.value() {
+            self.send_command(Command::Adj12VOutput, payload).await?;
+            self.adj_output_pending = None;
+        } else if self.is_read_only() {
+            let command = codec::Command::new(Command::Adj12VOutput as u32, payload);
+            return Err(device::not_sent(&self.name, &command));
+        } else {
+            debug!(
+                "{} kept for when the adjustable output is on",
+                voltage.volts()
+            );
+            self.adj_output_pending = Some(voltage);
+        }
+        self.adj_output.update_int(voltage.volts());
+        Ok(())
+    }
+
+    /// Switch the adjustable output on or off keeping its voltage.
+    pub async fn set_adj_output_status(&mut self, on: bool) -> Result<(), PegasusError> {
+        let payload = match self.adj_output_pending.filter(|_| on) {
+            Some(voltage) => Payload::Number(voltage.volts().into()),
+            None => Payload::Flag(on),
+        };
+        self.send_command(Command::Adj12VOutput, Some(payload))
+            .await?;
+        if on {
+            self.adj_output_pending = None;
+        }
+        self.adj_output_status.update_int(on);
+        Ok(())
+    }
+
+    /// Bring the adjustable output to the given state and voltage, `None`
+    /// leaves them as they are. Returns the result of every command sent.
+    async fn set_adj_output(
+        &mut self,
+        on: Option<bool>,
+        voltage: Option<AdjustableVoltage>,
+    ) -> Vec<Result<(), PegasusError>> {
+        let mut results = Vec::new();
+        // Switched off first so the new voltage is only kept, it doesn't
+        // power the output for a moment
+        if on == Some(false) {
+            results.push(self.set_adj_output_status(false).await);
+        }
+        if let Some(voltage) = voltage {
+            results.push(self.set_adjustable_voltage(voltage).await);
+        }
+        if on == Some(true) && !*self.adj_output_status.value() {
+            results.push(self.set_adj_output_status(true).await);
+        }
+        results
+    }
+
+    /// Let the device drive the dew heaters on its own from the dew point or
+    /// go back to the manually set PWM values.
+    pub async fn set_autodew(&mut self, on: bool) -> Result<(), PegasusError> {
+        self.send_command(Command::AutoDew, Some(Payload::Flag(on)))
+            .await?;
+        self.autodew.update_int(on);
+        Ok(())
+    }
+
+    pub fn dew_power(&self, channel: DewChannel) -> u8 {
+        match channel {
+            DewChannel::A => *self.dew1_power.value(),
+            DewChannel::B => *self.dew2_power.value(),
+        }
+    }
+
+    /// Set the PWM duty cycle (0-255) of the given dew heater output.
+    ///
+    /// When `dew_ramp_ms` is set, changes bigger than
+    /// [`DEFAULT_MAX_STEP`](crate::ramp::DEFAULT_MAX_STEP) are split in steps
+    /// spread over it and the device is busy until the last one is sent.
+    /// Drivers sharing the device ramp with [`DewRamp::over`] and send every
+    /// step with [`set_dew_power_at_once`](Self::set_dew_power_at_once)
+    /// instead.
+    pub async fn set_dew_power(
+        &mut self,
+        channel: DewChannel,
+        pwm: u8,
+    ) -> Result<(), PegasusError> {
+        let from = self.dew_power(channel);
+        let ramp = DewRamp::OFF.over(self.dew_ramp(), from, pwm);
+        let steps = ramp.steps(from, pwm);
+        if steps.len() > 1 {
+            debug!("Ramping dew heater {:?} through {:?}", channel, steps);
+        }
+        for (i, step) in steps.into_iter().enumerate() {
+            if i > 0 {
+                tokio::time::sleep(ramp.interval).await;
+            }
+            self.set_dew_power_at_once(channel, step).await?;
+        }
+        Ok(())
+    }
+
+    /// Same as [`set_dew_power`](Self::set_dew_power) skipping the ramp
+    pub async fn set_dew_power_at_once(
+        &mut self,
+        channel: DewChannel,
+        pwm: u8,
+    ) -> Result<(), PegasusError> {
+        let (comm, output) = match channel {
+            DewChannel::A => (Command::Dew1Power, Output::Dew1),
+            DewChannel::B => (Command::Dew2Power, Output::Dew2),
+        };
+
+        if pwm > 0 && self.current_guard.is_tripped(output) {
+            return Err(PegasusError::Validation(format!(
+                "{:?} tripped for over current, reset it first",
+                output
+            )));
+        }
+        self.send_command(comm, Some(Payload::Pwm(pwm))).await?;
+        self.store_dew_power(channel, pwm);
+        Ok(())
+    }
+
+    /// Spread the dew heater changes over `ms` milliseconds, at most
+    /// [`MAX_DEW_RAMP_MS`], to avoid current spikes on marginal supplies.
+    /// 0 applies them at once.
+    pub fn set_dew_ramp(&mut self, ms: u64) -> Result<(), PegasusError> {
+        if ms > MAX_DEW_RAMP_MS {
+            return Err(PegasusError::Validation(format!(
+                "Dew ramp of {}ms is above the maximum of {}ms",
+                ms, MAX_DEW_RAMP_MS
+            )));
+        }
+        self.dew_ramp_ms.update_int(ms);
+        Ok(())
+    }
+
+    /// Milliseconds dew heater changes are ramped over
+    pub fn dew_ramp(&self) -> Duration {
+        Duration::from_millis(*self.dew_ramp_ms.value())
+    }
+
+    /// Same as [`set_dew_power`](Self::set_dew_power) with a 0-100 percentage.
+    pub async fn set_dew_power_percent(
+        &mut self,
+        channel: DewChannel,
+        pct: f32,
+    ) -> Result<(), PegasusError> {
+        self.set_dew_power(channel, pct_to_pwm(pct)?).await
+    }
+
+    /// Keep the PWM and percentage properties of a dew heater in sync
+    fn store_dew_power(&mut self, channel: DewChannel, pwm: u8) {
+        let (power, pct) = match channel {
+            DewChannel::A => (&mut self.dew1_power, &mut self.dew1_power_pct),
+            DewChannel::B => (&mut self.dew2_power, &mut self.dew2_power_pct),
+        };
+        power.update_int(pwm);
+        pct.update_int(pwm_to_pct(pwm));
+    }
+
+    /// Switch the quad 12V output on or off.
+    pub async fn set_quadport(&mut self, on: bool) -> Result<(), PegasusError> {
+        if on && self.current_guard.is_tripped(Output::Quadport) {
+            return Err(PegasusError::Validation(
+                "Quadport tripped for over current, reset it first".to_string(),
+            ));
+        }
+        self.send_command(Command::QuadPortStatus, Some(Payload::Flag(on)))
+            .await?;
+        self.quadport_status.update_int(on);
+        Ok(())
+    }
+
+    pub fn set_current_limits(&mut self, limits: CurrentLimits) {
+        self.current_guard = CurrentGuard::new(limits);
+    }
+
+    /// Change the pause between two polls, at least
+    /// [`MIN_POLLING_INTERVAL_MS`](device::MIN_POLLING_INTERVAL_MS).
+    pub fn set_polling_interval(&mut self, ms: u64) -> Result<(), PegasusError> {
+        let ms = device::check_polling_interval(ms)?;
+        self.polling_interval.update_int(ms);
+        Ok(())
+    }
+
+    /// Identify the device by its USB serial number, its id is derived from it
+    /// with [`device_id`](device::device_id) instead of being random.
+    pub fn set_serial_number(&mut self, serial_number: &str) {
+        self.id = device::device_id(serial_number);
+        self.serial_number.update_int(serial_number.to_owned());
+    }
+
+    /// Reuse the id a device without serial number had, e.g. before a restart
+    pub fn set_id(&mut self, id: Uuid) {
+        self.id = id;
+    }
+
+    /// Correct the readings of the sensor from the next poll on
+    pub fn set_calibration(&mut self, calibration: SensorCalibration) -> Result<(), PegasusError> {
+        calibration.validate()?;
+        self.temperature_offset.update_int(calibration.temperature);
+        self.humidity_offset.update_int(calibration.humidity);
+        Ok(())
+    }
+
+    pub fn calibration(&self) -> SensorCalibration {
+        SensorCalibration {
+            temperature: *self.temperature_offset.value(),
+            humidity: *self.humidity_offset.value(),
+        }
+    }
+
+    /// Coefficients `dew_point_computed` is computed with from the next poll on
+    pub fn set_dew_point_formula(&mut self, formula: Magnus) -> Result<(), PegasusError> {
+        formula.validate()?;
+        self.dew_formula = formula;
+        Ok(())
+    }
+
+    /// Compute the dew point and the margin from the last readings
+    fn update_dew_margin(&mut self) {
+        let temperature = *self.temperature.value();
+        let dew_point = self
+            .dew_formula
+            .dew_point(temperature, *self.humidity.value());
+        self.dew_point_computed.update_int(dew_point);
+        self.dew_margin.update_int(temperature - dew_point);
+    }
+
+    /// Also expose a smoothed copy of a noisy reading, as `{reading}_smoothed`.
+    pub fn set_smoothing(&mut self, reading: &str, filter: Filter) -> Result<(), PegasusError> {
+        if self.reading(reading).is_none() {
+            return Err(PegasusError::Unsupported(format!(
+                "{} is not a reading of {}",
+                reading, self.name
+            )));
+        }
+        self.smoothing.set(reading, filter)
+    }
+
+    /// Smoothed value of a reading, if smoothing was enabled for it
+    pub fn smoothed(&self, reading: &str) -> Option<f32> {
+        self.smoothing.get(reading)
+    }
+
+    /// Estimate the charge of the battery powering the device at every poll
+    pub fn set_battery(&mut self, config: BatteryConfig) -> Result<(), PegasusError> {
+        self.battery = Some(BatteryModel::new(config)?);
+        Ok(())
+    }
+
+    pub fn battery(&self) -> Option<&BatteryModel> {
+        self.battery.as_ref()
+    }
+
+    /// Energy drawn by every output since the driver started
+    pub fn energy(&self) -> &EnergyMeter {
+        &self.energy
+    }
+
+    /// Account the power drawn since the last poll, the adjustable output
+    /// draws what the total current has on top of the other outputs.
+    fn record_energy(&mut self) {
+        let quadport = *self.current_12v_output.value();
+        let dew1 = *self.dew1_current.value();
+        let dew2 = *self.dew2_current.value();
+        let adjustable = *self.total_current.value() - quadport - dew1 - dew2;
+        self.energy.record(
+            Instant::now(),
+            chrono::Local::now().date_naive(),
+            *self.input_voltage.value(),
+            [quadport, dew1, dew2, adjustable],
+        );
+    }
+
+    /// Raw value of the readings that can be smoothed
+    fn reading(&self, name: &str) -> Option<f32> {
+        let prop = match name {
+            "input_voltage" => &self.input_voltage,
+            "current" => &self.current,
+            "temperature" => &self.temperature,
+            "humidity" => &self.humidity,
+            "dew_point" => &self.dew_point,
+            "dew_point_computed" => &self.dew_point_computed,
+            "dew_margin" => &self.dew_margin,
+            "dew1_current" => &self.dew1_current,
+            "dew2_current" => &self.dew2_current,
+            "average_amps" => &self.average_amps,
+            "total_current" => &self.total_current,
+            "current_12v_output" => &self.current_12v_output,
+            _ => return None,
+        };
+        Some(*prop.value())
+    }
+
+    /// Change how commands the device doesn't answer are retried.
+    pub fn set_retry_policies(&mut self, retry: RetryPolicies) {
+        self.retry = retry;
+    }
+
+    /// In read-only mode the settings are validated but not sent, changing
+    /// one fails with [`PegasusError::ReadOnly`].
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only.update_int(read_only);
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        *self.read_only.value()
+    }
+
+    /// Settings to save to restore them later with [`restore_settings`].
+    ///
+    /// [`restore_settings`]: PegasusPowerBox::restore_settings
+    pub fn saved_state(&self) -> SavedState {
+        SavedState {
+            quadport_status: *self.quadport_status.value(),
+            adj_output_status: *self.adj_output_status.value(),
+            adj_output: *self.adj_output.value(),
+            dew1_power: *self.dew1_power.value(),
+            dew2_power: *self.dew2_power.value(),
+            autodew: *self.autodew.value(),
+            polling_interval: *self.polling_interval.value(),
+            dew_ramp_ms: *self.dew_ramp_ms.value(),
+        }
+    }
+
+    /// Apply previously saved settings to the hardware, e.g. after a power cycle.
+    ///
+    /// Every setting is applied even if a previous one failed, the first error is returned.
+    pub async fn restore_settings(&mut self, state: &SavedState) -> Result<(), PegasusError> {
+        info!("Restoring settings of {}: {:?}", self.name, state);
+        let mut results = vec![
+            self.set_polling_interval(state.polling_interval),
+            self.set_dew_ramp(state.dew_ramp_ms),
+            self.set_quadport(state.quadport_status).await,
+        ];
+
+        let voltage = AdjustableVoltage::try_from(state.adj_output).ok();
+        results.extend(
+            self.set_adj_output(Some(state.adj_output_status), voltage)
+                .await,
+        );
+        results.push(self.set_dew_power(DewChannel::A, state.dew1_power).await);
+        results.push(self.set_dew_power(DewChannel::B, state.dew2_power).await);
+        // Auto dew last, it takes over the PWM values just set
+        results.push(self.set_autodew(state.autodew).await);
+
+        results.into_iter().collect()
+    }
+
+    /// Current settings as a profile, every setting included
+    pub fn capture_profile(&self) -> Profile {
+        Profile {
+            quadport_status: Some(*self.quadport_status.value()),
+            adj_output_status: Some(*self.adj_output_status.value()),
+            adj_output: AdjustableVoltage::try_from(*self.adj_output.value()).ok(),
+            dew1_power: Some(*self.dew1_power.value()),
+            dew2_power: Some(*self.dew2_power.value()),
+            autodew: Some(*self.autodew.value()),
+        }
+    }
+
+    /// Apply the settings of a profile, the ones it doesn't have are left untouched.
+    ///
+    /// Every setting is applied even if a previous one failed, the first error is returned.
+    pub async fn apply_profile(&mut self, profile: &Profile) -> Result<(), PegasusError> {
+        info!("Applying profile to {}: {:?}", self.name, profile);
+        let mut results = Vec::new();
+        if let Some(on) = profile.quadport_status {
+            results.push(self.set_quadport(on).await);
+        }
+        results.extend(
+            self.set_adj_output(profile.adj_output_status, profile.adj_output)
+                .await,
+        );
+        if let Some(pwm) = profile.dew1_power {
+            results.push(self.set_dew_power(DewChannel::A, pwm).await);
+        }
+        if let Some(pwm) = profile.dew2_power {
+            results.push(self.set_dew_power(DewChannel::B, pwm).await);
+        }
+        // Auto dew last, it takes over the PWM values just set
+        if let Some(on) = profile.autodew {
+            results.push(self.set_autodew(on).await);
+        }
+
+        results.into_iter().collect()
+    }
+
+    /// Return all the current readings and settings in one go
+    pub fn snapshot(&self) -> PowerBoxSnapshot {
+        PowerBoxSnapshot {
+            id: self.id,
+            name: self.name.clone(),
+            address: self.address.clone(),
+            serial_number: self.serial_number.value().clone(),
+            fw_version: self.fw_version.value().clone(),
+            capabilities: self.capabilities.value().clone(),
+            input_voltage: *self.input_voltage.value(),
+            current: *self.current.value(),
+            temperature: *self.temperature.value(),
+            humidity: *self.humidity.value(),
+            dew_point: *self.dew_point.value(),
+            dew_point_computed: *self.dew_point_computed.value(),
+            dew_margin: *self.dew_margin.value(),
+            quadport_status: *self.quadport_status.value(),
+            adj_output_status: *self.adj_output_status.value(),
+            adj_output: *self.adj_output.value(),
+            dew1_power: *self.dew1_power.value(),
+            dew1_power_pct: *self.dew1_power_pct.value(),
+            dew1_current: *self.dew1_current.value(),
+            dew2_power: *self.dew2_power.value(),
+            dew2_power_pct: *self.dew2_power_pct.value(),
+            dew2_current: *self.dew2_current.value(),
+            autodew: *self.autodew.value(),
+            pwr_warn: *self.pwr_warn.value(),
+            average_amps: *self.average_amps.value(),
+            amps_hours: *self.amps_hours.value(),
+            watt_hours: *self.watt_hours.value(),
+            uptime: *self.uptime.value(),
+            total_current: *self.total_current.value(),
+            current_12v_output: *self.current_12v_output.value(),
+            tripped_outputs: self.current_guard.tripped(),
+            power_status_on_boot: *self.power_status_on_boot.value(),
+        }
+    }
+}
+
+#[async_trait]
+impl AstronomicalDevice for PegasusPowerBox {
+    fn get_id(&self) -> Uuid {
+        self.id
+    }
+
+    fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    fn get_address(&self) -> &String {
+        &self.address
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn polling_interval(&self) -> Duration {
+        Duration::from_millis(*self.polling_interval.value())
+    }
+
+    fn read_times(&self) -> Option<&ReadTimes> {
+        Some(&self.read_times)
+    }
+
+    async fn reconnect(&mut self) -> Result<(), PegasusError> {
+        self.port.reopen().await?;
+        self.send_command(Command::Status, None).await?;
+        info!("{} is back on {}", self.name, self.address);
+        Ok(())
+    }
+
+    async fn fetch_props(&mut self) {
+        info!("Fetching properties for device {}", self.name);
+        self.update_power_consumption_and_stats().await;
+        self.update_power_metrics().await;
+        self.update_power_and_sensor_readings().await;
+        if self.connected {
+            let mut smoothing = std::mem::take(&mut self.smoothing);
+            smoothing.update(|name| self.reading(name));
+            self.smoothing = smoothing;
+            self.record_energy();
+            let (volts, amps) = (*self.input_voltage.value(), *self.average_amps.value());
+            if let Some(battery) = &mut self.battery {
+                battery.update(volts, amps);
+            }
+        }
+    }
+
+    /// Check the last fetched currents against the configured limits and
+    /// switch off, or reduce the PWM of, the outputs that exceeded them for
+    /// too long.
+    async fn enforce_current_limits(&mut self) -> Vec<CurrentTrip> {
+        let snapshot = self.snapshot();
+        let mut trips = Vec::new();
+
+        for output in Output::ALL {
+            let current = match output {
+                Output::Quadport => snapshot.current_12v_output,
+                Output::Dew1 => snapshot.dew1_current,
+                Output::Dew2 => snapshot.dew2_current,
+            };
+
+            if let Some(mut trip) = self.current_guard.check(output, current) {
+                let channel = match output {
+                    Output::Quadport => None,
+                    Output::Dew1 => Some(DewChannel::A),
+                    Output::Dew2 => Some(DewChannel::B),
+                };
+                if let (LimitAction::Reduce, Some(channel)) = (trip.action, channel) {
+                    let pwm = reduced_pwm(self.dew_power(channel), trip.current, trip.limit);
+                    warn!(
+                        "Reducing {:?} on {} to {}: {:.2}A over the limit of {:.2}A",
+                        output, self.name, pwm, trip.current, trip.limit
+                    );
+                    match self.set_dew_power_at_once(channel, pwm).await {
+                        Ok(()) => trip.pwm = Some(pwm),
+                        Err(e) => error!("Cannot reduce {:?}: {}", output, e),
+                    }
+                    trips.push(trip);
+                    continue;
+                }
+                warn!(
+                    "Switching off {:?} on {}: {:.2}A over the limit of {:.2}A",
+                    output, self.name, trip.current, trip.limit
+                );
+                let res = match output {
+                    Output::Quadport => self.set_quadport(false).await,
+                    Output::Dew1 => self.set_dew_power(DewChannel::A, 0).await,
+                    Output::Dew2 => self.set_dew_power(DewChannel::B, 0).await,
+                };
+                if let Err(e) = res {
+                    error!("Cannot switch off {:?}: {}", output, e);
+                }
+                trips.push(trip);
+            }
+        }
+        trips
+    }
+
+    async fn update_property(&mut self, prop_name: &str, val: &str) -> Result<(), PegasusError> {
+        let setting = Self::parse_setting(prop_name, val)?;
+        self.apply(setting).await
+    }
+}
+
+#[async_trait]
+impl PegasusDevice for PegasusPowerBox {
+    type Setting = Setting;
+
+    fn parse_setting(prop_name: &str, val: &str) -> Result<Setting, PegasusError> {
+        let invalid =
+            || PegasusError::Validation(format!("Invalid value for {}: {}", prop_name, val));
+        let switch = || match val {
+            "1" | "true" => Ok(true),
+            "0" | "false" => Ok(false),
+            _ => Err(invalid()),
+        };
+
+        if let Some(channel) = DewChannel::from_prop_name(prop_name) {
+            let pwm = val.parse().map_err(|_| invalid())?;
+            return Ok(Setting::DewPower(channel, pwm));
+        }
+        if let Some(channel) = DewChannel::from_pct_prop_name(prop_name) {
+            let pct = val.parse().map_err(|_| invalid())?;
+            return Ok(Setting::DewPower(channel, pct_to_pwm(pct)?));
+        }
+
+        match prop_name {
+            "quadport_status" => Ok(Setting::Quadport(switch()?)),
+            "adj_output_status" => Ok(Setting::AdjOutputStatus(switch()?)),
+            "adj_output" => {
+                let volts: u8 = val.parse().map_err(|_| invalid())?;
+                AdjustableVoltage::try_from(volts)
+                    .map(Setting::AdjOutput)
+                    .map_err(|e| PegasusError::Validation(e.to_string()))
+            }
+            "autodew" => Ok(Setting::Autodew(switch()?)),
+            // Either a mask (`1101`) or the JSON of a BootPowerConfig
+            "power_status_on_boot" => BootPowerConfig::from_mask(val)
+                .or_else(|| serde_json::from_str(val).ok())
+                .map(Setting::PowerOnBoot)
+                .ok_or_else(invalid),
+            "polling_interval" => device::parse_polling_interval(val).map(Setting::PollingInterval),
+            "dew_ramp_ms" => val.parse().map(Setting::DewRamp).map_err(|_| invalid()),
+            "temperature_offset" => val
+                .parse()
+                .map(Setting::TemperatureOffset)
+                .map_err(|_| invalid()),
+            "humidity_offset" => val
+                .parse()
+                .map(Setting::HumidityOffset)
+                .map_err(|_| invalid()),
+            "reset_energy" => switch()?
+                .then_some(Setting::ResetEnergy)
+                .ok_or_else(invalid),
+            "reset_trip" => Output::from_name(val)
+                .map(Setting::ResetTrip)
+                .ok_or_else(|| PegasusError::Validation(format!("Unknown output {}", val))),
+            _ => Err(PegasusError::Unsupported(format!(
+                "Property {} cannot be updated",
+                prop_name
+            ))),
+        }
+    }
+
+    async fn apply(&mut self, setting: Setting) -> Result<(), PegasusError> {
+        match setting {
+            Setting::Quadport(on) => self.set_quadport(on).await,
+            Setting::AdjOutputStatus(on) => self.set_adj_output_status(on).await,
+            Setting::AdjOutput(voltage) => self.set_adjustable_voltage(voltage).await,
+            Setting::DewPower(channel, pwm) => self.set_dew_power(channel, pwm).await,
+            Setting::Autodew(on) => self.set_autodew(on).await,
+            Setting::PowerOnBoot(config) => self.set_power_on_boot(config).await,
+            Setting::PollingInterval(ms) => self.set_polling_interval(ms),
+            Setting::DewRamp(ms) => self.set_dew_ramp(ms),
+            Setting::TemperatureOffset(offset) => self.set_calibration(SensorCalibration {
+                temperature: offset,
+                ..self.calibration()
+            }),
+            Setting::HumidityOffset(offset) => self.set_calibration(SensorCalibration {
+                humidity: offset,
+                ..self.calibration()
+            }),
+            Setting::ResetEnergy => {
+                info!("Energy counters of {} reset", self.name);
+                self.energy.reset();
+                Ok(())
+            }
+            // Outputs switched off for over current must be explicitly re-armed
+            Setting::ResetTrip(output) => {
+                if self.current_guard.rearm(output) {
+                    info!("{:?} re-armed on {}", output, self.name);
+                    Ok(())
+                } else {
+                    Err(PegasusError::Validation(format!(
+                        "{:?} is not tripped",
+                        output
+                    )))
+                }
+            }
+        }
+    }
+}
+
+impl Pegasus for PegasusPowerBox {
+    async fn update_firmware_version(&mut self) {
+        if let Ok(fw) = self.send_command(Command::FirmwareVersion, None).await {
+            self.store_firmware_version(&fw);
+        };
+    }
+
+    async fn update_power_consumption_and_stats(&mut self) {
+        let Ok(response) = self.send_command(Command::PowerConsumAndStats, None).await else {
+            error!("Couldn't read power consumption metrics");
+            return;
+        };
+        debug!("POWER CONSUMPTIONS STATS: {}", response);
+
+        match response.parse::<PowerStats>() {
+            Ok(stats) => {
+                self.current.update_int(stats.average_amps);
+                self.average_amps.update_int(stats.average_amps);
+                self.amps_hours.update_int(stats.amps_hours);
+                self.watt_hours.update_int(stats.watt_hours);
+                self.uptime.update_int(stats.uptime);
+                self.uptime_seconds.update_int(stats.uptime_seconds());
+                self.uptime_human.update_int(stats.uptime_human());
+                self.average_power.update_int(stats.average_power());
+                self.read_times.touch(STATS_READINGS);
+            }
+            Err(e) => warn!("Ignoring power consumption stats of {}: {}", self.name, e),
+        }
+    }
+
+    async fn update_power_metrics(&mut self) {
+        let Ok(response) = self.send_command(Command::PowerMetrics, None).await else {
+            error!("Couldn't read power metrics stats");
+            return;
+        };
+        debug!("POWER METRICS STATS:{}", response);
+
+        match PowerMetrics::parse_with(&response, self.layout()) {
+            Ok(metrics) => {
+                self.total_current.update_int(metrics.total_current);
+                self.current_12v_output
+                    .update_int(metrics.current_12v_output);
+                self.dew1_current.update_int(metrics.dew1_current);
+                self.dew2_current.update_int(metrics.dew2_current);
+                self.read_times.touch(METRICS_READINGS);
+            }
+            Err(e) => warn!("Ignoring power metrics of {}: {}", self.name, e),
+        }
+    }
+
+    async fn update_power_and_sensor_readings(&mut self) {
+        let Ok(response) = self
+            .send_command(Command::PowerAndSensorReadings, None)
+            .await
+        else {
+            error!("Couldn't read power and sensors reading");
+            return;
+        };
+        debug!("POWER AND SENSORS READINGS: {}", response);
+
+        let mut status = match PpbaStatus::parse_with(&response, self.layout()) {
+            Ok(status) => status,
+            Err(e) => {
+                warn!("Ignoring power and sensors reading of {}: {}", self.name, e);
+                return;
+            }
+        };
+        status.calibrate(&self.calibration());
+        self.input_voltage.update_int(status.input_voltage);
+        self.current_12v_output
+            .update_int(status.current_12v_output);
+        self.temperature.update_int(status.temperature);
+        self.humidity.update_int(status.humidity);
+        self.dew_point.update_int(status.dew_point);
+        self.update_dew_margin();
+        self.read_times.touch(STATUS_READINGS);
+        // Older firmwares stop after fewer fields, the missing ones keep their value
+        let mut not_sent = Vec::new();
+        match status.quadport_status {
+            Some(on) => self.quadport_status.update_int(on),
+            None => not_sent.push("quadport_status"),
+        }
+        match status.adj_output_status {
+            Some(on) => self.adj_output_status.update_int(on),
+            None => not_sent.push("adj_output_status"),
+        }
+        match status.dew1_power {
+            Some(pwm) => self.store_dew_power(DewChannel::A, pwm),
+            None => not_sent.extend(["dew1_power", "dew1_power_pct"]),
+        }
+        match status.dew2_power {
+            Some(pwm) => self.store_dew_power(DewChannel::B, pwm),
+            None => not_sent.extend(["dew2_power", "dew2_power_pct"]),
+        }
+        match status.autodew {
+            Some(on) => self.autodew.update_int(on),
+            None => not_sent.push("autodew"),
+        }
+        match status.pwr_warn {
+            Some(on) => self.pwr_warn.update_int(on),
+            None => not_sent.push("pwr_warn"),
+        }
+        let sent: Vec<&'static str> = STATUS_OPTIONAL_READINGS
+            .iter()
+            .copied()
+            .filter(|name| !not_sent.contains(name))
+            .collect();
+        self.read_times.touch(&sent);
+        self.read_times.mark_not_sent(&not_sent);
+        if let Some(volts) = status.adj_output {
+            // The device reports its previous voltage until the output is on
+            if self.adj_output_pending.is_none() {
+                self.adj_output.update_int(volts);
+            }
+            self.add_capability("adj_output");
+            self.read_times.touch(&["adj_output"]);
+        }
+    }
+}
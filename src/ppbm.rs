@@ -0,0 +1,506 @@
+use crate::calibration::SensorCalibration;
+use crate::codec::{self, Payload};
+use crate::device::{self, AstronomicalDevice, PegasusDevice, ReadTimes};
+use crate::dewpoint::Magnus;
+use crate::error::PegasusError;
+use crate::parser::{self, PowerStats, PpbmStatus};
+use crate::smoothing::{Filter, Smoothing};
+use crate::transport::{self, RetryPolicies, SerialConfig, SerialTransport};
+use astrotools::properties::{Permission, Prop, Property};
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+use serde::Serialize;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Properties read with `PA`
+const STATUS_READINGS: &[&str] = &[
+    "input_voltage",
+    "current",
+    "temperature",
+    "humidity",
+    "dew_point",
+    "dew_point_computed",
+    "dew_margin",
+    "dew_power",
+    "autodew",
+    "pwr_warn",
+];
+/// Properties read with `PS`
+const STATS_READINGS: &[&str] = &[
+    "average_amps",
+    "amps_hours",
+    "watt_hours",
+    "uptime",
+    "uptime_seconds",
+    "uptime_human",
+    "average_power",
+];
+
+/// Pocket Powerbox Micro, the smaller sibling of the PPBA with a single dew
+/// heater output and the 12V outputs always on.
+#[derive(Debug, Serialize)]
+pub struct PocketPowerBoxMicro {
+    #[serde(skip)]
+    pub id: Uuid,
+    name: String,
+    address: String,
+    pub baud: u32,
+    #[serde(skip)]
+    pub port: Box<dyn SerialTransport>,
+    /// USB serial number, `UNKNOWN` if the device was opened without it
+    serial_number: Property<String>,
+    fw_version: Property<String>,
+    input_voltage: Property<f32>,
+    current: Property<f32>,
+    temperature: Property<f32>,
+    humidity: Property<f32>,
+    dew_point: Property<f32>,
+    /// Added to the temperature read by the sensor, in °C
+    temperature_offset: Property<f32>,
+    /// Added to the humidity read by the sensor, in %
+    humidity_offset: Property<f32>,
+    /// Dew point computed with `dew_formula`, `dew_point` is the one of the firmware
+    dew_point_computed: Property<f32>,
+    /// Degrees the temperature is above `dew_point_computed`
+    dew_margin: Property<f32>,
+    #[serde(skip)]
+    dew_formula: Magnus,
+    dew_power: Property<u8>,
+    autodew: Property<bool>,
+    pwr_warn: Property<bool>,
+    average_amps: Property<f32>,
+    amps_hours: Property<f32>,
+    watt_hours: Property<f32>,
+    uptime: Property<u32>,
+    uptime_seconds: Property<u32>,
+    /// Uptime as `[Nd ]HH:MM:SS`
+    uptime_human: Property<String>,
+    /// Average power in W since the device was powered up
+    average_power: Property<f32>,
+    /// Milliseconds between two polls
+    polling_interval: Property<u64>,
+    #[serde(flatten)]
+    smoothing: Smoothing,
+    /// Set commands are validated and logged but never sent
+    read_only: Property<bool>,
+    #[serde(skip)]
+    retry: RetryPolicies,
+    #[serde(skip)]
+    read_times: ReadTimes,
+    /// False once the device stopped answering
+    #[serde(skip)]
+    connected: bool,
+}
+
+// The whole protocol is mapped here even if not every command is issued yet
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+enum Command {
+    /// Status command serial code is P#
+    Status = 0x5023,
+    /// Firmware version command serial code is PV
+    FirmwareVersion = 0x5056,
+    /// Power and sensor reading serial code is PA
+    PowerAndSensorReadings = 0x5041,
+    /// Power consumption and stats serial code is PS
+    PowerConsumAndStats = 0x5053,
+    /// Dew power SET command is P3:
+    DewPower = 0x50333a,
+    /// Auto dew SET command is PD:
+    AutoDew = 0x50443a,
+    /// Led indicator SET command is PL:
+    Led = 0x504c3a,
+    /// Reboot command is PF
+    Reboot = 0x5046,
+}
+
+impl Command {
+    /// Start of the response of the queries, to tell it from a late answer
+    /// to another command
+    fn response_prefix(self) -> Option<&'static str> {
+        match self {
+            Self::PowerAndSensorReadings => Some("PPBM:"),
+            Self::PowerConsumAndStats => Some("PS:"),
+            _ => None,
+        }
+    }
+}
+
+/// Settings a client can change on a PPBM
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Setting {
+    /// PWM duty cycle of the dew heater, `dew_power`
+    DewPower(u8),
+    /// Automatic dew heater control, `autodew`
+    Autodew(bool),
+    /// Milliseconds between two polls, `polling_interval`
+    PollingInterval(u64),
+    /// Offsets of the sensor, `temperature_offset` and `humidity_offset`
+    TemperatureOffset(f32),
+    HumidityOffset(f32),
+}
+
+impl PocketPowerBoxMicro {
+    pub async fn try_new(
+        name: &str,
+        address: &str,
+        baud: u32,
+        timeout_ms: u64,
+    ) -> Result<Self, PegasusError> {
+        Self::try_with_config(name, address, &SerialConfig::new(baud, timeout_ms)).await
+    }
+
+    /// Same as [`PocketPowerBoxMicro::try_new`] with every setting of the serial link,
+    /// e.g. flow control or a pause between commands.
+    pub async fn try_with_config(
+        name: &str,
+        address: &str,
+        config: &SerialConfig,
+    ) -> Result<Self, PegasusError> {
+        let port = transport::open_with_config(address, config).await?;
+        Self::new_with_port(name, address, config.baud, port).await
+    }
+
+    /// Build a device on top of an already open transport, e.g. a
+    /// [`FakePpbaPort`](crate::sim::FakePpbaPort) to run without hardware.
+    pub async fn new_with_port(
+        name: &str,
+        address: &str,
+        baud: u32,
+        port: Box<dyn SerialTransport>,
+    ) -> Result<Self, PegasusError> {
+        let mut dev = Self {
+            id: Uuid::new_v4(),
+            name: name.to_owned(),
+            address: address.to_owned(),
+            baud,
+            port,
+            serial_number: Property::<String>::new("UNKNOWN".to_string(), Permission::ReadOnly),
+            fw_version: Property::<String>::new("UNKNOWN".to_string(), Permission::ReadOnly),
+            input_voltage: Property::<f32>::new(0.0, Permission::ReadOnly),
+            current: Property::<f32>::new(0.0, Permission::ReadOnly),
+            temperature: Property::<f32>::new(0.0, Permission::ReadOnly),
+            humidity: Property::<f32>::new(0.0, Permission::ReadOnly),
+            dew_point: Property::<f32>::new(0.0, Permission::ReadOnly),
+            temperature_offset: Property::<f32>::new(0.0, Permission::ReadWrite),
+            humidity_offset: Property::<f32>::new(0.0, Permission::ReadWrite),
+            dew_point_computed: Property::<f32>::new(0.0, Permission::ReadOnly),
+            dew_margin: Property::<f32>::new(0.0, Permission::ReadOnly),
+            dew_formula: Magnus::default(),
+            dew_power: Property::<u8>::new(0, Permission::ReadWrite),
+            autodew: Property::<bool>::new(false, Permission::ReadWrite),
+            pwr_warn: Property::<bool>::new(false, Permission::ReadOnly),
+            average_amps: Property::<f32>::new(0.0, Permission::ReadOnly),
+            amps_hours: Property::<f32>::new(0.0, Permission::ReadOnly),
+            watt_hours: Property::<f32>::new(0.0, Permission::ReadOnly),
+            uptime: Property::<u32>::new(0, Permission::ReadOnly),
+            uptime_seconds: Property::<u32>::new(0, Permission::ReadOnly),
+            uptime_human: Property::<String>::new(parser::format_uptime(0), Permission::ReadOnly),
+            average_power: Property::<f32>::new(0.0, Permission::ReadOnly),
+            polling_interval: Property::<u64>::new(
+                device::DEFAULT_POLLING_INTERVAL_MS,
+                Permission::ReadWrite,
+            ),
+            smoothing: Smoothing::default(),
+            read_only: Property::new(false, Permission::ReadOnly),
+            retry: RetryPolicies::default(),
+            read_times: ReadTimes::new(&[STATUS_READINGS, STATS_READINGS].concat()),
+            connected: true,
+        };
+
+        match dev.send_command(Command::Status, None).await?.as_str() {
+            "PPBM_OK" => {
+                if let Ok(fw) = dev.send_command(Command::FirmwareVersion, None).await {
+                    dev.fw_version.update_int(fw);
+                }
+                dev.fetch_props().await;
+                Ok(dev)
+            }
+            other => Err(PegasusError::Unsupported(format!(
+                "Not a Pocket Powerbox Micro: {}",
+                other
+            ))),
+        }
+    }
+
+    /// Set the PWM duty cycle (0-255) of the dew heater output.
+    pub async fn set_dew_power(&mut self, pwm: u8) -> Result<(), PegasusError> {
+        self.send_command(Command::DewPower, Some(Payload::Pwm(pwm)))
+            .await?;
+        self.dew_power.update_int(pwm);
+        Ok(())
+    }
+
+    /// Let the device drive the dew heater on its own from the dew point.
+    pub async fn set_autodew(&mut self, on: bool) -> Result<(), PegasusError> {
+        self.send_command(Command::AutoDew, Some(Payload::Flag(on)))
+            .await?;
+        self.autodew.update_int(on);
+        Ok(())
+    }
+
+    /// Change the pause between two polls, at least
+    /// [`MIN_POLLING_INTERVAL_MS`](device::MIN_POLLING_INTERVAL_MS).
+    pub fn set_polling_interval(&mut self, ms: u64) -> Result<(), PegasusError> {
+        let ms = device::check_polling_interval(ms)?;
+        self.polling_interval.update_int(ms);
+        Ok(())
+    }
+
+    /// Identify the device by its USB serial number, its id is derived from it
+    /// with [`device_id`](device::device_id) instead of being random.
+    pub fn set_serial_number(&mut self, serial_number: &str) {
+        self.id = device::device_id(serial_number);
+        self.serial_number.update_int(serial_number.to_owned());
+    }
+
+    /// Reuse the id a device without serial number had, e.g. before a restart
+    pub fn set_id(&mut self, id: Uuid) {
+        self.id = id;
+    }
+
+    /// Correct the readings of the sensor from the next poll on
+    pub fn set_calibration(&mut self, calibration: SensorCalibration) -> Result<(), PegasusError> {
+        calibration.validate()?;
+        self.temperature_offset.update_int(calibration.temperature);
+        self.humidity_offset.update_int(calibration.humidity);
+        Ok(())
+    }
+
+    pub fn calibration(&self) -> SensorCalibration {
+        SensorCalibration {
+            temperature: *self.temperature_offset.value(),
+            humidity: *self.humidity_offset.value(),
+        }
+    }
+
+    /// Coefficients `dew_point_computed` is computed with from the next poll on
+    pub fn set_dew_point_formula(&mut self, formula: Magnus) -> Result<(), PegasusError> {
+        formula.validate()?;
+        self.dew_formula = formula;
+        Ok(())
+    }
+
+    /// Compute the dew point and the margin from the last readings
+    fn update_dew_margin(&mut self) {
+        let temperature = *self.temperature.value();
+        let dew_point = self
+            .dew_formula
+            .dew_point(temperature, *self.humidity.value());
+        self.dew_point_computed.update_int(dew_point);
+        self.dew_margin.update_int(temperature - dew_point);
+    }
+
+    /// Also expose a smoothed copy of a noisy reading, as `{reading}_smoothed`.
+    pub fn set_smoothing(&mut self, reading: &str, filter: Filter) -> Result<(), PegasusError> {
+        if self.reading(reading).is_none() {
+            return Err(PegasusError::Unsupported(format!(
+                "{} is not a reading of {}",
+                reading, self.name
+            )));
+        }
+        self.smoothing.set(reading, filter)
+    }
+
+    /// Smoothed value of a reading, if smoothing was enabled for it
+    pub fn smoothed(&self, reading: &str) -> Option<f32> {
+        self.smoothing.get(reading)
+    }
+
+    /// Raw value of the readings that can be smoothed
+    fn reading(&self, name: &str) -> Option<f32> {
+        let prop = match name {
+            "input_voltage" => &self.input_voltage,
+            "current" => &self.current,
+            "temperature" => &self.temperature,
+            "humidity" => &self.humidity,
+            "dew_point" => &self.dew_point,
+            "dew_point_computed" => &self.dew_point_computed,
+            "dew_margin" => &self.dew_margin,
+            "average_amps" => &self.average_amps,
+            _ => return None,
+        };
+        Some(*prop.value())
+    }
+
+    /// Change how commands the device doesn't answer are retried.
+    pub fn set_retry_policies(&mut self, retry: RetryPolicies) {
+        self.retry = retry;
+    }
+
+    /// In read-only mode the settings are validated but not sent, changing
+    /// one fails with [`PegasusError::ReadOnly`].
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only.update_int(read_only);
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        *self.read_only.value()
+    }
+
+    async fn send_command(
+        &mut self,
+        comm: Command,
+        payload: Option<Payload>,
+    ) -> Result<String, PegasusError> {
+        let command = codec::Command::new(comm as u32, payload).expecting(comm.response_prefix());
+        // Only the set commands have a value
+        if self.is_read_only() && command.payload().is_some() {
+            return Err(device::not_sent(&self.name, &command));
+        }
+        let res =
+            transport::send_command_with_retry(self.port.as_mut(), &command, &self.retry).await;
+        // A device answering with an error is still there, anything else means the link is gone
+        self.connected = !matches!(&res, Err(e) if e.link_failed());
+        res
+    }
+
+    async fn update_power_and_sensor_readings(&mut self) {
+        let Ok(response) = self
+            .send_command(Command::PowerAndSensorReadings, None)
+            .await
+        else {
+            error!("Couldn't read power and sensors reading");
+            return;
+        };
+        debug!("POWER AND SENSORS READINGS: {}", response);
+
+        match response.parse::<PpbmStatus>() {
+            Ok(mut status) => {
+                status.calibrate(&self.calibration());
+                self.input_voltage.update_int(status.input_voltage);
+                self.current.update_int(status.current);
+                self.temperature.update_int(status.temperature);
+                self.humidity.update_int(status.humidity);
+                self.dew_point.update_int(status.dew_point);
+                self.update_dew_margin();
+                self.dew_power.update_int(status.dew_power);
+                self.autodew.update_int(status.autodew);
+                self.pwr_warn.update_int(status.pwr_warn);
+                self.read_times.touch(STATUS_READINGS);
+            }
+            Err(e) => warn!("Ignoring power and sensors reading of {}: {}", self.name, e),
+        }
+    }
+
+    async fn update_power_consumption_and_stats(&mut self) {
+        let Ok(response) = self.send_command(Command::PowerConsumAndStats, None).await else {
+            error!("Couldn't read power consumption metrics");
+            return;
+        };
+        debug!("POWER CONSUMPTIONS STATS: {}", response);
+
+        match response.parse::<PowerStats>() {
+            Ok(stats) => {
+                self.average_amps.update_int(stats.average_amps);
+                self.amps_hours.update_int(stats.amps_hours);
+                self.watt_hours.update_int(stats.watt_hours);
+                self.uptime.update_int(stats.uptime);
+                self.uptime_seconds.update_int(stats.uptime_seconds());
+                self.uptime_human.update_int(stats.uptime_human());
+                self.average_power.update_int(stats.average_power());
+                self.read_times.touch(STATS_READINGS);
+            }
+            Err(e) => warn!("Ignoring power consumption stats of {}: {}", self.name, e),
+        }
+    }
+}
+
+#[async_trait]
+impl AstronomicalDevice for PocketPowerBoxMicro {
+    fn get_id(&self) -> Uuid {
+        self.id
+    }
+
+    fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    fn get_address(&self) -> &String {
+        &self.address
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn polling_interval(&self) -> Duration {
+        Duration::from_millis(*self.polling_interval.value())
+    }
+
+    fn read_times(&self) -> Option<&ReadTimes> {
+        Some(&self.read_times)
+    }
+
+    async fn reconnect(&mut self) -> Result<(), PegasusError> {
+        self.port.reopen().await?;
+        self.send_command(Command::Status, None).await?;
+        info!("{} is back on {}", self.name, self.address);
+        Ok(())
+    }
+
+    async fn fetch_props(&mut self) {
+        info!("Fetching properties for device {}", self.name);
+        self.update_power_consumption_and_stats().await;
+        self.update_power_and_sensor_readings().await;
+        if self.connected {
+            let mut smoothing = std::mem::take(&mut self.smoothing);
+            smoothing.update(|name| self.reading(name));
+            self.smoothing = smoothing;
+        }
+    }
+
+    async fn update_property(&mut self, prop_name: &str, val: &str) -> Result<(), PegasusError> {
+        let setting = Self::parse_setting(prop_name, val)?;
+        self.apply(setting).await
+    }
+}
+
+#[async_trait]
+impl PegasusDevice for PocketPowerBoxMicro {
+    type Setting = Setting;
+
+    fn parse_setting(prop_name: &str, val: &str) -> Result<Setting, PegasusError> {
+        let invalid =
+            || PegasusError::Validation(format!("Invalid value for {}: {}", prop_name, val));
+
+        match prop_name {
+            "dew_power" => val.parse().map(Setting::DewPower).map_err(|_| invalid()),
+            "autodew" => match val {
+                "1" | "true" => Ok(Setting::Autodew(true)),
+                "0" | "false" => Ok(Setting::Autodew(false)),
+                _ => Err(invalid()),
+            },
+            "polling_interval" => device::parse_polling_interval(val).map(Setting::PollingInterval),
+            "temperature_offset" => val
+                .parse()
+                .map(Setting::TemperatureOffset)
+                .map_err(|_| invalid()),
+            "humidity_offset" => val
+                .parse()
+                .map(Setting::HumidityOffset)
+                .map_err(|_| invalid()),
+            _ => Err(PegasusError::Unsupported(format!(
+                "Property {} cannot be updated",
+                prop_name
+            ))),
+        }
+    }
+
+    async fn apply(&mut self, setting: Setting) -> Result<(), PegasusError> {
+        match setting {
+            Setting::DewPower(pwm) => self.set_dew_power(pwm).await,
+            Setting::Autodew(on) => self.set_autodew(on).await,
+            Setting::PollingInterval(ms) => self.set_polling_interval(ms),
+            Setting::TemperatureOffset(offset) => self.set_calibration(SensorCalibration {
+                temperature: offset,
+                ..self.calibration()
+            }),
+            Setting::HumidityOffset(offset) => self.set_calibration(SensorCalibration {
+                humidity: offset,
+                ..self.calibration()
+            }),
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use std::time::Duration;
+
+/// Default maximum PWM change applied in a single step.
+pub const DEFAULT_MAX_STEP: u8 = 32;
+/// Default pause between two ramp steps.
+pub const DEFAULT_STEP_INTERVAL_MS: u64 = 300;
+
+/// Gradually apply large dew heater PWM changes.
+///
+/// Jumping from 0 to 255 at once causes an inrush that can make the input
+/// voltage sag enough to trip sensitive cameras, so changes bigger than
+/// `max_step` are split into several steps spaced by `interval`.
+/// A `max_step` of 0 disables ramping altogether.
+#[derive(Clone, Copy, Debug)]
+pub struct DewRamp {
+    pub max_step: u8,
+    pub interval: Duration,
+}
+
+impl Default for DewRamp {
+    fn default() -> Self {
+        Self {
+            max_step: DEFAULT_MAX_STEP,
+            interval: Duration::from_millis(DEFAULT_STEP_INTERVAL_MS),
+        }
+    }
+}
+
+impl DewRamp {
+    /// Build the ramp from `PPBA_DEW_RAMP_STEP` and `PPBA_DEW_RAMP_INTERVAL_MS`,
+    /// falling back to the defaults for missing or invalid values.
+    pub fn from_env() -> Self {
+        let mut ramp = Self::default();
+
+        if let Some(step) = std::env::var("PPBA_DEW_RAMP_STEP")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            ramp.max_step = step;
+        }
+
+        if let Some(ms) = std::env::var("PPBA_DEW_RAMP_INTERVAL_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+        {
+            ramp.interval = Duration::from_millis(ms);
+        }
+        ramp
+    }
+
+    /// No ramp, changes are applied at once
+    pub const OFF: Self = Self {
+        max_step: 0,
+        interval: Duration::ZERO,
+    };
+
+    pub fn enabled(&self) -> bool {
+        self.max_step > 0
+    }
+
+    /// Intermediate PWM values to go through to reach `to` starting from `from`,
+    /// the last element is always `to`.
+    pub fn steps(&self, from: u8, to: u8) -> Vec<u8> {
+        if !self.enabled() || from.abs_diff(to) <= self.max_step {
+            return vec![to];
+        }
+
+        let mut steps = Vec::new();
+        let mut current = from;
+
+        while current != to {
+            current = if to > current {
+                current.saturating_add(self.max_step).min(to)
+            } else {
+                current.saturating_sub(self.max_step).max(to)
+            };
+            steps.push(current);
+        }
+        steps
+    }
+
+    /// The ramp spreading the change from `from` to `to` over `duration`, in
+    /// steps of `max_step`, [`DEFAULT_MAX_STEP`] when the ramp is disabled.
+    /// A zero `duration` leaves the ramp as it is.
+    pub fn over(&self, duration: Duration, from: u8, to: u8) -> Self {
+        if duration.is_zero() {
+            return *self;
+        }
+        let max_step = if self.enabled() {
+            self.max_step
+        } else {
+            DEFAULT_MAX_STEP
+        };
+        let pauses = Self {
+            max_step,
+            interval: Duration::ZERO,
+        }
+        .steps(from, to)
+        .len()
+        .saturating_sub(1)
+        .max(1);
+        Self {
+            max_step,
+            interval: duration / pauses as u32,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ramp(max_step: u8) -> DewRamp {
+        DewRamp {
+            max_step,
+            interval: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn small_changes_are_applied_at_once() {
+        assert_eq!(ramp(32).steps(100, 132), [132]);
+        assert_eq!(ramp(32).steps(132, 100), [100]);
+        assert_eq!(ramp(32).steps(50, 50), [50]);
+    }
+
+    #[test]
+    fn large_changes_are_split() {
+        assert_eq!(ramp(100).steps(0, 255), [100, 200, 255]);
+        assert_eq!(ramp(100).steps(255, 0), [155, 55, 0]);
+        assert_eq!(ramp(64).steps(10, 138), [74, 138]);
+    }
+
+    #[test]
+    fn steps_end_on_the_target_without_overflowing() {
+        assert_eq!(ramp(200).steps(100, 255), [255]);
+        assert_eq!(ramp(200).steps(250, 10), [50, 10]);
+        assert_eq!(ramp(255).steps(0, 255), [255]);
+        let steps = ramp(1).steps(0, 255);
+        assert_eq!(steps.len(), 255);
+        assert_eq!(steps.last(), Some(&255));
+    }
+
+    #[test]
+    fn steps_are_spread_over_a_duration() {
+        let spread = ramp(100).over(Duration::from_millis(900), 0, 255);
+        assert_eq!(spread.steps(0, 255), [100, 200, 255]);
+        assert_eq!(spread.interval, Duration::from_millis(450));
+        // Steps of the default size without a ramp of its own
+        let spread = DewRamp::OFF.over(Duration::from_millis(300), 128, 0);
+        assert_eq!(spread.steps(128, 0), [96, 64, 32, 0]);
+        assert_eq!(spread.interval, Duration::from_millis(100));
+        // Nothing to spread
+        assert_eq!(
+            DewRamp::OFF.over(Duration::ZERO, 0, 255).steps(0, 255),
+            [255]
+        );
+        assert_eq!(
+            ramp(32).over(Duration::from_secs(1), 10, 20).steps(10, 20),
+            [20]
+        );
+    }
+
+    #[test]
+    fn zero_step_disables_the_ramp() {
+        assert!(!ramp(0).enabled());
+        assert_eq!(ramp(0).steps(0, 255), [255]);
+    }
+}
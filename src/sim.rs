@@ -0,0 +1,444 @@
+//! Simulated devices to run the drivers without any hardware connected.
+use crate::codec;
+use crate::dewpoint;
+use crate::transport::SerialTransport;
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::f32::consts::TAU;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+#[derive(Debug, Default)]
+struct FakeState {
+    /// Fixture responses, keyed by full command (`PA`) or command prefix (`P3:`)
+    responses: HashMap<String, Option<String>>,
+    /// Bytes of the last response not read yet
+    pending: VecDeque<u8>,
+    /// How many times each command is still going to be ignored
+    dropped: HashMap<String, u32>,
+    /// How many times each command is still going to be answered late
+    late: HashMap<String, u32>,
+    /// Response held back until the next command
+    held: Option<String>,
+    sent: Vec<String>,
+    /// Outputs and drifting sensors answering `PA`, `PS` and `PC`, see
+    /// [`FakePpbaPort::simulated`]
+    simulation: Option<Simulation>,
+}
+
+/// A PPBA in use: the outputs follow the SET commands received and the
+/// sensors drift slowly with time.
+#[derive(Debug)]
+struct Simulation {
+    started: Instant,
+    /// Phase of the drifts, so every simulated box reads differently
+    phase: f32,
+    quadport: bool,
+    adj_output: bool,
+    adj_volts: u8,
+    dew: [u8; 2],
+    autodew: bool,
+}
+
+impl Simulation {
+    fn new(index: u32) -> Self {
+        Self {
+            started: Instant::now(),
+            phase: index as f32 * 1.3,
+            quadport: true,
+            adj_output: false,
+            adj_volts: 12,
+            dew: [128, 0],
+            autodew: false,
+        }
+    }
+
+    /// Apply a SET command, returns false for the other commands
+    fn apply(&mut self, command: &str) -> bool {
+        let Ok((code, Some(value))) = codec::split_frame(command.as_bytes()) else {
+            return false;
+        };
+        let Ok(value) = value.parse::<u8>() else {
+            return false;
+        };
+        match code {
+            "P1:" => self.quadport = value != 0,
+            // `P2:0` and `P2:1` switch the output, any other value is a voltage
+            "P2:" if value <= 1 => self.adj_output = value == 1,
+            "P2:" => {
+                self.adj_output = true;
+                self.adj_volts = value;
+            }
+            "P3:" => self.dew[0] = value,
+            "P4:" => self.dew[1] = value,
+            "PD:" => self.autodew = value != 0,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Sine of period `period` seconds, shifted by the phase of the box
+    fn wave(&self, period: f32) -> f32 {
+        let t = self.started.elapsed().as_secs_f32();
+        (TAU * t / period + self.phase).sin()
+    }
+
+    fn temperature(&self) -> f32 {
+        8.0 + 4.0 * self.wave(1800.0)
+    }
+
+    fn humidity(&self) -> f32 {
+        70.0 + 15.0 * self.wave(2400.0)
+    }
+
+    /// PWM of the dew heaters, driven by the margin over the dew point with auto dew
+    fn dew_power(&self) -> [u8; 2] {
+        if !self.autodew {
+            return self.dew;
+        }
+        let margin = self.temperature() - dewpoint::dew_point(self.temperature(), self.humidity());
+        let pwm = (255.0 * (1.0 - margin / 10.0)).clamp(0.0, 255.0) as u8;
+        [pwm, pwm]
+    }
+
+    /// Currents of the 12V outputs and of the two dew heaters, in A
+    fn currents(&self) -> (f32, [f32; 2]) {
+        let mut outputs = 0.0;
+        if self.quadport {
+            outputs += 1.2 + 0.3 * self.wave(300.0);
+        }
+        if self.adj_output {
+            outputs += 0.04 * f32::from(self.adj_volts);
+        }
+        let dew = self.dew_power();
+        (
+            outputs,
+            [
+                1.5 * f32::from(dew[0]) / 255.0,
+                1.5 * f32::from(dew[1]) / 255.0,
+            ],
+        )
+    }
+
+    fn response(&self, command: &str) -> Option<String> {
+        let (outputs, dew_currents) = self.currents();
+        let total = outputs + dew_currents[0] + dew_currents[1];
+        let uptime_ms = self.started.elapsed().as_millis();
+        match command {
+            "PA" => {
+                let (temperature, humidity) = (self.temperature(), self.humidity());
+                let dew = self.dew_power();
+                Some(format!(
+                    "PPBA:{:.1}:{:.2}:{:.1}:{:.0}:{:.1}:{}:{}:{}:{}:{}:0:{}",
+                    12.4 - 0.05 * total + 0.05 * self.wave(90.0),
+                    total,
+                    temperature,
+                    humidity,
+                    dewpoint::dew_point(temperature, humidity),
+                    u8::from(self.quadport),
+                    u8::from(self.adj_output),
+                    dew[0],
+                    dew[1],
+                    u8::from(self.autodew),
+                    self.adj_volts,
+                ))
+            }
+            "PS" => {
+                let hours = uptime_ms as f32 / 3_600_000.0;
+                Some(format!(
+                    "PS:{:.2}:{:.2}:{:.1}:{}",
+                    total,
+                    total * hours,
+                    12.4 * total * hours,
+                    uptime_ms
+                ))
+            }
+            "PC" => Some(format!(
+                "PC:{:.2}:{:.2}:{:.2}:{:.2}:{}",
+                total, outputs, dew_currents[0], dew_currents[1], uptime_ms
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// In memory PPBA answering commands with configurable fixture responses.
+///
+/// Clones share the same state, so a test can keep a clone around to change
+/// the fixtures or inspect the sent commands after handing the port to a
+/// [`PegasusPowerBox`](crate::ppba::PegasusPowerBox).
+///
+/// SET commands without a fixture are echoed back like a real PPBA does.
+#[derive(Clone, Debug)]
+pub struct FakePpbaPort {
+    state: Arc<Mutex<FakeState>>,
+}
+
+impl Default for FakePpbaPort {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FakePpbaPort {
+    /// A healthy PPBA with a dew heater plugged on DewA
+    pub fn new() -> Self {
+        let port = Self {
+            state: Arc::new(Mutex::new(FakeState::default())),
+        };
+        port.set_response("P#", "PPBA_OK");
+        port.set_response("PV", "1.4");
+        port.set_response("PE", "PE:1111");
+        port.set_response("PA", "PPBA:12.2:0.5:21.5:45:9.1:1:0:128:0:0:0:12");
+        port.set_response("PS", "PS:0.75:1.5:18.2:3600000");
+        port.set_response("PC", "PC:1.2:0.5:0.3:0.0:3600000");
+        port
+    }
+
+    /// A PPBA in use, the `index`-th of a fleet: its outputs follow the SET
+    /// commands and its sensors and currents drift slowly, differently for
+    /// every index. Fixtures set afterwards for other commands still apply.
+    pub fn simulated(index: u32) -> Self {
+        let port = Self::new();
+        port.state.lock().unwrap().simulation = Some(Simulation::new(index));
+        port
+    }
+
+    /// Answer `command` with `response`, `command` can be a full command
+    /// (`PA`) or the prefix of a SET command (`P3:`) to match any value.
+    pub fn set_response(&self, command: &str, response: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .responses
+            .insert(command.to_owned(), Some(response.to_owned()));
+    }
+
+    /// Never answer `command`, reading the response times out.
+    pub fn set_silent(&self, command: &str) {
+        self.state
+            .lock()
+            .unwrap()
+            .responses
+            .insert(command.to_owned(), None);
+    }
+
+    /// Ignore the next `times` occurrences of `command` (exact match), like
+    /// a response lost on a noisy cable.
+    pub fn drop_responses(&self, command: &str, times: u32) {
+        self.state
+            .lock()
+            .unwrap()
+            .dropped
+            .insert(command.to_owned(), times);
+    }
+
+    /// Answer the next `times` occurrences of `command` (exact match) only
+    /// once the next command is received, before answering that one, like a
+    /// device answering after the driver gave up waiting.
+    pub fn answer_late(&self, command: &str, times: u32) {
+        self.state
+            .lock()
+            .unwrap()
+            .late
+            .insert(command.to_owned(), times);
+    }
+
+    /// Every command received so far, in order and without the trailing newline
+    pub fn sent_commands(&self) -> Vec<String> {
+        self.state.lock().unwrap().sent.clone()
+    }
+
+    /// Handle a command frame, the response is read back byte by byte
+    fn receive(&self, frame: &[u8]) {
+        let mut state = self.state.lock().unwrap();
+        let command = String::from_utf8_lossy(frame).trim_end().to_owned();
+
+        state.pending.clear();
+        if let Some(held) = state.held.take() {
+            state.pending.extend(held.bytes());
+            state.pending.extend(b"\r\n");
+        }
+        let dropped = take_one(&mut state.dropped, &command);
+        let late = take_one(&mut state.late, &command);
+        match state.response_for(&command).filter(|_| !dropped) {
+            Some(response) if late => state.held = Some(response),
+            Some(response) => {
+                state.pending.extend(response.bytes());
+                state.pending.extend(b"\r\n");
+            }
+            None => (),
+        }
+        state.sent.push(command);
+    }
+}
+
+/// Count down the occurrences of `command` left in `counts`, true if there was one
+fn take_one(counts: &mut HashMap<String, u32>, command: &str) -> bool {
+    match counts.get_mut(command) {
+        Some(times) if *times > 0 => {
+            *times -= 1;
+            true
+        }
+        _ => false,
+    }
+}
+
+impl FakeState {
+    fn response_for(&mut self, command: &str) -> Option<String> {
+        if let Some(simulation) = &mut self.simulation {
+            if simulation.apply(command) {
+                return Some(command.to_owned());
+            }
+            if let Some(response) = simulation.response(command) {
+                return Some(response);
+            }
+        }
+        if let Some(response) = self.responses.get(command) {
+            return response.clone();
+        }
+
+        match command.find(':') {
+            Some(idx) => match self.responses.get(&command[..=idx]) {
+                Some(response) => response.clone(),
+                None => Some(command.to_owned()),
+            },
+            None => None,
+        }
+    }
+}
+
+#[async_trait]
+impl SerialTransport for FakePpbaPort {
+    async fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.receive(frame);
+        Ok(())
+    }
+
+    async fn read_byte(&mut self) -> io::Result<u8> {
+        self.state
+            .lock()
+            .unwrap()
+            .pending
+            .pop_front()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::TimedOut))
+    }
+}
+
+/// Pseudo terminal pair with a [`FakePpbaPort`] answering on one end, like a
+/// `socat pty,raw,echo=0 pty,raw,echo=0` pair with a script on one side.
+///
+/// The other end is a real serial port the drivers open by its path, so the
+/// whole stack is exercised, from the tty settings to the parsing. The fake
+/// answers until the pair is dropped.
+#[cfg(all(unix, feature = "hw-sim"))]
+pub struct VirtualSerialPair {
+    path: String,
+    port: FakePpbaPort,
+    stop: Arc<std::sync::atomic::AtomicBool>,
+    responder: Option<std::thread::JoinHandle<()>>,
+    /// Kept open so the responder doesn't see the port hung up when the
+    /// driver closes it to reopen it
+    _device_end: std::fs::File,
+}
+
+#[cfg(all(unix, feature = "hw-sim"))]
+impl VirtualSerialPair {
+    pub fn new(port: FakePpbaPort) -> io::Result<Self> {
+        use std::io::{Read, Write};
+        use std::os::fd::FromRawFd;
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let (mut master, mut slave) = (0, 0);
+        let mut name = [0 as libc::c_char; 64];
+        // SAFETY: the buffers outlive the calls, the fds are owned by the files below
+        let path = unsafe {
+            if libc::openpty(
+                &mut master,
+                &mut slave,
+                name.as_mut_ptr(),
+                std::ptr::null(),
+                std::ptr::null(),
+            ) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            let mut termios = std::mem::zeroed();
+            libc::tcgetattr(slave, &mut termios);
+            libc::cfmakeraw(&mut termios);
+            libc::tcsetattr(slave, libc::TCSANOW, &termios);
+            std::ffi::CStr::from_ptr(name.as_ptr())
+                .to_string_lossy()
+                .into_owned()
+        };
+        // SAFETY: openpty returned two fds nobody else owns
+        let (mut responder_end, device_end) = unsafe {
+            (
+                std::fs::File::from_raw_fd(master),
+                std::fs::File::from_raw_fd(slave),
+            )
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let c_stop = Arc::clone(&stop);
+        let c_port = port.clone();
+        let responder = std::thread::spawn(move || {
+            let mut frame = Vec::new();
+            let mut poll = libc::pollfd {
+                fd: master,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            while !c_stop.load(Ordering::Relaxed) {
+                // Wake up regularly to notice the pair was dropped
+                // SAFETY: a single valid pollfd
+                if unsafe { libc::poll(&mut poll, 1, 50) } <= 0 {
+                    continue;
+                }
+                let mut byte = [0];
+                if responder_end.read(&mut byte).unwrap_or(0) == 0 {
+                    continue;
+                }
+                frame.push(byte[0]);
+                if byte[0] != b'\n' {
+                    continue;
+                }
+                c_port.receive(&frame);
+                frame.clear();
+                let response: Vec<u8> = c_port.state.lock().unwrap().pending.drain(..).collect();
+                if responder_end.write_all(&response).is_err() {
+                    return;
+                }
+            }
+        });
+
+        Ok(Self {
+            path,
+            port,
+            stop,
+            responder: Some(responder),
+            _device_end: device_end,
+        })
+    }
+
+    /// Path of the serial port to give to the drivers, e.g. `/dev/pts/3`
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The fake answering, to change its fixtures or check the commands sent
+    pub fn port(&self) -> &FakePpbaPort {
+        &self.port
+    }
+}
+
+#[cfg(all(unix, feature = "hw-sim"))]
+impl Drop for VirtualSerialPair {
+    fn drop(&mut self) {
+        self.stop.store(true, std::sync::atomic::Ordering::Relaxed);
+        if let Some(responder) = self.responder.take() {
+            let _ = responder.join();
+        }
+    }
+}
@@ -0,0 +1,128 @@
+use crate::error::PegasusError;
+use astrotools::properties::{Permission, Property};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::{BTreeMap, VecDeque};
+
+/// How a noisy reading is smoothed
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(tag = "filter", rename_all = "lowercase")]
+pub enum Filter {
+    /// Exponential moving average, `alpha` is the weight of the newest
+    /// reading, from 0 (excluded) to 1 (no smoothing)
+    Ema { alpha: f32 },
+    /// Median of the last `window` readings, drops isolated spikes
+    Median { window: usize },
+}
+
+impl Filter {
+    pub fn validate(&self) -> Result<(), PegasusError> {
+        match *self {
+            Filter::Ema { alpha } if !(alpha > 0.0 && alpha <= 1.0) => Err(
+                PegasusError::Validation(format!("EMA alpha must be in (0, 1]: {}", alpha)),
+            ),
+            Filter::Median { window: 0 } => Err(PegasusError::Validation(
+                "Median window cannot be empty".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Smoothed value of a single reading
+#[derive(Clone, Debug)]
+pub struct Smoother {
+    filter: Filter,
+    value: Option<f32>,
+    /// Last readings, only kept for the median
+    window: VecDeque<f32>,
+}
+
+impl Smoother {
+    pub fn new(filter: Filter) -> Self {
+        Self {
+            filter,
+            value: None,
+            window: VecDeque::new(),
+        }
+    }
+
+    /// Feed a new raw reading, returns the smoothed value
+    pub fn push(&mut self, raw: f32) -> f32 {
+        let value = match self.filter {
+            Filter::Ema { alpha } => match self.value {
+                Some(previous) => previous + alpha * (raw - previous),
+                // Start from the first reading rather than from 0
+                None => raw,
+            },
+            Filter::Median { window } => {
+                if self.window.len() == window {
+                    self.window.pop_front();
+                }
+                self.window.push_back(raw);
+                let mut sorted: Vec<f32> = self.window.iter().copied().collect();
+                sorted.sort_by(f32::total_cmp);
+                let mid = sorted.len() / 2;
+                if sorted.len().is_multiple_of(2) {
+                    (sorted[mid - 1] + sorted[mid]) / 2.0
+                } else {
+                    sorted[mid]
+                }
+            }
+        };
+        self.value = Some(value);
+        value
+    }
+
+    pub fn value(&self) -> Option<f32> {
+        self.value
+    }
+}
+
+/// Smoothed copies of some readings of a device.
+///
+/// Flattened in the serialized device next to the raw readings, every
+/// smoothed reading is a read only property named `{reading}_smoothed`.
+#[derive(Clone, Debug, Default)]
+pub struct Smoothing {
+    readings: BTreeMap<String, Smoother>,
+}
+
+impl Smoothing {
+    /// Smooth `reading` with the given filter, restarting from scratch if it already was
+    pub fn set(&mut self, reading: &str, filter: Filter) -> Result<(), PegasusError> {
+        filter.validate()?;
+        self.readings
+            .insert(reading.to_string(), Smoother::new(filter));
+        Ok(())
+    }
+
+    /// Feed the latest raw readings, looked up by name
+    pub fn update(&mut self, raw: impl Fn(&str) -> Option<f32>) {
+        for (name, smoother) in self.readings.iter_mut() {
+            if let Some(value) = raw(name) {
+                smoother.push(value);
+            }
+        }
+    }
+
+    /// Smoothed value of a reading, `None` if it isn't smoothed or was never read
+    pub fn get(&self, reading: &str) -> Option<f32> {
+        self.readings.get(reading).and_then(Smoother::value)
+    }
+}
+
+impl Serialize for Smoothing {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for (name, smoother) in &self.readings {
+            if let Some(value) = smoother.value() {
+                map.serialize_entry(
+                    &format!("{}_smoothed", name),
+                    &Property::new(value, Permission::ReadOnly),
+                )?;
+            }
+        }
+        map.end()
+    }
+}
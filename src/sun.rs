@@ -0,0 +1,228 @@
+//! Times of the sun at the site of the observatory, so automations can follow
+//! the night rather than the clock.
+//!
+//! Computed with the sunrise equation, accurate to a minute or two away from
+//! the polar circles, which is plenty to switch dew heaters.
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// Julian day of 2000-01-01 12:00 UTC
+const J2000: f64 = 2_451_545.0;
+/// Julian day of the Unix epoch
+const UNIX_EPOCH_JD: f64 = 2_440_587.5;
+/// Obliquity of the ecliptic, in degrees
+const OBLIQUITY: f64 = 23.4397;
+
+/// Location of the observatory, the `[site]` table of the config file
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Site {
+    /// Degrees, north is positive
+    pub latitude: f64,
+    /// Degrees, east is positive
+    pub longitude: f64,
+}
+
+impl Site {
+    pub fn validate(&self) -> Result<(), String> {
+        if !(-90.0..=90.0).contains(&self.latitude) {
+            return Err(format!("Invalid site latitude {}", self.latitude));
+        }
+        if !(-180.0..=180.0).contains(&self.longitude) {
+            return Err(format!("Invalid site longitude {}", self.longitude));
+        }
+        Ok(())
+    }
+
+    /// When `event` happens on the local `date`, `None` if the sun doesn't
+    /// reach its altitude that day, e.g. no astronomical dusk in summer far
+    /// enough north
+    pub fn time_of(&self, event: SunEvent, date: NaiveDate) -> Option<DateTime<Utc>> {
+        let epoch = NaiveDate::from_ymd_opt(2000, 1, 1)?;
+        let days = (date - epoch).num_days() as f64;
+
+        // Mean solar noon, then the true one from the equation of time
+        let noon = days - self.longitude / 360.0;
+        let anomaly = (357.5291 + 0.985_600_28 * noon)
+            .rem_euclid(360.0)
+            .to_radians();
+        let center =
+            1.9148 * anomaly.sin() + 0.02 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+        let ecliptic_longitude = (anomaly.to_degrees() + center + 180.0 + 102.9372)
+            .rem_euclid(360.0)
+            .to_radians();
+        let transit =
+            J2000 + noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+
+        let declination = (ecliptic_longitude.sin() * OBLIQUITY.to_radians().sin()).asin();
+        let latitude = self.latitude.to_radians();
+        let cos_hour_angle = (event.altitude().to_radians().sin()
+            - latitude.sin() * declination.sin())
+            / (latitude.cos() * declination.cos());
+        if !(-1.0..=1.0).contains(&cos_hour_angle) {
+            return None;
+        }
+        let hour_angle = cos_hour_angle.acos().to_degrees() / 360.0;
+        let julian_day = if event.is_morning() {
+            transit - hour_angle
+        } else {
+            transit + hour_angle
+        };
+
+        let millis = ((julian_day - UNIX_EPOCH_JD) * 86_400_000.0).round() as i64;
+        DateTime::from_timestamp_millis(millis)
+    }
+}
+
+/// Crossing of the horizon or of a twilight altitude by the sun
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SunEvent {
+    AstronomicalDawn,
+    NauticalDawn,
+    CivilDawn,
+    Sunrise,
+    Sunset,
+    CivilDusk,
+    NauticalDusk,
+    AstronomicalDusk,
+}
+
+impl SunEvent {
+    pub const ALL: [Self; 8] = [
+        Self::AstronomicalDawn,
+        Self::NauticalDawn,
+        Self::CivilDawn,
+        Self::Sunrise,
+        Self::Sunset,
+        Self::CivilDusk,
+        Self::NauticalDusk,
+        Self::AstronomicalDusk,
+    ];
+
+    /// Altitude of the center of the sun, in degrees, refraction and the
+    /// radius of the sun included for sunrise and sunset
+    fn altitude(self) -> f64 {
+        match self {
+            Self::Sunrise | Self::Sunset => -0.833,
+            Self::CivilDawn | Self::CivilDusk => -6.0,
+            Self::NauticalDawn | Self::NauticalDusk => -12.0,
+            Self::AstronomicalDawn | Self::AstronomicalDusk => -18.0,
+        }
+    }
+
+    fn is_morning(self) -> bool {
+        matches!(
+            self,
+            Self::AstronomicalDawn | Self::NauticalDawn | Self::CivilDawn | Self::Sunrise
+        )
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::AstronomicalDawn => "astronomical_dawn",
+            Self::NauticalDawn => "nautical_dawn",
+            Self::CivilDawn => "civil_dawn",
+            Self::Sunrise => "sunrise",
+            Self::Sunset => "sunset",
+            Self::CivilDusk => "civil_dusk",
+            Self::NauticalDusk => "nautical_dusk",
+            Self::AstronomicalDusk => "astronomical_dusk",
+        }
+    }
+}
+
+impl fmt::Display for SunEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for SunEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|event| event.as_str() == s)
+            .ok_or_else(|| format!("Unknown sun event {}", s))
+    }
+}
+
+/// A sun event shifted by an offset, e.g. `sunset-30m`, `nautical_dusk` or
+/// `astronomical_dawn+1h30m`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SunTime {
+    pub event: SunEvent,
+    pub offset: Duration,
+}
+
+impl SunTime {
+    /// When it happens for the local `date` of the event
+    pub fn on(&self, site: &Site, date: NaiveDate) -> Option<DateTime<Utc>> {
+        site.time_of(self.event, date)
+            .map(|time| time + self.offset)
+    }
+}
+
+/// `1h30m`, `45m` or `2h`
+fn parse_offset(s: &str) -> Option<Duration> {
+    let mut offset = Duration::zero();
+    let mut rest = s;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+        let value: i64 = rest[..digits].parse().ok()?;
+        offset += match rest[digits..].chars().next()? {
+            'h' => Duration::hours(value),
+            'm' => Duration::minutes(value),
+            _ => return None,
+        };
+        rest = &rest[digits + 1..];
+    }
+    Some(offset)
+}
+
+impl FromStr for SunTime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (event, offset) = match s.find(['+', '-']) {
+            Some(at) => {
+                let offset = parse_offset(&s[at + 1..])
+                    .filter(|_| at + 1 < s.len())
+                    .ok_or_else(|| format!("Invalid offset in {}", s))?;
+                let offset = if s[at..].starts_with('-') {
+                    -offset
+                } else {
+                    offset
+                };
+                (&s[..at], offset)
+            }
+            None => (s, Duration::zero()),
+        };
+        Ok(Self {
+            event: event.trim().parse()?,
+            offset,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for SunTime {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl fmt::Display for SunTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.event)?;
+        let minutes = self.offset.num_minutes();
+        if minutes != 0 {
+            let sign = if minutes < 0 { '-' } else { '+' };
+            write!(f, "{}{}m", sign, minutes.abs())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,341 @@
+//! Capture of the serial traffic, to reproduce protocol bugs offline.
+//!
+//! Once [`start`]ed, every link opened by [`transport::open`](crate::transport::open)
+//! logs the bytes written to and read from the device, one line per frame:
+//!
+//! ```text
+//! 2024-03-02T21:04:11.512Z  /dev/ttyUSB0  tx  PA\n
+//! 2024-03-02T21:04:11.538Z  /dev/ttyUSB0  rx  PPBA:12.2:0.5:21.5:45:9.1:1:0:128:0:0:0:12\r\n
+//! ```
+//!
+//! Fields are tab separated (spaces above): the time, the address of the
+//! device, `tx` for what was written and `rx` for what was read, then the
+//! bytes escaped with `\n`, `\r`, `\t`, `\\` and `\xNN` for anything not
+//! printable. Lines starting with `#` are comments. Bytes dropped by
+//! [`discard_input`](SerialTransport::discard_input) are not seen, so not logged.
+//!
+//! [`read`] loads a trace back and [`replay`] feeds the responses to the
+//! parser of their command.
+use crate::error::PegasusError;
+use crate::parser::{
+    FirmwareVersion, ParseError, PowerMetrics, PowerStats, PpbaLayout, PpbaStatus, PpbmStatus,
+};
+use crate::transport::SerialTransport;
+use async_trait::async_trait;
+use chrono::{SecondsFormat, Utc};
+use log::error;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+
+const HEADER: &str = "# pegasus-rs serial trace v1";
+
+static TRACE: OnceLock<Arc<SerialTrace>> = OnceLock::new();
+
+/// File the traffic of every traced link is appended to
+#[derive(Debug)]
+pub struct SerialTrace {
+    out: Mutex<LineWriter<File>>,
+}
+
+impl SerialTrace {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut out = LineWriter::new(file);
+        writeln!(out, "{}", HEADER)?;
+        Ok(Self {
+            out: Mutex::new(out),
+        })
+    }
+
+    fn log(&self, address: &str, direction: Direction, bytes: &[u8]) {
+        let line = format!(
+            "{}\t{}\t{}\t{}",
+            Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            address,
+            direction.as_str(),
+            escape(bytes)
+        );
+        if let Err(e) = writeln!(self.out.lock().unwrap(), "{}", line) {
+            error!("Cannot write the serial trace: {}", e);
+        }
+    }
+}
+
+/// Trace the links opened from now on to `path`, can only be called once
+pub fn start(path: &Path) -> Result<(), PegasusError> {
+    let trace = SerialTrace::create(path)?;
+    TRACE
+        .set(Arc::new(trace))
+        .map_err(|_| PegasusError::Validation("The serial trace is already started".to_string()))
+}
+
+/// Wrap `transport` in a [`TracingTransport`] if the trace is started
+pub(crate) fn wrap(address: &str, transport: Box<dyn SerialTransport>) -> Box<dyn SerialTransport> {
+    match TRACE.get() {
+        Some(trace) => Box::new(TracingTransport::new(address, transport, trace.clone())),
+        None => transport,
+    }
+}
+
+/// Transport logging the traffic of another one to a [`SerialTrace`]
+#[derive(Debug)]
+pub struct TracingTransport {
+    address: String,
+    inner: Box<dyn SerialTransport>,
+    trace: Arc<SerialTrace>,
+    /// Bytes read since the last line ending
+    received: Vec<u8>,
+}
+
+impl TracingTransport {
+    pub fn new(address: &str, inner: Box<dyn SerialTransport>, trace: Arc<SerialTrace>) -> Self {
+        Self {
+            address: address.to_owned(),
+            inner,
+            trace,
+            received: Vec::new(),
+        }
+    }
+
+    /// Log the bytes of an incomplete line, e.g. before a timeout
+    fn flush_received(&mut self) {
+        if !self.received.is_empty() {
+            self.trace.log(&self.address, Direction::Rx, &self.received);
+            self.received.clear();
+        }
+    }
+}
+
+#[async_trait]
+impl SerialTransport for TracingTransport {
+    async fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.flush_received();
+        self.trace.log(&self.address, Direction::Tx, frame);
+        self.inner.write_frame(frame).await
+    }
+
+    async fn read_byte(&mut self) -> io::Result<u8> {
+        match self.inner.read_byte().await {
+            Ok(byte) => {
+                self.received.push(byte);
+                if byte == b'\n' {
+                    self.flush_received();
+                }
+                Ok(byte)
+            }
+            Err(e) => {
+                self.flush_received();
+                Err(e)
+            }
+        }
+    }
+
+    async fn reopen(&mut self) -> io::Result<()> {
+        self.flush_received();
+        self.inner.reopen().await
+    }
+
+    async fn discard_input(&mut self) -> io::Result<()> {
+        self.flush_received();
+        self.inner.discard_input().await
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Written to the device
+    Tx,
+    /// Read from the device
+    Rx,
+}
+
+impl Direction {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Tx => "tx",
+            Self::Rx => "rx",
+        }
+    }
+}
+
+/// A line of a trace
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEntry {
+    /// RFC 3339, as written
+    pub timestamp: String,
+    pub address: String,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for &byte in bytes {
+        match byte {
+            b'\n' => escaped.push_str("\\n"),
+            b'\r' => escaped.push_str("\\r"),
+            b'\t' => escaped.push_str("\\t"),
+            b'\\' => escaped.push_str("\\\\"),
+            0x20..=0x7e => escaped.push(byte as char),
+            _ => {
+                let _ = write!(escaped, "\\x{:02x}", byte);
+            }
+        }
+    }
+    escaped
+}
+
+fn unescape(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.bytes();
+    while let Some(byte) = chars.next() {
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        bytes.push(match chars.next()? {
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'\\' => b'\\',
+            b'x' => {
+                let hex = [chars.next()?, chars.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            _ => return None,
+        });
+    }
+    Some(bytes)
+}
+
+/// Parse the content of a trace
+pub fn parse(content: &str) -> Result<Vec<TraceEntry>, PegasusError> {
+    let mut entries = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid =
+            || PegasusError::Parse(format!("Invalid trace line {}: {}", number + 1, line));
+        let fields: Vec<&str> = line.splitn(4, '\t').collect();
+        let [timestamp, address, direction, bytes] = fields[..] else {
+            return Err(invalid());
+        };
+        let direction = match direction {
+            "tx" => Direction::Tx,
+            "rx" => Direction::Rx,
+            _ => return Err(invalid()),
+        };
+        entries.push(TraceEntry {
+            timestamp: timestamp.to_owned(),
+            address: address.to_owned(),
+            direction,
+            bytes: unescape(bytes).ok_or_else(invalid)?,
+        });
+    }
+    Ok(entries)
+}
+
+/// Read a trace written by [`start`]
+pub fn read(path: &Path) -> Result<Vec<TraceEntry>, PegasusError> {
+    parse(&std::fs::read_to_string(path)?)
+}
+
+/// A command written to a device and the line it answered
+#[derive(Clone, Debug, PartialEq)]
+pub struct Exchange {
+    pub timestamp: String,
+    pub address: String,
+    /// Without the line ending, e.g. `P3:128`
+    pub command: String,
+    /// `None` if the device didn't answer
+    pub response: Option<String>,
+}
+
+/// Pair every command of a trace with the first line read after it from the
+/// same device
+pub fn exchanges(entries: &[TraceEntry]) -> Vec<Exchange> {
+    let mut exchanges: Vec<Exchange> = Vec::new();
+    // Index of the last command of every device still waiting for its response
+    let mut waiting: HashMap<&str, usize> = HashMap::new();
+    // Start of a line split by a timeout
+    let mut partial: HashMap<&str, Vec<u8>> = HashMap::new();
+
+    for entry in entries {
+        let text = |bytes: &[u8]| {
+            String::from_utf8_lossy(bytes)
+                .trim_end_matches(['\r', '\n'])
+                .to_owned()
+        };
+        match entry.direction {
+            Direction::Tx => {
+                partial.remove(entry.address.as_str());
+                waiting.insert(&entry.address, exchanges.len());
+                exchanges.push(Exchange {
+                    timestamp: entry.timestamp.clone(),
+                    address: entry.address.clone(),
+                    command: text(&entry.bytes),
+                    response: None,
+                });
+            }
+            Direction::Rx => {
+                let line = partial.entry(&entry.address).or_default();
+                line.extend(&entry.bytes);
+                if !line.ends_with(b"\n") {
+                    continue;
+                }
+                let line = partial.remove(entry.address.as_str()).unwrap_or_default();
+                if let Some(index) = waiting.remove(entry.address.as_str()) {
+                    exchanges[index].response = Some(text(&line));
+                }
+            }
+        }
+    }
+    exchanges
+}
+
+/// Response parsed by the parser of its command
+#[derive(Clone, Debug, PartialEq)]
+pub enum Parsed {
+    Firmware(FirmwareVersion),
+    PpbaStatus(PpbaStatus),
+    PpbmStatus(PpbmStatus),
+    PowerStats(PowerStats),
+    PowerMetrics(PowerMetrics),
+    /// No response, an error or a command without parser
+    Unparsed,
+}
+
+/// Feed the responses of a trace to the parser, with the layout of the
+/// firmware version read last on each device
+pub fn replay(entries: &[TraceEntry]) -> Vec<(Exchange, Result<Parsed, ParseError>)> {
+    let mut layouts: HashMap<String, PpbaLayout> = HashMap::new();
+    exchanges(entries)
+        .into_iter()
+        .map(|exchange| {
+            let layout = layouts.get(&exchange.address).copied();
+            let parsed = match exchange.response.as_deref() {
+                Some(response) if response.split(':').nth(1) == Some("ERR") => Ok(Parsed::Unparsed),
+                Some(response) => match exchange.command.as_str() {
+                    "PV" => response.parse().map(Parsed::Firmware),
+                    "PA" if response.starts_with("PPBM") => {
+                        response.parse().map(Parsed::PpbmStatus)
+                    }
+                    "PA" => PpbaStatus::parse_with(response, layout).map(Parsed::PpbaStatus),
+                    "PS" => response.parse().map(Parsed::PowerStats),
+                    "PC" => PowerMetrics::parse_with(response, layout).map(Parsed::PowerMetrics),
+                    _ => Ok(Parsed::Unparsed),
+                },
+                None => Ok(Parsed::Unparsed),
+            };
+            if let Ok(Parsed::Firmware(version)) = &parsed {
+                layouts.insert(exchange.address.clone(), PpbaLayout::for_firmware(version));
+            }
+            (exchange, parsed)
+        })
+        .collect()
+}
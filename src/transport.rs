@@ -0,0 +1,848 @@
+//! Byte level links used to talk with Pegasus devices.
+//!
+//! Devices never touch a serial port directly, they go through a
+//! [`SerialTransport`] so the same protocol code can run on top of a local
+//! serial port or anything else that can move bytes back and forth, e.g. the
+//! serial port of a remote host exposed by ser2net, see [`open`].
+use crate::codec::Command;
+use crate::error::PegasusError;
+use crate::trace;
+use async_trait::async_trait;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::Instant;
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+/// Speed of every Pegasus device but the focusers
+pub const DEFAULT_BAUD: u32 = 9600;
+/// Speed of the Focus Cube and the DMFC
+pub const FOCUSER_BAUD: u32 = 19200;
+/// Default time given to a device to answer a command
+pub const DEFAULT_TIMEOUT_MS: u64 = 500;
+
+/// Flow control of a serial link
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlowControl {
+    #[default]
+    None,
+    /// XON/XOFF
+    Software,
+    /// RTS/CTS
+    Hardware,
+}
+
+impl From<FlowControl> for tokio_serial::FlowControl {
+    fn from(flow: FlowControl) -> Self {
+        match flow {
+            FlowControl::None => Self::None,
+            FlowControl::Software => Self::Software,
+            FlowControl::Hardware => Self::Hardware,
+        }
+    }
+}
+
+/// How the link with a device is set up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SerialConfig {
+    pub baud: u32,
+    /// Time given to the device to answer a command
+    pub timeout: Duration,
+    /// Only used by local serial ports
+    pub flow_control: FlowControl,
+    /// Minimum pause between two commands, for devices or bridges dropping
+    /// commands sent too close to each other
+    pub command_delay: Duration,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            baud: DEFAULT_BAUD,
+            timeout: Duration::from_millis(DEFAULT_TIMEOUT_MS),
+            flow_control: FlowControl::None,
+            command_delay: Duration::ZERO,
+        }
+    }
+}
+
+impl SerialConfig {
+    pub fn new(baud: u32, timeout_ms: u64) -> Self {
+        Self {
+            baud,
+            timeout: Duration::from_millis(timeout_ms),
+            ..Default::default()
+        }
+    }
+}
+
+/// Serial link of a device in a config file, every unset field keeps the
+/// default of its model
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SerialOverrides {
+    pub baud: Option<u32>,
+    pub timeout_ms: Option<u64>,
+    pub flow_control: Option<FlowControl>,
+    /// Minimum pause between two commands
+    pub command_delay_ms: Option<u64>,
+}
+
+/// Serial links of the `[serial."{serial number or port}"]` tables of a TOML
+/// config file, e.g.
+///
+/// ```toml
+/// [serial."PPBA1234"]
+/// timeout_ms = 1000
+/// command_delay_ms = 50
+///
+/// [serial."/dev/ttyUSB3"]
+/// baud = 19200
+/// flow_control = "hardware"
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(transparent)]
+pub struct SerialSettings(HashMap<String, SerialOverrides>);
+
+impl SerialSettings {
+    /// Read the `[serial]` tables of a config file, its other tables are ignored
+    pub fn from_file(path: &Path) -> Result<Self, PegasusError> {
+        #[derive(Deserialize)]
+        struct File {
+            #[serde(default)]
+            serial: SerialSettings,
+        }
+
+        let content = std::fs::read_to_string(path)?;
+        let file: File = toml::from_str(&content).map_err(|e| {
+            PegasusError::Validation(format!("Invalid config {}: {}", path.display(), e))
+        })?;
+        file.serial.validate()?;
+        Ok(file.serial)
+    }
+
+    pub fn validate(&self) -> Result<(), PegasusError> {
+        for (device, overrides) in &self.0 {
+            let invalid = if overrides.baud == Some(0) {
+                "baud"
+            } else if overrides.timeout_ms == Some(0) {
+                "timeout_ms"
+            } else {
+                continue;
+            };
+            return Err(PegasusError::Validation(format!(
+                "Invalid serial settings of {}: {} cannot be 0",
+                device, invalid
+            )));
+        }
+        Ok(())
+    }
+
+    /// Link of a device, looked up by USB serial number then by port. The
+    /// settings not configured are the defaults of the model, at `baud`.
+    pub fn resolve(&self, serial: Option<&str>, port: &str, baud: u32) -> SerialConfig {
+        let overrides = serial
+            .and_then(|serial| self.0.get(serial))
+            .or_else(|| self.0.get(port))
+            .cloned()
+            .unwrap_or_default();
+        let default = SerialConfig::default();
+
+        SerialConfig {
+            baud: overrides.baud.unwrap_or(baud),
+            timeout: overrides
+                .timeout_ms
+                .map_or(default.timeout, Duration::from_millis),
+            flow_control: overrides.flow_control.unwrap_or(default.flow_control),
+            command_delay: overrides
+                .command_delay_ms
+                .map_or(default.command_delay, Duration::from_millis),
+        }
+    }
+}
+
+#[async_trait]
+pub trait SerialTransport: Debug + Send + Sync {
+    /// Write a whole command frame to the device.
+    async fn write_frame(&mut self, frame: &[u8]) -> io::Result<()>;
+
+    /// Read the next byte sent by the device, fails with
+    /// `io::ErrorKind::TimedOut` if the device stays silent for too long.
+    async fn read_byte(&mut self) -> io::Result<u8>;
+
+    /// Close and open again the link, e.g. after the device was power cycled.
+    /// Transports that cannot be reopened do nothing.
+    async fn reopen(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Drop the bytes received but not read yet, e.g. the late answer to a
+    /// command that timed out. Transports without a receive buffer do nothing.
+    async fn discard_input(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Transport on top of any async stream, every operation is bound by `timeout`.
+#[derive(Debug)]
+pub struct StreamTransport<S> {
+    stream: S,
+    timeout: Duration,
+}
+
+impl<S> StreamTransport<S> {
+    pub fn new(stream: S, timeout: Duration) -> Self {
+        Self { stream, timeout }
+    }
+}
+
+#[async_trait]
+impl<S> SerialTransport for StreamTransport<S>
+where
+    S: AsyncRead + AsyncWrite + Debug + Unpin + Send + Sync,
+{
+    async fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        tokio::time::timeout(self.timeout, self.stream.write_all(frame))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+    }
+
+    async fn read_byte(&mut self) -> io::Result<u8> {
+        tokio::time::timeout(self.timeout, self.stream.read_u8())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+    }
+
+    async fn discard_input(&mut self) -> io::Result<()> {
+        let mut buf = [0; 64];
+        // The stream is polled once before the zero timeout expires, so only
+        // what was already received is read
+        while let Ok(read) = tokio::time::timeout(Duration::ZERO, self.stream.read(&mut buf)).await
+        {
+            if read? == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Advisory lock of a local serial port, held for as long as the port is open.
+///
+/// Two programs talking to the same device mix their commands and responses
+/// on the serial stream, so every port opened takes a lock file in the
+/// temporary directory. The lock is an OS file lock, released even if the
+/// process dies, the file only records the process holding it.
+#[derive(Debug)]
+pub struct PortLock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl PortLock {
+    /// `pegasus-<port>.lock`, symbolic links like `/dev/serial/by-id/...` are
+    /// resolved so every name of the port shares the lock
+    fn path_of(address: &str) -> PathBuf {
+        let port = std::fs::canonicalize(address)
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| address.to_owned());
+        let name: String = port
+            .trim_start_matches('/')
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        std::env::temp_dir().join(format!("pegasus-{}.lock", name))
+    }
+
+    /// Lock the port on `address`, fails with `io::ErrorKind::ResourceBusy` if
+    /// another process holds it
+    pub fn acquire(address: &str) -> io::Result<Self> {
+        let path = Self::path_of(address);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                let mut holder = String::new();
+                // Only used for the message
+                let _ = file.read_to_string(&mut holder);
+                let holder = match holder.trim() {
+                    "" => String::from("another process"),
+                    pid => format!("process {}", pid),
+                };
+                return Err(io::Error::new(
+                    io::ErrorKind::ResourceBusy,
+                    format!("{} is used by {} ({})", address, holder, path.display()),
+                ));
+            }
+            Err(TryLockError::Error(e)) => return Err(e),
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", std::process::id())?;
+        debug!("Locked {} with {}", address, path.display());
+        Ok(Self { path, _file: file })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Local serial port that remembers its settings so it can be reopened.
+#[derive(Debug)]
+pub struct SerialPortTransport {
+    address: String,
+    config: SerialConfig,
+    inner: StreamTransport<SerialStream>,
+    /// Kept across reopens, the port stays ours
+    _lock: PortLock,
+}
+
+impl SerialPortTransport {
+    pub fn open(address: &str, baud: u32, timeout_ms: u64) -> io::Result<Self> {
+        Self::open_with_config(address, &SerialConfig::new(baud, timeout_ms))
+    }
+
+    pub fn open_with_config(address: &str, config: &SerialConfig) -> io::Result<Self> {
+        let lock = PortLock::acquire(address)?;
+        let stream = Self::open_stream(address, config)?;
+
+        Ok(Self {
+            address: address.to_owned(),
+            config: *config,
+            inner: StreamTransport::new(stream, config.timeout),
+            _lock: lock,
+        })
+    }
+
+    fn open_stream(address: &str, config: &SerialConfig) -> io::Result<SerialStream> {
+        Ok(tokio_serial::new(address, config.baud)
+            .timeout(config.timeout)
+            .flow_control(config.flow_control.into())
+            .open_native_async()?)
+    }
+}
+
+#[async_trait]
+impl SerialTransport for SerialPortTransport {
+    async fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.inner.write_frame(frame).await
+    }
+
+    async fn read_byte(&mut self) -> io::Result<u8> {
+        self.inner.read_byte().await
+    }
+
+    async fn reopen(&mut self) -> io::Result<()> {
+        debug!("Reopening {}", self.address);
+        let stream = Self::open_stream(&self.address, &self.config)?;
+        self.inner = StreamTransport::new(stream, self.config.timeout);
+        Ok(())
+    }
+
+    async fn discard_input(&mut self) -> io::Result<()> {
+        self.inner.discard_input().await
+    }
+}
+
+/// Transport waiting a minimum time between two commands
+#[derive(Debug)]
+pub struct PacedTransport {
+    inner: Box<dyn SerialTransport>,
+    delay: Duration,
+    last_command: Option<Instant>,
+}
+
+impl PacedTransport {
+    pub fn new(inner: Box<dyn SerialTransport>, delay: Duration) -> Self {
+        Self {
+            inner,
+            delay,
+            last_command: None,
+        }
+    }
+}
+
+#[async_trait]
+impl SerialTransport for PacedTransport {
+    async fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if let Some(last) = self.last_command {
+            tokio::time::sleep_until(last + self.delay).await;
+        }
+        self.last_command = Some(Instant::now());
+        self.inner.write_frame(frame).await
+    }
+
+    async fn read_byte(&mut self) -> io::Result<u8> {
+        self.inner.read_byte().await
+    }
+
+    async fn reopen(&mut self) -> io::Result<()> {
+        self.inner.reopen().await
+    }
+
+    async fn discard_input(&mut self) -> io::Result<()> {
+        self.inner.discard_input().await
+    }
+}
+
+/// Open a local serial port (/dev/ttyUSB0, COM6, ...) as an async transport.
+pub fn open_serial(
+    address: &str,
+    baud: u32,
+    timeout_ms: u64,
+) -> io::Result<Box<dyn SerialTransport>> {
+    Ok(Box::new(SerialPortTransport::open(
+        address, baud, timeout_ms,
+    )?))
+}
+
+// Telnet bytes used by RFC 2217
+const IAC: u8 = 255;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const COM_PORT_OPTION: u8 = 44;
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const PARITY_NONE: u8 = 1;
+const STOPSIZE_1: u8 = 1;
+
+/// Serial port of a remote host reached over TCP, e.g. exposed by ser2net.
+///
+/// In raw mode the bytes go through as they are and the port settings are the
+/// ones of the remote host. With RFC 2217 the link speaks telnet and sets the
+/// baud rate and the `8N1` framing of the remote port when connecting.
+#[derive(Debug)]
+pub struct NetworkTransport {
+    /// `host:port`
+    host: String,
+    rfc2217: bool,
+    baud: u32,
+    timeout: Duration,
+    inner: StreamTransport<TcpStream>,
+}
+
+impl NetworkTransport {
+    pub async fn connect(
+        host: &str,
+        rfc2217: bool,
+        baud: u32,
+        timeout_ms: u64,
+    ) -> io::Result<Self> {
+        let timeout = Duration::from_millis(timeout_ms);
+        let mut transport = Self {
+            host: host.to_owned(),
+            rfc2217,
+            baud,
+            timeout,
+            inner: StreamTransport::new(Self::open_stream(host, timeout).await?, timeout),
+        };
+        transport.configure().await?;
+        Ok(transport)
+    }
+
+    async fn open_stream(host: &str, timeout: Duration) -> io::Result<TcpStream> {
+        let stream = tokio::time::timeout(timeout, TcpStream::connect(host))
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        // Commands are tiny, don't wait to fill a segment
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+
+    /// Set the remote port up, RFC 2217 only
+    async fn configure(&mut self) -> io::Result<()> {
+        if !self.rfc2217 {
+            return Ok(());
+        }
+        let mut frame = vec![IAC, WILL, COM_PORT_OPTION];
+        let mut subnegotiation = |option: u8, value: &[u8]| {
+            frame.extend([IAC, SB, COM_PORT_OPTION, option]);
+            frame.extend(escape(value));
+            frame.extend([IAC, SE]);
+        };
+        subnegotiation(SET_BAUDRATE, &self.baud.to_be_bytes());
+        subnegotiation(SET_DATASIZE, &[8]);
+        subnegotiation(SET_PARITY, &[PARITY_NONE]);
+        subnegotiation(SET_STOPSIZE, &[STOPSIZE_1]);
+        self.inner.write_frame(&frame).await
+    }
+}
+
+/// Double the `IAC` bytes so telnet doesn't take them for commands
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &byte in data {
+        escaped.push(byte);
+        if byte == IAC {
+            escaped.push(IAC);
+        }
+    }
+    escaped
+}
+
+#[async_trait]
+impl SerialTransport for NetworkTransport {
+    async fn write_frame(&mut self, frame: &[u8]) -> io::Result<()> {
+        if self.rfc2217 {
+            self.inner.write_frame(&escape(frame)).await
+        } else {
+            self.inner.write_frame(frame).await
+        }
+    }
+
+    async fn read_byte(&mut self) -> io::Result<u8> {
+        if !self.rfc2217 {
+            return self.inner.read_byte().await;
+        }
+        // Skip the telnet negotiation and the answers of the remote port
+        loop {
+            let byte = self.inner.read_byte().await?;
+            if byte != IAC {
+                return Ok(byte);
+            }
+            match self.inner.read_byte().await? {
+                IAC => return Ok(IAC),
+                SB => {
+                    let mut previous = 0;
+                    loop {
+                        let byte = self.inner.read_byte().await?;
+                        if previous == IAC && byte == SE {
+                            break;
+                        }
+                        // An escaped IAC must not be taken for the start of IAC SE
+                        previous = if previous == IAC { 0 } else { byte };
+                    }
+                }
+                // WILL, WONT, DO and DONT are followed by the option
+                251..=254 => {
+                    self.inner.read_byte().await?;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    async fn reopen(&mut self) -> io::Result<()> {
+        debug!("Reconnecting to {}", self.host);
+        let stream = Self::open_stream(&self.host, self.timeout).await?;
+        self.inner = StreamTransport::new(stream, self.timeout);
+        self.configure().await
+    }
+
+    async fn discard_input(&mut self) -> io::Result<()> {
+        self.inner.discard_input().await
+    }
+}
+
+/// Open the link to a device from its address: `tcp://host:port` for a raw
+/// TCP serial bridge, `rfc2217://host:port` for an RFC 2217 one and a local
+/// serial port for anything else.
+pub async fn open(
+    address: &str,
+    baud: u32,
+    timeout_ms: u64,
+) -> io::Result<Box<dyn SerialTransport>> {
+    open_with_config(address, &SerialConfig::new(baud, timeout_ms)).await
+}
+
+/// Same as [`open`] with every setting of the link, the link is traced if
+/// [`trace::start`] was called
+pub async fn open_with_config(
+    address: &str,
+    config: &SerialConfig,
+) -> io::Result<Box<dyn SerialTransport>> {
+    let timeout_ms = config.timeout.as_millis() as u64;
+    let transport: Box<dyn SerialTransport> = if let Some(host) = address.strip_prefix("tcp://") {
+        Box::new(NetworkTransport::connect(host, false, config.baud, timeout_ms).await?)
+    } else if let Some(host) = address.strip_prefix("rfc2217://") {
+        Box::new(NetworkTransport::connect(host, true, config.baud, timeout_ms).await?)
+    } else {
+        Box::new(SerialPortTransport::open_with_config(address, config)?)
+    };
+    let transport = trace::wrap(address, transport);
+
+    if config.command_delay.is_zero() {
+        Ok(transport)
+    } else {
+        Ok(Box::new(PacedTransport::new(
+            transport,
+            config.command_delay,
+        )))
+    }
+}
+
+/// Lines skipped while waiting for the response of a command, past them the
+/// line read is taken as a garbled response
+const MAX_STALE_LINES: u32 = 3;
+
+static STALE_RESPONSES: AtomicU64 = AtomicU64::new(0);
+
+/// Responses discarded so far because they answered another command, e.g. a
+/// device answering after its command timed out
+pub fn stale_responses() -> u64 {
+    STALE_RESPONSES.load(Ordering::Relaxed)
+}
+
+/// Send a command to a Pegasus device and wait for its response.
+///
+/// All Pegasus devices speak the same line based protocol, the response is
+/// checked by [`Command::decode`], a response ending with `:ERR` is reported
+/// as a [`PegasusError::Protocol`]. Lines that don't
+/// [answer](Command::answers) the command are late responses to a previous
+/// one, they are skipped to get back in sync with the device.
+pub async fn send_command(
+    transport: &mut dyn SerialTransport,
+    command: &Command,
+) -> Result<String, PegasusError> {
+    let frame = command.encode()?;
+    transport.discard_input().await?;
+    transport.write_frame(&frame).await?;
+    debug!("Sent command: {}", command);
+
+    let mut stale = 0;
+    loop {
+        let line = read_line(transport).await?;
+        debug!("RESPONSE: {}", String::from_utf8_lossy(&line).trim_end());
+        match std::str::from_utf8(&line) {
+            Ok(response) if stale < MAX_STALE_LINES && !command.answers(response.trim_end()) => {
+                stale += 1;
+                STALE_RESPONSES.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Discarding {}, read after {} but not its response",
+                    response.trim_end(),
+                    command
+                );
+            }
+            _ => return command.decode(&line),
+        }
+    }
+}
+
+async fn read_line(transport: &mut dyn SerialTransport) -> Result<Vec<u8>, PegasusError> {
+    let mut line = Vec::new();
+    loop {
+        let byte = transport.read_byte().await.inspect_err(|e| {
+            if e.kind() != io::ErrorKind::TimedOut {
+                error!("{:?}", e);
+            }
+        })?;
+        line.push(byte);
+
+        if byte == b'\n' {
+            return Ok(line);
+        }
+    }
+}
+
+/// Default number of times a command is sent again when the device doesn't answer.
+pub const DEFAULT_MAX_RETRIES: u32 = 2;
+/// Default pause before the first retry.
+pub const DEFAULT_RETRY_DELAY_MS: u64 = 50;
+/// Default factor applied to the pause after every retry.
+pub const DEFAULT_RETRY_BACKOFF: f32 = 2.0;
+/// Longest pause between two attempts, however large the backoff.
+pub const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// How a command is sent again when the device doesn't answer, so a single
+/// byte lost on a long USB cable doesn't drop the whole device.
+///
+/// Only timeouts and garbled responses are retried, a device answering with
+/// an error is not.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Attempts after the first one, 0 disables retries
+    pub max_retries: u32,
+    /// Pause before the first retry
+    pub retry_delay: Duration,
+    /// Factor applied to the pause after every retry
+    pub backoff: f32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: DEFAULT_MAX_RETRIES,
+            retry_delay: Duration::from_millis(DEFAULT_RETRY_DELAY_MS),
+            backoff: DEFAULT_RETRY_BACKOFF,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Send every command only once
+    pub const NONE: Self = Self {
+        max_retries: 0,
+        retry_delay: Duration::ZERO,
+        backoff: 1.0,
+    };
+
+    /// Read the policy from `PPBA_MAX_RETRIES`, `PPBA_RETRY_DELAY_MS` and
+    /// `PPBA_RETRY_BACKOFF`, falling back to the defaults for missing or invalid values.
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            std::env::var(name).ok().and_then(|v| v.parse().ok())
+        }
+
+        let default = Self::default();
+        Self {
+            max_retries: var("PPBA_MAX_RETRIES").unwrap_or(default.max_retries),
+            retry_delay: var("PPBA_RETRY_DELAY_MS")
+                .map(Duration::from_millis)
+                .filter(|d| *d <= MAX_RETRY_DELAY)
+                .unwrap_or(default.retry_delay),
+            backoff: var("PPBA_RETRY_BACKOFF")
+                .filter(|b| Self::valid_backoff(*b))
+                .unwrap_or(default.backoff),
+        }
+    }
+
+    /// The pause can't exceed [`MAX_RETRY_DELAY`] and the backoff must be a
+    /// finite factor of at least 1.
+    pub fn validate(&self) -> Result<(), PegasusError> {
+        if self.retry_delay > MAX_RETRY_DELAY {
+            return Err(PegasusError::Validation(format!(
+                "retry delay cannot exceed {} ms",
+                MAX_RETRY_DELAY.as_millis()
+            )));
+        }
+        if !Self::valid_backoff(self.backoff) {
+            return Err(PegasusError::Validation(format!(
+                "invalid retry backoff {}, it must be a finite factor of at least 1",
+                self.backoff
+            )));
+        }
+        Ok(())
+    }
+
+    fn valid_backoff(backoff: f32) -> bool {
+        backoff.is_finite() && backoff >= 1.0
+    }
+
+    /// Pause before the retry number `retry` (starting at 0), at most
+    /// [`MAX_RETRY_DELAY`]
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = self.backoff.powi(retry.min(16) as i32);
+        Duration::try_from_secs_f64(self.retry_delay.as_secs_f64() * f64::from(factor))
+            .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+    }
+}
+
+/// Retry policy of a command in a config file, every unset field keeps the
+/// default policy
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryOverrides {
+    pub max_retries: Option<u32>,
+    pub retry_delay_ms: Option<u64>,
+    pub backoff: Option<f32>,
+}
+
+impl RetryOverrides {
+    /// `default` with the fields set here
+    pub fn apply(&self, default: RetryPolicy) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: self.max_retries.unwrap_or(default.max_retries),
+            retry_delay: self
+                .retry_delay_ms
+                .map_or(default.retry_delay, Duration::from_millis),
+            backoff: self.backoff.unwrap_or(default.backoff),
+        }
+    }
+}
+
+/// Retry policy of every command, with overrides for specific commands.
+#[derive(Clone, Debug, Default)]
+pub struct RetryPolicies {
+    pub default: RetryPolicy,
+    /// Keyed by command code, e.g. `PA` or `P3:`
+    overrides: HashMap<String, RetryPolicy>,
+}
+
+impl RetryPolicies {
+    pub fn new(default: RetryPolicy) -> Self {
+        Self {
+            default,
+            overrides: HashMap::new(),
+        }
+    }
+
+    /// Use `policy` for `command` (`PA`, `P3:`, ...) instead of the default one.
+    pub fn set_override(&mut self, command: &str, policy: RetryPolicy) {
+        self.overrides.insert(command.to_owned(), policy);
+    }
+
+    /// `default` with the `[retry."{command}"]` tables of a config file, e.g.
+    ///
+    /// ```toml
+    /// [retry."PF"]
+    /// max_retries = 0
+    ///
+    /// [retry."P3:"]
+    /// max_retries = 4
+    /// retry_delay_ms = 200
+    /// ```
+    pub fn with_overrides(
+        default: RetryPolicy,
+        overrides: &HashMap<String, RetryOverrides>,
+    ) -> Result<Self, PegasusError> {
+        let mut policies = Self::new(default);
+        for (command, fields) in overrides {
+            let policy = fields.apply(default);
+            policy.validate().map_err(|e| {
+                PegasusError::Validation(format!("Invalid retry policy of {}: {}", command, e))
+            })?;
+            policies.set_override(command, policy);
+        }
+        Ok(policies)
+    }
+
+    pub fn for_command(&self, command: &str) -> RetryPolicy {
+        self.overrides.get(command).copied().unwrap_or(self.default)
+    }
+}
+
+/// Same as [`send_command`], sending the command again according to `policies`
+/// when the device doesn't answer or the response is garbled.
+pub async fn send_command_with_retry(
+    transport: &mut dyn SerialTransport,
+    command: &Command,
+    policies: &RetryPolicies,
+) -> Result<String, PegasusError> {
+    let code = command.code();
+    let policy = policies.for_command(&code);
+
+    let mut retry = 0;
+    loop {
+        let res = send_command(transport, command).await;
+        match res {
+            Err(ref e) if e.link_failed() && retry < policy.max_retries => {
+                let delay = policy.delay(retry);
+                retry += 1;
+                warn!(
+                    "{} failed ({}), retry {}/{} in {:?}",
+                    code, e, retry, policy.max_retries, delay
+                );
+                tokio::time::sleep(delay).await;
+            }
+            _ => return res,
+        }
+    }
+}
@@ -0,0 +1,679 @@
+use crate::calibration::SensorCalibration;
+use crate::codec::{self, Payload};
+use crate::device::{self, AstronomicalDevice, PegasusDevice, ReadTimes};
+use crate::dewpoint::Magnus;
+use crate::error::PegasusError;
+use crate::parser;
+use crate::smoothing::{Filter, Smoothing};
+use crate::transport::{self, RetryPolicies, SerialConfig, SerialTransport};
+use astrotools::properties::{Permission, Prop, Property};
+use async_trait::async_trait;
+use log::{debug, error, info};
+use serde::Serialize;
+use std::str::FromStr;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Raw per port current readings must be divided by this to get Amps
+const PORT_CURRENT_DIVIDER: f32 = 480.0;
+/// Raw dew heater current readings must be divided by this to get Amps
+const DEW_CURRENT_DIVIDER: f32 = 700.0;
+/// Properties read with `PA` and `PS`, each field is taken on its own
+const READINGS: &[&str] = &[
+    "input_voltage",
+    "total_current",
+    "power",
+    "temperature",
+    "humidity",
+    "dew_point",
+    "dew_point_computed",
+    "dew_margin",
+    "power_ports",
+    "usb_ports",
+    "dew_power",
+    "power_ports_current",
+    "dew_current",
+    "autodew",
+    "average_amps",
+    "amps_hours",
+    "watt_hours",
+    "uptime",
+    "uptime_seconds",
+    "uptime_human",
+    "average_power",
+];
+
+#[derive(Debug, Serialize)]
+pub struct UltimatePowerBoxV2 {
+    #[serde(skip)]
+    pub id: Uuid,
+    name: String,
+    address: String,
+    pub baud: u32,
+    #[serde(skip)]
+    pub port: Box<dyn SerialTransport>,
+    /// USB serial number, `UNKNOWN` if the device was opened without it
+    serial_number: Property<String>,
+    fw_version: Property<String>,
+    input_voltage: Property<f32>,
+    total_current: Property<f32>,
+    power: Property<f32>,
+    temperature: Property<f32>,
+    humidity: Property<f32>,
+    dew_point: Property<f32>,
+    /// Added to the temperature read by the sensor, in °C
+    temperature_offset: Property<f32>,
+    /// Added to the humidity read by the sensor, in %
+    humidity_offset: Property<f32>,
+    /// Dew point computed with `dew_formula`, `dew_point` is the one of the firmware
+    dew_point_computed: Property<f32>,
+    /// Degrees the temperature is above `dew_point_computed`
+    dew_margin: Property<f32>,
+    #[serde(skip)]
+    dew_formula: Magnus,
+    power_ports: [Property<bool>; 4],
+    power_ports_current: [Property<f32>; 4],
+    usb_ports: [Property<bool>; 6],
+    dew_power: [Property<u8>; 3],
+    dew_current: [Property<f32>; 3],
+    adj_output: Property<u8>,
+    autodew: Property<bool>,
+    average_amps: Property<f32>,
+    amps_hours: Property<f32>,
+    watt_hours: Property<f32>,
+    uptime: Property<u32>,
+    uptime_seconds: Property<u32>,
+    /// Uptime as `[Nd ]HH:MM:SS`
+    uptime_human: Property<String>,
+    /// Average power in W since the device was powered up
+    average_power: Property<f32>,
+    /// Milliseconds between two polls
+    polling_interval: Property<u64>,
+    #[serde(flatten)]
+    smoothing: Smoothing,
+    /// Set commands are validated and logged but never sent
+    read_only: Property<bool>,
+    #[serde(skip)]
+    retry: RetryPolicies,
+    #[serde(skip)]
+    read_times: ReadTimes,
+    /// False once the device stopped answering
+    #[serde(skip)]
+    connected: bool,
+}
+
+// The whole protocol is mapped here even if not every command is issued yet
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+enum Command {
+    /// Status command serial code is P#
+    Status = 0x5023,
+    /// Firmware version command serial code is PV
+    FirmwareVersion = 0x5056,
+    /// Power and sensor reading serial code is PA
+    PowerAndSensorReadings = 0x5041,
+    /// Power consumption and stats serial code is PS
+    PowerConsumAndStats = 0x5053,
+    /// Power port 1 SET command is P1:
+    PowerPort1 = 0x50313a,
+    /// Power port 2 SET command is P2:
+    PowerPort2 = 0x50323a,
+    /// Power port 3 SET command is P3:
+    PowerPort3 = 0x50333a,
+    /// Power port 4 SET command is P4:
+    PowerPort4 = 0x50343a,
+    /// DewA power SET command is P5:
+    Dew1Power = 0x50353a,
+    /// DewB power SET command is P6:
+    Dew2Power = 0x50363a,
+    /// DewC power SET command is P7:
+    Dew3Power = 0x50373a,
+    /// Adjustable output voltage SET command is P8:
+    AdjOutput = 0x50383a,
+    /// USB port 1 SET command is U1:
+    UsbPort1 = 0x55313a,
+    /// USB port 2 SET command is U2:
+    UsbPort2 = 0x55323a,
+    /// USB port 3 SET command is U3:
+    UsbPort3 = 0x55333a,
+    /// USB port 4 SET command is U4:
+    UsbPort4 = 0x55343a,
+    /// USB port 5 SET command is U5:
+    UsbPort5 = 0x55353a,
+    /// USB port 6 SET command is U6:
+    UsbPort6 = 0x55363a,
+    /// Power status on boot SET command is PE:
+    PowerStatusOnBoot = 0x50453a,
+    /// Reboot command is PF
+    Reboot = 0x5046,
+}
+
+impl Command {
+    /// Start of the response of the queries, to tell it from a late answer
+    /// to another command
+    fn response_prefix(self) -> Option<&'static str> {
+        match self {
+            Self::PowerAndSensorReadings => Some("UPB2:"),
+            Self::PowerConsumAndStats => Some("PS:"),
+            _ => None,
+        }
+    }
+}
+
+/// Settings a client can change on an UPBv2, outputs are 0 based
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Setting {
+    /// 12V power output on or off, `power_port_1` to `power_port_4`
+    PowerPort(usize, bool),
+    /// USB port on or off, `usb_port_1` to `usb_port_6`
+    UsbPort(usize, bool),
+    /// PWM duty cycle of a dew heater, `dew_power_1` to `dew_power_3`
+    DewPower(usize, u8),
+    /// Voltage of the adjustable output (3-12V), `adj_output`
+    AdjOutput(u8),
+    /// Milliseconds between two polls, `polling_interval`
+    PollingInterval(u64),
+    /// Offsets of the sensor, `temperature_offset` and `humidity_offset`
+    TemperatureOffset(f32),
+    HumidityOffset(f32),
+}
+
+const POWER_PORTS: [Command; 4] = [
+    Command::PowerPort1,
+    Command::PowerPort2,
+    Command::PowerPort3,
+    Command::PowerPort4,
+];
+const USB_PORTS: [Command; 6] = [
+    Command::UsbPort1,
+    Command::UsbPort2,
+    Command::UsbPort3,
+    Command::UsbPort4,
+    Command::UsbPort5,
+    Command::UsbPort6,
+];
+const DEW_OUTPUTS: [Command; 3] = [Command::Dew1Power, Command::Dew2Power, Command::Dew3Power];
+
+/// Parse the field at `idx` of a response, None if missing or malformed
+fn field<T: FromStr>(chunks: &[&str], idx: usize) -> Option<T> {
+    chunks.get(idx).and_then(|v| v.parse().ok())
+}
+
+/// Parse names like `power_port_3` into the 0 based index of the output
+fn output_index(prop_name: &str, prefix: &str, count: usize) -> Option<usize> {
+    prop_name
+        .strip_prefix(prefix)
+        .and_then(|n| n.parse::<usize>().ok())
+        .filter(|n| (1..=count).contains(n))
+        .map(|n| n - 1)
+}
+
+fn parse_switch(prop_name: &str, val: &str) -> Result<bool, PegasusError> {
+    match val {
+        "1" | "true" => Ok(true),
+        "0" | "false" => Ok(false),
+        _ => Err(PegasusError::Validation(format!(
+            "Invalid value for {}: {}",
+            prop_name, val
+        ))),
+    }
+}
+
+fn no_such_output(idx: usize) -> PegasusError {
+    PegasusError::Validation(format!("No output at index {}", idx))
+}
+
+impl UltimatePowerBoxV2 {
+    pub async fn new(
+        name: &str,
+        address: &str,
+        baud: u32,
+        timeout_ms: u64,
+    ) -> Result<Self, PegasusError> {
+        Self::with_config(name, address, &SerialConfig::new(baud, timeout_ms)).await
+    }
+
+    /// Same as [`UltimatePowerBoxV2::new`] with every setting of the serial
+    /// link, e.g. flow control or a pause between commands.
+    pub async fn with_config(
+        name: &str,
+        address: &str,
+        config: &SerialConfig,
+    ) -> Result<Self, PegasusError> {
+        let port = transport::open_with_config(address, config).await?;
+
+        let mut dev = Self {
+            id: Uuid::new_v4(),
+            name: name.to_owned(),
+            address: address.to_owned(),
+            baud: config.baud,
+            port,
+            serial_number: Property::<String>::new("UNKNOWN".to_string(), Permission::ReadOnly),
+            fw_version: Property::<String>::new("UNKNOWN".to_string(), Permission::ReadOnly),
+            input_voltage: Property::<f32>::new(0.0, Permission::ReadOnly),
+            total_current: Property::<f32>::new(0.0, Permission::ReadOnly),
+            power: Property::<f32>::new(0.0, Permission::ReadOnly),
+            temperature: Property::<f32>::new(0.0, Permission::ReadOnly),
+            humidity: Property::<f32>::new(0.0, Permission::ReadOnly),
+            dew_point: Property::<f32>::new(0.0, Permission::ReadOnly),
+            temperature_offset: Property::<f32>::new(0.0, Permission::ReadWrite),
+            humidity_offset: Property::<f32>::new(0.0, Permission::ReadWrite),
+            dew_point_computed: Property::<f32>::new(0.0, Permission::ReadOnly),
+            dew_margin: Property::<f32>::new(0.0, Permission::ReadOnly),
+            dew_formula: Magnus::default(),
+            power_ports: [Property::<bool>::new(false, Permission::ReadWrite); 4],
+            power_ports_current: [Property::<f32>::new(0.0, Permission::ReadOnly); 4],
+            usb_ports: [Property::<bool>::new(false, Permission::ReadWrite); 6],
+            dew_power: [Property::<u8>::new(0, Permission::ReadWrite); 3],
+            dew_current: [Property::<f32>::new(0.0, Permission::ReadOnly); 3],
+            adj_output: Property::<u8>::new(0, Permission::ReadWrite),
+            autodew: Property::<bool>::new(false, Permission::ReadOnly),
+            average_amps: Property::<f32>::new(0.0, Permission::ReadOnly),
+            amps_hours: Property::<f32>::new(0.0, Permission::ReadOnly),
+            watt_hours: Property::<f32>::new(0.0, Permission::ReadOnly),
+            uptime: Property::<u32>::new(0, Permission::ReadOnly),
+            uptime_seconds: Property::<u32>::new(0, Permission::ReadOnly),
+            uptime_human: Property::<String>::new(parser::format_uptime(0), Permission::ReadOnly),
+            average_power: Property::<f32>::new(0.0, Permission::ReadOnly),
+            polling_interval: Property::<u64>::new(
+                device::DEFAULT_POLLING_INTERVAL_MS,
+                Permission::ReadWrite,
+            ),
+            smoothing: Smoothing::default(),
+            read_only: Property::new(false, Permission::ReadOnly),
+            retry: RetryPolicies::default(),
+            read_times: ReadTimes::new(READINGS),
+            connected: true,
+        };
+
+        // Both UPB revisions share the serial prefix, only v2 answers UPB2_OK
+        match dev.send_command(Command::Status, None).await?.as_str() {
+            "UPB2_OK" => {
+                if let Ok(fw) = dev.send_command(Command::FirmwareVersion, None).await {
+                    dev.fw_version.update_int(fw);
+                }
+                dev.fetch_props().await;
+                Ok(dev)
+            }
+            other => Err(PegasusError::Unsupported(format!(
+                "Not an Ultimate Powerbox v2: {}",
+                other
+            ))),
+        }
+    }
+
+    /// Change the pause between two polls, at least
+    /// [`MIN_POLLING_INTERVAL_MS`](device::MIN_POLLING_INTERVAL_MS).
+    pub fn set_polling_interval(&mut self, ms: u64) -> Result<(), PegasusError> {
+        let ms = device::check_polling_interval(ms)?;
+        self.polling_interval.update_int(ms);
+        Ok(())
+    }
+
+    /// Identify the device by its USB serial number, its id is derived from it
+    /// with [`device_id`](device::device_id) instead of being random.
+    pub fn set_serial_number(&mut self, serial_number: &str) {
+        self.id = device::device_id(serial_number);
+        self.serial_number.update_int(serial_number.to_owned());
+    }
+
+    /// Reuse the id a device without serial number had, e.g. before a restart
+    pub fn set_id(&mut self, id: Uuid) {
+        self.id = id;
+    }
+
+    /// Correct the readings of the sensor from the next poll on
+    pub fn set_calibration(&mut self, calibration: SensorCalibration) -> Result<(), PegasusError> {
+        calibration.validate()?;
+        self.temperature_offset.update_int(calibration.temperature);
+        self.humidity_offset.update_int(calibration.humidity);
+        Ok(())
+    }
+
+    pub fn calibration(&self) -> SensorCalibration {
+        SensorCalibration {
+            temperature: *self.temperature_offset.value(),
+            humidity: *self.humidity_offset.value(),
+        }
+    }
+
+    /// Coefficients `dew_point_computed` is computed with from the next poll on
+    pub fn set_dew_point_formula(&mut self, formula: Magnus) -> Result<(), PegasusError> {
+        formula.validate()?;
+        self.dew_formula = formula;
+        Ok(())
+    }
+
+    /// Compute the dew point and the margin from the last readings
+    fn update_dew_margin(&mut self) {
+        let temperature = *self.temperature.value();
+        let dew_point = self
+            .dew_formula
+            .dew_point(temperature, *self.humidity.value());
+        self.dew_point_computed.update_int(dew_point);
+        self.dew_margin.update_int(temperature - dew_point);
+    }
+
+    /// Also expose a smoothed copy of a noisy reading, as `{reading}_smoothed`.
+    pub fn set_smoothing(&mut self, reading: &str, filter: Filter) -> Result<(), PegasusError> {
+        if self.reading(reading).is_none() {
+            return Err(PegasusError::Unsupported(format!(
+                "{} is not a reading of {}",
+                reading, self.name
+            )));
+        }
+        self.smoothing.set(reading, filter)
+    }
+
+    /// Smoothed value of a reading, if smoothing was enabled for it
+    pub fn smoothed(&self, reading: &str) -> Option<f32> {
+        self.smoothing.get(reading)
+    }
+
+    /// Raw value of the readings that can be smoothed
+    fn reading(&self, name: &str) -> Option<f32> {
+        let prop = match name {
+            "input_voltage" => &self.input_voltage,
+            "total_current" => &self.total_current,
+            "power" => &self.power,
+            "temperature" => &self.temperature,
+            "humidity" => &self.humidity,
+            "dew_point" => &self.dew_point,
+            "dew_point_computed" => &self.dew_point_computed,
+            "dew_margin" => &self.dew_margin,
+            "average_amps" => &self.average_amps,
+            _ => return None,
+        };
+        Some(*prop.value())
+    }
+
+    /// Change how commands the device doesn't answer are retried.
+    pub fn set_retry_policies(&mut self, retry: RetryPolicies) {
+        self.retry = retry;
+    }
+
+    /// In read-only mode the settings are validated but not sent, changing
+    /// one fails with [`PegasusError::ReadOnly`].
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only.update_int(read_only);
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        *self.read_only.value()
+    }
+
+    async fn send_command(
+        &mut self,
+        comm: Command,
+        payload: Option<Payload>,
+    ) -> Result<String, PegasusError> {
+        let command = codec::Command::new(comm as u32, payload).expecting(comm.response_prefix());
+        // Only the set commands have a value
+        if self.is_read_only() && command.payload().is_some() {
+            return Err(device::not_sent(&self.name, &command));
+        }
+        let res =
+            transport::send_command_with_retry(self.port.as_mut(), &command, &self.retry).await;
+        // A device answering with an error is still there, anything else means the link is gone
+        self.connected = !matches!(&res, Err(e) if e.link_failed());
+        res
+    }
+
+    async fn update_power_and_sensor_readings(&mut self) {
+        let stats = match self
+            .send_command(Command::PowerAndSensorReadings, None)
+            .await
+        {
+            Ok(stats) => stats,
+            Err(_) => {
+                error!("Couldn't read power and sensors reading");
+                return;
+            }
+        };
+        debug!("POWER AND SENSORS READINGS: {}", stats);
+        let chunks: Vec<&str> = stats.split(':').collect();
+
+        // The response is UPB2:voltage:current:power:temp:humidity:dewpoint:ports_status:usb_status:
+        // dewA:dewB:dewC:port1_current:port2_current:port3_current:port4_current:
+        // dewA_current:dewB_current:dewC_current:overcurrent_flags:autodew
+        if let Some(v) = field(&chunks, 1) {
+            self.input_voltage.update_int(v);
+            self.read_times.touch(&["input_voltage"]);
+        }
+        if let Some(v) = field(&chunks, 2) {
+            self.total_current.update_int(v);
+            self.read_times.touch(&["total_current"]);
+        }
+        if let Some(v) = field(&chunks, 3) {
+            self.power.update_int(v);
+            self.read_times.touch(&["power"]);
+        }
+        let calibration = self.calibration();
+        if let Some(v) = field(&chunks, 4) {
+            self.temperature.update_int(calibration.temperature(v));
+            self.read_times.touch(&["temperature"]);
+        }
+        if let Some(v) = field(&chunks, 5) {
+            self.humidity.update_int(calibration.humidity(v));
+            self.read_times.touch(&["humidity"]);
+        }
+        if let Some(v) = field(&chunks, 6) {
+            let (temperature, humidity) = (*self.temperature.value(), *self.humidity.value());
+            self.dew_point
+                .update_int(calibration.dew_point(v, temperature, humidity));
+            self.read_times.touch(&["dew_point"]);
+        }
+        self.update_dew_margin();
+        if field::<f32>(&chunks, 4).is_some() && field::<f32>(&chunks, 5).is_some() {
+            self.read_times.touch(&["dew_point_computed", "dew_margin"]);
+        }
+        if let Some(status) = chunks.get(7) {
+            for (port, c) in self.power_ports.iter_mut().zip(status.chars()) {
+                port.update_int(c == '1');
+            }
+            self.read_times.touch(&["power_ports"]);
+        }
+        if let Some(status) = chunks.get(8) {
+            for (port, c) in self.usb_ports.iter_mut().zip(status.chars()) {
+                port.update_int(c == '1');
+            }
+            self.read_times.touch(&["usb_ports"]);
+        }
+        for (i, dew) in self.dew_power.iter_mut().enumerate() {
+            if let Some(v) = field(&chunks, 9 + i) {
+                dew.update_int(v);
+            }
+        }
+        for (i, current) in self.power_ports_current.iter_mut().enumerate() {
+            if let Some(v) = field::<f32>(&chunks, 12 + i) {
+                current.update_int(v / PORT_CURRENT_DIVIDER);
+            }
+        }
+        for (i, current) in self.dew_current.iter_mut().enumerate() {
+            if let Some(v) = field::<f32>(&chunks, 16 + i) {
+                current.update_int(v / DEW_CURRENT_DIVIDER);
+            }
+        }
+        if let Some(v) = field::<u8>(&chunks, 20) {
+            self.autodew.update_int(v > 0);
+            self.read_times.touch(&["autodew"]);
+        }
+        // Arrays are fresh when their last field is
+        for (name, idx) in [
+            ("dew_power", 11),
+            ("power_ports_current", 15),
+            ("dew_current", 18),
+        ] {
+            if field::<f32>(&chunks, idx).is_some() {
+                self.read_times.touch(&[name]);
+            }
+        }
+    }
+
+    async fn update_power_consumption_and_stats(&mut self) {
+        let stats = match self.send_command(Command::PowerConsumAndStats, None).await {
+            Ok(stats) => stats,
+            Err(_) => {
+                error!("Couldn't read power consumption metrics");
+                return;
+            }
+        };
+        debug!("POWER CONSUMPTIONS STATS: {}", stats);
+        let chunks: Vec<&str> = stats.split(':').collect();
+
+        // The response is PS:averageAmps:ampHours:wattHours:uptime_in_milliseconds
+        if let Some(v) = field(&chunks, 1) {
+            self.average_amps.update_int(v);
+            self.read_times.touch(&["average_amps"]);
+        }
+        if let Some(v) = field(&chunks, 2) {
+            self.amps_hours.update_int(v);
+            self.read_times.touch(&["amps_hours"]);
+        }
+        if let Some(v) = field(&chunks, 3) {
+            self.watt_hours.update_int(v);
+            self.read_times.touch(&["watt_hours"]);
+        }
+        if let Some(v) = field(&chunks, 4) {
+            self.uptime.update_int(v);
+            self.read_times.touch(&["uptime"]);
+        }
+        let (watt_hours, uptime) = (*self.watt_hours.value(), *self.uptime.value());
+        if field::<u32>(&chunks, 4).is_some() {
+            self.read_times
+                .touch(&["uptime_seconds", "uptime_human", "average_power"]);
+        }
+        self.uptime_seconds.update_int(uptime / 1000);
+        self.uptime_human.update_int(parser::format_uptime(uptime));
+        self.average_power
+            .update_int(parser::average_power(watt_hours, uptime));
+    }
+}
+
+#[async_trait]
+impl AstronomicalDevice for UltimatePowerBoxV2 {
+    fn get_id(&self) -> Uuid {
+        self.id
+    }
+
+    fn get_name(&self) -> &String {
+        &self.name
+    }
+
+    fn get_address(&self) -> &String {
+        &self.address
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn polling_interval(&self) -> Duration {
+        Duration::from_millis(*self.polling_interval.value())
+    }
+
+    fn read_times(&self) -> Option<&ReadTimes> {
+        Some(&self.read_times)
+    }
+
+    async fn reconnect(&mut self) -> Result<(), PegasusError> {
+        self.port.reopen().await?;
+        self.send_command(Command::Status, None).await?;
+        info!("{} is back on {}", self.name, self.address);
+        Ok(())
+    }
+
+    async fn fetch_props(&mut self) {
+        info!("Fetching properties for device {}", self.name);
+        self.update_power_consumption_and_stats().await;
+        self.update_power_and_sensor_readings().await;
+        if self.connected {
+            let mut smoothing = std::mem::take(&mut self.smoothing);
+            smoothing.update(|name| self.reading(name));
+            self.smoothing = smoothing;
+        }
+    }
+
+    async fn update_property(&mut self, prop_name: &str, val: &str) -> Result<(), PegasusError> {
+        let setting = Self::parse_setting(prop_name, val)?;
+        self.apply(setting).await
+    }
+}
+
+#[async_trait]
+impl PegasusDevice for UltimatePowerBoxV2 {
+    type Setting = Setting;
+
+    fn parse_setting(prop_name: &str, val: &str) -> Result<Setting, PegasusError> {
+        let invalid =
+            || PegasusError::Validation(format!("Invalid value for {}: {}", prop_name, val));
+
+        if let Some(idx) = output_index(prop_name, "power_port_", POWER_PORTS.len()) {
+            return Ok(Setting::PowerPort(idx, parse_switch(prop_name, val)?));
+        }
+        if let Some(idx) = output_index(prop_name, "usb_port_", USB_PORTS.len()) {
+            return Ok(Setting::UsbPort(idx, parse_switch(prop_name, val)?));
+        }
+        if let Some(idx) = output_index(prop_name, "dew_power_", DEW_OUTPUTS.len()) {
+            let pwm = val.parse().map_err(|_| invalid())?;
+            return Ok(Setting::DewPower(idx, pwm));
+        }
+
+        match prop_name {
+            "polling_interval" => device::parse_polling_interval(val).map(Setting::PollingInterval),
+            "temperature_offset" => val
+                .parse()
+                .map(Setting::TemperatureOffset)
+                .map_err(|_| invalid()),
+            "humidity_offset" => val
+                .parse()
+                .map(Setting::HumidityOffset)
+                .map_err(|_| invalid()),
+            "adj_output" => val
+                .parse()
+                .ok()
+                .filter(|v| (3..=12).contains(v))
+                .map(Setting::AdjOutput)
+                .ok_or_else(invalid),
+            _ => Err(PegasusError::Unsupported(format!(
+                "Property {} cannot be updated",
+                prop_name
+            ))),
+        }
+    }
+
+    async fn apply(&mut self, setting: Setting) -> Result<(), PegasusError> {
+        match setting {
+            Setting::PowerPort(idx, on) => {
+                let comm = *POWER_PORTS.get(idx).ok_or_else(|| no_such_output(idx))?;
+                self.send_command(comm, Some(Payload::Flag(on))).await?;
+                self.power_ports[idx].update_int(on);
+            }
+            Setting::UsbPort(idx, on) => {
+                let comm = *USB_PORTS.get(idx).ok_or_else(|| no_such_output(idx))?;
+                self.send_command(comm, Some(Payload::Flag(on))).await?;
+                self.usb_ports[idx].update_int(on);
+            }
+            Setting::DewPower(idx, pwm) => {
+                let comm = *DEW_OUTPUTS.get(idx).ok_or_else(|| no_such_output(idx))?;
+                self.send_command(comm, Some(Payload::Pwm(pwm))).await?;
+                self.dew_power[idx].update_int(pwm);
+            }
+            Setting::AdjOutput(volts) => {
+                self.send_command(Command::AdjOutput, Some(Payload::Number(volts.into())))
+                    .await?;
+                self.adj_output.update_int(volts);
+            }
+            Setting::PollingInterval(ms) => self.set_polling_interval(ms)?,
+            Setting::TemperatureOffset(offset) => self.set_calibration(SensorCalibration {
+                temperature: offset,
+                ..self.calibration()
+            })?,
+            Setting::HumidityOffset(offset) => self.set_calibration(SensorCalibration {
+                humidity: offset,
+                ..self.calibration()
+            })?,
+        }
+        Ok(())
+    }
+}
@@ -1,16 +1,98 @@
-use serialport::{available_ports, SerialPortType, UsbPortInfo};
-pub fn look_for_devices(device_name: &str) -> Vec<(String, UsbPortInfo)> {
-    let ports = available_ports().unwrap();
-    let mut devices = Vec::new();
+use crate::codec::Command;
+use crate::discovery::DeviceFamily;
+use crate::transport;
+use log::debug;
 
-    for port in ports {
-        if let SerialPortType::UsbPort(info) = port.port_type {
-            if let Some(ref serial) = info.serial_number {
-                if &serial[0..4] == device_name {
-                    devices.push((port.port_name, info));
+/// A kind of Pegasus device the driver can find on the USB ports
+#[derive(Debug, PartialEq)]
+pub struct DeviceModel {
+    /// Prefix of the USB serial number
+    pub serial_prefix: &'static str,
+    pub baud: u32,
+    /// Command asking the device for its status, `P#` or `#` for the focusers
+    pub status_command: u32,
+    /// Prefix of the answer to the status command
+    pub status_prefix: &'static str,
+}
+
+impl DeviceModel {
+    pub fn family(&self) -> Option<DeviceFamily> {
+        DeviceFamily::from_serial(self.serial_prefix)
+    }
+}
+
+/// Every device model, the UPB entry only matches the v2
+pub const MODELS: &[DeviceModel] = &[
+    DeviceModel {
+        serial_prefix: "PPBA",
+        baud: transport::DEFAULT_BAUD,
+        status_command: 0x5023,
+        status_prefix: "PPBA_OK",
+    },
+    DeviceModel {
+        serial_prefix: "UPB",
+        baud: transport::DEFAULT_BAUD,
+        status_command: 0x5023,
+        status_prefix: "UPB2_OK",
+    },
+    DeviceModel {
+        serial_prefix: "PPBM",
+        baud: transport::DEFAULT_BAUD,
+        status_command: 0x5023,
+        status_prefix: "PPBM_OK",
+    },
+    DeviceModel {
+        serial_prefix: "DMFC",
+        baud: transport::FOCUSER_BAUD,
+        status_command: 0x23,
+        status_prefix: "OK_DMFC",
+    },
+    DeviceModel {
+        serial_prefix: "FC",
+        baud: transport::FOCUSER_BAUD,
+        status_command: 0x23,
+        status_prefix: "OK_FC",
+    },
+];
+
+/// USB vendor and product ids of the serial chips inside Pegasus devices,
+/// FTDI FT232R and FT230X
+pub const USB_IDS: &[(u16, u16)] = &[(0x0403, 0x6001), (0x0403, 0x6015)];
+
+/// Ask the device on `address` for its status at the speed of every model,
+/// returns the model that answered.
+pub async fn probe(address: &str) -> Option<&'static DeviceModel> {
+    let mut tried = Vec::new();
+    for model in MODELS {
+        // Models sharing the speed and the status command answer the same query
+        let query = (model.baud, model.status_command);
+        if tried.contains(&query) {
+            continue;
+        }
+        tried.push(query);
+
+        let mut port =
+            match transport::open_serial(address, model.baud, transport::DEFAULT_TIMEOUT_MS) {
+                Ok(port) => port,
+                Err(e) => {
+                    debug!("Cannot probe {}: {}", address, e);
+                    return None;
                 }
-            }
+            };
+        let status_query = Command::new(model.status_command, None);
+        let Ok(status) = transport::send_command(port.as_mut(), &status_query).await else {
+            continue;
+        };
+        let found = MODELS
+            .iter()
+            .find(|m| (m.baud, m.status_command) == query && status.starts_with(m.status_prefix));
+        if let Some(found) = found {
+            debug!(
+                "{} answered {}, it's a {}",
+                address, status, found.serial_prefix
+            );
+            return Some(found);
         }
     }
-    devices
+    None
 }
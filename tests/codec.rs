@@ -0,0 +1,153 @@
+use pegasus_astro::codec::{self, Command, Payload};
+use pegasus_astro::error::PegasusError;
+use proptest::prelude::*;
+
+/// SET commands of the PPBA, the UPBv2, the PPBM and the FocusCube
+const SET_CODES: &[(u32, &str)] = &[
+    (0x50313a, "P1:"),
+    (0x50323a, "P2:"),
+    (0x50333a, "P3:"),
+    (0x50343a, "P4:"),
+    (0x50353a, "P5:"),
+    (0x50363a, "P6:"),
+    (0x50373a, "P7:"),
+    (0x50383a, "P8:"),
+    (0x50443a, "PD:"),
+    (0x50453a, "PE:"),
+    (0x504c3a, "PL:"),
+    (0x55313a, "U1:"),
+    (0x55363a, "U6:"),
+    (0x4d3a, "M:"),
+    (0x473a, "G:"),
+    (0x573a, "W:"),
+    (0x433a, "C:"),
+    (0x4e3a, "N:"),
+    (0x4c3a, "L:"),
+];
+
+/// Queries of the same devices
+const QUERY_CODES: &[(u32, &str)] = &[
+    (0x5023, "P#"),
+    (0x5056, "PV"),
+    (0x5053, "PS"),
+    (0x5043, "PC"),
+    (0x5041, "PA"),
+    (0x5045, "PE"),
+    (0x5046, "PF"),
+    (0x23, "#"),
+    (0x56, "V"),
+    (0x54, "T"),
+    (0x50, "P"),
+    (0x49, "I"),
+    (0x48, "H"),
+];
+
+/// Encode `command`, check it's a single line and split it back
+fn round_trip(command: &Command) -> (String, Option<String>) {
+    let frame = command.encode().unwrap();
+    assert_eq!(frame.last(), Some(&b'\n'), "{}", command);
+    assert_eq!(frame.iter().filter(|&&b| b == b'\n').count(), 1);
+    let (code, value) = codec::split_frame(&frame).unwrap();
+    (code.to_owned(), value.map(str::to_owned))
+}
+
+fn numbers() -> Vec<i64> {
+    let mut numbers: Vec<i64> = (-1000..=1000).collect();
+    for exp in 3..19 {
+        let n = 10_i64.pow(exp);
+        numbers.extend([n - 1, n, n + 1, -n + 1, -n, -n - 1]);
+    }
+    numbers.extend([i64::MIN, i64::MAX]);
+    numbers
+}
+
+#[test]
+fn queries_are_sent_without_value() {
+    for &(code, text) in QUERY_CODES {
+        let command = Command::new(code, None);
+        assert!(!command.is_set());
+        assert_eq!(
+            command.encode().unwrap(),
+            format!("{}\n", text).into_bytes()
+        );
+        assert_eq!(round_trip(&command), (text.to_owned(), None));
+    }
+}
+
+#[test]
+fn every_value_round_trips() {
+    let numbers = numbers();
+    for &(code, text) in SET_CODES {
+        let command = Command::new(code, None);
+        assert!(command.is_set());
+        assert_eq!(command.code(), text);
+
+        for on in [false, true] {
+            let (sent, value) = round_trip(&Command::new(code, Some(Payload::Flag(on))));
+            assert_eq!(sent, text);
+            assert_eq!(value.unwrap(), if on { "1" } else { "0" });
+        }
+        for pwm in 0..=u8::MAX {
+            let (sent, value) = round_trip(&Command::new(code, Some(Payload::Pwm(pwm))));
+            let value = value.unwrap();
+            assert_eq!(sent, text);
+            // Always on 3 digits, `P3:5` would not be understood
+            assert_eq!(value.len(), 3, "{}", value);
+            assert_eq!(value.parse::<u8>().unwrap(), pwm);
+        }
+        for &n in &numbers {
+            let (sent, value) = round_trip(&Command::new(code, Some(Payload::Number(n))));
+            assert_eq!(sent, text);
+            assert_eq!(value.unwrap().parse::<i64>().unwrap(), n);
+        }
+        for mask in 0..16_u8 {
+            let mask = format!("{:04b}", mask);
+            let (sent, value) = round_trip(&Command::new(code, Some(Payload::Text(mask.clone()))));
+            assert_eq!(sent, text);
+            assert_eq!(value.unwrap(), mask);
+        }
+    }
+}
+
+proptest! {
+    // Printable ASCII, from `!` to `~`
+    #[test]
+    fn random_text_round_trips(text in "[!-~]{1,16}") {
+        let command = Command::new(0x50453a, Some(Payload::Text(text.clone())));
+        prop_assert_eq!(command.to_string(), format!("PE:{}", text));
+        let (code, value) = round_trip(&command);
+        // Only the first `:` ends the code
+        prop_assert_eq!(code, "PE:");
+        prop_assert_eq!(value.unwrap(), text);
+    }
+
+    #[test]
+    fn non_ascii_characters_are_rejected(
+        ones in 0..8_usize,
+        bad in (0x80..0x1_0000_u32).prop_filter_map("not a char", char::from_u32),
+        at in any::<prop::sample::Index>(),
+    ) {
+        let mut text = "1".repeat(ones);
+        text.insert(at.index(ones + 1), bad);
+        let command = Command::new(0x50453a, Some(Payload::Text(text)));
+        prop_assert!(command.encode().is_err());
+    }
+}
+
+#[test]
+fn non_ascii_payloads_are_rejected() {
+    for text in ["1011\n", "10\r11", "1 0", "\u{0}", "10é1", "１０１１", "🔌"] {
+        let command = Command::new(0x50453a, Some(Payload::Text(text.to_owned())));
+        assert!(
+            matches!(command.encode(), Err(PegasusError::Validation(_))),
+            "{:?} was encoded",
+            text
+        );
+    }
+
+    assert!(matches!(
+        codec::split_frame("P3:12é\n".as_bytes()),
+        Err(PegasusError::Parse(_))
+    ));
+    assert!(codec::split_frame(&[b'P', b'3', b':', 0xff, b'\n']).is_err());
+}
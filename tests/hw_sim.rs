@@ -0,0 +1,204 @@
+//! The drivers against simulated PPBAs behind pseudo terminals, run with
+//! `cargo test --features hw-sim`.
+#![cfg(all(unix, feature = "hw-sim"))]
+
+use pegasus_astro::device::AstronomicalDevice;
+use pegasus_astro::error::PegasusError;
+use pegasus_astro::ppba::PegasusPowerBox;
+use pegasus_astro::sim::{FakePpbaPort, VirtualSerialPair};
+use pegasus_astro::utils;
+use serde_json::Value;
+use std::process::{Command, Output};
+
+async fn connect(pair: &VirtualSerialPair) -> PegasusPowerBox {
+    PegasusPowerBox::try_new("sim", pair.path(), 9600, 500)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn ppba_is_discovered() {
+    let pair = VirtualSerialPair::new(FakePpbaPort::new()).unwrap();
+
+    let model = utils::probe(pair.path()).await.unwrap();
+    assert_eq!(model.serial_prefix, "PPBA");
+}
+
+#[tokio::test]
+async fn ppba_is_polled_and_updated() {
+    let pair = VirtualSerialPair::new(FakePpbaPort::new()).unwrap();
+    let mut ppba = connect(&pair).await;
+
+    pair.port()
+        .set_response("PA", "PPBA:12.2:0.4:21.5:45:9.1:1:0:128:0:0:0:12");
+    ppba.fetch_props().await;
+    assert!(ppba.is_connected());
+    assert_eq!(ppba.snapshot().input_voltage, 12.2);
+
+    ppba.update_property("dew1_power", "64").await.unwrap();
+    assert!(pair.port().sent_commands().contains(&"P3:064".to_owned()));
+    assert_eq!(ppba.snapshot().dew1_power, 64);
+}
+
+#[tokio::test]
+async fn ppba_errors_are_reported() {
+    let pair = VirtualSerialPair::new(FakePpbaPort::new()).unwrap();
+    let mut ppba = connect(&pair).await;
+
+    pair.port().set_response("P4:", "P4:ERR");
+    assert!(matches!(
+        ppba.update_property("dew2_power", "10").await,
+        Err(PegasusError::Protocol(response)) if response == "P4:ERR"
+    ));
+
+    for command in ["P#", "PA", "PS", "PC"] {
+        pair.port().set_silent(command);
+    }
+    ppba.fetch_props().await;
+    assert!(!ppba.is_connected());
+
+    pair.port().set_response("P#", "PPBA_OK");
+    ppba.reconnect().await.unwrap();
+    assert!(ppba.is_connected());
+}
+
+#[tokio::test]
+async fn open_port_is_busy() {
+    let pair = VirtualSerialPair::new(FakePpbaPort::new()).unwrap();
+    let ppba = connect(&pair).await;
+
+    assert!(matches!(
+        PegasusPowerBox::try_new("sim", pair.path(), 9600, 500).await,
+        Err(PegasusError::DeviceBusy(_))
+    ));
+    assert!(utils::probe(pair.path()).await.is_none());
+
+    drop(ppba);
+    connect(&pair).await;
+}
+
+/// Run the MQTT driver with `flag` on a simulated PPBA, returns its output
+/// and the registry it wrote
+fn run_driver(pair: &VirtualSerialPair, flag: &str) -> (Output, std::io::Result<String>) {
+    let dir = std::env::temp_dir().join(format!("pegasus-hw-sim-{}{}", std::process::id(), flag));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // Self tests and diagnoses run before connecting to the broker, none is needed
+    let output = Command::new(env!("CARGO_BIN_EXE_ppba"))
+        .args(["--add-device", pair.path(), flag])
+        .args(["--rescan-interval", "0"])
+        .arg("--settings-file")
+        .arg(dir.join("settings.json"))
+        .arg("--aliases-file")
+        .arg(dir.join("aliases.json"))
+        .arg("--profiles-file")
+        .arg(dir.join("profiles.json"))
+        .arg("--registry-file")
+        .arg(dir.join("registry.json"))
+        .output()
+        .unwrap();
+    let registry = std::fs::read_to_string(dir.join("registry.json"));
+    let _ = std::fs::remove_dir_all(&dir);
+    (output, registry)
+}
+
+fn check_passed(report: &Value, check: &str) -> Option<Value> {
+    report["checks"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|c| c["name"] == check)
+        .map(|c| c["passed"].clone())
+}
+
+#[test]
+fn mqtt_driver_self_tests_ppba() {
+    let pair = VirtualSerialPair::new(FakePpbaPort::new()).unwrap();
+    let (output, registry) = run_driver(&pair, "--self-test");
+
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["address"], pair.path());
+    // The fake doesn't draw current, only the checks of the readings can pass
+    for check in ["status", "firmware_version", "led", "readings"] {
+        assert_eq!(
+            check_passed(&report, check),
+            Some(Value::Bool(true)),
+            "{}",
+            check
+        );
+    }
+
+    // Without a serial number the id is only kept by the registry
+    let registry: Value = serde_json::from_str(&registry.unwrap()).unwrap();
+    let entries: Vec<&Value> = registry.as_object().unwrap().values().collect();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0]["family"], "ppba");
+    assert_eq!(entries[0]["address"], pair.path());
+}
+
+#[test]
+fn mqtt_driver_diagnoses_ppba() {
+    let pair = VirtualSerialPair::new(FakePpbaPort::new()).unwrap();
+    let (output, _) = run_driver(&pair, "--diagnose");
+
+    let report: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["address"], pair.path());
+    assert!(report["latency"]["avg_ms"].as_f64().unwrap() > 0.0);
+    assert!(!report["firmware_version"].as_str().unwrap().is_empty());
+    for check in [
+        "latency",
+        "firmware_version",
+        "input_voltage",
+        "temperature",
+        "humidity",
+        "dew_point",
+    ] {
+        assert_eq!(
+            check_passed(&report, check),
+            Some(Value::Bool(true)),
+            "{}",
+            check
+        );
+    }
+    // The fake always reports the same PWM, dew heater A is stuck at 128
+    assert_eq!(
+        check_passed(&report, "dew1_readback"),
+        Some(Value::Bool(false))
+    );
+    assert!(!output.status.success());
+}
+
+#[cfg(feature = "capi")]
+#[test]
+fn ppba_is_driven_through_the_c_api() {
+    use pegasus_astro::capi::*;
+    use std::ffi::{CStr, CString};
+
+    let pair = VirtualSerialPair::new(FakePpbaPort::new()).unwrap();
+    let address = CString::new(pair.path()).unwrap();
+
+    unsafe {
+        let ppba = pegasus_ppba_open(address.as_ptr());
+        assert!(!ppba.is_null());
+
+        let json = pegasus_ppba_state_json(ppba);
+        let state: Value = serde_json::from_str(CStr::from_ptr(json).to_str().unwrap()).unwrap();
+        pegasus_string_free(json);
+        let volts = state["input_voltage"]["value"].as_f64().unwrap();
+        assert!((volts - 12.2).abs() < 1e-3);
+
+        let (name, value) = (c"dew1_power", c"64");
+        assert_eq!(
+            pegasus_ppba_set_property(ppba, name.as_ptr(), value.as_ptr()),
+            PegasusStatus::Ok
+        );
+        let (name, value) = (c"no_such_property", c"1");
+        assert_eq!(
+            pegasus_ppba_set_property(ppba, name.as_ptr(), value.as_ptr()),
+            PegasusStatus::Unsupported
+        );
+        assert!(!CStr::from_ptr(pegasus_last_error()).is_empty());
+        pegasus_ppba_close(ppba);
+    }
+    assert!(pair.port().sent_commands().contains(&"P3:064".to_owned()));
+}
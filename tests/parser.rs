@@ -0,0 +1,302 @@
+use chrono::{DateTime, Duration, NaiveDate, TimeZone, Utc};
+use pegasus_astro::codec::{Command, Payload};
+use pegasus_astro::dewpoint::{self, Magnus, MagnusPreset};
+use pegasus_astro::discovery::DeviceFamily;
+use pegasus_astro::error::PegasusError;
+use pegasus_astro::parser::{
+    self, FirmwareVersion, ParseError, PowerMetrics, PowerStats, PpbaLayout, PpbaStatus, PpbmStatus,
+};
+use pegasus_astro::sun::{Site, SunEvent, SunTime};
+
+#[test]
+fn status_is_parsed() {
+    let status: PpbaStatus = "PPBA:12.2:0.5:21.5:45:9.1:1:0:128:0:0:0:12\r\n"
+        .parse()
+        .unwrap();
+
+    assert_eq!(status.input_voltage, 12.2);
+    assert_eq!(status.dew_point, 9.1);
+    assert_eq!(status.quadport_status, Some(true));
+    assert_eq!(status.adj_output_status, Some(false));
+    assert_eq!(status.dew1_power, Some(128));
+    assert_eq!(status.adj_output, Some(12));
+}
+
+#[test]
+fn firmware_variants_are_accepted() {
+    // Older firmwares don't send the adjustable output voltage nor the PC uptime
+    let status: PpbaStatus = "PPBA:12.2:0.5:21.5:45:9.1:1:0:128:0:0:0".parse().unwrap();
+    assert_eq!(status.adj_output, None);
+
+    let metrics: PowerMetrics = "PC:1.2:0.5:0.3:0.0".parse().unwrap();
+    assert_eq!(metrics.uptime, None);
+
+    // Firmwares 1.x stop after fewer fields, whatever the layout
+    let old = PpbaLayout::for_firmware(&FirmwareVersion::new(1, 2, 0));
+    for response in [
+        "PPBA:12.2:0.5:21.5:45:9.1",
+        "PPBA:12.2:0.5:21.5:45:9.1:1:0:128",
+    ] {
+        let status = PpbaStatus::parse_with(response, Some(old)).unwrap();
+        assert_eq!(status.dew_point, 9.1);
+        assert_eq!(status.dew2_power, None);
+        assert_eq!(status.autodew, None);
+        assert_eq!(status.pwr_warn, None);
+    }
+    let status: PpbaStatus = "PPBA:12.2:0.5:21.5:45:9.1:1:0:128".parse().unwrap();
+    assert_eq!(status.quadport_status, Some(true));
+    assert_eq!(status.dew1_power, Some(128));
+    // The fields that are sent are still checked
+    assert!("PPBA:12.2:0.5:21.5:45:9.1:2".parse::<PpbaStatus>().is_err());
+}
+
+#[test]
+fn firmware_version_is_parsed() {
+    assert_eq!("1.4".parse(), Ok(FirmwareVersion::new(1, 4, 0)));
+    assert_eq!("PV:2.1.3\r\n".parse(), Ok(FirmwareVersion::new(2, 1, 3)));
+    assert!("1.x".parse::<FirmwareVersion>().is_err());
+    assert!("1.2.3.4".parse::<FirmwareVersion>().is_err());
+    assert!(FirmwareVersion::new(1, 10, 0) > FirmwareVersion::new(1, 4, 2));
+}
+
+#[test]
+fn layout_follows_firmware() {
+    let old = PpbaLayout::for_firmware(&FirmwareVersion::new(1, 3, 9));
+    let new = PpbaLayout::for_firmware(&FirmwareVersion::new(1, 4, 0));
+    assert!(!old.adj_output_voltage && !old.metrics_uptime);
+    assert!(new.adj_output_voltage && new.metrics_uptime);
+
+    // A field the firmware should send is required, one it shouldn't is ignored
+    let short = "PPBA:12.2:0.5:21.5:45:9.1:1:0:128:0:0:0";
+    let full = "PPBA:12.2:0.5:21.5:45:9.1:1:0:128:0:0:0:12";
+    assert!(matches!(
+        PpbaStatus::parse_with(short, Some(new)),
+        Err(ParseError::MissingField { index: 12, .. })
+    ));
+    assert_eq!(
+        PpbaStatus::parse_with(full, Some(old)).unwrap().adj_output,
+        None
+    );
+    assert_eq!(
+        PowerMetrics::parse_with("PC:1.2:0.5:0.3:0.0:3600000", Some(new))
+            .unwrap()
+            .uptime,
+        Some(3600000)
+    );
+}
+
+#[test]
+fn uptime_and_power_are_derived() {
+    let stats: PowerStats = "PS:0.75:1.5:18.2:7200000".parse().unwrap();
+    assert_eq!(stats.uptime_seconds(), 7200);
+    assert_eq!(stats.uptime_human(), "02:00:00");
+    assert_eq!(stats.average_power(), 9.1);
+
+    assert_eq!(parser::format_uptime(93784000), "1d 02:03:04");
+    assert_eq!(parser::average_power(1.0, 0), 0.0);
+}
+
+#[test]
+fn micro_status_is_parsed() {
+    let status: PpbmStatus = "PPBM:12.1:0.8:18.0:60:10.2:96:1:0".parse().unwrap();
+    assert_eq!(status.dew_power, 96);
+    assert!(status.autodew);
+
+    // The two models answer with their own header
+    assert!("PPBM:12.1:0.8:18.0:60:10.2:96:1:0"
+        .parse::<PpbaStatus>()
+        .is_err());
+    assert!("PPBA:12.2:0.5:21.5:45:9.1:1:0:128:0:0:0:12"
+        .parse::<PpbmStatus>()
+        .is_err());
+}
+
+#[test]
+fn short_responses_are_rejected() {
+    assert_eq!(
+        "PPBA:12.2:0.5".parse::<PpbaStatus>(),
+        Err(ParseError::MissingField {
+            field: "temperature",
+            index: 3
+        })
+    );
+    assert!("PS:0.75:1.5".parse::<PowerStats>().is_err());
+    assert!("PC".parse::<PowerMetrics>().is_err());
+}
+
+#[test]
+fn garbage_is_rejected() {
+    assert!(matches!(
+        "".parse::<PpbaStatus>(),
+        Err(ParseError::UnexpectedHeader { .. })
+    ));
+    assert!(matches!(
+        "PS:0.75:1.5:18.2:3600000".parse::<PpbaStatus>(),
+        Err(ParseError::UnexpectedHeader { .. })
+    ));
+    assert_eq!(
+        "PPBA:12.2:0.5:21.5:45:9.1:2:0:128:0:0:0:12".parse::<PpbaStatus>(),
+        Err(ParseError::InvalidField {
+            field: "quadport status",
+            value: "2".to_string()
+        })
+    );
+    assert!("PS:abc:1.5:18.2:3600000".parse::<PowerStats>().is_err());
+    assert!("PC:1.2:0.5:0.3:0.0:-1".parse::<PowerMetrics>().is_err());
+}
+
+#[test]
+fn commands_are_framed_and_echoes_checked() {
+    let dew = Command::new(0x50333a, Some(Payload::Pwm(64)));
+    assert_eq!(dew.encode().unwrap(), b"P3:064\n");
+    assert_eq!(dew.to_string(), "P3:064");
+    assert_eq!(Command::new(0x5023, None).encode().unwrap(), b"P#\n");
+    assert_eq!(
+        Command::new(0x4d3a, Some(Payload::Number(-250)))
+            .encode()
+            .unwrap(),
+        b"M:-250\n"
+    );
+
+    assert_eq!(dew.decode(b"P3:064\r\n").unwrap(), "P3:064");
+    assert!(matches!(
+        dew.decode(b"P3:ERR\r\n"),
+        Err(PegasusError::Protocol(response)) if response == "P3:ERR"
+    ));
+    // A stale response of another command
+    assert!(matches!(
+        dew.decode(b"PS:0.75:1.5:18.2:3600000\r\n"),
+        Err(PegasusError::Parse(_))
+    ));
+}
+
+#[test]
+fn device_family_is_guessed_from_serial() {
+    assert_eq!(
+        DeviceFamily::from_serial("PPBA1A2B"),
+        Some(DeviceFamily::Ppba)
+    );
+    assert_eq!(
+        DeviceFamily::from_serial("PPBM0042"),
+        Some(DeviceFamily::Ppbm)
+    );
+    assert_eq!(
+        DeviceFamily::from_serial("UPB2C3D4"),
+        Some(DeviceFamily::Upb)
+    );
+    assert_eq!(
+        DeviceFamily::from_serial("DMFC1234"),
+        Some(DeviceFamily::Dmfc)
+    );
+    assert_eq!(
+        DeviceFamily::from_serial("FC3A1B2C"),
+        Some(DeviceFamily::FocusCube)
+    );
+    assert_eq!(DeviceFamily::from_serial("A10K8ZX1"), None);
+    assert!(DeviceFamily::FocusCube.is_focuser());
+    assert!(!DeviceFamily::Ppba.is_focuser());
+}
+
+#[test]
+fn sun_times_are_computed() {
+    let greenwich = Site {
+        latitude: 51.4769,
+        longitude: 0.0,
+    };
+    let date = |m, d| NaiveDate::from_ymd_opt(2024, m, d).unwrap();
+    let utc = |m, d, h, min| Utc.with_ymd_and_hms(2024, m, d, h, min, 0).unwrap();
+    // Within a couple of minutes of the almanac
+    let close = |event, (m, d), expected: DateTime<Utc>| {
+        let time = greenwich.time_of(event, date(m, d)).unwrap();
+        assert!(
+            (time - expected).num_minutes().abs() <= 2,
+            "{} on {}/{} at {}",
+            event,
+            m,
+            d,
+            time
+        );
+    };
+    close(SunEvent::Sunrise, (6, 21), utc(6, 21, 3, 43));
+    close(SunEvent::Sunset, (6, 21), utc(6, 21, 20, 21));
+    close(SunEvent::Sunrise, (12, 21), utc(12, 21, 8, 4));
+    close(SunEvent::Sunset, (12, 21), utc(12, 21, 15, 53));
+    // No astronomical night in London around midsummer
+    assert_eq!(
+        greenwich.time_of(SunEvent::AstronomicalDusk, date(6, 21)),
+        None
+    );
+    assert!(greenwich.validate().is_ok());
+    assert!(Site {
+        latitude: 91.0,
+        longitude: 0.0
+    }
+    .validate()
+    .is_err());
+
+    let at: SunTime = "sunset-30m".parse().unwrap();
+    assert_eq!(at.event, SunEvent::Sunset);
+    assert_eq!(
+        at.on(&greenwich, date(6, 21)),
+        greenwich
+            .time_of(SunEvent::Sunset, date(6, 21))
+            .map(|t| t - Duration::minutes(30))
+    );
+    let at: SunTime = "astronomical_dawn+1h30m".parse().unwrap();
+    assert_eq!(at.offset, Duration::minutes(90));
+    assert_eq!(at.to_string(), "astronomical_dawn+90m");
+    assert_eq!(
+        "nautical_dusk".parse::<SunTime>().unwrap().offset,
+        Duration::zero()
+    );
+    assert!("sunset-".parse::<SunTime>().is_err());
+    assert!("sunset-30s".parse::<SunTime>().is_err());
+    assert!("midnight".parse::<SunTime>().is_err());
+}
+
+#[test]
+fn dew_point_formulas_agree() {
+    // 20 °C at 50 % is a dew point of 9.3 °C in the psychrometric tables
+    for preset in [
+        MagnusPreset::Sonntag,
+        MagnusPreset::AlduchovEskridge,
+        MagnusPreset::Tetens,
+    ] {
+        let dew_point = preset.coefficients().dew_point(20.0, 50.0);
+        assert!((dew_point - 9.3).abs() < 0.1, "{:?}: {}", preset, dew_point);
+        assert!((preset.coefficients().dew_point(12.5, 100.0) - 12.5).abs() < 0.01);
+    }
+    assert_eq!(
+        dewpoint::dew_point(20.0, 50.0),
+        Magnus::default().dew_point(20.0, 50.0)
+    );
+    assert!(Magnus { b: 17.62, c: 0.0 }.validate().is_err());
+}
+
+#[test]
+fn random_responses_never_panic() {
+    const ALPHABET: &[u8] = b"PABCMS0123456789.:-\r\n x";
+    // Small xorshift so the test is reproducible without extra dependencies
+    let mut seed: u32 = 0x2545_f491;
+    let mut next = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed
+    };
+
+    for _ in 0..10_000 {
+        let len = next() as usize % 64;
+        let mut response: String = (0..len)
+            .map(|_| ALPHABET[next() as usize % ALPHABET.len()] as char)
+            .collect();
+        if next() % 2 == 0 {
+            response.insert_str(0, ["PPBA:", "PPBM:", "PS:", "PC:"][next() as usize % 4]);
+        }
+
+        let _ = response.parse::<PpbaStatus>();
+        let _ = response.parse::<PpbmStatus>();
+        let _ = response.parse::<PowerStats>();
+        let _ = response.parse::<PowerMetrics>();
+    }
+}
@@ -0,0 +1,1167 @@
+use pegasus_astro::battery::{BatteryConfig, Chemistry};
+use pegasus_astro::calibration::SensorCalibration;
+use pegasus_astro::codec::Command;
+use pegasus_astro::device::{AstronomicalDevice, PegasusDevice};
+use pegasus_astro::dewpoint::{Magnus, MagnusPreset};
+use pegasus_astro::energy::{EnergyMeter, EnergyOutput};
+use pegasus_astro::error::PegasusError;
+use pegasus_astro::focuscube::FocusCube;
+use pegasus_astro::limits::{CurrentLimits, LimitAction, Output};
+use pegasus_astro::parser::FirmwareVersion;
+use pegasus_astro::ppba::{
+    AdjustableVoltage, BootPowerConfig, DewChannel, PegasusPowerBox, Profile, SavedState, Setting,
+};
+use pegasus_astro::ppbm::PocketPowerBoxMicro;
+use pegasus_astro::sim::FakePpbaPort;
+use pegasus_astro::smoothing::Filter;
+use pegasus_astro::trace::{self, Parsed, SerialTrace, TracingTransport};
+use pegasus_astro::transport::{
+    self, FlowControl, PacedTransport, RetryPolicies, RetryPolicy, SerialSettings, SerialTransport,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+async fn fake_ppba(port: &FakePpbaPort) -> PegasusPowerBox {
+    PegasusPowerBox::new_with_port("sim", "/dev/fake", 9600, Box::new(port.clone()))
+        .await
+        .unwrap()
+}
+
+/// Serve a fake PPBA on a local TCP port like ser2net does, returns the port
+async fn serve_over_tcp(mut fake: FakePpbaPort, rfc2217: bool) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut frames = BufReader::new(read).split(b'\n');
+        if rfc2217 {
+            // IAC DO COM-PORT-OPTION
+            write.write_all(&[255, 253, 44]).await.unwrap();
+        }
+        while let Some(frame) = frames.next_segment().await.unwrap() {
+            // Drop the telnet negotiation sent before the first command
+            let start = frame.iter().rposition(|&b| b == 240).map_or(0, |i| i + 1);
+            let mut command = frame[start..].to_vec();
+            command.push(b'\n');
+            fake.write_frame(&command).await.unwrap();
+
+            let mut response = Vec::new();
+            if rfc2217 {
+                // The baud rate set, as acknowledged by the remote port
+                response.extend([255, 250, 44, 101, 0, 0, 0x25, 0x80, 255, 240]);
+            }
+            while let Ok(byte) = fake.read_byte().await {
+                response.push(byte);
+            }
+            write.write_all(&response).await.unwrap();
+        }
+    });
+    port
+}
+
+#[tokio::test]
+async fn fetch_props_reads_fixtures() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+
+    port.set_response("PA", "PPBA:11.8:0.4:21.5:45:9.1:1:0:128:0:0:0:12");
+    ppba.fetch_props().await;
+    let snapshot = ppba.snapshot();
+
+    assert_eq!(snapshot.fw_version, "1.4");
+    assert_eq!(snapshot.input_voltage, 11.8);
+    assert_eq!(snapshot.current_12v_output, 0.4);
+    assert_eq!(snapshot.total_current, 1.2);
+    assert_eq!(snapshot.dew1_current, 0.3);
+    assert_eq!(snapshot.uptime, 3600000);
+}
+
+#[tokio::test]
+async fn every_status_field_is_kept() {
+    let port = FakePpbaPort::new();
+    port.set_response("PA", "PPBA:12.1:0.4:18.5:80:15.2:1:1:0:0:1:1:9");
+    let ppba = fake_ppba(&port).await;
+
+    let snapshot = ppba.snapshot();
+    assert_eq!(snapshot.dew_point, 15.2);
+    assert!(snapshot.adj_output_status);
+    assert!(snapshot.autodew);
+    assert!(snapshot.pwr_warn);
+    assert_eq!(snapshot.adj_output, 9);
+
+    let state = serde_json::to_value(&ppba).unwrap();
+    assert_eq!(state["dew_point"]["value"].as_f64().unwrap() as f32, 15.2);
+    assert_eq!(state["pwr_warn"]["value"], true);
+    assert_eq!(state["pwr_warn"]["permission"], "ReadOnly");
+}
+
+#[tokio::test]
+async fn update_property_sends_set_command() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+
+    ppba.update_property("dew1_power", "128").await.unwrap();
+    ppba.update_property("quadport_status", "false")
+        .await
+        .unwrap();
+
+    let sent = port.sent_commands();
+    assert!(sent.contains(&"P3:128".to_string()));
+    assert!(sent.contains(&"P1:0".to_string()));
+    let snapshot = ppba.snapshot();
+    assert_eq!(snapshot.dew1_power, 128);
+    assert!(!snapshot.quadport_status);
+}
+
+#[tokio::test]
+async fn simulated_ppba_follows_the_set_commands() {
+    let port = FakePpbaPort::simulated(0);
+    let mut ppba = fake_ppba(&port).await;
+
+    ppba.update_property("quadport_status", "false")
+        .await
+        .unwrap();
+    ppba.update_property("dew2_power", "255").await.unwrap();
+    ppba.fetch_props().await;
+
+    let snapshot = ppba.snapshot();
+    assert!(!snapshot.quadport_status);
+    assert_eq!(snapshot.dew2_power, 255);
+    assert!((snapshot.dew2_current - 1.5).abs() < 0.01);
+    assert!((0.0..20.0).contains(&snapshot.temperature));
+    assert!((50.0..90.0).contains(&snapshot.humidity));
+    assert!(snapshot.dew_point < snapshot.temperature);
+}
+
+#[tokio::test]
+async fn dew_power_is_set_as_percentage() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+
+    ppba.update_property("dew_a_power_pct", "50").await.unwrap();
+    ppba.set_dew_power_percent(DewChannel::B, 100.0)
+        .await
+        .unwrap();
+
+    let sent = port.sent_commands();
+    assert!(sent.contains(&"P3:128".to_string()));
+    assert!(sent.contains(&"P4:255".to_string()));
+    let snapshot = ppba.snapshot();
+    assert_eq!(snapshot.dew1_power, 128);
+    assert!((snapshot.dew1_power_pct - 50.2).abs() < 0.1);
+    assert_eq!(snapshot.dew2_power_pct, 100.0);
+    assert!(ppba
+        .update_property("dew_b_power_pct", "101")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn update_property_rejects_invalid_values() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+
+    assert!(ppba.update_property("dew1_power", "256").await.is_err());
+    assert!(ppba
+        .update_property("quadport_status", "maybe")
+        .await
+        .is_err());
+    assert!(ppba.update_property("uptime", "1").await.is_err());
+}
+
+#[tokio::test]
+async fn typed_settings_are_applied() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+
+    assert_eq!(
+        PegasusPowerBox::parse_setting("dew2_power", "64").unwrap(),
+        Setting::DewPower(DewChannel::B, 64)
+    );
+    ppba.apply(Setting::AdjOutputStatus(true)).await.unwrap();
+    ppba.apply(Setting::AdjOutput(AdjustableVoltage::V9))
+        .await
+        .unwrap();
+
+    assert!(port.sent_commands().contains(&"P2:9".to_string()));
+    assert_eq!(ppba.snapshot().adj_output, 9);
+    assert!(PegasusPowerBox::parse_setting("adj_output", "7").is_err());
+
+    let state = serde_json::to_value(&ppba).unwrap();
+    assert_eq!(state["adj_output"]["value"], 9);
+    assert_eq!(
+        state["adj_output"]["choices"],
+        serde_json::json!([3, 5, 8, 9, 12])
+    );
+}
+
+#[tokio::test]
+async fn adjustable_voltage_waits_for_the_output() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+    assert!(!ppba.snapshot().adj_output_status);
+    assert_eq!(ppba.snapshot().adj_output, 12);
+
+    // P2:5 would switch the output on, the voltage is only kept
+    let sent = port.sent_commands().len();
+    ppba.update_property("adj_output", "5").await.unwrap();
+    assert_eq!(port.sent_commands().len(), sent);
+    ppba.fetch_props().await;
+    assert_eq!(ppba.snapshot().adj_output, 5);
+    assert!(!ppba.snapshot().adj_output_status);
+
+    ppba.update_property("adj_output_status", "1")
+        .await
+        .unwrap();
+    assert_eq!(port.sent_commands().last().unwrap(), "P2:5");
+    assert!(ppba.snapshot().adj_output_status);
+
+    ppba.update_property("adj_output", "9").await.unwrap();
+    assert_eq!(port.sent_commands().last().unwrap(), "P2:9");
+    ppba.update_property("adj_output_status", "0")
+        .await
+        .unwrap();
+    assert_eq!(port.sent_commands().last().unwrap(), "P2:0");
+    ppba.update_property("adj_output_status", "1")
+        .await
+        .unwrap();
+    assert_eq!(port.sent_commands().last().unwrap(), "P2:1");
+    assert_eq!(ppba.snapshot().adj_output, 9);
+}
+
+#[tokio::test]
+async fn power_on_boot_is_read_and_set() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+    assert_eq!(
+        ppba.snapshot().power_status_on_boot.map(|c| c.ports()),
+        Some([true; 4])
+    );
+
+    ppba.update_property("power_status_on_boot", "1010")
+        .await
+        .unwrap();
+    assert!(port.sent_commands().contains(&"PE:1010".to_string()));
+    assert_eq!(
+        ppba.snapshot().power_status_on_boot,
+        BootPowerConfig::from_mask("1010")
+    );
+    assert!(ppba
+        .update_property("power_status_on_boot", "10")
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn old_firmware_layout_is_used() {
+    let port = FakePpbaPort::new();
+    port.set_response("PV", "1.2");
+    port.set_silent("PE");
+    port.set_response("PA", "PPBA:11.8:0.4:21.5:45:9.1:1:0:128:0:0:0");
+    port.set_response("PC", "PC:1.2:0.4:0.3:0.0");
+    let ppba = fake_ppba(&port).await;
+
+    let snapshot = ppba.snapshot();
+    assert_eq!(ppba.firmware(), Some(FirmwareVersion::new(1, 2, 0)));
+    assert_eq!(snapshot.input_voltage, 11.8);
+    assert_eq!(snapshot.total_current, 1.2);
+    assert!(snapshot.capabilities.is_empty());
+
+    let ppba = fake_ppba(&FakePpbaPort::new()).await;
+    assert_eq!(
+        ppba.snapshot().capabilities,
+        ["adj_output", "power_status_on_boot"]
+    );
+}
+
+#[tokio::test]
+async fn network_serial_bridges_are_supported() {
+    for scheme in ["tcp", "rfc2217"] {
+        let fake = FakePpbaPort::new();
+        let port = serve_over_tcp(fake.clone(), scheme == "rfc2217").await;
+        let address = format!("{}://127.0.0.1:{}", scheme, port);
+
+        let mut ppba = PegasusPowerBox::try_new("remote", &address, 9600, 500)
+            .await
+            .unwrap();
+        assert_eq!(ppba.snapshot().input_voltage, 12.2);
+        ppba.update_property("dew1_power", "64").await.unwrap();
+        assert!(fake.sent_commands().contains(&"P3:064".to_string()));
+    }
+}
+
+#[tokio::test]
+async fn serial_settings_are_configured_per_device() {
+    let path = std::env::temp_dir().join(format!("pegasus_serial_{}.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        r#"
+[mqtt]
+host = "broker"
+
+[serial."PPBA1234"]
+timeout_ms = 1000
+command_delay_ms = 50
+
+[serial."/dev/ttyUSB3"]
+baud = 19200
+flow_control = "hardware"
+"#,
+    )
+    .unwrap();
+    let settings = SerialSettings::from_file(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let by_serial = settings.resolve(Some("PPBA1234"), "/dev/ttyUSB3", transport::DEFAULT_BAUD);
+    assert_eq!(by_serial.baud, 9600);
+    assert_eq!(by_serial.timeout, Duration::from_millis(1000));
+    assert_eq!(by_serial.command_delay, Duration::from_millis(50));
+    let by_port = settings.resolve(None, "/dev/ttyUSB3", transport::DEFAULT_BAUD);
+    assert_eq!(by_port.baud, 19200);
+    assert_eq!(by_port.flow_control, FlowControl::Hardware);
+    assert_eq!(
+        settings.resolve(None, "/dev/ttyUSB0", transport::FOCUSER_BAUD),
+        transport::SerialConfig {
+            baud: transport::FOCUSER_BAUD,
+            ..Default::default()
+        }
+    );
+
+    let fake = FakePpbaPort::new();
+    let mut paced = PacedTransport::new(Box::new(fake), Duration::from_millis(100));
+    let status = Command::new(0x5023, None);
+    let start = tokio::time::Instant::now();
+    transport::send_command(&mut paced, &status).await.unwrap();
+    transport::send_command(&mut paced, &status).await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100));
+}
+
+#[tokio::test]
+async fn batch_updates_are_applied_together() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+    let batch = |updates: &[(&str, &str)]| -> Vec<(String, String)> {
+        updates
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    };
+
+    let sent = port.sent_commands().len();
+    let results = ppba
+        .update_properties(&batch(&[
+            ("dew1_power", "64"),
+            ("quadport_status", "maybe"),
+        ]))
+        .await;
+    assert!(matches!(results[0], Err(PegasusError::Validation(_))));
+    assert!(matches!(results[1], Err(PegasusError::Validation(_))));
+    assert_eq!(port.sent_commands().len(), sent);
+
+    let results = ppba
+        .update_properties(&batch(&[
+            ("dew1_power", "64"),
+            ("dew2_power", "128"),
+            ("quadport_status", "0"),
+        ]))
+        .await;
+    assert!(results.iter().all(Result::is_ok));
+    assert_eq!(port.sent_commands()[sent..], ["P3:064", "P4:128", "P1:0"]);
+
+    port.set_response("P4:", "P4:ERR");
+    let results = ppba
+        .update_properties(&batch(&[("dew2_power", "10"), ("dew1_power", "0")]))
+        .await;
+    assert!(matches!(&results[0], Err(PegasusError::Protocol(_))));
+    assert!(results[1].is_err());
+    assert_eq!(ppba.snapshot().dew1_power, 64);
+}
+
+#[tokio::test]
+async fn read_only_properties_are_refused() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+    let sent = port.sent_commands().len();
+
+    assert!(matches!(
+        ppba.check_writable("input_voltage"),
+        Err(PegasusError::PermissionDenied(prop)) if prop == "input_voltage"
+    ));
+    assert!(ppba.check_writable("dew1_power").is_ok());
+    let results = ppba
+        .update_properties(&[
+            ("dew1_power".to_string(), "64".to_string()),
+            ("input_voltage".to_string(), "12".to_string()),
+        ])
+        .await;
+    assert!(matches!(results[1], Err(PegasusError::PermissionDenied(_))));
+    assert_eq!(port.sent_commands().len(), sent);
+}
+
+#[tokio::test]
+async fn read_only_devices_get_no_setting() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+    ppba.set_read_only(true);
+    let sent = port.sent_commands().len();
+    let dew1_power = ppba.snapshot().dew1_power;
+
+    assert!(matches!(
+        ppba.update_property("dew1_power", "64").await,
+        Err(PegasusError::ReadOnly(command)) if command == "P3:064"
+    ));
+    assert!(matches!(
+        ppba.update_property("dew1_power", "300").await,
+        Err(PegasusError::Validation(_))
+    ));
+    assert!(ppba.reboot().await.unwrap_err().is_read_only());
+    let results = ppba
+        .update_properties(&[
+            ("quadport_status".to_string(), "0".to_string()),
+            ("autodew".to_string(), "1".to_string()),
+        ])
+        .await;
+    assert!(results
+        .iter()
+        .all(|res| matches!(res, Err(PegasusError::ReadOnly(_)))));
+
+    // Readings are still polled
+    ppba.fetch_props().await;
+    assert!(port.sent_commands()[sent..]
+        .iter()
+        .all(|c| !c.contains(':')));
+    assert_eq!(ppba.snapshot().dew1_power, dew1_power);
+    assert_eq!(
+        serde_json::to_value(&ppba).unwrap()["read_only"]["value"],
+        true
+    );
+}
+
+#[tokio::test]
+async fn device_error_is_reported() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+
+    port.set_response("P4:", "P4:ERR");
+    assert!(matches!(
+        ppba.update_property("dew2_power", "10").await,
+        Err(PegasusError::Protocol(response)) if response == "P4:ERR"
+    ));
+    assert_eq!(ppba.snapshot().dew2_power, 0);
+}
+
+#[test]
+fn only_link_errors_are_link_failures() {
+    for e in [
+        PegasusError::Serial(std::io::ErrorKind::TimedOut.into()),
+        PegasusError::DeviceBusy("/dev/ttyUSB0".to_string()),
+        PegasusError::Parse("PPBA:12".to_string()),
+        PegasusError::NotConnected,
+    ] {
+        assert!(e.link_failed(), "{:?}", e);
+    }
+    for e in [
+        PegasusError::Protocol("P4:ERR".to_string()),
+        PegasusError::Validation("300".to_string()),
+        PegasusError::Unsupported("usb_port_7".to_string()),
+        PegasusError::PermissionDenied("input_voltage".to_string()),
+        PegasusError::ReadOnly("P3:064".to_string()),
+        PegasusError::Broker("refused".to_string()),
+    ] {
+        assert!(!e.link_failed(), "{:?}", e);
+    }
+}
+
+#[tokio::test]
+async fn refused_commands_are_not_retried() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+    ppba.set_retry_policies(RetryPolicies::new(RetryPolicy {
+        max_retries: 3,
+        retry_delay: Duration::ZERO,
+        backoff: 1.0,
+    }));
+
+    port.set_response("P4:", "P4:ERR");
+    assert!(ppba.update_property("dew2_power", "10").await.is_err());
+    let sent = port.sent_commands();
+    assert_eq!(sent.iter().filter(|c| c.starts_with("P4:")).count(), 1);
+    assert!(ppba.is_connected());
+}
+
+#[test]
+fn retry_delays_are_capped() {
+    let policy = RetryPolicy {
+        max_retries: 20,
+        retry_delay: Duration::from_millis(50),
+        backoff: 2.0,
+    };
+    assert_eq!(policy.delay(0), Duration::from_millis(50));
+    assert_eq!(policy.delay(2), Duration::from_millis(200));
+    assert_eq!(policy.delay(19), transport::MAX_RETRY_DELAY);
+
+    for backoff in [1e30, f32::INFINITY, f32::NAN] {
+        let policy = RetryPolicy { backoff, ..policy };
+        assert_eq!(policy.delay(3), transport::MAX_RETRY_DELAY, "{}", backoff);
+        assert_eq!(
+            policy.validate().is_ok(),
+            backoff.is_finite(),
+            "{}",
+            backoff
+        );
+    }
+    let policy = RetryPolicy {
+        retry_delay: Duration::MAX,
+        ..policy
+    };
+    assert!(policy.validate().is_err());
+    assert_eq!(policy.delay(0), transport::MAX_RETRY_DELAY);
+}
+
+#[test]
+fn retry_policies_are_overridden_per_command() {
+    let overrides: HashMap<String, transport::RetryOverrides> = toml::from_str(
+        r#"
+[PF]
+max_retries = 0
+
+["P3:"]
+max_retries = 4
+retry_delay_ms = 200
+"#,
+    )
+    .unwrap();
+    let policies = RetryPolicies::with_overrides(RetryPolicy::default(), &overrides).unwrap();
+    assert_eq!(policies.for_command("PF").max_retries, 0);
+    let p3 = policies.for_command("P3:");
+    assert_eq!(p3.max_retries, 4);
+    assert_eq!(p3.retry_delay, Duration::from_millis(200));
+    assert_eq!(p3.backoff, transport::DEFAULT_RETRY_BACKOFF);
+    assert_eq!(policies.for_command("PA"), RetryPolicy::default());
+
+    let overrides: HashMap<String, transport::RetryOverrides> =
+        toml::from_str("[PA]\nbackoff = inf\n").unwrap();
+    assert!(RetryPolicies::with_overrides(RetryPolicy::default(), &overrides).is_err());
+}
+
+#[tokio::test]
+async fn silent_device_fails_to_connect() {
+    let port = FakePpbaPort::new();
+    port.set_silent("P#");
+
+    let res = PegasusPowerBox::new_with_port("sim", "/dev/fake", 9600, Box::new(port)).await;
+    assert!(res.unwrap_err().is_timeout());
+}
+
+#[tokio::test]
+async fn autodew_is_toggled() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+    assert!(!ppba.snapshot().autodew);
+
+    ppba.update_property("autodew", "true").await.unwrap();
+
+    assert_eq!(port.sent_commands().last().unwrap(), "PD:1");
+    assert!(ppba.snapshot().autodew);
+}
+
+#[tokio::test]
+async fn reconnects_once_device_answers_again() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+
+    for command in ["P#", "PA", "PS", "PC"] {
+        port.set_silent(command);
+    }
+    ppba.fetch_props().await;
+    assert!(!ppba.is_connected());
+    assert!(ppba.reconnect().await.is_err());
+
+    port.set_response("P#", "PPBA_OK");
+    ppba.reconnect().await.unwrap();
+    assert!(ppba.is_connected());
+}
+
+#[tokio::test]
+async fn late_response_is_skipped() {
+    let mut port = FakePpbaPort::new();
+    let stats = Command::new(0x5053, None).expecting(Some("PS:"));
+    let metrics = Command::new(0x5043, None).expecting(Some("PC:"));
+
+    port.answer_late("PS", 1);
+    let res = transport::send_command(&mut port, &stats).await;
+    assert!(res.unwrap_err().is_timeout());
+
+    let stale = transport::stale_responses();
+    assert_eq!(
+        transport::send_command(&mut port, &metrics).await.unwrap(),
+        "PC:1.2:0.5:0.3:0.0:3600000"
+    );
+    assert!(transport::stale_responses() > stale);
+
+    // The readings of the last poll come late, before the echo of the dew heater
+    let mut ppba = fake_ppba(&port).await;
+    ppba.set_retry_policies(RetryPolicies::new(RetryPolicy::NONE));
+    port.answer_late("PA", 1);
+    ppba.fetch_props().await;
+    let stale = transport::stale_responses();
+    ppba.update_property("dew1_power", "64").await.unwrap();
+    assert!(transport::stale_responses() > stale);
+    assert_eq!(ppba.snapshot().dew1_power, 64);
+}
+
+#[tokio::test]
+async fn missed_response_is_retried() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+
+    port.set_response("PA", "PPBA:11.8:0.4:21.5:45:9.1:1:0:128:0:0:0:12");
+    port.drop_responses("PA", 1);
+    ppba.fetch_props().await;
+    assert!(ppba.is_connected());
+    assert_eq!(ppba.snapshot().input_voltage, 11.8);
+
+    ppba.set_retry_policies(RetryPolicies::new(RetryPolicy::NONE));
+    port.drop_responses("PA", 1);
+    ppba.fetch_props().await;
+    assert!(!ppba.is_connected());
+}
+
+#[tokio::test]
+async fn device_error_keeps_connection() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+
+    port.set_response("P3:", "P3:ERR");
+    assert!(ppba.update_property("dew1_power", "10").await.is_err());
+    assert!(ppba.is_connected());
+}
+
+#[tokio::test]
+async fn saved_settings_are_restored() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+    let state: SavedState = serde_json::from_str(
+        r#"{"quadport_status": {"value": false, "permission": "ReadWrite"},
+            "adj_output_status": true, "adj_output": 9,
+            "dew1_power": 64, "dew2_power": 32, "autodew": false}"#,
+    )
+    .unwrap();
+
+    ppba.restore_settings(&state).await.unwrap();
+
+    let sent = port.sent_commands();
+    for command in ["P1:0", "P2:9", "P3:064", "P4:032", "PD:0"] {
+        assert!(sent.contains(&command.to_string()), "{} not sent", command);
+    }
+    assert_eq!(ppba.saved_state(), state);
+}
+
+#[tokio::test]
+async fn profile_changes_only_its_settings() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+    ppba.apply(Setting::DewPower(DewChannel::B, 50))
+        .await
+        .unwrap();
+
+    let profile: Profile = serde_json::from_str(
+        r#"{"quadport_status": false, "adj_output": 9, "adj_output_status": true, "dew1_power": 200}"#,
+    )
+    .unwrap();
+    ppba.apply_profile(&profile).await.unwrap();
+
+    let snapshot = ppba.snapshot();
+    assert!(!snapshot.quadport_status);
+    assert!(snapshot.adj_output_status);
+    assert_eq!(snapshot.adj_output, 9);
+    assert_eq!(snapshot.dew1_power, 200);
+    assert_eq!(snapshot.dew2_power, 50);
+    assert_eq!(ppba.capture_profile().dew2_power, Some(50));
+    assert!(serde_json::from_str::<Profile>(r#"{"adj_output": 7}"#).is_err());
+}
+
+#[tokio::test]
+async fn polling_interval_is_bounded() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+
+    ppba.update_property("polling_interval", "2000")
+        .await
+        .unwrap();
+    assert_eq!(ppba.polling_interval(), Duration::from_secs(2));
+    assert_eq!(ppba.saved_state().polling_interval, 2000);
+
+    assert!(ppba
+        .update_property("polling_interval", "10")
+        .await
+        .is_err());
+    assert_eq!(ppba.polling_interval(), Duration::from_secs(2));
+}
+
+#[tokio::test]
+async fn sensor_offsets_correct_the_readings() {
+    let port = FakePpbaPort::new();
+    port.set_response("PA", "PPBA:12.1:0.4:18.5:80:15.2:1:1:0:0:1:1:9");
+    let mut ppba = fake_ppba(&port).await;
+
+    ppba.update_property("temperature_offset", "-1.5")
+        .await
+        .unwrap();
+    ppba.update_property("humidity_offset", "25").await.unwrap();
+    ppba.fetch_props().await;
+    let snapshot = ppba.snapshot();
+    assert_eq!(snapshot.temperature, 17.0);
+    assert_eq!(snapshot.humidity, 100.0);
+    // Computed again from the corrected readings
+    assert!((snapshot.dew_point - 17.0).abs() < 0.1);
+    assert_eq!(
+        ppba.calibration(),
+        SensorCalibration {
+            temperature: -1.5,
+            humidity: 25.0
+        }
+    );
+
+    assert!(matches!(
+        ppba.update_property("temperature_offset", "40").await,
+        Err(PegasusError::Validation(_))
+    ));
+    ppba.set_calibration(SensorCalibration::default()).unwrap();
+    ppba.fetch_props().await;
+    assert_eq!(ppba.snapshot().dew_point, 15.2);
+}
+
+#[tokio::test]
+async fn dew_margin_uses_the_chosen_formula() {
+    let port = FakePpbaPort::new();
+    port.set_response("PA", "PPBA:12.1:0.4:20:50:9.1:1:1:0:0:1:1:9");
+    let mut ppba = fake_ppba(&port).await;
+
+    let snapshot = ppba.snapshot();
+    assert_eq!(snapshot.dew_point, 9.1);
+    assert!((snapshot.dew_point_computed - 9.3).abs() < 0.1);
+    assert_eq!(
+        snapshot.dew_margin,
+        snapshot.temperature - snapshot.dew_point_computed
+    );
+
+    let tetens = MagnusPreset::Tetens.coefficients();
+    ppba.set_dew_point_formula(tetens).unwrap();
+    ppba.fetch_props().await;
+    let state = serde_json::to_value(&ppba).unwrap();
+    assert_eq!(
+        state["dew_point_computed"]["value"].as_f64().unwrap() as f32,
+        tetens.dew_point(20.0, 50.0)
+    );
+    assert_eq!(state["dew_margin"]["permission"], "ReadOnly");
+    assert!(ppba
+        .set_dew_point_formula(Magnus { b: -1.0, c: 243.0 })
+        .is_err());
+}
+
+#[tokio::test]
+async fn energy_is_integrated_per_output() {
+    let day = chrono::NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+    let start = Instant::now();
+    let mut meter = EnergyMeter::default();
+    meter.record(start, day, 12.0, [2.0, 1.0, 0.0, 0.5]);
+    meter.record(
+        start + Duration::from_secs(30),
+        day,
+        12.0,
+        [2.0, 1.0, 0.0, 0.5],
+    );
+    assert!((meter.total(EnergyOutput::Quadport) - 0.2).abs() < 1e-9);
+    assert!((meter.today(EnergyOutput::Dew1) - 0.1).abs() < 1e-9);
+    assert_eq!(meter.total(EnergyOutput::Dew2), 0.0);
+
+    // A device unplugged for a while doesn't count the gap
+    meter.record(
+        start + Duration::from_secs(600),
+        day,
+        12.0,
+        [2.0, 1.0, 0.0, 0.5],
+    );
+    assert!((meter.daily()[&day] - 0.35).abs() < 1e-9);
+
+    // Today restarts at midnight, the totals since the reset don't
+    let next = day.succ_opt().unwrap();
+    meter.record(
+        start + Duration::from_secs(630),
+        next,
+        12.0,
+        [2.0, 0.0, 0.0, 0.0],
+    );
+    assert!((meter.today(EnergyOutput::Quadport) - 0.2).abs() < 1e-9);
+    assert!((meter.total(EnergyOutput::Quadport) - 0.4).abs() < 1e-9);
+    assert_eq!(meter.daily().len(), 2);
+
+    meter.reset();
+    assert_eq!(meter.total(EnergyOutput::Quadport), 0.0);
+    assert!((meter.today(EnergyOutput::Quadport) - 0.2).abs() < 1e-9);
+
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+    ppba.fetch_props().await;
+    assert!(ppba.update_property("reset_energy", "1").await.is_ok());
+    assert_eq!(ppba.energy().daily().len(), 1);
+    assert_eq!(
+        serde_json::to_value(&ppba).unwrap()["adj_output_energy"]["permission"],
+        "ReadOnly"
+    );
+}
+
+#[tokio::test]
+async fn dew_heaters_over_their_limit_are_reduced() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+    ppba.set_current_limits(CurrentLimits {
+        max_amps: HashMap::from([(Output::Dew1, 0.2)]),
+        trip_samples: 2,
+        actions: HashMap::from([(Output::Dew1, LimitAction::Reduce)]),
+    });
+
+    assert!(ppba.enforce_current_limits().await.is_empty());
+    let trips = ppba.enforce_current_limits().await;
+    assert_eq!(trips.len(), 1);
+    assert_eq!(trips[0].action, LimitAction::Reduce);
+    // 0.3A drawn at 128, brought 10 % under the 0.2A limit
+    assert_eq!(trips[0].pwm, Some(76));
+    assert!(port.sent_commands().contains(&"P3:076".to_string()));
+    // The heater stays on, it can still be changed
+    assert!(ppba.update_property("dew1_power", "100").await.is_ok());
+    assert_eq!(
+        serde_json::to_value(&ppba).unwrap()["tripped_outputs"],
+        serde_json::json!([])
+    );
+}
+
+#[tokio::test]
+async fn dew_heater_changes_are_ramped() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+    assert!(ppba.update_property("dew_ramp_ms", "20000").await.is_err());
+    assert!(ppba.update_property("dew_ramp_ms", "90").await.is_ok());
+
+    let start = Instant::now();
+    // From the 128 reported by the fake device
+    ppba.set_dew_power(DewChannel::A, 0).await.unwrap();
+    let sent: Vec<String> = port
+        .sent_commands()
+        .into_iter()
+        .filter(|c| c.starts_with("P3:"))
+        .collect();
+    assert_eq!(sent, ["P3:096", "P3:064", "P3:032", "P3:000"]);
+    assert!(start.elapsed() >= Duration::from_millis(90));
+    assert_eq!(ppba.dew_power(DewChannel::A), 0);
+
+    // Small changes and explicit immediate ones are sent at once
+    ppba.set_dew_power(DewChannel::A, 20).await.unwrap();
+    ppba.set_dew_power_at_once(DewChannel::A, 255)
+        .await
+        .unwrap();
+    assert!(port
+        .sent_commands()
+        .ends_with(&["P3:020".to_string(), "P3:255".to_string()]));
+    assert_eq!(ppba.saved_state().dew_ramp_ms, 90);
+}
+
+#[tokio::test]
+async fn battery_charge_is_estimated_from_the_input_voltage() {
+    let mut config = BatteryConfig {
+        chemistry: Chemistry::LeadAcid,
+        capacity_ah: 50.0,
+        curve: None,
+        low_soc: 20.0,
+    };
+    assert_eq!(config.soc(13.0), 100.0);
+    assert_eq!(config.soc(12.2), 60.0);
+    assert_eq!(config.soc(9.0), 0.0);
+    config.curve = Some(vec![(12.0, 0.0), (14.0, 100.0)]);
+    assert!((config.soc(13.0) - 50.0).abs() < 1e-4);
+    config.capacity_ah = 0.0;
+    assert!(matches!(
+        config.validate(),
+        Err(PegasusError::Validation(_))
+    ));
+
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+    let battery = BatteryConfig {
+        chemistry: Chemistry::LeadAcid,
+        capacity_ah: 50.0,
+        curve: None,
+        low_soc: 20.0,
+    };
+    assert!(ppba.set_battery(battery).is_ok());
+    assert!(serde_json::to_value(&ppba)
+        .unwrap()
+        .get("battery_soc")
+        .is_none());
+    ppba.fetch_props().await;
+    let model = ppba.battery().unwrap();
+    assert!((model.soc().unwrap() - 60.0).abs() < 1e-3);
+    assert!((model.remaining_ah().unwrap() - 30.0).abs() < 1e-3);
+    assert!(!model.is_low());
+    let state = serde_json::to_value(&ppba).unwrap();
+    assert_eq!(state["battery_soc"]["permission"], "ReadOnly");
+    assert_eq!(state["battery_low"]["value"], false);
+}
+
+#[tokio::test]
+async fn micro_is_driven_through_the_same_interface() {
+    let port = FakePpbaPort::new();
+    port.set_response("P#", "PPBM_OK");
+    port.set_response("PA", "PPBM:12.1:0.8:18.0:60:10.2:96:1:0");
+    let mut ppbm =
+        PocketPowerBoxMicro::new_with_port("sim", "/dev/fake", 9600, Box::new(port.clone()))
+            .await
+            .unwrap();
+
+    let state = serde_json::to_value(&ppbm).unwrap();
+    assert_eq!(state["dew_power"]["value"], 96);
+    assert_eq!(state["autodew"]["value"], true);
+
+    ppbm.update_property("dew_power", "32").await.unwrap();
+    assert!(port.sent_commands().contains(&"P3:032".to_string()));
+    assert!(ppbm.update_property("quadport_status", "1").await.is_err());
+
+    // A PPBA is not mistaken for a micro
+    assert!(PocketPowerBoxMicro::new_with_port(
+        "sim",
+        "/dev/fake",
+        9600,
+        Box::new(FakePpbaPort::new())
+    )
+    .await
+    .is_err());
+}
+
+#[tokio::test]
+async fn focuser_moves_and_halts() {
+    let port = FakePpbaPort::new();
+    port.set_response("#", "OK_FC");
+    port.set_response("V", "2.1");
+    port.set_response("T", "12.5");
+    port.set_response("P", "1500");
+    port.set_response("I", "0");
+    port.set_response("H", "H");
+    let mut focuser = FocusCube::new_with_port("sim", "/dev/fake", 19200, Box::new(port.clone()))
+        .await
+        .unwrap();
+    assert_eq!(focuser.position(), 1500);
+
+    focuser.update_property("position", "2000").await.unwrap();
+    focuser
+        .update_property("move_relative", "-250")
+        .await
+        .unwrap();
+    assert!(focuser.is_moving());
+    focuser.update_property("halt", "").await.unwrap();
+    assert!(!focuser.is_moving());
+
+    let sent = port.sent_commands();
+    for command in ["M:2000", "G:-250", "H"] {
+        assert!(sent.contains(&command.to_string()), "{} not sent", command);
+    }
+    assert!(focuser.update_property("position", "far").await.is_err());
+}
+
+#[tokio::test]
+async fn reboot_is_sent_once() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+
+    ppba.reboot().await.unwrap();
+
+    let reboots = port.sent_commands().iter().filter(|c| *c == "PF").count();
+    assert_eq!(reboots, 1);
+    assert!(!ppba.is_connected());
+}
+
+#[tokio::test]
+async fn id_is_derived_from_serial_number() {
+    let mut first = fake_ppba(&FakePpbaPort::new()).await;
+    let mut second = fake_ppba(&FakePpbaPort::new()).await;
+    assert_ne!(first.get_id(), second.get_id());
+
+    first.set_serial_number("PPBA12345");
+    second.set_serial_number("PPBA12345");
+
+    assert_eq!(first.get_id(), second.get_id());
+    assert_eq!(
+        first.get_id(),
+        pegasus_astro::device::device_id("PPBA12345")
+    );
+    assert_eq!(first.snapshot().serial_number, "PPBA12345");
+}
+
+#[tokio::test]
+async fn median_drops_voltage_spikes() {
+    let port = FakePpbaPort::new();
+    let mut ppba = fake_ppba(&port).await;
+    assert!(ppba
+        .set_smoothing("uptime", Filter::Ema { alpha: 0.5 })
+        .is_err());
+    ppba.set_smoothing("input_voltage", Filter::Median { window: 3 })
+        .unwrap();
+
+    for volts in ["12.1", "12.0", "4.2", "12.2"] {
+        port.set_response(
+            "PA",
+            &format!("PPBA:{}:0.4:21.5:45:9.1:1:0:128:0:0:0:12", volts),
+        );
+        ppba.fetch_props().await;
+    }
+
+    assert_eq!(ppba.snapshot().input_voltage, 12.2);
+    assert_eq!(ppba.smoothed("input_voltage"), Some(12.0));
+    let state = serde_json::to_value(&ppba).unwrap();
+    assert_eq!(state["input_voltage_smoothed"]["value"], 12.0);
+    assert_eq!(state["input_voltage_smoothed"]["permission"], "ReadOnly");
+}
+
+#[tokio::test]
+async fn serial_traffic_is_traced_and_replayed() {
+    let path = std::env::temp_dir().join(format!("pegasus_trace_{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let fake = FakePpbaPort::new();
+    fake.set_response("P4:", "P4:ERR");
+    let traced = TracingTransport::new(
+        "/dev/fake",
+        Box::new(fake.clone()),
+        Arc::new(SerialTrace::create(&path).unwrap()),
+    );
+    let mut ppba = PegasusPowerBox::new_with_port("sim", "/dev/fake", 9600, Box::new(traced))
+        .await
+        .unwrap();
+    ppba.fetch_props().await;
+    ppba.update_property("dew1_power", "64").await.unwrap();
+    assert!(ppba.update_property("dew2_power", "10").await.is_err());
+
+    let entries = trace::read(&path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    let replayed = trace::replay(&entries);
+    let response = |command: &str| {
+        replayed
+            .iter()
+            .find(|(exchange, _)| exchange.command == command)
+            .map(|(exchange, parsed)| (exchange.response.clone(), parsed.clone()))
+            .unwrap()
+    };
+
+    let (_, status) = response("PA");
+    assert!(matches!(status, Ok(Parsed::PpbaStatus(s)) if s.input_voltage == 12.2));
+    let (_, firmware) = response("PV");
+    assert_eq!(
+        firmware,
+        Ok(Parsed::Firmware(FirmwareVersion::new(1, 4, 0)))
+    );
+    assert_eq!(
+        response("P3:064"),
+        (Some("P3:064".to_string()), Ok(Parsed::Unparsed))
+    );
+    assert_eq!(
+        response("P4:010"),
+        (Some("P4:ERR".to_string()), Ok(Parsed::Unparsed))
+    );
+    assert!(replayed
+        .iter()
+        .all(|(exchange, _)| exchange.address == "/dev/fake"));
+
+    // Responses the parser rejects are reported
+    let garbled =
+        trace::parse("t\t/dev/fake\ttx\tPA\\n\nt\t/dev/fake\trx\tPPBA:12.2:x\\r\\n\n").unwrap();
+    assert!(trace::replay(&garbled)[0].1.is_err());
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn ppba_is_driven_without_a_runtime() {
+    use pegasus_astro::blocking::Ppba;
+
+    let fake = FakePpbaPort::new();
+    let ppba = Ppba::with_port("/dev/fake", Box::new(fake.clone())).unwrap();
+
+    assert_eq!(ppba.refresh().unwrap().input_voltage, 12.2);
+    ppba.set_dew_a_percent(50.0).unwrap();
+    ppba.set_quadport(false).unwrap();
+    ppba.set_adj_voltage(AdjustableVoltage::V5).unwrap();
+    assert_eq!(ppba.firmware_version().unwrap(), "1.4");
+
+    let sent = fake.sent_commands();
+    assert!(sent.contains(&"P3:128".to_owned()));
+    assert!(sent.contains(&"P1:0".to_owned()));
+    assert_eq!(ppba.snapshot().dew1_power, 128);
+}
+
+#[tokio::test]
+async fn readings_not_parsed_are_stale() {
+    let port = FakePpbaPort::new();
+    port.set_response("PA", "PPBA:garbage");
+    let mut ppba = fake_ppba(&port).await;
+
+    ppba.fetch_props().await;
+    assert!(ppba.is_connected());
+    let read_times = ppba.read_times().unwrap();
+    assert!(read_times.last_read("total_current").is_some());
+    assert!(read_times.is_reading("input_voltage"));
+    assert_eq!(read_times.last_read("input_voltage"), None);
+    assert!(read_times.is_stale(Duration::from_secs(60)));
+    // Only there once the firmware sends it
+    assert!(!read_times.is_reading("adj_output"));
+
+    port.set_response("PA", "PPBA:11.8:0.4:21.5:45:9.1:1:0:128:0:0:0:12");
+    ppba.fetch_props().await;
+    let read_times = ppba.read_times().unwrap();
+    assert!(read_times.last_read("input_voltage").is_some());
+    assert!(read_times.last_read("adj_output").is_some());
+    assert!(!read_times.is_stale(Duration::from_secs(60)));
+    assert!(read_times.is_stale(Duration::ZERO));
+}
+
+#[tokio::test]
+async fn partial_status_of_old_firmware_is_used() {
+    let port = FakePpbaPort::new();
+    port.set_response("PV", "1.2");
+    port.set_response("PA", "PPBA:11.8:0.4:21.5:45:9.1:1:0:64");
+    let mut ppba = fake_ppba(&port).await;
+    ppba.fetch_props().await;
+
+    let snapshot = ppba.snapshot();
+    assert_eq!(snapshot.input_voltage, 11.8);
+    assert!(snapshot.quadport_status);
+    assert_eq!(snapshot.dew1_power, 64);
+    assert_eq!(snapshot.dew2_power, 0);
+    let read_times = ppba.read_times().unwrap();
+    assert!(read_times.last_read("dew1_power").is_some());
+    assert_eq!(read_times.last_read("pwr_warn"), None);
+    // Fields the firmware doesn't send don't make the device stale
+    assert!(!read_times.is_stale(Duration::from_secs(60)));
+}
+
+#[tokio::test]
+async fn published_state_is_the_snapshot() {
+    let port = FakePpbaPort::simulated(0);
+    let mut ppba = fake_ppba(&port).await;
+    ppba.set_dew_power(DewChannel::B, 60).await.unwrap();
+    ppba.fetch_props().await;
+
+    let state = serde_json::to_value(&ppba).unwrap();
+    let serde_json::Value::Object(snapshot) = serde_json::to_value(ppba.snapshot()).unwrap() else {
+        panic!("a snapshot is an object");
+    };
+    assert!(state.get("id").is_none());
+    for (name, value) in snapshot.iter().filter(|(name, _)| *name != "id") {
+        let published = match name.as_str() {
+            "name" | "address" | "tripped_outputs" => &state[name],
+            _ => &state[name]["value"],
+        };
+        assert_eq!(published, value, "{}", name);
+    }
+}